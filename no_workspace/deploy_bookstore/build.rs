@@ -0,0 +1,13 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // Use the bundled protoc, so building doesn't need one installed
+    if std::env::var_os("PROTOC").is_none() {
+        std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    }
+    tonic_build::compile_protos("proto/auth.proto")?;
+
+    // `sqlx::migrate!` embeds the migrations, so rebuild when they change
+    for module in ["auth", "bookstore", "orders", "webhooks"] {
+        println!("cargo:rerun-if-changed=src/{module}/migrations");
+    }
+    Ok(())
+}
@@ -1,17 +1,313 @@
+use std::{fmt, sync::Arc};
+use anyhow::Result;
 use axum::{
-    extract::Request, http::{HeaderMap, StatusCode}, middleware::Next, response::IntoResponse, Extension
+    extract::{Request, State}, http::{header::AUTHORIZATION, HeaderMap, StatusCode}, middleware::Next, response::IntoResponse, Extension
 };
-use super::db;
+use serde::{Deserialize, Serialize};
+use crate::{
+    tenancy::{Tenant, TenantLookup, TenantSource, DEFAULT_TENANT},
+    tls::ClientCertificate,
+};
+use super::{db, remote::RemoteAuth};
+
+/// A permission an API key can be granted.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Scope {
+    #[serde(rename = "books:read")]
+    BooksRead,
+    #[serde(rename = "books:write")]
+    BooksWrite,
+    #[serde(rename = "users:admin")]
+    UsersAdmin,
+    #[serde(rename = "orders:admin")]
+    OrdersAdmin,
+    #[serde(rename = "reviews:moderate")]
+    ReviewsModerate,
+    #[serde(rename = "webhooks:admin")]
+    WebhooksAdmin,
+    #[serde(rename = "backups:admin")]
+    BackupsAdmin,
+    #[serde(rename = "tenants:admin")]
+    TenantsAdmin,
+}
+
+impl Scope {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::BooksRead => "books:read",
+            Self::BooksWrite => "books:write",
+            Self::UsersAdmin => "users:admin",
+            Self::OrdersAdmin => "orders:admin",
+            Self::ReviewsModerate => "reviews:moderate",
+            Self::WebhooksAdmin => "webhooks:admin",
+            Self::BackupsAdmin => "backups:admin",
+            Self::TenantsAdmin => "tenants:admin",
+        }
+    }
+
+    pub fn parse(scope: &str) -> Option<Self> {
+        match scope {
+            "books:read" => Some(Self::BooksRead),
+            "books:write" => Some(Self::BooksWrite),
+            "users:admin" => Some(Self::UsersAdmin),
+            "orders:admin" => Some(Self::OrdersAdmin),
+            "reviews:moderate" => Some(Self::ReviewsModerate),
+            "webhooks:admin" => Some(Self::WebhooksAdmin),
+            "backups:admin" => Some(Self::BackupsAdmin),
+            "tenants:admin" => Some(Self::TenantsAdmin),
+            _ => None,
+        }
+    }
+
+    /// Scopes over the whole deployment rather than one tenant. Only the
+    /// default tenant's users and keys can hold them.
+    pub fn is_deployment_wide(self) -> bool {
+        matches!(self, Self::WebhooksAdmin | Self::BackupsAdmin | Self::TenantsAdmin)
+    }
+
+    /// Parses a comma-separated list, as stored with a key.
+    pub fn parse_list(scopes: &str) -> Vec<Self> {
+        scopes.split(',').filter_map(|scope| Self::parse(scope.trim())).collect()
+    }
+}
+
+/// What a user may do. Local users created before roles existed are admins.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, PartialOrd)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Reader,
+    Editor,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Reader => "reader",
+            Self::Editor => "editor",
+            Self::Admin => "admin",
+        }
+    }
+
+    /// Parses a stored role. Anything unrecognised gets the least access.
+    pub fn parse(role: &str) -> Self {
+        match role {
+            "admin" => Self::Admin,
+            "editor" => Self::Editor,
+            _ => Self::Reader,
+        }
+    }
+
+    fn has_scope(self, scope: Scope) -> bool {
+        match self {
+            Self::Admin => true,
+            Self::Editor => matches!(scope, Scope::BooksRead | Scope::BooksWrite),
+            Self::Reader => scope == Scope::BooksRead,
+        }
+    }
+}
+
+/// Who made an authenticated request. `require_token` adds it to the
+/// request's extensions.
+#[derive(Clone, Debug)]
+pub enum Principal {
+    /// A signed-in user, by token or client certificate, with the scopes of
+    /// their role.
+    User { user_id: i32, role: Role, tenant_id: i32 },
+    /// A service calling with an API key, limited to the key's scopes.
+    ApiKey { key_id: i32, scopes: Vec<Scope>, tenant_id: i32 },
+}
+
+impl Principal {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        if scope.is_deployment_wide() && self.tenant_id() != DEFAULT_TENANT {
+            return false;
+        }
+        match self {
+            Self::User { role, .. } => role.has_scope(scope),
+            Self::ApiKey { scopes, .. } => scopes.contains(&scope),
+        }
+    }
+
+    /// The tenant the user or key belongs to.
+    pub fn tenant_id(&self) -> i32 {
+        match self {
+            Self::User { tenant_id, .. } | Self::ApiKey { tenant_id, .. } => *tenant_id,
+        }
+    }
+
+    /// The signed-in user, if it isn't an API key.
+    pub fn user_id(&self) -> Option<i32> {
+        match self {
+            Self::User { user_id, .. } => Some(*user_id),
+            Self::ApiKey { .. } => None,
+        }
+    }
+
+    /// Returns 403 unless the principal has `scope`.
+    pub fn require(&self, scope: Scope) -> Result<(), StatusCode> {
+        if self.has_scope(scope) {
+            Ok(())
+        } else {
+            Err(StatusCode::FORBIDDEN)
+        }
+    }
+}
+
+impl fmt::Display for Principal {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::User { user_id, .. } => write!(f, "user:{user_id}"),
+            Self::ApiKey { key_id, .. } => write!(f, "apikey:{key_id}"),
+        }
+    }
+}
 
+/// A credential presented with a request.
 #[derive(Clone, Copy, Debug)]
-pub struct ValidUser(pub i32);
+pub enum Credential<'a> {
+    /// The `Token` header, from logging in.
+    SessionToken(&'a str),
+    /// The `Authorization: ApiKey` header.
+    ApiKey(&'a str),
+    /// The common name of a verified client certificate.
+    ClientCertificate(&'a str),
+}
+
+/// Where `require_token` checks credentials. The service adds it as an
+/// extension, so every module's routes authenticate the same way.
+#[derive(Clone)]
+pub enum Authenticator {
+    /// This instance's auth database.
+    Local(db::AuthDb),
+    /// A separate auth service, over gRPC.
+    Remote(Arc<RemoteAuth>),
+}
+
+impl Authenticator {
+    /// Finds who a credential belongs to, within `tenant` or, given
+    /// `None`, whichever tenant it belongs to. `None` if it's unknown,
+    /// expired or revoked.
+    pub async fn authenticate(&self, credential: Credential<'_>, tenant: Option<i32>) -> Result<Option<Principal>> {
+        match self {
+            Self::Local(db_pool) => local_principal(db_pool.clone(), credential, tenant).await,
+            Self::Remote(remote) => remote.authenticate(credential, tenant).await,
+        }
+    }
+
+    /// Finds a tenant. `None` if there's no such tenant.
+    pub async fn tenant(&self, lookup: TenantLookup<'_>) -> Result<Option<Tenant>> {
+        match self {
+            Self::Local(db_pool) => db::get_tenant(db_pool.clone(), lookup).await,
+            Self::Remote(remote) => remote.tenant(lookup).await,
+        }
+    }
+
+    pub fn failure(&self, error: anyhow::Error) -> (StatusCode, String) {
+        match self {
+            Self::Local(_) => (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()),
+            Self::Remote(_) => {
+                tracing::warn!("Unable to reach the auth service: {error:#}");
+                (StatusCode::SERVICE_UNAVAILABLE, "auth service unavailable".to_string())
+            }
+        }
+    }
+}
 
+/// Checks a credential against the auth database. Common names are only
+/// unique within a tenant, so certificates are always checked within one.
+pub async fn local_principal(
+    db_pool: db::AuthDb,
+    credential: Credential<'_>,
+    tenant: Option<i32>,
+) -> Result<Option<Principal>> {
+    let principal = match credential {
+        Credential::SessionToken(token) => db::get_user_from_token(db_pool, token, tenant)
+            .await?
+            .map(|(user_id, role, tenant_id)| Principal::User {
+                user_id,
+                role: Role::parse(&role),
+                tenant_id,
+            }),
+        Credential::ApiKey(key) => db::get_api_key(db_pool, key, tenant)
+            .await?
+            .map(|grant| Principal::ApiKey {
+                key_id: grant.id,
+                scopes: Scope::parse_list(&grant.scopes),
+                tenant_id: grant.tenant_id,
+            }),
+        Credential::ClientCertificate(common_name) => {
+            db::get_user_from_certificate(db_pool, common_name, tenant.unwrap_or(DEFAULT_TENANT))
+                .await?
+                .map(|(user_id, role)| Principal::User {
+                    user_id,
+                    role: Role::parse(&role),
+                    tenant_id: tenant.unwrap_or(DEFAULT_TENANT),
+                })
+        }
+    };
+    Ok(principal)
+}
+
+/// Refuses requests without a valid credential, adding the `Principal` to
+/// the request's extensions. Credentials only count within the request's
+/// tenant, unless nothing named one; then the request is for the
+/// credential's own tenant.
 pub async fn require_token(
-    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(authenticator): Extension<Authenticator>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(source): Extension<TenantSource>,
     headers: HeaderMap,
     mut req: Request,
     next: Next,
 ) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let within = (source != TenantSource::Unnamed).then_some(tenant.id);
+    let certificate = req.extensions().get::<ClientCertificate>().cloned();
+    let principal = identify(&authenticator, within, tenant.id, &headers, certificate.as_ref())
+        .await?
+        .ok_or((StatusCode::UNAUTHORIZED, "invalid header".to_string()))?;
+
+    if principal.tenant_id() != tenant.id {
+        if within.is_some() {
+            return Err((StatusCode::UNAUTHORIZED, "invalid header".to_string()));
+        }
+        let tenant = authenticator
+            .tenant(TenantLookup::Id(principal.tenant_id()))
+            .await
+            .map_err(|e| authenticator.failure(e))?
+            .ok_or((StatusCode::UNAUTHORIZED, "invalid header".to_string()))?;
+        req.extensions_mut().insert(tenant);
+        req.extensions_mut().insert(TenantSource::Credential);
+    }
+    req.extensions_mut().insert(principal);
+    Ok(next.run(req).await)
+}
+
+/// Finds who made a request from the first of its credentials that's
+/// valid. An API key that isn't is refused outright; other `Authorization`
+/// schemes, such as a proxy's `Bearer`, are left alone.
+async fn identify(
+    authenticator: &Authenticator,
+    within: Option<i32>,
+    tenant_id: i32,
+    headers: &HeaderMap,
+    certificate: Option<&ClientCertificate>,
+) -> Result<Option<Principal>, (StatusCode, String)> {
+    let api_key = headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("ApiKey "));
+    if let Some(key) = api_key {
+        return match authenticator
+            .authenticate(Credential::ApiKey(key.trim()), within)
+            .await
+            .map_err(|e| authenticator.failure(e))?
+        {
+            Some(principal) => Ok(Some(principal)),
+            None => Err((StatusCode::UNAUTHORIZED, "invalid API key".to_string())),
+        };
+    }
+
     if let Some(auth_header) = headers.get("Token") {
         let token = auth_header.to_str().map_err(|_| {
             (
@@ -20,19 +316,36 @@ pub async fn require_token(
             )
         })?;
 
-        if let Some(user_id) = db::get_user_id_from_token(db_pool, token)
+        if let Some(principal) = authenticator
+            .authenticate(Credential::SessionToken(token), within)
             .await
-            .map_err(|_| {
-                (
-                    StatusCode::INTERNAL_SERVER_ERROR,
-                    "database error".to_string(),
-                )
-            })?
+            .map_err(|e| authenticator.failure(e))?
         {
-            req.extensions_mut().insert(ValidUser(user_id));
-            return Ok(next.run(req).await);
+            return Ok(Some(principal));
         }
     }
 
-    Err((StatusCode::UNAUTHORIZED, "invalid header".to_string()))
+    // Service accounts on mutual TLS connections are identified by the
+    // common name of their client certificate.
+    if let Some(certificate) = certificate {
+        return authenticator
+            .authenticate(Credential::ClientCertificate(&certificate.common_name), Some(tenant_id))
+            .await
+            .map_err(|e| authenticator.failure(e));
+    }
+
+    Ok(None)
+}
+
+/// Refuses requests whose principal lacks a scope. Add it with
+/// `middleware::from_fn_with_state(scope, require_scope)`, inside
+/// `require_token`.
+pub async fn require_scope(
+    State(scope): State<Scope>,
+    Extension(principal): Extension<Principal>,
+    req: Request,
+    next: Next,
+) -> Result<impl IntoResponse, StatusCode> {
+    principal.require(scope)?;
+    Ok(next.run(req).await)
 }
@@ -0,0 +1,70 @@
+use anyhow::{bail, Result};
+use super::{configuration::AuthConfiguration, db::{self, AuthDb, User}};
+use crate::tenancy::TenantLookup;
+
+async fn open_database(config: &AuthConfiguration) -> Result<AuthDb> {
+    let db_pool = db::get_connection_pool(&config.db_filename).await?;
+    db::perform_migrations(db_pool.clone()).await?;
+    Ok(db_pool)
+}
+
+async fn require_tenant(db_pool: AuthDb, slug: &str) -> Result<i32> {
+    match db::get_tenant(db_pool, TenantLookup::Slug(slug)).await? {
+        Some(tenant) => Ok(tenant.id),
+        None => bail!("no such tenant: {slug}"),
+    }
+}
+
+async fn require_user(db_pool: AuthDb, tenant: &str, username: &str) -> Result<i32> {
+    let tenant_id = require_tenant(db_pool.clone(), tenant).await?;
+    match db::find_user_id(db_pool, tenant_id, username).await? {
+        Some(user_id) => Ok(user_id),
+        None => bail!("no such user: {username}"),
+    }
+}
+
+/// Creates a user. A service account may also sign in with a client
+/// certificate whose common name is its username.
+pub async fn create_user(
+    config: &AuthConfiguration,
+    tenant: &str,
+    username: &str,
+    password: &str,
+    service_account: bool,
+) -> Result<()> {
+    let db_pool = open_database(config).await?;
+    let tenant_id = require_tenant(db_pool.clone(), tenant).await?;
+    if db::find_user_id(db_pool.clone(), tenant_id, username).await?.is_some() {
+        bail!("user {username} already exists");
+    }
+
+    let user = User {
+        id: 0,
+        username: username.to_string(),
+        password: password.to_string(),
+    };
+    let user_id = db::add_user(db_pool.clone(), tenant_id, &user).await?;
+    if service_account {
+        db::set_service_account(db_pool, user_id).await?;
+        println!("Created service account {username}");
+    } else {
+        println!("Created user {username}");
+    }
+    Ok(())
+}
+
+pub async fn set_password(config: &AuthConfiguration, tenant: &str, username: &str, password: &str) -> Result<()> {
+    let db_pool = open_database(config).await?;
+    let user_id = require_user(db_pool.clone(), tenant, username).await?;
+    db::set_password(db_pool, user_id, password).await?;
+    println!("Changed password for {username}");
+    Ok(())
+}
+
+pub async fn disable_user(config: &AuthConfiguration, tenant: &str, username: &str) -> Result<()> {
+    let db_pool = open_database(config).await?;
+    let user_id = require_user(db_pool.clone(), tenant, username).await?;
+    db::disable_user(db_pool, user_id).await?;
+    println!("Disabled {username} and revoked their tokens");
+    Ok(())
+}
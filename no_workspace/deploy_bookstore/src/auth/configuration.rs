@@ -1,26 +1,278 @@
-use anyhow::Result;
-use config::Config;
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
+use crate::service_config::{check_writable_file, string_list};
+use crate::tenancy::Overridable;
+use super::auth_layers::Role;
 
+/// The `auth` section of the service configuration.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct AuthConfiguration {
+    /// Whether this instance hosts the auth module or uses another
+    /// instance's, over gRPC.
+    pub mode: AuthMode,
     pub db_filename: String,
+    /// Allow logging in with a local username and password at `/login`.
+    pub local_login: bool,
+    /// Single sign-on through an OpenID Connect provider.
+    pub oidc: Option<OidcConfiguration>,
+    /// The name authenticator apps show next to two-factor codes.
+    pub mfa_issuer: String,
+    /// Let anyone create a reader account at `/register`.
+    pub open_registration: bool,
+    /// The shortest password users can choose for themselves.
+    pub min_password_length: usize,
+    /// How long a password reset token stays valid.
+    pub password_reset_minutes: u64,
+    /// Where reset tokens are posted, as JSON with the user's username and
+    /// email, for a mailer to send on. Passwords can only be reset when
+    /// it's set.
+    pub password_reset_url: Option<String>,
+    /// Writes reset tokens to the log instead, for trying resets out
+    /// locally. Only allowed in development builds without TLS.
+    pub password_reset_log: bool,
+    /// Serves the gRPC API, so instances in remote mode can use this one.
+    pub grpc: Option<GrpcConfiguration>,
+    /// The auth service to use in remote mode.
+    pub remote: Option<RemoteAuthConfiguration>,
+}
+
+impl Default for AuthConfiguration {
+    fn default() -> Self {
+        Self {
+            mode: AuthMode::Local,
+            db_filename: "auth.db".to_string(),
+            local_login: true,
+            oidc: None,
+            mfa_issuer: "Bookstore".to_string(),
+            open_registration: false,
+            min_password_length: 8,
+            password_reset_minutes: 30,
+            password_reset_url: None,
+            password_reset_log: false,
+            grpc: None,
+            remote: None,
+        }
+    }
+}
+
+impl Overridable for AuthConfiguration {
+    const SECTION: &'static str = "auth";
+    const OVERRIDABLE: &'static [&'static str] = &["open_registration", "min_password_length", "mfa_issuer"];
 }
 
 impl AuthConfiguration {
-    pub fn load() -> Result<Self> {
-        // Load any .env files
-        // Ignore the result of loading .env --- it's ok if it doesn't exist
-        let _ = dotenvy::dotenv();
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.mode == AuthMode::Remote {
+            match &self.remote {
+                Some(remote) => remote.validate(problems),
+                None => problems.push("auth.mode: remote needs auth.remote".to_string()),
+            }
+            if self.grpc.is_some() {
+                problems.push("auth.grpc: can only be served in local mode".to_string());
+            }
+            return;
+        }
 
-        let settings_reader = Config::builder()
-            .add_source(config::File::with_name("settings").required(false))
-            .add_source(config::Environment::with_prefix("AUTH"))
-            .build()?;
+        check_writable_file("auth.db_filename", &self.db_filename, problems);
+        if let Some(grpc) = &self.grpc {
+            grpc.validate(problems);
+        }
+        if self.open_registration && !self.local_login {
+            problems.push("auth.open_registration: needs auth.local_login".to_string());
+        }
+        if self.password_reset_minutes == 0 {
+            problems.push("auth.password_reset_minutes: must be at least 1".to_string());
+        }
+        if let Some(url) = &self.password_reset_url {
+            if !url.starts_with("http://") && !url.starts_with("https://") {
+                problems.push(format!("auth.password_reset_url: {url:?} must be an http or https URL"));
+            }
+            if !self.local_login {
+                problems.push("auth.password_reset_url: needs auth.local_login".to_string());
+            }
+        }
+        if self.password_reset_log {
+            if !cfg!(debug_assertions) {
+                problems.push("auth.password_reset_log: only allowed in development builds".to_string());
+            }
+            if self.password_reset_url.is_some() {
+                problems.push("auth.password_reset_log: can't be used with auth.password_reset_url".to_string());
+            }
+            if !self.local_login {
+                problems.push("auth.password_reset_log: needs auth.local_login".to_string());
+            }
+        }
+        match &self.oidc {
+            Some(oidc) => oidc.validate(problems),
+            None if !self.local_login => {
+                problems.push("auth.local_login: can't be disabled without auth.oidc".to_string());
+            }
+            None => {}
+        }
+    }
+}
 
-        let settings = settings_reader
-            .try_deserialize()?;
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum AuthMode {
+    /// Users and tokens are in this instance's database.
+    Local,
+    /// Credentials are checked by a separate auth service, and this
+    /// instance doesn't serve the auth routes.
+    Remote,
+}
 
-        Ok(settings)
+/// The `auth.grpc` section: the API other instances validate tokens with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GrpcConfiguration {
+    pub listen_address: String,
+    pub listen_port: u16,
+    /// Callers must send this as a bearer token.
+    pub shared_secret: String,
+    /// Serve over TLS with the certificate in the `tls` section. Without
+    /// it, credentials cross the network in the clear, so the port must
+    /// only be reachable on a private network.
+    pub tls: bool,
+}
+
+impl Default for GrpcConfiguration {
+    fn default() -> Self {
+        Self {
+            listen_address: "127.0.0.1".to_string(),
+            listen_port: 50051,
+            shared_secret: String::new(),
+            tls: false,
+        }
+    }
+}
+
+impl GrpcConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.listen_address.parse::<std::net::IpAddr>().is_err() {
+            problems.push(format!(
+                "auth.grpc.listen_address: {:?} is not an IP address",
+                self.listen_address
+            ));
+        }
+        if self.shared_secret.len() < 16 {
+            problems.push("auth.grpc.shared_secret: must be at least 16 characters".to_string());
+        }
     }
-}
\ No newline at end of file
+}
+
+/// The `auth.remote` section: where to check credentials in remote mode.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RemoteAuthConfiguration {
+    /// The auth service's gRPC endpoint, such as `http://auth:50051`, or
+    /// `https://auth:50051` if it serves gRPC over TLS.
+    pub url: String,
+    /// PEM bundle of CAs to trust for an `https` url, as well as the
+    /// public ones.
+    pub ca_path: Option<String>,
+    /// The auth service's `auth.grpc.shared_secret`.
+    pub shared_secret: String,
+    /// How long a validated credential is trusted before asking again.
+    /// Revoked tokens keep working for up to this long.
+    pub cache_seconds: u64,
+    pub timeout_seconds: u64,
+}
+
+impl Default for RemoteAuthConfiguration {
+    fn default() -> Self {
+        Self {
+            url: String::new(),
+            ca_path: None,
+            shared_secret: String::new(),
+            cache_seconds: 30,
+            timeout_seconds: 5,
+        }
+    }
+}
+
+impl RemoteAuthConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.url.parse::<tonic::transport::Uri>().map_or(true, |uri| uri.scheme().is_none()) {
+            problems.push(format!("auth.remote.url: {:?} is not a valid URL", self.url));
+        }
+        if let Some(path) = &self.ca_path {
+            if !std::path::Path::new(path).is_file() {
+                problems.push(format!("auth.remote.ca_path: {path} does not exist"));
+            }
+        }
+        if self.shared_secret.is_empty() {
+            problems.push("auth.remote.shared_secret: must be set".to_string());
+        }
+        if self.timeout_seconds == 0 {
+            problems.push("auth.remote.timeout_seconds: must be at least 1".to_string());
+        }
+    }
+}
+
+/// The `auth.oidc` section: an OpenID Connect provider, used with the
+/// authorization code flow and PKCE.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OidcConfiguration {
+    /// The provider's issuer, such as `https://login.example.com/realms/shop`.
+    /// Its endpoints are found through discovery.
+    pub issuer_url: String,
+    pub client_id: String,
+    /// Only needed for confidential clients.
+    pub client_secret: Option<String>,
+    /// Where the provider sends users back to: this service's
+    /// `/api/v1/auth/oidc/callback`.
+    pub redirect_url: String,
+    #[serde(deserialize_with = "string_list")]
+    pub scopes: Vec<String>,
+    /// The ID token claim used as the username of new users.
+    pub username_claim: String,
+    /// The ID token claim holding the user's groups or roles.
+    pub role_claim: String,
+    /// Maps values of `role_claim` to roles. The most powerful match wins.
+    pub role_mapping: HashMap<String, Role>,
+    /// The role for users who match nothing in `role_mapping`. `None`
+    /// refuses them.
+    pub default_role: Option<Role>,
+    /// How long to keep the provider's signing keys before fetching them
+    /// again.
+    pub jwks_cache_seconds: u64,
+}
+
+impl Default for OidcConfiguration {
+    fn default() -> Self {
+        Self {
+            issuer_url: String::new(),
+            client_id: String::new(),
+            client_secret: None,
+            redirect_url: String::new(),
+            scopes: vec!["openid".to_string(), "profile".to_string(), "email".to_string()],
+            username_claim: "preferred_username".to_string(),
+            role_claim: "groups".to_string(),
+            role_mapping: HashMap::new(),
+            default_role: Some(Role::Reader),
+            jwks_cache_seconds: 3600,
+        }
+    }
+}
+
+impl OidcConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        for (setting, url) in [
+            ("auth.oidc.issuer_url", &self.issuer_url),
+            ("auth.oidc.redirect_url", &self.redirect_url),
+        ] {
+            if reqwest::Url::parse(url).is_err() {
+                problems.push(format!("{setting}: {url:?} is not a valid URL"));
+            }
+        }
+        if self.client_id.is_empty() {
+            problems.push("auth.oidc.client_id: must be set".to_string());
+        }
+        if !self.scopes.iter().any(|scope| scope == "openid") {
+            problems.push("auth.oidc.scopes: must include openid".to_string());
+        }
+    }
+}
@@ -1,6 +1,16 @@
-use anyhow::Result;
+use std::sync::LazyLock;
+use anyhow::{anyhow, Result};
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, sqlite::SqliteConnectOptions, Row};
+use sha2::{Digest, Sha256};
+use sqlx::{migrate::Migrator, prelude::FromRow, sqlite::SqliteConnectOptions, Row, SqliteConnection};
+use crate::{
+    event_bus::DomainEvent,
+    tenancy::{Tenant, TenantLookup, TenantOverrides},
+};
 
 #[derive(Clone)]
 pub struct AuthDb(pub sqlx::SqlitePool);
@@ -10,27 +20,90 @@ pub async fn get_connection_pool(filename: &str) -> Result<AuthDb> {
         .filename(filename)
         .create_if_missing(true);
 
-    let connection_pool = sqlx::SqlitePool::connect_with(options)
-        .await?;
+    let connection_pool = sqlx::SqlitePool::connect_lazy_with(options);
     Ok(AuthDb(connection_pool))
 }
 
+/// This build's migrations, up and down.
+pub static MIGRATOR: Migrator = sqlx::migrate!("src/auth/migrations");
+
 pub async fn perform_migrations(db_pool: AuthDb) -> Result<()> {
-    sqlx::migrate!("src/auth/migrations")
-        .run(&db_pool.0)
-        .await?;
+    crate::migrations::migrate("auth", &MIGRATOR, &db_pool.0).await
+}
+
+pub async fn ping(db_pool: AuthDb) -> Result<()> {
+    sqlx::query("SELECT 1").execute(&db_pool.0).await?;
     Ok(())
 }
 
-pub async fn login(db_pool: AuthDb, username: &str, password: &str) -> Result<Option<i32>> {
-    let user_id = sqlx::query("SELECT id FROM users WHERE username = ? AND password = ?")
+/// Hashes a password for storage with Argon2id and a random salt. It's
+/// slow on purpose, so it runs off the async threads.
+pub async fn hash_password(password: &str) -> Result<String> {
+    let password = password.to_string();
+    tokio::task::spawn_blocking(move || {
+        let salt = SaltString::generate(&mut OsRng);
+        let hash = Argon2::default()
+            .hash_password(password.as_bytes(), &salt)
+            .map_err(|e| anyhow!("unable to hash a password: {e}"))?;
+        Ok(hash.to_string())
+    })
+    .await?
+}
+
+/// Checks a password against the stored one. Passwords stored before they
+/// were hashed are compared as they are, until `login` rehashes them.
+async fn verify_password(stored: &str, password: &str) -> Result<bool> {
+    if !is_password_hash(stored) {
+        return Ok(!stored.is_empty() && stored == password);
+    }
+    let (stored, password) = (stored.to_string(), password.to_string());
+    tokio::task::spawn_blocking(move || {
+        let hash = PasswordHash::new(&stored).map_err(|e| anyhow!("unreadable password hash: {e}"))?;
+        Ok(Argon2::default().verify_password(password.as_bytes(), &hash).is_ok())
+    })
+    .await?
+}
+
+fn is_password_hash(stored: &str) -> bool {
+    stored.starts_with("$argon2")
+}
+
+/// Checked in place of a missing user's password, so that logging in as
+/// one takes as long as a wrong password does.
+static MISSING_USER_PASSWORD: LazyLock<String> = LazyLock::new(|| {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(b"no such user", &salt)
+        .expect("hashing a constant can't fail")
+        .to_string()
+});
+
+/// Checks a local user's password. Users provisioned by single sign-on
+/// can't log in this way.
+pub async fn login(db_pool: AuthDb, tenant_id: i32, username: &str, password: &str) -> Result<Option<i32>> {
+    let user = sqlx::query(
+        "SELECT id, password FROM users
+         WHERE tenant_id = ? AND username = ? AND disabled = 0 AND oidc_subject IS NULL",
+    )
+        .bind(tenant_id)
         .bind(username)
-        .bind(password)
         .fetch_optional(&db_pool.0)
         .await?
-        .map(|row| row.get::<i32, _>(0) as i32);
+        .map(|row| (row.get::<i32, _>(0), row.get::<String, _>(1)));
 
-    Ok(user_id)
+    let Some((user_id, stored)) = user else {
+        let missing_user_password = tokio::task::spawn_blocking(|| MISSING_USER_PASSWORD.as_str()).await?;
+        verify_password(missing_user_password, password).await?;
+        return Ok(None);
+    };
+    if !verify_password(&stored, password).await? {
+        return Ok(None);
+    }
+    if !is_password_hash(&stored) {
+        set_password(db_pool, user_id, password).await?;
+    }
+
+    Ok(Some(user_id))
 }
 
 pub async fn add_token(db_pool: AuthDb, user_id: i32) -> Result<String> {
@@ -45,42 +118,144 @@ pub async fn add_token(db_pool: AuthDb, user_id: i32) -> Result<String> {
     Ok(new_token)
 }
 
-pub async fn get_user_id_from_token(db_pool: AuthDb, token: &str) -> Result<Option<i32>> {
-    let user_id = sqlx::query("SELECT user_id FROM tokens WHERE token = ?")
+/// Finds the id, role and tenant of the enabled user a token belongs to,
+/// within `tenant_id` or, given `None`, any tenant.
+pub async fn get_user_from_token(
+    db_pool: AuthDb,
+    token: &str,
+    tenant_id: Option<i32>,
+) -> Result<Option<(i32, String, i32)>> {
+    let user = sqlx::query(
+        "SELECT users.id, users.role, users.tenant_id FROM tokens JOIN users ON users.id = tokens.user_id
+         WHERE token = ?1 AND users.disabled = 0 AND (?2 IS NULL OR users.tenant_id = ?2)",
+    )
         .bind(token)
+        .bind(tenant_id)
         .fetch_optional(&db_pool.0)
         .await?
-        .map(|row| row.get::<i32, _>(0) as i32);
+        .map(|row| (row.get(0), row.get(1), row.get(2)));
 
-    Ok(user_id)
+    Ok(user)
 }
 
 #[derive(Deserialize, Serialize, Debug, FromRow)]
 pub struct User {
-    id: i32,
-    username: String,
-    password: String,
+    pub id: i32,
+    pub username: String,
+    /// A new password when the user is written, or the stored hash when
+    /// read. The hash is never sent out.
+    #[serde(skip_serializing)]
+    pub password: String,
 }
 
-pub async fn get_all_users(db_pool: AuthDb) -> Result<Vec<User>> {
-    let users = sqlx::query_as::<_, User>("SELECT id, username, password FROM users")
+pub async fn get_all_users(db_pool: AuthDb, tenant_id: i32) -> Result<Vec<User>> {
+    let users = sqlx::query_as::<_, User>("SELECT id, username, password FROM users WHERE tenant_id = ?")
+        .bind(tenant_id)
         .fetch_all(&db_pool.0)
         .await?;
 
     Ok(users)
 }
 
-pub async fn get_user(db_pool: AuthDb, user_id: i32) -> Result<Option<User>> {
-    let user = sqlx::query_as::<_, User>("SELECT id, username, password FROM users WHERE id = ?")
+pub async fn get_user(db_pool: AuthDb, tenant_id: i32, user_id: i32) -> Result<Option<User>> {
+    let user = sqlx::query_as::<_, User>("SELECT id, username, password FROM users WHERE id = ? AND tenant_id = ?")
         .bind(user_id)
+        .bind(tenant_id)
         .fetch_optional(&db_pool.0)
         .await?;
 
     Ok(user)
 }
 
-pub async fn delete_user(db_pool: AuthDb, user_id: i32) -> Result<()> {
-    sqlx::query("DELETE FROM users WHERE id = ?")
+/// Deletes a user, returning false if the tenant has no such user.
+pub async fn delete_user(db_pool: AuthDb, tenant_id: i32, user_id: i32) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let deleted = sqlx::query("DELETE FROM users WHERE id = ? AND tenant_id = ?")
+        .bind(user_id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted > 0 {
+        record_user_event(&mut tx, "user.deleted", tenant_id, user_id, None).await?;
+    }
+    tx.commit().await?;
+
+    Ok(deleted > 0)
+}
+
+/// Updates a user, returning false if the tenant has no such user.
+pub async fn update_user(db_pool: AuthDb, tenant_id: i32, user_id: i32, user: &User) -> Result<bool> {
+    let hash = hash_password(&user.password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    let updated = sqlx::query("UPDATE users SET username = ?, password = ? WHERE id = ? AND tenant_id = ?")
+        .bind(&user.username)
+        .bind(hash)
+        .bind(user_id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated > 0 {
+        record_user_event(&mut tx, "user.updated", tenant_id, user_id, Some(&user.username)).await?;
+    }
+    tx.commit().await?;
+
+    Ok(updated > 0)
+}
+
+/// Adds a user to a tenant, returning their id.
+pub async fn add_user(db_pool: AuthDb, tenant_id: i32, user: &User) -> Result<i32> {
+    let hash = hash_password(&user.password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    let user_id = sqlx::query("INSERT INTO users (username, password, tenant_id) VALUES (?, ?, ?) RETURNING id")
+        .bind(&user.username)
+        .bind(hash)
+        .bind(tenant_id)
+        .fetch_one(&mut *tx)
+        .await?
+        .get::<i32, _>(0);
+    record_user_event(&mut tx, "user.created", tenant_id, user_id, Some(&user.username)).await?;
+    tx.commit().await?;
+
+    Ok(user_id)
+}
+
+pub async fn find_user_id(db_pool: AuthDb, tenant_id: i32, username: &str) -> Result<Option<i32>> {
+    let user_id = sqlx::query("SELECT id FROM users WHERE tenant_id = ? AND username = ?")
+        .bind(tenant_id)
+        .bind(username)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| row.get::<i32, _>(0));
+
+    Ok(user_id)
+}
+
+/// Finds the id and role of the tenant's enabled service account a client
+/// certificate's common name belongs to. Other users can't sign in with a
+/// certificate.
+pub async fn get_user_from_certificate(
+    db_pool: AuthDb,
+    common_name: &str,
+    tenant_id: i32,
+) -> Result<Option<(i32, String)>> {
+    let user = sqlx::query("SELECT id, role FROM users
+         WHERE tenant_id = ? AND username = ? AND disabled = 0 AND service_account = 1",
+    )
+        .bind(tenant_id)
+        .bind(common_name)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| (row.get(0), row.get(1)));
+
+    Ok(user)
+}
+
+/// Marks a user as a service account, which may sign in with a client
+/// certificate.
+pub async fn set_service_account(db_pool: AuthDb, user_id: i32) -> Result<()> {
+    sqlx::query("UPDATE users SET service_account = 1 WHERE id = ?")
         .bind(user_id)
         .execute(&db_pool.0)
         .await?;
@@ -88,10 +263,9 @@ pub async fn delete_user(db_pool: AuthDb, user_id: i32) -> Result<()> {
     Ok(())
 }
 
-pub async fn update_user(db_pool: AuthDb, user_id: i32, user: &User) -> Result<()> {
-    sqlx::query("UPDATE users SET username = ?, password = ? WHERE id = ?")
-        .bind(&user.username)
-        .bind(&user.password)
+pub async fn set_password(db_pool: AuthDb, user_id: i32, password: &str) -> Result<()> {
+    sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+        .bind(hash_password(password).await?)
         .bind(user_id)
         .execute(&db_pool.0)
         .await?;
@@ -99,12 +273,781 @@ pub async fn update_user(db_pool: AuthDb, user_id: i32, user: &User) -> Result<(
     Ok(())
 }
 
-pub async fn add_user(db_pool: AuthDb, user: &User) -> Result<()> {
-    sqlx::query("INSERT INTO users (username, password) VALUES (?, ?)")
-        .bind(&user.username)
-        .bind(&user.password)
+/// Disables a user and revokes all of their tokens.
+pub async fn disable_user(db_pool: AuthDb, user_id: i32) -> Result<()> {
+    let mut tx = db_pool.0.begin().await?;
+    sqlx::query("UPDATE users SET disabled = 1 WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// An API key as listed to admins. The key itself is only shown once, when
+/// it's created; the database keeps a SHA-256 hash of it.
+#[derive(Serialize, Debug, FromRow)]
+pub struct ApiKey {
+    pub id: i32,
+    pub name: String,
+    pub prefix: String,
+    pub scopes: String,
+    /// The principal that created the key, such as `user:1`.
+    pub created_by: String,
+    pub created_at: i64,
+    pub expires_at: Option<i64>,
+    pub last_used_at: Option<i64>,
+    pub revoked: bool,
+}
+
+/// A valid API key, as found by `get_api_key`.
+pub struct ApiKeyGrant {
+    pub id: i32,
+    pub scopes: String,
+    pub tenant_id: i32,
+}
+
+/// Hashes a high-entropy secret, such as an API key or recovery code, for
+/// storage.
+pub fn hash_secret(secret: &str) -> String {
+    format!("{:x}", Sha256::digest(secret.as_bytes()))
+}
+
+/// Creates an API key, returning its id and the key. Keys look like
+/// `bk_<prefix>_<secret>`; the prefix identifies the key in listings.
+pub async fn add_api_key(
+    db_pool: AuthDb,
+    tenant_id: i32,
+    name: &str,
+    scopes: &str,
+    created_by: &str,
+    expires_at: Option<i64>,
+) -> Result<(i32, String)> {
+    let prefix = uuid::Uuid::new_v4().simple().to_string()[..8].to_string();
+    let secret = uuid::Uuid::new_v4().simple().to_string();
+    let key = format!("bk_{prefix}_{secret}");
+
+    let id = sqlx::query(
+        "INSERT INTO api_keys (tenant_id, name, prefix, key_hash, scopes, created_by, created_at, expires_at)
+         VALUES (?, ?, ?, ?, ?, ?, unixepoch(), ?) RETURNING id",
+    )
+        .bind(tenant_id)
+        .bind(name)
+        .bind(&prefix)
+        .bind(hash_secret(&key))
+        .bind(scopes)
+        .bind(created_by)
+        .bind(expires_at)
+        .fetch_one(&db_pool.0)
+        .await?
+        .get::<i32, _>(0);
+
+    Ok((id, key))
+}
+
+pub async fn list_api_keys(db_pool: AuthDb, tenant_id: i32) -> Result<Vec<ApiKey>> {
+    let keys = sqlx::query_as::<_, ApiKey>(
+        "SELECT id, name, prefix, scopes, created_by, created_at, expires_at, last_used_at, revoked
+         FROM api_keys WHERE tenant_id = ?",
+    )
+        .bind(tenant_id)
+        .fetch_all(&db_pool.0)
+        .await?;
+
+    Ok(keys)
+}
+
+/// Revokes a key, returning false if the tenant has no such key.
+pub async fn revoke_api_key(db_pool: AuthDb, tenant_id: i32, key_id: i32) -> Result<bool> {
+    let result = sqlx::query("UPDATE api_keys SET revoked = 1 WHERE id = ? AND tenant_id = ?")
+        .bind(key_id)
+        .bind(tenant_id)
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// How up to date an API key's `last_used_at` is kept.
+const LAST_USED_RESOLUTION_SECONDS: i64 = 60;
+
+/// Looks up an unexpired, unrevoked key, within `tenant_id` or, given
+/// `None`, any tenant, and records that it was used, to the minute.
+pub async fn get_api_key(db_pool: AuthDb, key: &str, tenant_id: Option<i32>) -> Result<Option<ApiKeyGrant>> {
+    let Some(row) = sqlx::query(
+        "SELECT id, scopes, tenant_id, last_used_at >= unixepoch() - ?3 FROM api_keys
+         WHERE key_hash = ?1 AND revoked = 0 AND (?2 IS NULL OR tenant_id = ?2)
+           AND (expires_at IS NULL OR expires_at > unixepoch())",
+    )
+        .bind(hash_secret(key))
+        .bind(tenant_id)
+        .bind(LAST_USED_RESOLUTION_SECONDS)
+        .fetch_optional(&db_pool.0)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let grant = ApiKeyGrant {
+        id: row.get(0),
+        scopes: row.get(1),
+        tenant_id: row.get(2),
+    };
+
+    // Writing on every request would serialise them all on the database
+    let recently_used = row.get::<Option<bool>, _>(3).unwrap_or(false);
+    if !recently_used {
+        sqlx::query("UPDATE api_keys SET last_used_at = unixepoch() WHERE id = ?")
+            .bind(grant.id)
+            .execute(&db_pool.0)
+            .await?;
+    }
+
+    Ok(Some(grant))
+}
+
+/// Finds or creates the user for a single sign-on identity, updating their
+/// username and role from the provider. Returns their id and whether they
+/// were created, or `None` if the user is disabled, or a new user's name is
+/// already taken by another account.
+pub async fn provision_oidc_user(
+    db_pool: AuthDb,
+    tenant_id: i32,
+    subject: &str,
+    username: &str,
+    role: &str,
+) -> Result<Option<(i32, bool)>> {
+    let mut tx = db_pool.0.begin().await?;
+    let existing = sqlx::query("SELECT id, disabled FROM users WHERE tenant_id = ? AND oidc_subject = ?")
+        .bind(tenant_id)
+        .bind(subject)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(|row| (row.get::<i32, _>(0), row.get::<bool, _>(1)));
+
+    let user_id = match existing {
+        Some((_, true)) => None,
+        Some((user_id, false)) => {
+            sqlx::query("UPDATE users SET role = ? WHERE id = ?")
+                .bind(role)
+                .bind(user_id)
+                .execute(&mut *tx)
+                .await?;
+            Some((user_id, false))
+        }
+        None => {
+            let taken = sqlx::query("SELECT id FROM users WHERE tenant_id = ? AND username = ?")
+                .bind(tenant_id)
+                .bind(username)
+                .fetch_optional(&mut *tx)
+                .await?
+                .is_some();
+            if taken {
+                None
+            } else {
+                let user_id = sqlx::query(
+                    "INSERT INTO users (tenant_id, username, password, role, oidc_subject)
+                     VALUES (?, ?, '', ?, ?) RETURNING id",
+                )
+                    .bind(tenant_id)
+                    .bind(username)
+                    .bind(role)
+                    .bind(subject)
+                    .fetch_one(&mut *tx)
+                    .await?
+                    .get::<i32, _>(0);
+                record_user_event(&mut tx, "user.created", tenant_id, user_id, Some(username)).await?;
+                Some((user_id, true))
+            }
+        }
+    };
+    tx.commit().await?;
+
+    Ok(user_id)
+}
+
+/// A user's two-factor authentication settings.
+pub struct TotpState {
+    pub secret: Option<String>,
+    pub enabled: bool,
+    pub last_step: Option<i64>,
+}
+
+pub async fn get_totp(db_pool: AuthDb, user_id: i32) -> Result<Option<TotpState>> {
+    let state = sqlx::query("SELECT totp_secret, totp_enabled, totp_last_step FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| TotpState {
+            secret: row.get(0),
+            enabled: row.get(1),
+            last_step: row.get(2),
+        });
+
+    Ok(state)
+}
+
+/// Stores a new secret, which isn't used until `enable_totp` confirms the
+/// user can generate codes from it.
+pub async fn set_totp_secret(db_pool: AuthDb, user_id: i32, secret: &str) -> Result<()> {
+    sqlx::query("UPDATE users SET totp_secret = ?, totp_enabled = 0, totp_last_step = NULL WHERE id = ?")
+        .bind(secret)
+        .bind(user_id)
         .execute(&db_pool.0)
         .await?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+/// Turns on two-factor authentication, replacing any recovery codes.
+pub async fn enable_totp(db_pool: AuthDb, user_id: i32, step: i64, recovery_codes: &[String]) -> Result<()> {
+    let mut tx = db_pool.0.begin().await?;
+    sqlx::query("UPDATE users SET totp_enabled = 1, totp_last_step = ? WHERE id = ?")
+        .bind(step)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    for code in recovery_codes {
+        sqlx::query("INSERT INTO recovery_codes (user_id, code_hash) VALUES (?, ?)")
+            .bind(user_id)
+            .bind(hash_secret(code))
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+
+    Ok(())
+}
+
+pub async fn disable_totp(db_pool: AuthDb, user_id: i32) -> Result<()> {
+    let mut tx = db_pool.0.begin().await?;
+    sqlx::query("UPDATE users SET totp_secret = NULL, totp_enabled = 0, totp_last_step = NULL WHERE id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM recovery_codes WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Records the time step of an accepted code. Returns false if a code from
+/// that step or a later one was already accepted.
+pub async fn use_totp_step(db_pool: AuthDb, user_id: i32, step: i64) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE users SET totp_last_step = ? WHERE id = ? AND (totp_last_step IS NULL OR totp_last_step < ?)",
+    )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Uses up a recovery code, returning false if it isn't one of the user's
+/// unused codes.
+pub async fn use_recovery_code(db_pool: AuthDb, user_id: i32, code: &str) -> Result<bool> {
+    let result = sqlx::query(
+        "UPDATE recovery_codes SET used_at = unixepoch() WHERE user_id = ? AND code_hash = ? AND used_at IS NULL",
+    )
+        .bind(user_id)
+        .bind(hash_secret(&code.trim().to_lowercase()))
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(result.rows_affected() > 0)
+}
+
+/// Starts the second step of a login, returning the challenge that
+/// `take_mfa_challenge` accepts for the next few minutes.
+pub async fn add_mfa_challenge(db_pool: AuthDb, user_id: i32) -> Result<String> {
+    let challenge = uuid::Uuid::new_v4().to_string();
+
+    sqlx::query("INSERT INTO mfa_challenges (challenge, user_id, expires_at) VALUES (?, ?, unixepoch() + 300)")
+        .bind(&challenge)
+        .bind(user_id)
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(challenge)
+}
+
+/// Finds the tenant's user a live challenge belongs to, counting the
+/// attempt. Each challenge allows a handful of attempts, so codes can't be
+/// guessed.
+pub async fn take_mfa_challenge(db_pool: AuthDb, tenant_id: i32, challenge: &str) -> Result<Option<i32>> {
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at <= unixepoch()")
+        .execute(&db_pool.0)
+        .await?;
+    let user_id = sqlx::query(
+        "UPDATE mfa_challenges SET attempts = attempts + 1
+         WHERE challenge = ? AND attempts < 5
+           AND user_id IN (SELECT id FROM users WHERE tenant_id = ?)
+         RETURNING user_id",
+    )
+        .bind(challenge)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| row.get::<i32, _>(0));
+
+    Ok(user_id)
+}
+
+pub async fn delete_mfa_challenge(db_pool: AuthDb, challenge: &str) -> Result<()> {
+    sqlx::query("DELETE FROM mfa_challenges WHERE challenge = ?")
+        .bind(challenge)
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(())
+}
+
+/// A user's view of their own account.
+#[derive(Serialize, Debug, FromRow)]
+pub struct Account {
+    pub id: i32,
+    pub username: String,
+    pub email: Option<String>,
+    pub role: String,
+    pub mfa_enabled: bool,
+    /// Accounts created by single sign-on have no local password.
+    pub single_sign_on: bool,
+}
+
+pub async fn get_account(db_pool: AuthDb, user_id: i32) -> Result<Option<Account>> {
+    let account = sqlx::query_as::<_, Account>(
+        "SELECT id, username, email, role, totp_enabled AS mfa_enabled, oidc_subject IS NOT NULL AS single_sign_on
+         FROM users WHERE id = ?",
+    )
+        .bind(user_id)
+        .fetch_optional(&db_pool.0)
+        .await?;
+
+    Ok(account)
+}
+
+/// Changes a user's username and email. Returns false if the username
+/// belongs to someone else in their tenant.
+pub async fn update_account(db_pool: AuthDb, user_id: i32, username: &str, email: Option<&str>) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let tenant_id: i32 = sqlx::query_scalar("SELECT tenant_id FROM users WHERE id = ?")
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    let taken = sqlx::query("SELECT id FROM users WHERE tenant_id = ? AND username = ? AND id != ?")
+        .bind(tenant_id)
+        .bind(username)
+        .bind(user_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if taken {
+        return Ok(false);
+    }
+    sqlx::query("UPDATE users SET username = ?, email = ? WHERE id = ?")
+        .bind(username)
+        .bind(email)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    record_user_event(&mut tx, "user.updated", tenant_id, user_id, Some(username)).await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Checks a local user's current password.
+pub async fn check_password(db_pool: AuthDb, user_id: i32, password: &str) -> Result<bool> {
+    let stored = sqlx::query("SELECT password FROM users WHERE id = ? AND oidc_subject IS NULL")
+        .bind(user_id)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| row.get::<String, _>(0));
+
+    match stored {
+        Some(stored) => verify_password(&stored, password).await,
+        None => Ok(false),
+    }
+}
+
+/// Changes a password and revokes every token except `keep_token`, so
+/// other sessions have to log in again.
+pub async fn change_password(db_pool: AuthDb, user_id: i32, password: &str, keep_token: Option<&str>) -> Result<()> {
+    let hash = hash_password(password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+        .bind(hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM tokens WHERE user_id = ? AND token IS NOT ?")
+        .bind(user_id)
+        .bind(keep_token)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(())
+}
+
+/// Creates a user in a tenant through open registration. Returns `None` if
+/// the username is taken.
+pub async fn register_user(
+    db_pool: AuthDb,
+    tenant_id: i32,
+    username: &str,
+    password: &str,
+    email: Option<&str>,
+    role: &str,
+) -> Result<Option<i32>> {
+    let hash = hash_password(password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    let taken = sqlx::query("SELECT id FROM users WHERE tenant_id = ? AND username = ?")
+        .bind(tenant_id)
+        .bind(username)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if taken {
+        return Ok(None);
+    }
+    let user_id = sqlx::query(
+        "INSERT INTO users (tenant_id, username, password, email, role) VALUES (?, ?, ?, ?, ?) RETURNING id",
+    )
+        .bind(tenant_id)
+        .bind(username)
+        .bind(hash)
+        .bind(email)
+        .bind(role)
+        .fetch_one(&mut *tx)
+        .await?
+        .get::<i32, _>(0);
+    record_user_event(&mut tx, "user.created", tenant_id, user_id, Some(username)).await?;
+    tx.commit().await?;
+
+    Ok(Some(user_id))
+}
+
+/// Finds an enabled local user who can reset their password, returning
+/// their id and email.
+pub async fn find_resettable_user(
+    db_pool: AuthDb,
+    tenant_id: i32,
+    username: &str,
+) -> Result<Option<(i32, Option<String>)>> {
+    let user = sqlx::query(
+        "SELECT id, email FROM users
+         WHERE tenant_id = ? AND username = ? AND disabled = 0 AND oidc_subject IS NULL",
+    )
+        .bind(tenant_id)
+        .bind(username)
+        .fetch_optional(&db_pool.0)
+        .await?
+        .map(|row| (row.get(0), row.get(1)));
+
+    Ok(user)
+}
+
+/// Creates a password reset token that works once, within `minutes`.
+pub async fn add_password_reset(db_pool: AuthDb, user_id: i32, minutes: u64) -> Result<String> {
+    let token = format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    );
+
+    sqlx::query("INSERT INTO password_resets (user_id, token_hash, expires_at) VALUES (?, ?, unixepoch() + ?)")
+        .bind(user_id)
+        .bind(hash_secret(&token))
+        .bind(minutes as i64 * 60)
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(token)
+}
+
+/// Sets a new password with a reset token for one of the tenant's users,
+/// using the token up and revoking every session. Returns false if the
+/// token is unknown, used or expired.
+pub async fn reset_password(db_pool: AuthDb, tenant_id: i32, token: &str, password: &str) -> Result<bool> {
+    let hash = hash_password(password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    let user_id = sqlx::query(
+        "UPDATE password_resets SET used_at = unixepoch()
+         WHERE token_hash = ? AND used_at IS NULL AND expires_at > unixepoch()
+           AND user_id IN (SELECT id FROM users WHERE tenant_id = ?)
+         RETURNING user_id",
+    )
+        .bind(hash_secret(token.trim()))
+        .bind(tenant_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .map(|row| row.get::<i32, _>(0));
+    let Some(user_id) = user_id else {
+        return Ok(false);
+    };
+
+    sqlx::query("UPDATE users SET password = ? WHERE id = ?")
+        .bind(hash)
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM tokens WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(true)
+}
+
+/// Deletes login challenges and reset tokens that can no longer be used.
+pub async fn purge_expired(db_pool: AuthDb) -> Result<()> {
+    sqlx::query("DELETE FROM mfa_challenges WHERE expires_at <= unixepoch()")
+        .execute(&db_pool.0)
+        .await?;
+    sqlx::query("DELETE FROM password_resets WHERE expires_at <= unixepoch() OR used_at IS NOT NULL")
+        .execute(&db_pool.0)
+        .await?;
+
+    Ok(())
+}
+
+const TENANT_COLUMNS: &str = "SELECT id, slug, name, host, overrides FROM tenants";
+
+pub async fn get_tenant(db_pool: AuthDb, lookup: TenantLookup<'_>) -> Result<Option<Tenant>> {
+    let tenant = match lookup {
+        TenantLookup::Id(id) => {
+            sqlx::query_as::<_, Tenant>(&format!("{TENANT_COLUMNS} WHERE id = ?"))
+                .bind(id)
+                .fetch_optional(&db_pool.0)
+                .await?
+        }
+        TenantLookup::Slug(slug) => {
+            sqlx::query_as::<_, Tenant>(&format!("{TENANT_COLUMNS} WHERE slug = ?"))
+                .bind(slug)
+                .fetch_optional(&db_pool.0)
+                .await?
+        }
+        TenantLookup::Host(host) => {
+            sqlx::query_as::<_, Tenant>(&format!("{TENANT_COLUMNS} WHERE host = ?"))
+                .bind(host)
+                .fetch_optional(&db_pool.0)
+                .await?
+        }
+    };
+
+    Ok(tenant)
+}
+
+pub async fn list_tenants(db_pool: AuthDb) -> Result<Vec<Tenant>> {
+    let tenants = sqlx::query_as::<_, Tenant>(&format!("{TENANT_COLUMNS} ORDER BY id"))
+        .fetch_all(&db_pool.0)
+        .await?;
+
+    Ok(tenants)
+}
+
+/// Creates a tenant along with its first admin. Returns `None` if the slug
+/// or host belongs to another tenant.
+pub async fn add_tenant(
+    db_pool: AuthDb,
+    slug: &str,
+    name: &str,
+    host: Option<&str>,
+    overrides: &TenantOverrides,
+    admin: &User,
+) -> Result<Option<Tenant>> {
+    let admin_password = hash_password(&admin.password).await?;
+    let mut tx = db_pool.0.begin().await?;
+    let taken = sqlx::query("SELECT id FROM tenants WHERE slug = ? OR host = ?")
+        .bind(slug)
+        .bind(host)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if taken {
+        return Ok(None);
+    }
+    let tenant = sqlx::query_as::<_, Tenant>(
+        "INSERT INTO tenants (slug, name, host, overrides) VALUES (?, ?, ?, ?)
+         RETURNING id, slug, name, host, overrides",
+    )
+        .bind(slug)
+        .bind(name)
+        .bind(host)
+        .bind(serde_json::to_string(overrides)?)
+        .fetch_one(&mut *tx)
+        .await?;
+    let admin_id = sqlx::query(
+        "INSERT INTO users (tenant_id, username, password, role) VALUES (?, ?, ?, 'admin') RETURNING id",
+    )
+        .bind(tenant.id)
+        .bind(&admin.username)
+        .bind(admin_password)
+        .fetch_one(&mut *tx)
+        .await?
+        .get::<i32, _>(0);
+    record_user_event(&mut tx, "user.created", tenant.id, admin_id, Some(&admin.username)).await?;
+    tx.commit().await?;
+
+    Ok(Some(tenant))
+}
+
+/// What `update_tenant` found.
+pub enum TenantUpdate {
+    Updated(Tenant),
+    NotFound,
+    /// Another tenant has the host.
+    HostTaken,
+}
+
+/// Changes a tenant's name, host and overrides. Its slug stays the same,
+/// so paths to it keep working.
+pub async fn update_tenant(
+    db_pool: AuthDb,
+    tenant_id: i32,
+    name: &str,
+    host: Option<&str>,
+    overrides: &TenantOverrides,
+) -> Result<TenantUpdate> {
+    let mut tx = db_pool.0.begin().await?;
+    let taken = sqlx::query("SELECT id FROM tenants WHERE host = ? AND id != ?")
+        .bind(host)
+        .bind(tenant_id)
+        .fetch_optional(&mut *tx)
+        .await?
+        .is_some();
+    if taken {
+        return Ok(TenantUpdate::HostTaken);
+    }
+    let tenant = sqlx::query_as::<_, Tenant>(
+        "UPDATE tenants SET name = ?, host = ?, overrides = ? WHERE id = ?
+         RETURNING id, slug, name, host, overrides",
+    )
+        .bind(name)
+        .bind(host)
+        .bind(serde_json::to_string(overrides)?)
+        .bind(tenant_id)
+        .fetch_optional(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(tenant.map_or(TenantUpdate::NotFound, TenantUpdate::Updated))
+}
+
+/// What other modules, and webhooks, are told about a user. Credentials
+/// are never included.
+#[derive(Serialize, Debug)]
+struct UserChange<'a> {
+    user_id: i32,
+    tenant_id: i32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    username: Option<&'a str>,
+}
+
+/// Writes an event about a user to the outbox. It must be in the same
+/// transaction as the change, so the event is relayed if and only if the
+/// change is committed.
+async fn record_user_event(
+    connection: &mut SqliteConnection,
+    event_type: &str,
+    tenant_id: i32,
+    user_id: i32,
+    username: Option<&str>,
+) -> Result<()> {
+    sqlx::query("INSERT INTO outbox (event_id, event_type, payload) VALUES (?, ?, ?)")
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(event_type)
+        .bind(serde_json::to_string(&UserChange { user_id, tenant_id, username })?)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+/// An event in the outbox, with its position.
+#[derive(Debug, FromRow)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: String,
+    pub created_at: i64,
+}
+
+impl OutboxEntry {
+    pub fn to_event(&self) -> Result<DomainEvent> {
+        Ok(DomainEvent {
+            id: self.event_id.clone(),
+            event_type: self.event_type.clone(),
+            occurred_at: self.created_at,
+            data: serde_json::from_str(&self.payload)?,
+        })
+    }
+}
+
+/// Lists up to `limit` outbox entries after `after`, oldest first.
+pub async fn outbox_after(db_pool: AuthDb, after: i64, limit: i64) -> Result<Vec<OutboxEntry>> {
+    let entries = sqlx::query_as::<_, OutboxEntry>("SELECT * FROM outbox WHERE id > ? ORDER BY id LIMIT ?")
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(entries)
+}
+
+/// The id of the last entry a sink was given. A sink that hasn't been given
+/// any yet starts at the beginning of what's left in the outbox.
+pub async fn outbox_cursor(db_pool: AuthDb, sink: &str) -> Result<i64> {
+    sqlx::query("INSERT OR IGNORE INTO outbox_cursors (sink, last_id) VALUES (?, 0)")
+        .bind(sink)
+        .execute(&db_pool.0)
+        .await?;
+    let last_id = sqlx::query_scalar("SELECT last_id FROM outbox_cursors WHERE sink = ?")
+        .bind(sink)
+        .fetch_one(&db_pool.0)
+        .await?;
+    Ok(last_id)
+}
+
+pub async fn save_outbox_cursor(db_pool: AuthDb, sink: &str, last_id: i64) -> Result<()> {
+    sqlx::query("UPDATE outbox_cursors SET last_id = ? WHERE sink = ?")
+        .bind(last_id)
+        .bind(sink)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+/// Deletes the entries that all of `sinks` have been given. Sinks that are
+/// no longer configured don't hold entries back; those that haven't been
+/// given any yet do.
+pub async fn prune_outbox(db_pool: AuthDb, sinks: &[&str]) -> Result<()> {
+    for sink in sinks {
+        outbox_cursor(db_pool.clone(), sink).await?;
+    }
+    sqlx::query(
+        "DELETE FROM outbox WHERE id <= COALESCE(
+             (SELECT MIN(last_id) FROM outbox_cursors WHERE sink IN (SELECT value FROM json_each(?))),
+             id
+         )",
+    )
+        .bind(serde_json::to_string(sinks)?)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,184 @@
+use std::{future::Future, net::SocketAddr};
+use anyhow::{Context, Result};
+use tonic::{
+    transport::{server::TcpIncoming, Identity, Server, ServerTlsConfig},
+    Request, Response, Status,
+};
+use super::{
+    auth_layers::{self, Credential, Principal},
+    configuration::GrpcConfiguration,
+    db,
+    proto::{
+        auth_server::{Auth, AuthServer},
+        get_tenant_request, login_reply, validate_token_reply, ApiKeyPrincipal, CredentialKind, GetTenantRequest,
+        GetUserReply, GetUserRequest, LoginReply, LoginRequest, TenantReply, UserPrincipal, ValidateTokenReply,
+        ValidateTokenRequest,
+    },
+};
+use crate::{
+    tenancy::{TenantLookup, DEFAULT_TENANT},
+    tls::TlsConfiguration,
+};
+
+/// The auth module's gRPC API, over the same database as its routes.
+struct AuthService {
+    db_pool: db::AuthDb,
+    local_login: bool,
+}
+
+fn internal(error: anyhow::Error) -> Status {
+    tracing::warn!("Auth gRPC request failed: {error:#}");
+    Status::internal("database error")
+}
+
+#[tonic::async_trait]
+impl Auth for AuthService {
+    async fn validate_token(
+        &self,
+        request: Request<ValidateTokenRequest>,
+    ) -> Result<Response<ValidateTokenReply>, Status> {
+        let request = request.into_inner();
+        let credential = match request.kind() {
+            CredentialKind::SessionToken => Credential::SessionToken(&request.credential),
+            CredentialKind::ApiKey => Credential::ApiKey(&request.credential),
+            CredentialKind::ClientCertificate => Credential::ClientCertificate(&request.credential),
+        };
+
+        let principal = auth_layers::local_principal(self.db_pool.clone(), credential, request.tenant_id)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::unauthenticated("invalid credential"))?;
+        let tenant_id = principal.tenant_id();
+        let principal = match principal {
+            Principal::User { user_id, role, .. } => validate_token_reply::Principal::User(UserPrincipal {
+                user_id,
+                role: role.as_str().to_string(),
+            }),
+            Principal::ApiKey { key_id, scopes, .. } => validate_token_reply::Principal::ApiKey(ApiKeyPrincipal {
+                key_id,
+                scopes: scopes.iter().map(|scope| scope.as_str().to_string()).collect(),
+            }),
+        };
+        Ok(Response::new(ValidateTokenReply {
+            principal: Some(principal),
+            tenant_id,
+        }))
+    }
+
+    async fn login(&self, request: Request<LoginRequest>) -> Result<Response<LoginReply>, Status> {
+        if !self.local_login {
+            return Err(Status::failed_precondition("local login is disabled"));
+        }
+        let request = request.into_inner();
+        // Other tenants' users log in through the service's routes
+        let user_id = db::login(self.db_pool.clone(), DEFAULT_TENANT, &request.username, &request.password)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::unauthenticated("invalid username or password"))?;
+
+        let totp = db::get_totp(self.db_pool.clone(), user_id).await.map_err(internal)?;
+        let result = if totp.is_some_and(|totp| totp.enabled) {
+            let challenge = db::add_mfa_challenge(self.db_pool.clone(), user_id)
+                .await
+                .map_err(internal)?;
+            login_reply::Result::MfaChallenge(challenge)
+        } else {
+            let token = db::add_token(self.db_pool.clone(), user_id).await.map_err(internal)?;
+            login_reply::Result::Token(token)
+        };
+        Ok(Response::new(LoginReply { result: Some(result) }))
+    }
+
+    async fn get_user(&self, request: Request<GetUserRequest>) -> Result<Response<GetUserReply>, Status> {
+        let account = db::get_account(self.db_pool.clone(), request.into_inner().user_id)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("no such user"))?;
+        Ok(Response::new(GetUserReply {
+            id: account.id,
+            username: account.username,
+            email: account.email,
+            role: account.role,
+            mfa_enabled: account.mfa_enabled,
+            single_sign_on: account.single_sign_on,
+        }))
+    }
+
+    async fn get_tenant(&self, request: Request<GetTenantRequest>) -> Result<Response<TenantReply>, Status> {
+        let request = request.into_inner();
+        let lookup = match &request.lookup {
+            Some(get_tenant_request::Lookup::Id(id)) => TenantLookup::Id(*id),
+            Some(get_tenant_request::Lookup::Slug(slug)) => TenantLookup::Slug(slug),
+            Some(get_tenant_request::Lookup::Host(host)) => TenantLookup::Host(host),
+            None => return Err(Status::invalid_argument("no lookup")),
+        };
+        let tenant = db::get_tenant(self.db_pool.clone(), lookup)
+            .await
+            .map_err(internal)?
+            .ok_or_else(|| Status::not_found("no such tenant"))?;
+        Ok(Response::new(TenantReply {
+            id: tenant.id,
+            slug: tenant.slug,
+            name: tenant.name,
+            host: tenant.host,
+            overrides: serde_json::to_string(&tenant.overrides).map_err(|e| internal(e.into()))?,
+        }))
+    }
+}
+
+/// Listens for the gRPC API, over TLS with the service's certificate if
+/// `config.tls` is set, returning the server to run until the process
+/// exits. Every call must carry the shared secret.
+pub async fn bind(
+    config: &GrpcConfiguration,
+    tls: &TlsConfiguration,
+    db_pool: db::AuthDb,
+    local_login: bool,
+) -> Result<impl Future<Output = Result<()>>> {
+    let address: SocketAddr = format!("{}:{}", config.listen_address, config.listen_port)
+        .parse()
+        .context("invalid auth.grpc listen address")?;
+    let listener = tokio::net::TcpListener::bind(address)
+        .await
+        .with_context(|| format!("unable to listen for auth gRPC on {address}"))?;
+    let incoming = TcpIncoming::from_listener(listener, true, None)
+        .map_err(|e| anyhow::anyhow!("unable to listen for auth gRPC on {address}: {e}"))?;
+
+    let mut server = Server::builder();
+    if config.tls {
+        // Read once: unlike HTTPS, the gRPC API needs a restart to pick up
+        // a new certificate
+        let certificate = std::fs::read(&tls.cert_path).with_context(|| format!("unable to read {}", tls.cert_path))?;
+        let key = std::fs::read(&tls.key_path).with_context(|| format!("unable to read {}", tls.key_path))?;
+        server = server
+            .tls_config(ServerTlsConfig::new().identity(Identity::from_pem(certificate, key)))
+            .context("invalid TLS certificate for auth gRPC")?;
+    }
+    let expected = db::hash_secret(&format!("Bearer {}", config.shared_secret));
+    // The signature is tonic's, Status and all
+    #[allow(clippy::result_large_err)]
+    let check_secret = move |request: Request<()>| {
+        // Compare hashes, so the time taken doesn't reveal the secret
+        let presented = request
+            .metadata()
+            .get("authorization")
+            .and_then(|value| value.to_str().ok())
+            .map(db::hash_secret);
+        match presented {
+            Some(presented) if presented == expected => Ok(request),
+            _ => Err(Status::permission_denied("invalid shared secret")),
+        }
+    };
+
+    tracing::info!("Serving auth gRPC on {address}");
+    let router = server.add_service(AuthServer::with_interceptor(
+        AuthService { db_pool, local_login },
+        check_secret,
+    ));
+    Ok(async move {
+        router
+            .serve_with_incoming(incoming)
+            .await
+            .context("auth gRPC server failed")
+    })
+}
@@ -0,0 +1,6 @@
+-- New users are readers; only the admin the first migration seeded is
+-- made an admin
+ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'reader';
+UPDATE users SET role = 'admin' WHERE id = 1;
+ALTER TABLE users ADD COLUMN oidc_subject TEXT;
+CREATE UNIQUE INDEX users_oidc_subject ON users (oidc_subject) WHERE oidc_subject IS NOT NULL;
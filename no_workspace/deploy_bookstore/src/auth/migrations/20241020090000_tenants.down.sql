@@ -0,0 +1,12 @@
+-- Only the default tenant's users and keys are kept
+DELETE FROM tokens WHERE user_id IN (SELECT id FROM users WHERE tenant_id != 1);
+DELETE FROM users WHERE tenant_id != 1;
+DELETE FROM api_keys WHERE tenant_id != 1;
+
+DROP INDEX users_tenant_username;
+DROP INDEX users_oidc_subject;
+CREATE UNIQUE INDEX users_oidc_subject ON users (oidc_subject) WHERE oidc_subject IS NOT NULL;
+
+ALTER TABLE api_keys DROP COLUMN tenant_id;
+ALTER TABLE users DROP COLUMN tenant_id;
+DROP TABLE tenants;
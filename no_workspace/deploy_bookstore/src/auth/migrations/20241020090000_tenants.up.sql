@@ -0,0 +1,22 @@
+-- Each tenant is a shop with its own users, API keys and catalogue.
+-- Everything from before there were tenants belongs to the default one.
+CREATE TABLE tenants (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    slug TEXT NOT NULL UNIQUE,
+    name TEXT NOT NULL,
+    host TEXT UNIQUE,
+    -- Settings the tenant has changed from the deployment's, as JSON
+    overrides TEXT NOT NULL DEFAULT '{}',
+    created_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+INSERT INTO tenants (id, slug, name) VALUES (1, 'default', 'Default');
+
+ALTER TABLE users ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
+ALTER TABLE api_keys ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
+
+-- Usernames and single sign-on identities only need be unique within a
+-- tenant
+DROP INDEX users_oidc_subject;
+CREATE UNIQUE INDEX users_oidc_subject ON users (tenant_id, oidc_subject) WHERE oidc_subject IS NOT NULL;
+CREATE UNIQUE INDEX users_tenant_username ON users (tenant_id, username);
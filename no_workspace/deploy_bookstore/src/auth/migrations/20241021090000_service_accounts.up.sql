@@ -0,0 +1,2 @@
+-- Only service accounts may sign in with a client certificate
+ALTER TABLE users ADD COLUMN service_account INTEGER NOT NULL DEFAULT 0;
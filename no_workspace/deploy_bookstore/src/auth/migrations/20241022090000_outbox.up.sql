@@ -0,0 +1,18 @@
+-- Every change to a user, as a domain event written in the same
+-- transaction as the change, so an event exists if and only if its change
+-- is committed. The relay hands them to each sink, such as webhooks, in
+-- order.
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+-- How far through the outbox each sink has got. Rows every sink has
+-- passed are deleted.
+CREATE TABLE outbox_cursors (
+    sink TEXT PRIMARY KEY,
+    last_id INTEGER NOT NULL
+);
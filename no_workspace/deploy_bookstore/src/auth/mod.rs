@@ -1,29 +1,226 @@
+pub mod commands;
 mod configuration;
 mod db;
+mod grpc;
+pub mod notifier;
+mod oidc;
+mod outbox;
+mod remote;
+mod totp;
 mod web_service;
+#[cfg(test)]
+mod tests;
 pub mod auth_layers;
+use std::{sync::Arc, time::Duration};
 use anyhow::Result;
+use async_trait::async_trait;
 use axum::{middleware, routing::{get, post}, Extension, Router};
+use sqlx::migrate::Migrator;
+use auth_layers::Scope;
+use notifier::{HttpNotifier, LogNotifier, Notifier};
+use outbox::OutboxRelay;
+use crate::{
+    cors::RouteGroup,
+    event_bus::OutboxSink,
+    modules::{BackgroundTask, Database, ModuleContext, ServiceModule},
+    idempotency::IdempotencyLayer,
+    rate_limit::RateLimitLayer,
+    tls::TlsConfiguration,
+};
 
-pub async fn setup_service() -> Result<Router> {
-    let config = configuration::AuthConfiguration::load()?;
-    let db_pool = db::get_connection_pool(&config.db_filename).await?;
+pub use configuration::{AuthConfiguration, AuthMode};
+pub use db::AuthDb;
+pub use remote::RemoteAuth;
 
-    db::perform_migrations(db_pool.clone()).await?;
+/// Code generated from `proto/auth.proto`.
+mod proto {
+    tonic::include_proto!("auth");
+}
+
+/// How often expired challenges and reset tokens are cleared out.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Users, tokens and API keys. The database pool is shared with other
+/// modules, so they can protect their own routes with
+/// `auth_layers::require_token`.
+pub struct AuthModule {
+    config: AuthConfiguration,
+    db_pool: AuthDb,
+    /// Sends password reset tokens. Without one, passwords can't be reset.
+    notifier: Option<Arc<dyn Notifier>>,
+    relay: OutboxRelay,
+    /// The service's certificate, which the gRPC API can be served with.
+    tls: TlsConfiguration,
+}
+
+impl AuthModule {
+    /// Changes to users are written to the module's outbox, and relayed to
+    /// each of `sinks`.
+    pub async fn open(
+        config: AuthConfiguration,
+        tls: TlsConfiguration,
+        sinks: Vec<Arc<dyn OutboxSink>>,
+    ) -> Result<Self> {
+        let db_pool = db::get_connection_pool(&config.db_filename).await?;
+        let notifier: Option<Arc<dyn Notifier>> = match &config.password_reset_url {
+            Some(url) => Some(Arc::new(HttpNotifier::new(url.clone())?)),
+            None if config.password_reset_log => Some(Arc::new(LogNotifier)),
+            None => None,
+        };
+        let relay = OutboxRelay::new(db_pool.clone(), sinks);
+        Ok(Self {
+            config,
+            db_pool,
+            notifier,
+            relay,
+            tls,
+        })
+    }
+
+    pub fn db(&self) -> AuthDb {
+        self.db_pool.clone()
+    }
+}
+
+#[async_trait]
+impl ServiceModule for AuthModule {
+    fn name(&self) -> &'static str {
+        "auth"
+    }
+
+    fn mount_path(&self) -> &'static str {
+        "/api/v1/auth"
+    }
+
+    fn route_group(&self) -> RouteGroup {
+        RouteGroup::Auth
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &db::MIGRATOR
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        db::perform_migrations(self.db_pool.clone()).await
+    }
 
+    async fn router(&self, context: &ModuleContext) -> Result<Router> {
+        setup_service(
+            self.config.clone(),
+            self.db_pool.clone(),
+            self.notifier.clone(),
+            context.rate_limit.clone(),
+            context.idempotency.clone(),
+        )
+        .await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        db::ping(self.db_pool.clone()).await
+    }
+
+    fn database(&self) -> Database {
+        Database {
+            filename: self.config.db_filename.clone(),
+            pool: self.db_pool.0.clone(),
+        }
+    }
+
+    async fn background_tasks(&self) -> Result<Vec<BackgroundTask>> {
+        let db_pool = self.db_pool.clone();
+        let mut tasks: Vec<BackgroundTask> = vec![Box::pin(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = db::purge_expired(db_pool.clone()).await {
+                    tracing::warn!("Unable to purge expired auth records: {e:#}");
+                }
+            }
+        })];
+        tasks.extend(self.relay.tasks());
+
+        if let Some(grpc) = &self.config.grpc {
+            let server = grpc::bind(grpc, &self.tls, self.db_pool.clone(), self.config.local_login).await?;
+            tasks.push(Box::pin(async move {
+                if let Err(e) = server.await {
+                    tracing::error!("{e:#}");
+                }
+            }));
+        }
+        Ok(tasks)
+    }
+}
+
+async fn setup_service(
+    config: AuthConfiguration,
+    db_pool: AuthDb,
+    notifier: Option<Arc<dyn Notifier>>,
+    rate_limit: RateLimitLayer,
+    idempotency: IdempotencyLayer,
+) -> Result<Router> {
     let secure_router = Router::new()
         .route("/users", get(web_service::list_users))
         .route("/users/:id", get(web_service::get_user))
         .route("/users/delete/:id", get(web_service::delete_user))
         .route("/users/add", post(web_service::add_user))
         .route("/users/update/:id", post(web_service::update_user))
-        .layer(Extension(config.clone()))
-        .layer(Extension(db_pool.clone()))
+        .route("/keys", get(web_service::list_api_keys))
+        .route("/keys/add", post(web_service::add_api_key))
+        .route("/keys/revoke/:id", post(web_service::revoke_api_key))
+        .route_layer(middleware::from_fn_with_state(Scope::UsersAdmin, auth_layers::require_scope))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
         .route_layer(middleware::from_fn(auth_layers::require_token));
 
-    let router = Router::new()
-        .route("/login", post(web_service::do_login))
+    // Tenants are managed by the deployment's operators
+    let tenants_router = Router::new()
+        .route("/tenants", get(web_service::list_tenants))
+        .route("/tenants/add", post(web_service::add_tenant))
+        .route("/tenants/update/:id", post(web_service::update_tenant))
+        .route_layer(middleware::from_fn_with_state(Scope::TenantsAdmin, auth_layers::require_scope))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    // Routes for any signed-in user, about their own account
+    let account_router = Router::new()
+        .route("/me", get(web_service::get_me).patch(web_service::update_me))
+        .route("/me/password", post(web_service::change_password))
+        .route("/mfa/enroll", post(web_service::mfa_enroll))
+        .route("/mfa/activate", post(web_service::mfa_activate))
+        .route("/mfa/disable", post(web_service::mfa_disable))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    let mut public_router = Router::new();
+    if config.local_login {
+        public_router = public_router
+            .route("/login", post(web_service::do_login))
+            .route("/mfa/verify", post(web_service::mfa_verify));
+    }
+    if let Some(notifier) = notifier.filter(|_| config.local_login) {
+        let reset_router = Router::new()
+            .route("/password-reset/request", post(web_service::request_password_reset))
+            .route("/password-reset/confirm", post(web_service::reset_password))
+            .layer(Extension(notifier));
+        public_router = public_router.merge(reset_router);
+    }
+    if config.open_registration {
+        public_router = public_router.route("/register", post(web_service::register));
+    }
+    if let Some(oidc) = &config.oidc {
+        public_router = public_router
+            .route("/oidc/login", get(web_service::oidc_login))
+            .route("/oidc/callback", get(web_service::oidc_callback))
+            .layer(Extension(Arc::new(oidc::OidcClient::new(oidc.clone()))));
+    }
+
+    let router = public_router
+        .route_layer(rate_limit)
         .nest("/", secure_router)
+        .merge(tenants_router)
+        .merge(account_router)
         .layer(Extension(config))
         .layer(Extension(db_pool));
 
@@ -0,0 +1,72 @@
+use std::time::Duration;
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+/// Who a notification is for.
+#[derive(Debug, Clone, Serialize)]
+pub struct Recipient {
+    pub username: String,
+    pub email: Option<String>,
+}
+
+/// Delivers messages to users, such as password reset tokens. Implement it
+/// for an email or SMS provider; `LogNotifier` is enough for development.
+#[async_trait]
+pub trait Notifier: Send + Sync {
+    async fn password_reset(&self, recipient: &Recipient, token: &str) -> Result<()>;
+}
+
+/// Writes notifications to the log instead of sending them. Anyone who can
+/// read the log can reset passwords, so it's only allowed in development
+/// builds without TLS.
+pub struct LogNotifier;
+
+#[async_trait]
+impl Notifier for LogNotifier {
+    async fn password_reset(&self, recipient: &Recipient, token: &str) -> Result<()> {
+        tracing::info!(
+            "Password reset for {} ({}): token {token}",
+            recipient.username,
+            recipient.email.as_deref().unwrap_or("no email"),
+        );
+        Ok(())
+    }
+}
+
+#[derive(Serialize)]
+struct PasswordResetMessage<'a> {
+    #[serde(flatten)]
+    recipient: &'a Recipient,
+    token: &'a str,
+}
+
+/// How long the mailer has to take a notification.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Posts notifications as JSON to a service that sends them on, such as a
+/// mailer on the same private network.
+pub struct HttpNotifier {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl HttpNotifier {
+    pub fn new(url: String) -> Result<Self> {
+        let http = reqwest::Client::builder().timeout(TIMEOUT).build()?;
+        Ok(Self { url, http })
+    }
+}
+
+#[async_trait]
+impl Notifier for HttpNotifier {
+    async fn password_reset(&self, recipient: &Recipient, token: &str) -> Result<()> {
+        self.http
+            .post(&self.url)
+            .json(&PasswordResetMessage { recipient, token })
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,330 @@
+use std::{
+    collections::HashMap,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use anyhow::{bail, Context, Result};
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+use jsonwebtoken::{
+    jwk::{Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::Deserialize;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use tokio::sync::RwLock;
+use super::{auth_layers::Role, configuration::OidcConfiguration};
+
+/// How long a user has to finish logging in at the provider.
+const LOGIN_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+/// Unfinished logins kept at once, so abandoned ones can't use up memory.
+const MAX_PENDING_LOGINS: usize = 10_000;
+/// How often an unknown key id can force the signing keys to be fetched.
+const JWKS_REFRESH_COOLDOWN: Duration = Duration::from_secs(60);
+/// What ID tokens may be signed with when their key doesn't say.
+const DEFAULT_ALGORITHMS: [Algorithm; 2] = [Algorithm::RS256, Algorithm::ES256];
+
+/// The parts of the provider's discovery document that we use.
+#[derive(Deserialize, Debug, Clone)]
+struct ProviderMetadata {
+    issuer: String,
+    authorization_endpoint: String,
+    token_endpoint: String,
+    jwks_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenResponse {
+    id_token: String,
+}
+
+struct PendingLogin {
+    code_verifier: String,
+    nonce: String,
+    started: Instant,
+}
+
+struct CachedKeys {
+    keys: JwkSet,
+    fetched: Instant,
+}
+
+/// A user the provider has vouched for.
+#[derive(Debug)]
+pub struct Identity {
+    pub subject: String,
+    pub username: String,
+    pub role: Role,
+}
+
+/// Runs the authorization code flow with PKCE against one provider. The
+/// discovery document and signing keys are fetched on first use, so the
+/// service starts even if the provider is down.
+pub struct OidcClient {
+    config: OidcConfiguration,
+    http: reqwest::Client,
+    metadata: RwLock<Option<ProviderMetadata>>,
+    keys: RwLock<Option<CachedKeys>>,
+    pending: Mutex<HashMap<String, PendingLogin>>,
+}
+
+impl OidcClient {
+    pub fn new(config: OidcConfiguration) -> Self {
+        Self {
+            config,
+            http: reqwest::Client::new(),
+            metadata: RwLock::new(None),
+            keys: RwLock::new(None),
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    async fn metadata(&self) -> Result<ProviderMetadata> {
+        if let Some(metadata) = self.metadata.read().await.as_ref() {
+            return Ok(metadata.clone());
+        }
+
+        let issuer = self.config.issuer_url.trim_end_matches('/');
+        let url = format!("{issuer}/.well-known/openid-configuration");
+        let metadata: ProviderMetadata = self
+            .http
+            .get(&url)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("unable to read {url}"))?;
+        if metadata.issuer.trim_end_matches('/') != issuer {
+            bail!("discovery document is for issuer {}", metadata.issuer);
+        }
+
+        *self.metadata.write().await = Some(metadata.clone());
+        Ok(metadata)
+    }
+
+    /// Finds the key an ID token was signed with, fetching the provider's
+    /// keys if they're stale or don't include it (keys get rotated).
+    async fn signing_key(&self, kid: Option<&str>) -> Result<Jwk> {
+        let find = |keys: &JwkSet| match kid {
+            Some(kid) => keys.find(kid).cloned(),
+            None => keys.keys.first().cloned(),
+        };
+
+        if let Some(cached) = self.keys.read().await.as_ref() {
+            let fresh = cached.fetched.elapsed() < Duration::from_secs(self.config.jwks_cache_seconds);
+            match find(&cached.keys) {
+                Some(jwk) if fresh => return Ok(jwk),
+                None if cached.fetched.elapsed() < JWKS_REFRESH_COOLDOWN => {
+                    bail!("unknown signing key {kid:?}")
+                }
+                _ => {}
+            }
+        }
+
+        let jwks_uri = self.metadata().await?.jwks_uri;
+        let keys: JwkSet = self
+            .http
+            .get(&jwks_uri)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("unable to read {jwks_uri}"))?;
+        let jwk = find(&keys);
+        *self.keys.write().await = Some(CachedKeys {
+            keys,
+            fetched: Instant::now(),
+        });
+
+        jwk.with_context(|| format!("unknown signing key {kid:?}"))
+    }
+
+    /// Starts a login, returning the provider URL to send the user to.
+    pub async fn authorization_url(&self) -> Result<String> {
+        let metadata = self.metadata().await?;
+        let state = random_string();
+        let nonce = random_string();
+        let code_verifier = random_string();
+        let code_challenge = URL_SAFE_NO_PAD.encode(Sha256::digest(code_verifier.as_bytes()));
+
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.retain(|_, login| login.started.elapsed() < LOGIN_TIMEOUT);
+            if pending.len() >= MAX_PENDING_LOGINS {
+                bail!("too many logins in progress");
+            }
+            pending.insert(
+                state.clone(),
+                PendingLogin {
+                    code_verifier,
+                    nonce: nonce.clone(),
+                    started: Instant::now(),
+                },
+            );
+        }
+
+        let url = reqwest::Url::parse_with_params(
+            &metadata.authorization_endpoint,
+            &[
+                ("response_type", "code"),
+                ("client_id", &self.config.client_id),
+                ("redirect_uri", &self.config.redirect_url),
+                ("scope", &self.config.scopes.join(" ")),
+                ("state", &state),
+                ("nonce", &nonce),
+                ("code_challenge", &code_challenge),
+                ("code_challenge_method", "S256"),
+            ],
+        )?;
+        Ok(url.to_string())
+    }
+
+    /// Finishes a login: exchanges the code for an ID token and validates it.
+    pub async fn complete(&self, code: &str, state: &str) -> Result<Identity> {
+        let login = self
+            .pending
+            .lock()
+            .unwrap()
+            .remove(state)
+            .filter(|login| login.started.elapsed() < LOGIN_TIMEOUT)
+            .context("unknown or expired login")?;
+
+        let metadata = self.metadata().await?;
+        let mut form = vec![
+            ("grant_type", "authorization_code"),
+            ("code", code),
+            ("redirect_uri", &self.config.redirect_url),
+            ("client_id", &self.config.client_id),
+            ("code_verifier", &login.code_verifier),
+        ];
+        if let Some(secret) = &self.config.client_secret {
+            form.push(("client_secret", secret));
+        }
+        let tokens: TokenResponse = self
+            .http
+            .post(&metadata.token_endpoint)
+            .form(&form)
+            .send()
+            .await?
+            .error_for_status()
+            .context("code exchange failed")?
+            .json()
+            .await?;
+
+        let claims = self.validate_id_token(&tokens.id_token, &metadata, &login.nonce).await?;
+        self.identity(&claims)
+    }
+
+    async fn validate_id_token(
+        &self,
+        id_token: &str,
+        metadata: &ProviderMetadata,
+        nonce: &str,
+    ) -> Result<HashMap<String, Value>> {
+        let header = jsonwebtoken::decode_header(id_token)?;
+        let jwk = self.signing_key(header.kid.as_deref()).await?;
+        let key = DecodingKey::from_jwk(&jwk)?;
+
+        // The token doesn't get to pick its algorithm: it's the one the key
+        // names, or one of a few public key ones.
+        let algorithms = allowed_algorithms(&jwk)?;
+        if !algorithms.contains(&header.alg) {
+            bail!("ID token is signed with {:?}, which its key doesn't allow", header.alg);
+        }
+        let mut validation = Validation::new(header.alg);
+        validation.algorithms = algorithms;
+        validation.set_issuer(&[&metadata.issuer]);
+        validation.set_audience(&[&self.config.client_id]);
+        validation.set_required_spec_claims(&["exp", "iss", "aud", "sub"]);
+        let claims = jsonwebtoken::decode::<HashMap<String, Value>>(id_token, &key, &validation)?.claims;
+
+        if claims.get("nonce").and_then(Value::as_str) != Some(nonce) {
+            bail!("ID token nonce doesn't match");
+        }
+        Ok(claims)
+    }
+
+    fn identity(&self, claims: &HashMap<String, Value>) -> Result<Identity> {
+        let subject = claims
+            .get("sub")
+            .and_then(Value::as_str)
+            .context("ID token has no subject")?
+            .to_string();
+        let username = [self.config.username_claim.as_str(), "email", "sub"]
+            .iter()
+            .find_map(|claim| claims.get(*claim).and_then(Value::as_str))
+            .unwrap_or(&subject)
+            .to_string();
+
+        let values: Vec<&str> = match claims.get(&self.config.role_claim) {
+            Some(Value::String(value)) => vec![value.as_str()],
+            Some(Value::Array(values)) => values.iter().filter_map(Value::as_str).collect(),
+            _ => Vec::new(),
+        };
+        let mapped = values
+            .iter()
+            .filter_map(|value| self.config.role_mapping.get(*value).copied())
+            .fold(None, |best: Option<Role>, role| match best {
+                Some(best) if best >= role => Some(best),
+                _ => Some(role),
+            });
+        let role = mapped
+            .or(self.config.default_role)
+            .with_context(|| format!("{username} has no role mapped from {}", self.config.role_claim))?;
+
+        Ok(Identity {
+            subject,
+            username,
+            role,
+        })
+    }
+}
+
+/// Over 200 random bits as URL-safe text, for state, nonce and PKCE values.
+fn random_string() -> String {
+    format!(
+        "{}{}",
+        uuid::Uuid::new_v4().simple(),
+        uuid::Uuid::new_v4().simple()
+    )
+}
+
+/// The algorithms a provider's key may have signed with. Keys used for
+/// HMAC would be shared secrets, so they're never accepted.
+fn allowed_algorithms(jwk: &Jwk) -> Result<Vec<Algorithm>> {
+    let Some(key_algorithm) = jwk.common.key_algorithm else {
+        return Ok(DEFAULT_ALGORITHMS.to_vec());
+    };
+    let algorithm = Algorithm::from_str(&key_algorithm.to_string())
+        .with_context(|| format!("{key_algorithm} keys can't sign ID tokens"))?;
+    if matches!(algorithm, Algorithm::HS256 | Algorithm::HS384 | Algorithm::HS512) {
+        bail!("{key_algorithm} keys can't sign ID tokens");
+    }
+    Ok(vec![algorithm])
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{jwk::Jwk, Algorithm};
+    use serde_json::json;
+    use super::allowed_algorithms;
+
+    fn jwk(alg: Option<&str>) -> Jwk {
+        let mut jwk = json!({ "kty": "RSA", "kid": "1", "n": "AQAB", "e": "AQAB" });
+        if let Some(alg) = alg {
+            jwk["alg"] = alg.into();
+        }
+        serde_json::from_value(jwk).unwrap()
+    }
+
+    #[test]
+    fn keys_pin_the_signing_algorithm() {
+        assert_eq!(allowed_algorithms(&jwk(None)).unwrap(), [Algorithm::RS256, Algorithm::ES256]);
+        assert_eq!(allowed_algorithms(&jwk(Some("PS256"))).unwrap(), [Algorithm::PS256]);
+        assert!(allowed_algorithms(&jwk(Some("HS256"))).is_err());
+        assert!(allowed_algorithms(&jwk(Some("RSA1_5"))).is_err());
+    }
+}
@@ -0,0 +1,143 @@
+use std::{sync::Arc, time::Duration};
+use anyhow::Result;
+use crate::{event_bus::OutboxSink, modules::BackgroundTask};
+use super::db::{self, AuthDb};
+
+/// How often the relay looks for new events. Changes are made by the
+/// service and by `user` commands alike, so it polls.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long a sink that failed is left before it's tried again.
+const RETRY_INTERVAL: Duration = Duration::from_secs(10);
+const BATCH_SIZE: i64 = 100;
+/// How often entries every sink has been given are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Hands the outbox of changes to users to each sink, such as webhooks. As
+/// with the catalogue's, every sink has its own cursor, kept in the
+/// database, so a sink that's failing holds up only itself, and each picks
+/// up where it left off after a restart.
+#[derive(Clone)]
+pub struct OutboxRelay {
+    db_pool: AuthDb,
+    sinks: Vec<Arc<dyn OutboxSink>>,
+}
+
+impl OutboxRelay {
+    pub fn new(db_pool: AuthDb, sinks: Vec<Arc<dyn OutboxSink>>) -> Self {
+        Self { db_pool, sinks }
+    }
+
+    /// A task for each sink, relaying for as long as the service is up, and
+    /// one that prunes the outbox.
+    pub fn tasks(&self) -> Vec<BackgroundTask> {
+        let mut tasks: Vec<BackgroundTask> = self
+            .sinks
+            .iter()
+            .map(|sink| Box::pin(self.clone().run(sink.clone())) as BackgroundTask)
+            .collect();
+        tasks.push(Box::pin(self.clone().prune_every(PRUNE_INTERVAL)));
+        tasks
+    }
+
+    async fn run(self, sink: Arc<dyn OutboxSink>) {
+        loop {
+            match self.relay(sink.as_ref()).await {
+                Ok(()) => tokio::time::sleep(POLL_INTERVAL).await,
+                Err(e) => {
+                    tracing::warn!("Unable to relay user events to the {} sink: {e:#}", sink.name());
+                    tokio::time::sleep(RETRY_INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    /// Gives the sink everything after its cursor, moving the cursor on
+    /// after each event it accepts.
+    async fn relay(&self, sink: &dyn OutboxSink) -> Result<()> {
+        let mut after = db::outbox_cursor(self.db_pool.clone(), sink.name()).await?;
+        loop {
+            let entries = db::outbox_after(self.db_pool.clone(), after, BATCH_SIZE).await?;
+            let count = entries.len();
+            for entry in entries {
+                sink.publish(&entry.to_event()?).await?;
+                after = entry.id;
+                db::save_outbox_cursor(self.db_pool.clone(), sink.name(), after).await?;
+            }
+            if count < BATCH_SIZE as usize {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Deletes the entries every sink has been given.
+    async fn prune_every(self, period: Duration) {
+        let names: Vec<&str> = self.sinks.iter().map(|sink| sink.name()).collect();
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = db::prune_outbox(self.db_pool.clone(), &names).await {
+                tracing::warn!("Unable to prune the users' outbox: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use async_trait::async_trait;
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use crate::{event_bus::DomainEvent, test_harness::TestApp};
+    use super::*;
+
+    /// Records the events it's given.
+    #[derive(Default)]
+    struct TestSink {
+        received: Mutex<Vec<DomainEvent>>,
+    }
+
+    #[async_trait]
+    impl OutboxSink for TestSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        async fn publish(&self, event: &DomainEvent) -> Result<()> {
+            self.received.lock().unwrap().push(event.clone());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn committed_changes_to_users_are_relayed() {
+        let app = TestApp::new().await;
+        let token = app.admin_token().await;
+        let user = json!({ "id": 0, "username": "clerk", "password": "clerkpass1" });
+        let response = app.post("/api/v1/auth/users/add", Some(&token), user.clone()).await;
+        assert_eq!(response.status, StatusCode::OK);
+        // Refused changes leave nothing behind
+        let response = app.post("/api/v1/auth/users/add", Some(&token), user).await;
+        assert_eq!(response.status, StatusCode::CONFLICT);
+        let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+        let id = users[1]["id"].as_i64().unwrap();
+        let response = app.get(&format!("/api/v1/auth/users/delete/{id}"), Some(&token)).await;
+        assert_eq!(response.status, StatusCode::OK);
+
+        let db_pool = db::get_connection_pool(&app.config.auth.db_filename).await.unwrap();
+        let sink = Arc::new(TestSink::default());
+        let relay = OutboxRelay::new(db_pool.clone(), vec![sink.clone()]);
+        relay.relay(sink.as_ref()).await.unwrap();
+        relay.relay(sink.as_ref()).await.unwrap();
+        let received = sink.received.lock().unwrap().clone();
+        let types: Vec<&str> = received.iter().map(|event| event.event_type.as_str()).collect();
+        assert_eq!(types, ["user.created", "user.deleted"]);
+        assert_eq!(received[0].data, json!({ "user_id": id, "tenant_id": 1, "username": "clerk" }));
+
+        // Once every sink has them, they're deleted
+        db::prune_outbox(db_pool.clone(), &["test", "absent"]).await.unwrap();
+        assert_eq!(db::outbox_after(db_pool.clone(), 0, 10).await.unwrap().len(), 2);
+        db::prune_outbox(db_pool.clone(), &["test"]).await.unwrap();
+        assert!(db::outbox_after(db_pool, 0, 10).await.unwrap().is_empty());
+    }
+}
@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+use anyhow::{bail, Context, Result};
+use tonic::{
+    metadata::{Ascii, MetadataValue},
+    transport::{Certificate, Channel, ClientTlsConfig, Endpoint},
+    Code, Request,
+};
+use super::{
+    auth_layers::{Credential, Principal, Role, Scope},
+    configuration::RemoteAuthConfiguration,
+    db,
+    proto::{
+        auth_client::AuthClient, get_tenant_request, validate_token_reply, CredentialKind, GetTenantRequest,
+        ValidateTokenRequest,
+    },
+};
+use crate::tenancy::{Tenant, TenantLookup};
+
+/// Credentials remembered at once, so a flood of them can't use up memory.
+const MAX_CACHED: usize = 10_000;
+
+struct Cached<T> {
+    value: T,
+    checked: Instant,
+}
+
+/// Checks credentials with a separate auth service. Valid ones are cached
+/// for `cache_seconds`, so most requests don't wait on the service, as are
+/// tenants, including the hosts that aren't any tenant's.
+pub struct RemoteAuth {
+    client: AuthClient<Channel>,
+    authorization: MetadataValue<Ascii>,
+    cache_ttl: Duration,
+    cache: Mutex<HashMap<String, Cached<Principal>>>,
+    tenants: Mutex<HashMap<String, Cached<Option<Tenant>>>>,
+}
+
+impl RemoteAuth {
+    /// Connects on first use, so the service starts even if auth is down.
+    pub fn new(config: &RemoteAuthConfiguration) -> Result<Self> {
+        let timeout = Duration::from_secs(config.timeout_seconds);
+        let mut endpoint = Endpoint::from_shared(config.url.clone())
+            .with_context(|| format!("invalid auth service URL {}", config.url))?;
+        if config.url.starts_with("https://") {
+            let mut tls = ClientTlsConfig::new().with_webpki_roots();
+            if let Some(path) = &config.ca_path {
+                let bundle = std::fs::read(path).with_context(|| format!("unable to read {path}"))?;
+                tls = tls.ca_certificate(Certificate::from_pem(bundle));
+            }
+            endpoint = endpoint.tls_config(tls).context("invalid auth.remote TLS settings")?;
+        }
+        let channel = endpoint
+            .connect_timeout(timeout)
+            .timeout(timeout)
+            .connect_lazy();
+        let authorization = format!("Bearer {}", config.shared_secret)
+            .parse()
+            .context("auth.remote.shared_secret can't be sent as a header")?;
+
+        Ok(Self {
+            client: AuthClient::new(channel),
+            authorization,
+            cache_ttl: Duration::from_secs(config.cache_seconds),
+            cache: Mutex::new(HashMap::new()),
+            tenants: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub async fn authenticate(&self, credential: Credential<'_>, tenant: Option<i32>) -> Result<Option<Principal>> {
+        let (kind, value) = match credential {
+            Credential::SessionToken(token) => (CredentialKind::SessionToken, token),
+            Credential::ApiKey(key) => (CredentialKind::ApiKey, key),
+            Credential::ClientCertificate(common_name) => (CredentialKind::ClientCertificate, common_name),
+        };
+        // Only hashes of credentials are kept
+        let scope = tenant.map_or("*".to_string(), |tenant| tenant.to_string());
+        let cache_key = format!("{}:{scope}:{}", kind.as_str_name(), db::hash_secret(value));
+        if let Some(principal) = cached(&self.cache, &cache_key, self.cache_ttl) {
+            return Ok(Some(principal));
+        }
+
+        let mut request = Request::new(ValidateTokenRequest {
+            kind: kind.into(),
+            credential: value.to_string(),
+            tenant_id: tenant,
+        });
+        request.metadata_mut().insert("authorization", self.authorization.clone());
+        let reply = match self.client.clone().validate_token(request).await {
+            Ok(reply) => reply.into_inner(),
+            Err(status) if status.code() == Code::Unauthenticated => return Ok(None),
+            Err(status) => bail!("ValidateToken failed: {status}"),
+        };
+        let principal = match reply.principal {
+            Some(validate_token_reply::Principal::User(user)) => Principal::User {
+                user_id: user.user_id,
+                role: Role::parse(&user.role),
+                tenant_id: reply.tenant_id,
+            },
+            Some(validate_token_reply::Principal::ApiKey(key)) => Principal::ApiKey {
+                key_id: key.key_id,
+                scopes: key.scopes.iter().filter_map(|scope| Scope::parse(scope)).collect(),
+                tenant_id: reply.tenant_id,
+            },
+            None => bail!("ValidateToken replied without a principal"),
+        };
+
+        remember(&self.cache, cache_key, principal.clone(), self.cache_ttl);
+        Ok(Some(principal))
+    }
+
+    pub async fn tenant(&self, lookup: TenantLookup<'_>) -> Result<Option<Tenant>> {
+        let (cache_key, lookup) = match lookup {
+            TenantLookup::Id(id) => (format!("id:{id}"), get_tenant_request::Lookup::Id(id)),
+            TenantLookup::Slug(slug) => (format!("slug:{slug}"), get_tenant_request::Lookup::Slug(slug.to_string())),
+            TenantLookup::Host(host) => (format!("host:{host}"), get_tenant_request::Lookup::Host(host.to_string())),
+        };
+        if let Some(tenant) = cached(&self.tenants, &cache_key, self.cache_ttl) {
+            return Ok(tenant);
+        }
+
+        let mut request = Request::new(GetTenantRequest { lookup: Some(lookup) });
+        request.metadata_mut().insert("authorization", self.authorization.clone());
+        let tenant = match self.client.clone().get_tenant(request).await {
+            Ok(reply) => {
+                let reply = reply.into_inner();
+                Some(Tenant {
+                    id: reply.id,
+                    slug: reply.slug,
+                    name: reply.name,
+                    host: reply.host,
+                    overrides: reply.overrides.try_into().context("GetTenant replied with invalid overrides")?,
+                })
+            }
+            Err(status) if status.code() == Code::NotFound => None,
+            Err(status) => bail!("GetTenant failed: {status}"),
+        };
+
+        remember(&self.tenants, cache_key, tenant.clone(), self.cache_ttl);
+        Ok(tenant)
+    }
+}
+
+fn cached<T: Clone>(cache: &Mutex<HashMap<String, Cached<T>>>, key: &str, ttl: Duration) -> Option<T> {
+    cache
+        .lock()
+        .unwrap()
+        .get(key)
+        .filter(|cached| cached.checked.elapsed() < ttl)
+        .map(|cached| cached.value.clone())
+}
+
+fn remember<T>(cache: &Mutex<HashMap<String, Cached<T>>>, key: String, value: T, ttl: Duration) {
+    let mut cache = cache.lock().unwrap();
+    if cache.len() >= MAX_CACHED {
+        cache.retain(|_, cached| cached.checked.elapsed() < ttl);
+    }
+    if cache.len() < MAX_CACHED {
+        cache.insert(
+            key,
+            Cached {
+                value,
+                checked: Instant::now(),
+            },
+        );
+    }
+}
@@ -0,0 +1,492 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use axum::{
+    body::Body,
+    http::{header::AUTHORIZATION, HeaderValue, Method, StatusCode},
+    routing::post,
+    Json, Router,
+};
+use serde_json::{json, Value};
+use crate::{
+    test_harness::{request, TestApp, TestResponse, ADMIN_PASSWORD, ADMIN_USERNAME},
+    tls::ClientCertificate,
+};
+use super::{commands, configuration::OidcConfiguration, db, totp};
+
+/// A route that needs a signed-in user and no particular scope.
+const ME: &str = "/api/v1/auth/me";
+
+async fn with_api_key(app: &TestApp, uri: &str, key: &str) -> TestResponse {
+    let request = request(Method::GET, uri, None)
+        .header(AUTHORIZATION, format!("ApiKey {key}"))
+        .body(Body::empty())
+        .unwrap();
+    app.send(request).await
+}
+
+/// Creates an API key with `scopes`, returning its id and the key.
+async fn add_api_key(app: &TestApp, token: &str, scopes: &[&str]) -> (i64, String) {
+    let response = app
+        .post("/api/v1/auth/keys/add", Some(token), json!({ "name": "test key", "scopes": scopes }))
+        .await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let created = response.json();
+    (created["id"].as_i64().unwrap(), created["key"].as_str().unwrap().to_string())
+}
+
+fn user_named<'a>(users: &'a Value, username: &str) -> Option<&'a Value> {
+    users.as_array().unwrap().iter().find(|user| user["username"] == username)
+}
+
+#[tokio::test]
+async fn requests_without_credentials_are_refused() {
+    let app = TestApp::new().await;
+    let response = app.get(ME, None).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid header");
+}
+
+#[tokio::test]
+async fn authorization_headers_must_be_api_keys() {
+    let app = TestApp::new().await;
+    let request = request(Method::GET, ME, None)
+        .header(AUTHORIZATION, "Bearer something")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.send(request).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid header");
+}
+
+#[tokio::test]
+async fn unknown_api_keys_are_refused() {
+    let app = TestApp::new().await;
+    let response = with_api_key(&app, "/api/v1/auth/users", "not-a-key").await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid API key");
+}
+
+#[tokio::test]
+async fn unknown_and_unreadable_tokens_are_refused() {
+    let app = TestApp::new().await;
+    let response = app.get(ME, Some("not-a-token")).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid header");
+
+    let request = request(Method::GET, ME, None)
+        .header("Token", HeaderValue::from_bytes(b"caf\xe9").unwrap())
+        .body(Body::empty())
+        .unwrap();
+    let response = app.send(request).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid header");
+}
+
+#[tokio::test]
+async fn other_authorization_schemes_fall_through_to_the_token() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let request = request(Method::GET, ME, Some(&token))
+        .header(AUTHORIZATION, "Bearer from-a-proxy")
+        .body(Body::empty())
+        .unwrap();
+    let response = app.send(request).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["username"], ADMIN_USERNAME);
+}
+
+#[tokio::test]
+async fn disabled_users_tokens_stop_working() {
+    let app = TestApp::new().await;
+    let token = app.reader_token("reader").await;
+    assert_eq!(app.get(ME, Some(&token)).await.status, StatusCode::OK);
+
+    commands::disable_user(&app.config.auth, "default", "reader").await.unwrap();
+    assert_eq!(app.get(ME, Some(&token)).await.status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn client_certificates_authenticate_service_accounts() {
+    let app = TestApp::new().await;
+    commands::create_user(&app.config.auth, "default", "indexer", "indexer-password", true).await.unwrap();
+    let accounts = [
+        ("indexer", StatusCode::OK),
+        (ADMIN_USERNAME, StatusCode::UNAUTHORIZED),
+        ("stranger", StatusCode::UNAUTHORIZED),
+    ];
+    for (common_name, status) in accounts {
+        let mut request = request(Method::GET, ME, None)
+            .body(Body::empty())
+            .unwrap();
+        request.extensions_mut().insert(ClientCertificate {
+            common_name: common_name.to_string(),
+        });
+        assert_eq!(app.send(request).await.status, status, "{common_name}");
+    }
+}
+
+#[tokio::test]
+async fn routes_need_their_scope() {
+    let app = TestApp::new().await;
+    let token = app.reader_token("reader").await;
+    assert_eq!(app.get("/api/v1/auth/users", Some(&token)).await.status, StatusCode::FORBIDDEN);
+    assert_eq!(app.get("/api/v1/auth/keys", Some(&token)).await.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn login_returns_a_token_or_a_failure() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    assert_eq!(app.get(ME, Some(&token)).await.json()["username"], ADMIN_USERNAME);
+
+    let response = app
+        .post("/api/v1/auth/login", None, json!({ "username": ADMIN_USERNAME, "password": "wrong" }))
+        .await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["Failure"]["reason"], "Invalid username or password");
+}
+
+#[tokio::test]
+async fn admins_manage_users() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+
+    let new_user = json!({ "id": 0, "username": "clerk", "password": "clerkpass1" });
+    let response = app.post("/api/v1/auth/users/add", Some(&token), new_user).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+    let id = user_named(&users, "clerk").unwrap()["id"].as_i64().unwrap();
+    app.login("clerk", "clerkpass1").await;
+
+    let user = app.get(&format!("/api/v1/auth/users/{id}"), Some(&token)).await.json();
+    assert_eq!(user["username"], "clerk");
+
+    let update = json!({ "id": id, "username": "cashier", "password": "cashierpass1" });
+    let response = app.post(&format!("/api/v1/auth/users/update/{id}"), Some(&token), update).await;
+    assert_eq!(response.status, StatusCode::OK);
+    app.login("cashier", "cashierpass1").await;
+
+    // Usernames are taken once per tenant
+    let duplicate = json!({ "id": 0, "username": "cashier", "password": "otherpass1" });
+    let response = app.post("/api/v1/auth/users/add", Some(&token), duplicate).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    let update = json!({ "id": id, "username": ADMIN_USERNAME, "password": "cashierpass1" });
+    let response = app.post(&format!("/api/v1/auth/users/update/{id}"), Some(&token), update).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+    assert_eq!(users.as_array().unwrap().len(), 2);
+
+    let response = app.get(&format!("/api/v1/auth/users/delete/{id}"), Some(&token)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let user = app.get(&format!("/api/v1/auth/users/{id}"), Some(&token)).await.json();
+    assert_eq!(user, Value::Null);
+    let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+    assert!(user_named(&users, "cashier").is_none());
+}
+
+#[tokio::test]
+async fn api_keys_are_limited_to_their_scopes() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let (id, key) = add_api_key(&app, &token, &["users:admin"]).await;
+
+    let keys = app.get("/api/v1/auth/keys", Some(&token)).await.json();
+    assert_eq!(keys.as_array().unwrap().len(), 1);
+    assert_eq!(keys[0]["id"], id);
+    assert!(keys[0].get("key").is_none(), "the key itself is never listed");
+
+    assert_eq!(with_api_key(&app, "/api/v1/auth/users", &key).await.status, StatusCode::OK);
+    // Keys have no account of their own
+    assert_eq!(with_api_key(&app, ME, &key).await.status, StatusCode::FORBIDDEN);
+
+    // A key can't create a key with more than it holds
+    let request = request(Method::POST, "/api/v1/auth/keys/add", None)
+        .header(AUTHORIZATION, format!("ApiKey {key}"))
+        .header("content-type", "application/json")
+        .body(Body::from(json!({ "name": "wider", "scopes": ["books:write"] }).to_string()))
+        .unwrap();
+    assert_eq!(app.send(request).await.status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn api_keys_need_a_name_and_scopes() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    for new_key in [
+        json!({ "name": " ", "scopes": ["books:read"] }),
+        json!({ "name": "no scopes", "scopes": [] }),
+    ] {
+        let response = app.post("/api/v1/auth/keys/add", Some(&token), new_key).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    }
+}
+
+#[tokio::test]
+async fn revoked_api_keys_stop_working() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let (id, key) = add_api_key(&app, &token, &["users:admin"]).await;
+
+    let response = app.post(&format!("/api/v1/auth/keys/revoke/{id}"), Some(&token), json!({})).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let response = with_api_key(&app, "/api/v1/auth/users", &key).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid API key");
+
+    let response = app.post("/api/v1/auth/keys/revoke/999", Some(&token), json!({})).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn users_see_and_update_their_account() {
+    let app = TestApp::new().await;
+    let token = app.reader_token("reader").await;
+    app.reader_token("other").await;
+
+    let account = app.get(ME, Some(&token)).await.json();
+    assert_eq!(account["username"], "reader");
+    assert_eq!(account["role"], "reader");
+    assert_eq!(account["mfa_enabled"], false);
+
+    let update = json!({ "username": "renamed", "email": "reader@example.com" });
+    let response = app.send_json(Method::PATCH, ME, Some(&token), update).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["username"], "renamed");
+    assert_eq!(response.json()["email"], "reader@example.com");
+
+    let response = app.send_json(Method::PATCH, ME, Some(&token), json!({ "username": " " })).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let response = app.send_json(Method::PATCH, ME, Some(&token), json!({ "username": "other" })).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn changing_a_password_logs_out_other_sessions() {
+    let app = TestApp::new().await;
+    let token = app.reader_token("reader").await;
+    let other_session = app.login("reader", "reader-password").await;
+
+    let change = json!({ "current_password": "wrong", "new_password": "new-password" });
+    let response = app.post("/api/v1/auth/me/password", Some(&token), change).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    let change = json!({ "current_password": "reader-password", "new_password": "short" });
+    let response = app.post("/api/v1/auth/me/password", Some(&token), change).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+
+    let change = json!({ "current_password": "reader-password", "new_password": "new-password" });
+    let response = app.post("/api/v1/auth/me/password", Some(&token), change).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(app.get(ME, Some(&token)).await.status, StatusCode::OK);
+    assert_eq!(app.get(ME, Some(&other_session)).await.status, StatusCode::UNAUTHORIZED);
+    app.login("reader", "new-password").await;
+}
+
+#[tokio::test]
+async fn two_factor_logins_need_a_code() {
+    let app = TestApp::new().await;
+    let token = app.reader_token("reader").await;
+
+    let enrolment = app.post("/api/v1/auth/mfa/enroll", Some(&token), json!({})).await.json();
+    let secret = enrolment["secret"].as_str().unwrap().to_string();
+    assert!(enrolment["provisioning_uri"].as_str().unwrap().starts_with("otpauth://totp/"));
+
+    let response = app.post("/api/v1/auth/mfa/activate", Some(&token), json!({ "code": "000000x" })).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    let code = totp::code_after(&secret, 0);
+    let response = app.post("/api/v1/auth/mfa/activate", Some(&token), json!({ "code": code })).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let recovery_codes = response.json()["recovery_codes"].clone();
+    let response = app.post("/api/v1/auth/mfa/enroll", Some(&token), json!({})).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+
+    let login = json!({ "username": "reader", "password": "reader-password" });
+    let response = app.post("/api/v1/auth/login", None, login.clone()).await.json();
+    let challenge = response["MfaRequired"]["challenge"].as_str().unwrap().to_string();
+    let verify = json!({ "challenge": challenge, "code": "123" });
+    let response = app.post("/api/v1/auth/mfa/verify", None, verify).await.json();
+    assert_eq!(response["Failure"]["reason"], "Invalid code");
+    let verify = json!({ "challenge": challenge, "code": totp::code_after(&secret, 1) });
+    let response = app.post("/api/v1/auth/mfa/verify", None, verify.clone()).await.json();
+    assert!(response["Success"]["token"].is_string(), "{response}");
+    // Challenges work once
+    let response = app.post("/api/v1/auth/mfa/verify", None, verify).await.json();
+    assert_eq!(response["Failure"]["reason"], "Invalid or expired challenge");
+
+    let disable = json!({ "code": recovery_codes[0] });
+    let response = app.post("/api/v1/auth/mfa/disable", Some(&token), disable).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let response = app.post("/api/v1/auth/login", None, login).await.json();
+    assert!(response["Success"]["token"].is_string(), "{response}");
+}
+
+#[tokio::test]
+async fn anyone_can_register_when_registration_is_open() {
+    let app = TestApp::new().await;
+    app.reader_token("reader").await;
+
+    let registration = json!({ "username": "reader", "password": "another-password" });
+    let response = app.post("/api/v1/auth/register", None, registration).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+    let registration = json!({ "username": "newcomer", "password": "short" });
+    let response = app.post("/api/v1/auth/register", None, registration).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let registration = json!({ "username": " ", "password": "long-enough" });
+    let response = app.post("/api/v1/auth/register", None, registration).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn registration_can_be_closed() {
+    let app = TestApp::with_config(|config| config.auth.open_registration = false).await;
+    let registration = json!({ "username": "newcomer", "password": "long-enough" });
+    // With no such route, the request falls through to the static files,
+    // which only serve GET and HEAD
+    let response = app.post("/api/v1/auth/register", None, registration).await;
+    assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+/// Starts a mailer that records the password resets it's sent, taking
+/// `delay` over each, returning its URL.
+async fn mailer(delay: Duration) -> (String, Arc<Mutex<Vec<Value>>>) {
+    let sent = Arc::new(Mutex::new(Vec::new()));
+    let recorded = sent.clone();
+    let app = Router::new().route(
+        "/reset",
+        post(move |Json(message): Json<Value>| async move {
+            tokio::time::sleep(delay).await;
+            recorded.lock().unwrap().push(message);
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let url = format!("http://{}/reset", listener.local_addr().unwrap());
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    (url, sent)
+}
+
+/// Waits for the mailer to be sent a message, which happens after the
+/// request for it has been answered.
+async fn next_message(sent: &Mutex<Vec<Value>>) -> Value {
+    for _ in 0..100 {
+        if let Some(message) = sent.lock().unwrap().pop() {
+            return message;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    panic!("the mailer wasn't sent anything");
+}
+
+#[tokio::test]
+async fn passwords_are_reset_with_a_token() {
+    let (url, sent) = mailer(Duration::ZERO).await;
+    let app = TestApp::with_config(|config| config.auth.password_reset_url = Some(url)).await;
+    let token = app.reader_token("reader").await;
+
+    // The same answer whether or not the user exists
+    for username in ["nobody", "reader"] {
+        let response = app
+            .post("/api/v1/auth/password-reset/request", None, json!({ "username": username }))
+            .await;
+        assert_eq!(response.status, StatusCode::ACCEPTED);
+    }
+    let message = next_message(&sent).await;
+    assert!(sent.lock().unwrap().is_empty());
+    assert_eq!(message["username"], "reader");
+    let reset_token = message["token"].as_str().unwrap();
+
+    let reset = json!({ "token": reset_token, "new_password": "short" });
+    let response = app.post("/api/v1/auth/password-reset/confirm", None, reset).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    let reset = json!({ "token": reset_token, "new_password": "reset-password" });
+    let response = app.post("/api/v1/auth/password-reset/confirm", None, reset.clone()).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(app.get(ME, Some(&token)).await.status, StatusCode::UNAUTHORIZED);
+    app.login("reader", "reset-password").await;
+
+    let response = app.post("/api/v1/auth/password-reset/confirm", None, reset).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+    assert_eq!(response.text(), "invalid or expired token");
+}
+
+#[tokio::test]
+async fn slow_mailers_dont_hold_up_reset_requests() {
+    let (url, sent) = mailer(Duration::from_secs(2)).await;
+    let app = TestApp::with_config(|config| config.auth.password_reset_url = Some(url)).await;
+    let request = app.post("/api/v1/auth/password-reset/request", None, json!({ "username": ADMIN_USERNAME }));
+    let response = tokio::time::timeout(Duration::from_secs(1), request).await.unwrap();
+    assert_eq!(response.status, StatusCode::ACCEPTED);
+    assert!(sent.lock().unwrap().is_empty());
+}
+
+#[tokio::test]
+async fn reset_tokens_are_logged_only_when_asked() {
+    let app = TestApp::with_config(|config| config.auth.password_reset_log = true).await;
+    let response = app
+        .post("/api/v1/auth/password-reset/request", None, json!({ "username": ADMIN_USERNAME }))
+        .await;
+    assert_eq!(response.status, StatusCode::ACCEPTED);
+
+    let mut config = app.config.clone();
+    config.tls.enabled = true;
+    let error = config.validate(false).unwrap_err().to_string();
+    assert!(error.contains("auth.password_reset_log"), "{error}");
+}
+
+#[tokio::test]
+async fn passwords_cant_be_reset_without_a_mailer() {
+    let app = TestApp::new().await;
+    let response = app
+        .post("/api/v1/auth/password-reset/request", None, json!({ "username": ADMIN_USERNAME }))
+        .await;
+    assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+}
+
+#[tokio::test]
+async fn passwords_are_stored_hashed() {
+    let app = TestApp::new().await;
+    app.reader_token("reader").await;
+    let db_pool = db::get_connection_pool(&app.config.auth.db_filename).await.unwrap();
+    let stored = |username| {
+        sqlx::query_scalar::<_, String>("SELECT password FROM users WHERE username = ?")
+            .bind(username)
+            .fetch_one(&db_pool.0)
+    };
+    assert!(stored("reader").await.unwrap().starts_with("$argon2id$"));
+
+    // The seeded admin's password predates hashing, and is hashed when
+    // they first log in
+    assert_eq!(stored(ADMIN_USERNAME).await.unwrap(), ADMIN_PASSWORD);
+    app.admin_token().await;
+    assert!(stored(ADMIN_USERNAME).await.unwrap().starts_with("$argon2id$"));
+
+    let users = app.get("/api/v1/auth/users", Some(&app.admin_token().await)).await.json();
+    assert!(users[0].get("password").is_none());
+}
+
+#[tokio::test]
+async fn single_sign_on_fails_cleanly_without_a_provider() {
+    let app = TestApp::with_config(|config| {
+        config.auth.oidc = Some(OidcConfiguration {
+            issuer_url: "http://127.0.0.1:9".to_string(),
+            client_id: "bookstore".to_string(),
+            redirect_url: "http://localhost/api/v1/auth/oidc/callback".to_string(),
+            ..OidcConfiguration::default()
+        });
+    })
+    .await;
+
+    let response = app.get("/api/v1/auth/oidc/login", None).await;
+    assert_eq!(response.status, StatusCode::BAD_GATEWAY);
+    let response = app.get("/api/v1/auth/oidc/callback?code=abc&state=unknown", None).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["Failure"]["reason"], "Single sign-on failed");
+}
+
+#[tokio::test]
+async fn local_login_can_be_turned_off() {
+    let app = TestApp::with_config(|config| config.auth.local_login = false).await;
+    let login = json!({ "username": ADMIN_USERNAME, "password": ADMIN_PASSWORD });
+    let response = app.post("/api/v1/auth/login", None, login).await;
+    assert_eq!(response.status, StatusCode::METHOD_NOT_ALLOWED);
+}
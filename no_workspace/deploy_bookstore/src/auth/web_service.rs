@@ -1,7 +1,17 @@
-use axum::{http::StatusCode, Extension, Json};
+use std::{sync::Arc, time::{SystemTime, UNIX_EPOCH}};
+use axum::{extract::Query, http::{HeaderMap, StatusCode}, response::Redirect, Extension, Json};
 use serde::{Deserialize, Serialize};
-use super::{auth_layers::ValidUser, db::{self, User}};
-
+use super::{
+    auth_layers::{Principal, Role, Scope},
+    configuration::AuthConfiguration,
+    db::{self, Account, ApiKey, TenantUpdate, User},
+    notifier::{Notifier, Recipient},
+    oidc::OidcClient,
+    totp,
+};
+use crate::{
+    tenancy::{Tenant, TenantConfig, TenantOverrides},
+};
 
 #[derive(Deserialize, Serialize, Debug)]
 pub struct LoginRequest {
@@ -13,20 +23,35 @@ pub struct LoginRequest {
 pub enum LoginResponse {
     Success { token: String },
     Failure { reason: String },
+    /// The password was right, but the user must also send a two-factor
+    /// code with this challenge to `/mfa/verify`.
+    MfaRequired { challenge: String },
 }
 
 pub async fn do_login(
     Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
     login_request: Json<LoginRequest>,
 ) -> Result<Json<LoginResponse>, StatusCode> {
     match db::login(
         db_pool.clone(),
+        tenant.id,
         &login_request.username,
         &login_request.password,
     )
     .await
     {
         Ok(Some(user_id)) => {
+            let totp = db::get_totp(db_pool.clone(), user_id)
+                .await
+                .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+            if totp.is_some_and(|totp| totp.enabled) {
+                let challenge = db::add_mfa_challenge(db_pool, user_id)
+                    .await
+                    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+                return Ok(Json(LoginResponse::MfaRequired { challenge }));
+            }
+
             let token = db::add_token(db_pool.clone(), user_id)
                 .await
                 .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
@@ -39,11 +64,434 @@ pub async fn do_login(
     }
 }
 
+#[derive(Deserialize, Debug)]
+pub struct MfaVerifyRequest {
+    challenge: String,
+    code: String,
+}
+
+/// The second step of a login: exchanges an `MfaRequired` challenge and a
+/// current code (or an unused recovery code) for a token.
+pub async fn mfa_verify(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Json(request): Json<MfaVerifyRequest>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let Some(user_id) = db::take_mfa_challenge(db_pool.clone(), tenant.id, &request.challenge)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+    else {
+        return Ok(Json(LoginResponse::Failure {
+            reason: "Invalid or expired challenge".to_string(),
+        }));
+    };
+
+    if !check_second_factor(db_pool.clone(), user_id, &request.code).await? {
+        return Ok(Json(LoginResponse::Failure {
+            reason: "Invalid code".to_string(),
+        }));
+    }
+
+    db::delete_mfa_challenge(db_pool.clone(), &request.challenge)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let token = db::add_token(db_pool, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(LoginResponse::Success { token }))
+}
+
+/// Accepts a code from the user's authenticator, or one of their recovery
+/// codes, using it up either way.
+async fn check_second_factor(db_pool: db::AuthDb, user_id: i32, code: &str) -> Result<bool, StatusCode> {
+    let totp = db::get_totp(db_pool.clone(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(db::TotpState { secret: Some(secret), enabled: true, last_step }) = totp else {
+        return Ok(false);
+    };
+
+    if let Some(step) = totp::verify(&secret, code, last_step) {
+        return db::use_totp_step(db_pool, user_id, step)
+            .await
+            .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR);
+    }
+    db::use_recovery_code(db_pool, user_id, code)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)
+}
+
+/// Two-factor settings belong to users; API keys can't change them.
+fn signed_in_user(principal: &Principal) -> Result<i32, StatusCode> {
+    principal.user_id().ok_or(StatusCode::FORBIDDEN)
+}
+
+#[derive(Serialize, Debug)]
+pub struct MfaEnrolment {
+    secret: String,
+    provisioning_uri: String,
+    /// The provisioning URI as an SVG QR code.
+    qr_code_svg: Option<String>,
+}
+
+/// Starts two-factor enrolment with a new secret. It takes effect once
+/// `/mfa/activate` sees a code generated from it.
+pub async fn mfa_enroll(
+    TenantConfig(config): TenantConfig<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+) -> Result<Json<MfaEnrolment>, StatusCode> {
+    let user_id = signed_in_user(&principal)?;
+    let totp = db::get_totp(db_pool.clone(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if totp.is_some_and(|totp| totp.enabled) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let user = db::get_user(db_pool.clone(), principal.tenant_id(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let secret = totp::generate_secret();
+    db::set_totp_secret(db_pool, user_id, &secret)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    let provisioning_uri = totp::provisioning_uri(&config.mfa_issuer, &user.username, &secret);
+    Ok(Json(MfaEnrolment {
+        qr_code_svg: totp::qr_code_svg(&provisioning_uri),
+        secret,
+        provisioning_uri,
+    }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct MfaCode {
+    code: String,
+}
+
+#[derive(Serialize, Debug)]
+pub struct RecoveryCodes {
+    /// Single-use codes for when the authenticator is lost. They can't be
+    /// shown again.
+    recovery_codes: Vec<String>,
+}
+
+const RECOVERY_CODE_COUNT: usize = 10;
+
+/// Finishes enrolment, returning a fresh set of recovery codes.
+pub async fn mfa_activate(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<MfaCode>,
+) -> Result<Json<RecoveryCodes>, StatusCode> {
+    let user_id = signed_in_user(&principal)?;
+    let totp = db::get_totp(db_pool.clone(), user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some(db::TotpState { secret: Some(secret), enabled: false, .. }) = totp else {
+        return Err(StatusCode::CONFLICT);
+    };
+    let step = totp::verify(&secret, &request.code, None).ok_or(StatusCode::UNAUTHORIZED)?;
+
+    let recovery_codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code = uuid::Uuid::new_v4().simple().to_string();
+            format!("{}-{}", &code[..5], &code[5..10])
+        })
+        .collect();
+    db::enable_totp(db_pool, user_id, step, &recovery_codes)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::info!("{principal} turned on two-factor authentication");
+
+    Ok(Json(RecoveryCodes { recovery_codes }))
+}
+
+/// Turns two-factor authentication off, given a current or recovery code.
+pub async fn mfa_disable(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    Json(request): Json<MfaCode>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = signed_in_user(&principal)?;
+    if !check_second_factor(db_pool.clone(), user_id, &request.code).await? {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    db::disable_totp(db_pool, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::info!("{principal} turned off two-factor authentication");
+
+    Ok(StatusCode::OK)
+}
+
+fn check_new_password(config: &AuthConfiguration, password: &str) -> Result<(), (StatusCode, String)> {
+    if password.chars().count() < config.min_password_length {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            format!("password must be at least {} characters", config.min_password_length),
+        ));
+    }
+    Ok(())
+}
+
+pub async fn get_me(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+) -> Result<Json<Account>, StatusCode> {
+    let user_id = signed_in_user(&principal)?;
+    let account = db::get_account(db_pool, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    Ok(Json(account))
+}
+
+/// Changes to your own account. Missing fields are left alone; an empty
+/// email removes it.
+#[derive(Deserialize, Debug)]
+pub struct AccountUpdate {
+    username: Option<String>,
+    email: Option<String>,
+}
+
+pub async fn update_me(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    Json(update): Json<AccountUpdate>,
+) -> Result<Json<Account>, (StatusCode, String)> {
+    let user_id = signed_in_user(&principal).map_err(|status| (status, "API keys have no account".to_string()))?;
+    let account = db::get_account(db_pool.clone(), user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "no such user".to_string()))?;
+
+    let username = update.username.as_deref().map(str::trim).unwrap_or(&account.username);
+    if username.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "username is empty".to_string()));
+    }
+    let email = match &update.email {
+        Some(email) => Some(email.trim()).filter(|email| !email.is_empty()),
+        None => account.email.as_deref(),
+    };
+    let updated = db::update_account(db_pool.clone(), user_id, username, email)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?;
+    if !updated {
+        return Err((StatusCode::CONFLICT, "username is taken".to_string()));
+    }
+
+    let account = db::get_account(db_pool, user_id)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "no such user".to_string()))?;
+    Ok(Json(account))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordChange {
+    current_password: String,
+    new_password: String,
+}
+
+/// Changes your own password. Every other session is logged out.
+pub async fn change_password(
+    TenantConfig(config): TenantConfig<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    headers: HeaderMap,
+    Json(change): Json<PasswordChange>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    let user_id = signed_in_user(&principal).map_err(|status| (status, "API keys have no password".to_string()))?;
+    let current = db::check_password(db_pool.clone(), user_id, &change.current_password)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?;
+    if !current {
+        return Err((StatusCode::FORBIDDEN, "current password is wrong".to_string()));
+    }
+    check_new_password(&config, &change.new_password)?;
+
+    let token = headers.get("Token").and_then(|token| token.to_str().ok());
+    db::change_password(db_pool, user_id, &change.new_password, token)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?;
+    tracing::info!("{principal} changed their password");
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct Registration {
+    username: String,
+    password: String,
+    email: Option<String>,
+}
+
+/// Creates a reader account, when `auth.open_registration` is on. A
+/// tenant can close registration, but not open it.
+pub async fn register(
+    TenantConfig(config): TenantConfig<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Json(registration): Json<Registration>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    if !config.open_registration {
+        return Err((StatusCode::NOT_FOUND, "registration is closed".to_string()));
+    }
+    let username = registration.username.trim();
+    if username.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "username is empty".to_string()));
+    }
+    check_new_password(&config, &registration.password)?;
+    let email = registration.email.as_deref().map(str::trim).filter(|email| !email.is_empty());
+
+    let user_id = db::register_user(db_pool, tenant.id, username, &registration.password, email, Role::Reader.as_str())
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?
+        .ok_or((StatusCode::CONFLICT, "username is taken".to_string()))?;
+    tracing::info!("Registered user {user_id} ({username}) in tenant {}", tenant.slug);
+
+    Ok(StatusCode::CREATED)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordResetRequest {
+    username: String,
+}
+
+/// Sends a password reset token through the notifier. That's done in the
+/// background, so the response is the same, and as quick, whether or not
+/// the user exists, and it can't be used to find users.
+pub async fn request_password_reset(
+    Extension(config): Extension<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(notifier): Extension<Arc<dyn Notifier>>,
+    Extension(tenant): Extension<Tenant>,
+    Json(request): Json<PasswordResetRequest>,
+) -> StatusCode {
+    let username = request.username.trim().to_string();
+    tokio::spawn(async move {
+        if let Err(e) = send_password_reset(config, db_pool, notifier, tenant.id, username).await {
+            tracing::warn!("{e:#}");
+        }
+    });
+    StatusCode::ACCEPTED
+}
+
+async fn send_password_reset(
+    config: AuthConfiguration,
+    db_pool: db::AuthDb,
+    notifier: Arc<dyn Notifier>,
+    tenant_id: i32,
+    username: String,
+) -> anyhow::Result<()> {
+    let Some((user_id, email)) = db::find_resettable_user(db_pool.clone(), tenant_id, &username).await? else {
+        return Ok(());
+    };
+    let token = db::add_password_reset(db_pool, user_id, config.password_reset_minutes).await?;
+    tracing::info!("Password reset requested for user {user_id}");
+    let recipient = Recipient { username, email };
+    notifier
+        .password_reset(&recipient, &token)
+        .await
+        .map_err(|e| e.context(format!("unable to send a password reset to user {user_id}")))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PasswordReset {
+    token: String,
+    new_password: String,
+}
+
+/// Sets a new password with a reset token. Every session is logged out.
+pub async fn reset_password(
+    TenantConfig(config): TenantConfig<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Json(reset): Json<PasswordReset>,
+) -> Result<StatusCode, (StatusCode, String)> {
+    check_new_password(&config, &reset.new_password)?;
+    let reset = db::reset_password(db_pool, tenant.id, &reset.token, &reset.new_password)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?;
+    if !reset {
+        return Err((StatusCode::BAD_REQUEST, "invalid or expired token".to_string()));
+    }
+
+    Ok(StatusCode::OK)
+}
+
+/// Sends the user to the identity provider to log in.
+pub async fn oidc_login(
+    Extension(oidc): Extension<Arc<OidcClient>>,
+) -> Result<Redirect, (StatusCode, String)> {
+    match oidc.authorization_url().await {
+        Ok(url) => Ok(Redirect::to(&url)),
+        Err(e) => {
+            tracing::warn!("Unable to start a single sign-on login: {e:#}");
+            Err((StatusCode::BAD_GATEWAY, "identity provider unavailable".to_string()))
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OidcCallback {
+    code: String,
+    state: String,
+}
+
+/// Where the identity provider sends the user back to. Creates the user on
+/// their first login, then issues a token as `do_login` does.
+pub async fn oidc_callback(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(oidc): Extension<Arc<OidcClient>>,
+    Query(callback): Query<OidcCallback>,
+) -> Result<Json<LoginResponse>, StatusCode> {
+    let identity = match oidc.complete(&callback.code, &callback.state).await {
+        Ok(identity) => identity,
+        Err(e) => {
+            tracing::warn!("Single sign-on login failed: {e:#}");
+            return Ok(Json(LoginResponse::Failure {
+                reason: "Single sign-on failed".to_string(),
+            }));
+        }
+    };
+
+    let provisioned = db::provision_oidc_user(
+        db_pool.clone(),
+        tenant.id,
+        &identity.subject,
+        &identity.username,
+        identity.role.as_str(),
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    let Some((user_id, created)) = provisioned else {
+        tracing::warn!("Refused single sign-on for {}: disabled or name taken", identity.username);
+        return Ok(Json(LoginResponse::Failure {
+            reason: "Account unavailable".to_string(),
+        }));
+    };
+    if created {
+        tracing::info!("Created user {user_id} ({}) by single sign-on", identity.username);
+    }
+
+    let token = db::add_token(db_pool, user_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(LoginResponse::Success { token }))
+}
+
 pub async fn list_users(
     Extension(db_pool): Extension<db::AuthDb>,
-    Extension(_valid_user): Extension<ValidUser>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(_principal): Extension<Principal>,
 ) -> Result<Json<Vec<User>>, StatusCode> {
-    let users = db::get_all_users(db_pool)
+    let users = db::get_all_users(db_pool, tenant.id)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -52,10 +500,11 @@ pub async fn list_users(
 
 pub async fn get_user(
     Extension(db_pool): Extension<db::AuthDb>,
-    Extension(_valid_user): Extension<ValidUser>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(_principal): Extension<Principal>,
     path: axum::extract::Path<i32>,
 ) -> Result<Json<Option<User>>, StatusCode> {
-    let user = db::get_user(db_pool, path.0)
+    let user = db::get_user(db_pool, tenant.id, path.0)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
 
@@ -64,37 +513,257 @@ pub async fn get_user(
 
 pub async fn delete_user(
     Extension(db_pool): Extension<db::AuthDb>,
-    Extension(_valid_user): Extension<ValidUser>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
     path: axum::extract::Path<i32>,
 ) -> Result<StatusCode, StatusCode> {
-    db::delete_user(db_pool, path.0)
+    let deleted = db::delete_user(db_pool, tenant.id, path.0)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!("{principal} deleted user {}", path.0);
 
     Ok(StatusCode::OK)
 }
 
+/// Renames a user and sets their password. A username another user has
+/// gets a 409.
 pub async fn update_user(
     Extension(db_pool): Extension<db::AuthDb>,
-    Extension(_valid_user): Extension<ValidUser>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(_principal): Extension<Principal>,
     path: axum::extract::Path<i32>,
     update: Json<User>,
 ) -> Result<StatusCode, StatusCode> {
-    db::update_user(db_pool, path.0, &update)
+    let existing = db::find_user_id(db_pool.clone(), tenant.id, &update.username)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if existing.is_some_and(|user_id| user_id != path.0) {
+        return Err(StatusCode::CONFLICT);
+    }
+    let updated = db::update_user(db_pool, tenant.id, path.0, &update)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
 
     Ok(StatusCode::OK)
 }
 
+/// Adds a user to the tenant, or answers 409 if the username is taken.
 pub async fn add_user(
     Extension(db_pool): Extension<db::AuthDb>,
-    Extension(_valid_user): Extension<ValidUser>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(_principal): Extension<Principal>,
     new_user: Json<User>,
 ) -> Result<StatusCode, StatusCode> {
-    db::add_user(db_pool, &new_user)
+    let existing = db::find_user_id(db_pool.clone(), tenant.id, &new_user.username)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if existing.is_some() {
+        return Err(StatusCode::CONFLICT);
+    }
+    db::add_user(db_pool, tenant.id, &new_user)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewApiKey {
+    name: String,
+    scopes: Vec<Scope>,
+    expires_in_days: Option<u32>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedApiKey {
+    id: i32,
+    /// The key itself. It can't be recovered later.
+    key: String,
+    expires_at: Option<i64>,
+}
+
+pub async fn list_api_keys(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(_principal): Extension<Principal>,
+) -> Result<Json<Vec<ApiKey>>, StatusCode> {
+    let keys = db::list_api_keys(db_pool, tenant.id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(keys))
+}
+
+pub async fn add_api_key(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Json(new_key): Json<NewApiKey>,
+) -> Result<Json<CreatedApiKey>, StatusCode> {
+    // A key can't hand out more than its creator holds
+    for scope in &new_key.scopes {
+        principal.require(*scope)?;
+    }
+    if new_key.name.trim().is_empty() || new_key.scopes.is_empty() {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+
+    let expires_at = new_key.expires_in_days.map(|days| {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        now.as_secs() as i64 + i64::from(days) * 24 * 60 * 60
+    });
+    let scopes: Vec<&str> = new_key.scopes.iter().map(|scope| scope.as_str()).collect();
+    let (id, key) = db::add_api_key(
+        db_pool,
+        tenant.id,
+        new_key.name.trim(),
+        &scopes.join(","),
+        &principal.to_string(),
+        expires_at,
+    )
+    .await
+    .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    tracing::info!("{principal} created API key {id}");
+
+    Ok(Json(CreatedApiKey { id, key, expires_at }))
+}
+
+pub async fn revoke_api_key(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    path: axum::extract::Path<i32>,
+) -> Result<StatusCode, StatusCode> {
+    let revoked = db::revoke_api_key(db_pool, tenant.id, path.0)
         .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !revoked {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    tracing::info!("{principal} revoked API key {}", path.0);
 
     Ok(StatusCode::OK)
-}
\ No newline at end of file
+}
+
+/// A new tenant and its first admin, who can add the rest of its users.
+#[derive(Deserialize, Debug)]
+pub struct NewTenant {
+    slug: String,
+    name: String,
+    host: Option<String>,
+    #[serde(default)]
+    overrides: TenantOverrides,
+    admin_username: String,
+    admin_password: String,
+}
+
+/// Changes to a tenant. Its slug can't be changed.
+#[derive(Deserialize, Debug)]
+pub struct TenantChanges {
+    name: String,
+    host: Option<String>,
+    #[serde(default)]
+    overrides: TenantOverrides,
+}
+
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug.len() <= 63
+        && slug.bytes().all(|byte| byte.is_ascii_lowercase() || byte.is_ascii_digit() || byte == b'-')
+}
+
+/// Hosts are matched lowercase, without a port or trailing dot.
+fn normalise_host(host: Option<&str>) -> Option<String> {
+    host.map(|host| host.trim().trim_end_matches('.').to_ascii_lowercase())
+        .filter(|host| !host.is_empty())
+}
+
+fn check_tenant(name: &str, overrides: &TenantOverrides) -> Result<(), (StatusCode, String)> {
+    let mut problems = Vec::new();
+    if name.trim().is_empty() {
+        problems.push("name is empty".to_string());
+    }
+    overrides.validate(&mut problems);
+    if !problems.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, problems.join("; ")));
+    }
+    Ok(())
+}
+
+pub async fn list_tenants(
+    Extension(db_pool): Extension<db::AuthDb>,
+) -> Result<Json<Vec<Tenant>>, StatusCode> {
+    let tenants = db::list_tenants(db_pool)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+
+    Ok(Json(tenants))
+}
+
+pub async fn add_tenant(
+    Extension(config): Extension<AuthConfiguration>,
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    Json(new_tenant): Json<NewTenant>,
+) -> Result<(StatusCode, Json<Tenant>), (StatusCode, String)> {
+    if !is_valid_slug(&new_tenant.slug) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "slug must be lowercase letters, digits and hyphens".to_string(),
+        ));
+    }
+    check_tenant(&new_tenant.name, &new_tenant.overrides)?;
+    let admin_username = new_tenant.admin_username.trim();
+    if admin_username.is_empty() {
+        return Err((StatusCode::BAD_REQUEST, "admin_username is empty".to_string()));
+    }
+    check_new_password(&new_tenant.overrides.apply(&config).unwrap_or(config), &new_tenant.admin_password)?;
+
+    let admin = User {
+        id: 0,
+        username: admin_username.to_string(),
+        password: new_tenant.admin_password,
+    };
+    let host = normalise_host(new_tenant.host.as_deref());
+    let tenant = db::add_tenant(
+        db_pool,
+        &new_tenant.slug,
+        new_tenant.name.trim(),
+        host.as_deref(),
+        &new_tenant.overrides,
+        &admin,
+    )
+    .await
+    .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?
+    .ok_or((StatusCode::CONFLICT, "slug or host is taken".to_string()))?;
+    tracing::info!("{principal} created tenant {}", tenant.slug);
+
+    Ok((StatusCode::CREATED, Json(tenant)))
+}
+
+pub async fn update_tenant(
+    Extension(db_pool): Extension<db::AuthDb>,
+    Extension(principal): Extension<Principal>,
+    path: axum::extract::Path<i32>,
+    Json(changes): Json<TenantChanges>,
+) -> Result<Json<Tenant>, (StatusCode, String)> {
+    check_tenant(&changes.name, &changes.overrides)?;
+    let host = normalise_host(changes.host.as_deref());
+    let update = db::update_tenant(db_pool, path.0, changes.name.trim(), host.as_deref(), &changes.overrides)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?;
+    match update {
+        TenantUpdate::Updated(tenant) => {
+            tracing::info!("{principal} updated tenant {}", tenant.slug);
+            Ok(Json(tenant))
+        }
+        TenantUpdate::NotFound => Err((StatusCode::NOT_FOUND, "no such tenant".to_string())),
+        TenantUpdate::HostTaken => Err((StatusCode::CONFLICT, "host is taken".to_string())),
+    }
+}
@@ -0,0 +1,440 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use anyhow::{bail, Context, Result};
+use axum::{http::StatusCode, middleware, routing::{get, post}, Extension, Json, Router};
+use chrono::{NaiveDateTime, Utc};
+use fs4::fs_std::FileExt;
+use serde::{Deserialize, Serialize};
+use sqlx::sqlite::SqliteConnectOptions;
+use tokio::sync::Mutex;
+use crate::{
+    auth::auth_layers::{self, Scope},
+    modules::{Database, ModuleContext, Registry},
+};
+
+/// Backups are named for when they were taken, in UTC.
+const NAME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+/// A backup is written under this suffix and renamed once it's complete.
+const PARTIAL_SUFFIX: &str = ".partial";
+/// The file a restore replaces is kept alongside it with this suffix,
+/// followed by when it was replaced.
+const REPLACED_SUFFIX: &str = ".before-restore-";
+/// Each database has a lock file alongside it with this suffix.
+const LOCK_SUFFIX: &str = ".lock";
+
+/// The `backup` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct BackupConfiguration {
+    /// Where backups are written, one directory per backup. It should be on
+    /// a different volume from the databases.
+    pub directory: String,
+    /// How often the service takes a backup, in hours. 0 turns scheduled
+    /// backups off.
+    pub interval_hours: u64,
+    /// How many of the latest backups are kept.
+    pub keep: usize,
+}
+
+impl Default for BackupConfiguration {
+    fn default() -> Self {
+        Self {
+            directory: "backups".to_string(),
+            interval_hours: 24,
+            keep: 7,
+        }
+    }
+}
+
+impl BackupConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        let directory = Path::new(&self.directory);
+        if directory.exists() && !directory.is_dir() {
+            problems.push(format!("backup.directory: {} is not a directory", self.directory));
+        }
+        if self.keep == 0 {
+            problems.push("backup.keep: must be at least 1".to_string());
+        }
+    }
+}
+
+/// A complete backup: a snapshot of every module's database.
+#[derive(Serialize, Debug, Clone)]
+pub struct Backup {
+    pub name: String,
+    pub created_at: i64,
+    /// The modules whose databases it holds.
+    pub databases: Vec<String>,
+    pub size_bytes: u64,
+}
+
+/// Takes, lists and prunes backups of the modules' databases.
+#[derive(Clone)]
+pub struct Backups {
+    config: BackupConfiguration,
+    databases: Vec<(&'static str, Database)>,
+    /// One backup at a time, however it was asked for.
+    running: Arc<Mutex<()>>,
+}
+
+impl Backups {
+    pub fn new(config: BackupConfiguration, registry: &Registry) -> Self {
+        let databases = registry
+            .modules()
+            .iter()
+            .map(|module| (module.name(), module.database()))
+            .collect();
+        Self {
+            config,
+            databases,
+            running: Arc::new(Mutex::new(())),
+        }
+    }
+
+    /// Snapshots every database while the service is running, then deletes
+    /// the backups beyond `keep`. `VACUUM INTO` reads each database in a
+    /// single transaction, so each snapshot is consistent.
+    pub async fn create(&self) -> Result<Backup> {
+        let _running = self.running.lock().await;
+        let name = Utc::now().format(NAME_FORMAT).to_string();
+        let directory = Path::new(&self.config.directory);
+        let destination = directory.join(&name);
+        if destination.exists() {
+            bail!("backup {name} already exists");
+        }
+
+        let partial = directory.join(format!("{name}{PARTIAL_SUFFIX}"));
+        tokio::fs::create_dir_all(&partial)
+            .await
+            .with_context(|| format!("unable to create {}", partial.display()))?;
+        for (module, database) in &self.databases {
+            let file = partial.join(format!("{module}.db"));
+            let snapshot = sqlx::query("VACUUM INTO ?")
+                .bind(file.to_string_lossy().as_ref())
+                .execute(&database.pool)
+                .await;
+            if let Err(e) = snapshot {
+                let _ = tokio::fs::remove_dir_all(&partial).await;
+                return Err(anyhow::Error::new(e).context(format!("unable to back up the {module} database")));
+            }
+        }
+        tokio::fs::rename(&partial, &destination).await?;
+        tracing::info!("Backed up to {}", destination.display());
+
+        self.prune().await?;
+        read_backup(&destination)
+            .await?
+            .context("the backup disappeared as it was written")
+    }
+
+    /// Every complete backup, newest first.
+    pub async fn list(&self) -> Result<Vec<Backup>> {
+        let directory = Path::new(&self.config.directory);
+        if !directory.exists() {
+            return Ok(Vec::new());
+        }
+        let mut backups = Vec::new();
+        let mut entries = tokio::fs::read_dir(directory).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            if let Some(backup) = read_backup(&entry.path()).await? {
+                backups.push(backup);
+            }
+        }
+        backups.sort_by(|a, b| b.name.cmp(&a.name));
+        Ok(backups)
+    }
+
+    pub async fn latest(&self) -> Result<Option<Backup>> {
+        Ok(self.list().await?.into_iter().next())
+    }
+
+    async fn prune(&self) -> Result<()> {
+        for backup in self.list().await?.into_iter().skip(self.config.keep) {
+            let path = Path::new(&self.config.directory).join(&backup.name);
+            tokio::fs::remove_dir_all(&path).await?;
+            tracing::info!("Deleted old backup {}", backup.name);
+        }
+        Ok(())
+    }
+
+    /// Takes a backup every `interval_hours`, counting from the latest one,
+    /// so restarting the service doesn't put the schedule back. If there
+    /// isn't one yet, it takes one straight away.
+    pub async fn run(self) {
+        let interval = Duration::from_secs(self.config.interval_hours * 60 * 60);
+        loop {
+            let since_latest = match self.latest().await {
+                Ok(Some(backup)) => Duration::from_secs((Utc::now().timestamp() - backup.created_at).max(0) as u64),
+                Ok(None) => interval,
+                Err(e) => {
+                    tracing::warn!("Unable to list backups: {e:#}");
+                    Duration::ZERO
+                }
+            };
+            tokio::time::sleep(interval.saturating_sub(since_latest)).await;
+            if let Err(e) = self.create().await {
+                tracing::error!("Scheduled backup failed: {e:#}");
+                // Try again later rather than straight away
+                tokio::time::sleep(interval.min(Duration::from_secs(60 * 60))).await;
+            }
+        }
+    }
+}
+
+/// Reads a backup's directory, or returns `None` if it isn't a complete
+/// backup.
+async fn read_backup(path: &Path) -> Result<Option<Backup>> {
+    let Some(name) = path.file_name().and_then(|name| name.to_str()) else {
+        return Ok(None);
+    };
+    let Ok(created_at) = NaiveDateTime::parse_from_str(name, NAME_FORMAT) else {
+        return Ok(None);
+    };
+    if !path.is_dir() {
+        return Ok(None);
+    }
+
+    let mut databases = Vec::new();
+    let mut size_bytes = 0;
+    let mut entries = tokio::fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+        let file = entry.path();
+        if let (Some(module), Some("db")) = (
+            file.file_stem().and_then(|stem| stem.to_str()),
+            file.extension().and_then(|extension| extension.to_str()),
+        ) {
+            databases.push(module.to_string());
+            size_bytes += entry.metadata().await?.len();
+        }
+    }
+    databases.sort();
+    Ok(Some(Backup {
+        name: name.to_string(),
+        created_at: created_at.and_utc().timestamp(),
+        databases,
+        size_bytes,
+    }))
+}
+
+/// Replaces the modules' databases with those in a backup. Every file is
+/// copied next to the one it replaces and checked with `PRAGMA
+/// integrity_check` before any is swapped in. The replaced files, with
+/// their write-ahead logs, are kept with a `.before-restore-<time>`
+/// suffix. Refuses to start while the service is running.
+pub async fn restore(config: &BackupConfiguration, mut registry: Registry, name: &str, only: &[String]) -> Result<()> {
+    let backup = Path::new(&config.directory).join(name);
+    if read_backup(&backup).await?.is_none() {
+        bail!("{} is not a backup", backup.display());
+    }
+    registry.lock().context("stop the service before restoring")?;
+
+    let mut databases = Vec::new();
+    for module in registry.modules() {
+        if !only.is_empty() && !only.iter().any(|name| name == module.name()) {
+            continue;
+        }
+        let database = module.database();
+        // Nothing may have the files open while they're swapped
+        database.pool.close().await;
+        databases.push((module.name(), PathBuf::from(database.filename)));
+    }
+    if let Some(unknown) = only.iter().find(|name| !databases.iter().any(|(module, _)| module == name)) {
+        bail!("there's no {unknown} module to restore");
+    }
+
+    let staged: Vec<_> = databases
+        .iter()
+        .map(|(module, target)| (*module, with_suffix(target, ".restoring"), target))
+        .collect();
+    for (module, restoring, _) in &staged {
+        if let Err(e) = stage(&backup, module, restoring).await {
+            for (_, restoring, _) in &staged {
+                let _ = tokio::fs::remove_file(restoring).await;
+            }
+            return Err(e.context(format!("nothing was restored from backup {name}")));
+        }
+    }
+
+    let replaced_suffix = format!("{REPLACED_SUFFIX}{}", Utc::now().format(NAME_FORMAT));
+    for (module, restoring, target) in staged {
+        let replaced = with_suffix(target, &replaced_suffix);
+        if target.exists() {
+            tokio::fs::rename(target, &replaced).await?;
+        }
+        // The old database's write-ahead log goes with it, rather than
+        // being replayed into the new one
+        for suffix in ["-wal", "-shm"] {
+            let log = with_suffix(target, suffix);
+            if log.exists() {
+                tokio::fs::rename(&log, with_suffix(&replaced, suffix)).await?;
+            }
+        }
+        tokio::fs::rename(&restoring, target).await?;
+        println!("{module}: restored {} from backup {name}", target.display());
+    }
+    Ok(())
+}
+
+/// Copies a module's database out of a backup to `restoring`, and checks
+/// the copy.
+async fn stage(backup: &Path, module: &str, restoring: &Path) -> Result<()> {
+    let source = backup.join(format!("{module}.db"));
+    if !source.exists() {
+        bail!("the backup has no {module} database");
+    }
+    tokio::fs::copy(&source, restoring)
+        .await
+        .with_context(|| format!("unable to copy {} to {}", source.display(), restoring.display()))?;
+    check_integrity(restoring)
+        .await
+        .with_context(|| format!("the {module} database in the backup is damaged"))
+}
+
+async fn check_integrity(file: &Path) -> Result<()> {
+    let options = SqliteConnectOptions::new().filename(file).read_only(true);
+    let pool = sqlx::SqlitePool::connect_with(options).await?;
+    let problems: Vec<String> = sqlx::query_scalar("PRAGMA integrity_check")
+        .fetch_all(&pool)
+        .await?;
+    pool.close().await;
+    if problems != ["ok"] {
+        bail!("{}", problems.join("; "));
+    }
+    Ok(())
+}
+
+/// Locks the lock file alongside a database, until the file returned is
+/// dropped. Fails if something else holds the lock.
+pub fn lock_database(filename: &str) -> Result<std::fs::File> {
+    let path = with_suffix(Path::new(filename), LOCK_SUFFIX);
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(&path)
+        .with_context(|| format!("unable to open {}", path.display()))?;
+    let locked = file
+        .try_lock_exclusive()
+        .with_context(|| format!("unable to lock {}", path.display()))?;
+    if !locked {
+        bail!("{filename} is in use by a running service or a restore");
+    }
+    Ok(file)
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+type ApiError = (StatusCode, String);
+
+fn internal(e: anyhow::Error) -> ApiError {
+    tracing::error!("Backup failed: {e:#}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "backup failed".to_string())
+}
+
+async fn list_backups(Extension(backups): Extension<Backups>) -> Result<Json<Vec<Backup>>, ApiError> {
+    Ok(Json(backups.list().await.map_err(internal)?))
+}
+
+async fn create_backup(Extension(backups): Extension<Backups>) -> Result<(StatusCode, Json<Backup>), ApiError> {
+    Ok((StatusCode::CREATED, Json(backups.create().await.map_err(internal)?)))
+}
+
+/// Admin routes for backups, mounted at `/api/v1/admin`.
+pub fn router(backups: Backups, context: &ModuleContext) -> Router {
+    Router::new()
+        .route("/backups", get(list_backups))
+        .route("/backups/create", post(create_backup))
+        .route_layer(middleware::from_fn_with_state(Scope::BackupsAdmin, auth_layers::require_scope))
+        .route_layer(context.idempotency.clone())
+        .route_layer(context.rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token))
+        .layer(Extension(backups))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use crate::{modules::Registry, test_harness::TestApp};
+    use super::restore;
+
+    async fn usernames(app: &TestApp) -> Vec<String> {
+        let token = app.admin_token().await;
+        let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+        let mut usernames: Vec<String> = users
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|user| user["username"].as_str().unwrap().to_string())
+            .collect();
+        usernames.sort();
+        usernames
+    }
+
+    async fn back_up(app: &TestApp) -> String {
+        let token = app.admin_token().await;
+        let response = app.post("/api/v1/admin/backups/create", Some(&token), json!({})).await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+        response.json()["name"].as_str().unwrap().to_string()
+    }
+
+    async fn restore_all(app: &TestApp, name: &str) -> anyhow::Result<()> {
+        let registry = Registry::open(&app.config).await?;
+        restore(&app.config.backup, registry, name, &[]).await
+    }
+
+    /// The files alongside the auth database.
+    fn files(app: &TestApp) -> Vec<String> {
+        let directory = Path::new(&app.config.auth.db_filename).parent().unwrap();
+        let mut files: Vec<String> = std::fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        files.sort();
+        files
+    }
+
+    #[tokio::test]
+    async fn restores_put_the_databases_back() {
+        let mut app = TestApp::new().await;
+        app.reader_token("alice").await;
+        let name = back_up(&app).await;
+        app.reader_token("bob").await;
+
+        let error = restore_all(&app, &name).await.unwrap_err();
+        assert!(format!("{error:#}").contains("stop the service"), "{error:#}");
+
+        app.stop().await;
+        restore_all(&app, &name).await.unwrap();
+        let files = files(&app);
+        assert!(files.iter().any(|file| file.starts_with("auth.db.before-restore-")), "{files:?}");
+        assert!(!files.iter().any(|file| file.contains(".restoring")), "{files:?}");
+        app.start().await;
+        assert_eq!(usernames(&app).await, ["admin", "alice"]);
+    }
+
+    #[tokio::test]
+    async fn damaged_backups_restore_nothing() {
+        let mut app = TestApp::new().await;
+        let name = back_up(&app).await;
+        app.reader_token("alice").await;
+        let damaged = Path::new(&app.config.backup.directory).join(&name).join("auth.db");
+        std::fs::write(damaged, b"not a database").unwrap();
+
+        app.stop().await;
+        let before = files(&app);
+        let error = restore_all(&app, &name).await.unwrap_err();
+        assert!(format!("{error:#}").contains("damaged"), "{error:#}");
+        assert_eq!(files(&app), before);
+        app.start().await;
+        assert_eq!(usernames(&app).await, ["admin", "alice"]);
+    }
+}
@@ -0,0 +1,88 @@
+use std::{fs::File, io::{self, Read, Write}};
+use anyhow::Result;
+use super::{
+    configuration::BookstoreConfiguration,
+    db::{self, StoreDb},
+    transfer::{self, BookRecord, DataFormat, Importer},
+};
+
+async fn open_database(config: &BookstoreConfiguration) -> Result<StoreDb> {
+    let db_pool = db::get_connection_pool(&config.db_filename).await?;
+    db::perform_migrations(db_pool.clone()).await?;
+    Ok(db_pool)
+}
+
+/// Writes a tenant's whole catalogue to `output`, or to stdout if there
+/// isn't one.
+pub async fn export_books(
+    config: &BookstoreConfiguration,
+    tenant_id: i32,
+    format: DataFormat,
+    output: Option<&str>,
+) -> Result<()> {
+    let db_pool = open_database(config).await?;
+    let books = db::list_books(db_pool, tenant_id).await?;
+
+    let mut writer: Box<dyn Write> = match output {
+        Some(filename) => Box::new(File::create(filename)?),
+        None => Box::new(io::stdout().lock()),
+    };
+    match format {
+        DataFormat::Json => {
+            serde_json::to_writer_pretty(&mut writer, &books)?;
+            writeln!(writer)?;
+        }
+        DataFormat::Ndjson | DataFormat::Csv => {
+            if format == DataFormat::Csv {
+                writer.write_all(transfer::CSV_HEADER)?;
+            }
+            for book in books {
+                writer.write_all(&transfer::encode_book(format, &book)?)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Adds every book in `input` (or stdin) to a tenant's catalogue, skipping
+/// duplicates. Nothing is added if any row is invalid.
+pub async fn import_books(
+    config: &BookstoreConfiguration,
+    tenant_id: i32,
+    format: DataFormat,
+    input: Option<&str>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut reader: Box<dyn Read> = match input {
+        Some(filename) => Box::new(File::open(filename)?),
+        None => Box::new(io::stdin().lock()),
+    };
+    let mut contents = Vec::new();
+    reader.read_to_end(&mut contents)?;
+
+    let db_pool = open_database(config).await?;
+    let mut importer = Importer::begin(&db_pool, tenant_id, format, dry_run).await?;
+    if format == DataFormat::Json {
+        let records: Vec<BookRecord> = serde_json::from_slice(&contents)?;
+        for (index, record) in records.into_iter().enumerate() {
+            importer.add(index + 1, record).await?;
+        }
+    } else {
+        importer.push(&contents).await?;
+    }
+
+    let report = importer.finish().await?;
+    for error in &report.errors {
+        eprintln!("Row {}: {}", error.row, error.message);
+    }
+    println!(
+        "{} {} books, skipped {} duplicates",
+        if report.committed { "Imported" } else { "Would import" },
+        report.imported,
+        report.duplicates
+    );
+    if !report.errors.is_empty() {
+        anyhow::bail!("{} rows were invalid; nothing was imported", report.errors.len());
+    }
+    Ok(())
+}
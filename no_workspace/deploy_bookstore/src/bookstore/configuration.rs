@@ -1,27 +1,168 @@
-use anyhow::Result;
-use config::Config;
+use std::path::Path;
 use serde::{Deserialize, Serialize};
+use crate::{service_config::check_writable_file, tenancy::Overridable};
 
+/// The `bookstore` section of the service configuration.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct BookstoreConfiguration {
     pub db_filename: String,
+    /// The largest upload accepted by `/import`, in bytes.
+    pub max_import_bytes: usize,
+    /// The longest review text, in characters.
+    pub max_review_length: usize,
+    pub covers: CoversConfiguration,
+    pub events: ChangeFeedConfiguration,
+    pub outbox: OutboxConfiguration,
+}
+
+impl Default for BookstoreConfiguration {
+    fn default() -> Self {
+        Self {
+            db_filename: "bookstore.db".to_string(),
+            max_import_bytes: 16 * 1024 * 1024,
+            max_review_length: 5000,
+            covers: CoversConfiguration::default(),
+            events: ChangeFeedConfiguration::default(),
+            outbox: OutboxConfiguration::default(),
+        }
+    }
+}
+
+impl Overridable for BookstoreConfiguration {
+    const SECTION: &'static str = "bookstore";
+    const OVERRIDABLE: &'static [&'static str] = &["max_review_length"];
 }
 
 impl BookstoreConfiguration {
-    pub fn load() -> Result<Self> {
-        // Load any .env files
-        // Ignore the result of loading .env --- it's ok if it doesn't exist
-        let _ = dotenvy::dotenv();
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        check_writable_file("bookstore.db_filename", &self.db_filename, problems);
+        self.covers.validate(problems);
+        self.events.validate(problems);
+        self.outbox.validate(problems);
+    }
+}
 
-        let settings_reader = Config::builder()
-            .add_source(config::File::with_name("settings").required(false))
-            .add_source(config::Environment::with_prefix("BOOKSTORE"))
-            .build()?;
+/// The `bookstore.covers` section: cover image uploads.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CoversConfiguration {
+    /// Where cover images are stored. Created if it doesn't exist.
+    pub directory: String,
+    /// The largest upload accepted, in bytes.
+    pub max_upload_bytes: usize,
+    /// The widest or tallest image accepted, in pixels.
+    pub max_dimension: u32,
+    /// Thumbnails fit in a square this many pixels across.
+    pub thumbnail_size: u32,
+    /// How long browsers may cache a cover, in seconds.
+    pub cache_seconds: u64,
+}
 
-        let settings = settings_reader
-            .try_deserialize()?;
+impl Default for CoversConfiguration {
+    fn default() -> Self {
+        Self {
+            directory: "covers".to_string(),
+            max_upload_bytes: 5 * 1024 * 1024,
+            max_dimension: 6000,
+            thumbnail_size: 200,
+            cache_seconds: 24 * 60 * 60,
+        }
+    }
+}
 
-        Ok(settings)
+impl CoversConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        let directory = Path::new(&self.directory);
+        if directory.exists() && !directory.is_dir() {
+            problems.push(format!("bookstore.covers.directory: {} is not a directory", self.directory));
+        }
+        if self.max_upload_bytes == 0 || self.max_dimension == 0 || self.thumbnail_size == 0 {
+            problems.push("bookstore.covers: limits must be greater than zero".to_string());
+        }
+    }
+}
 
+/// The `bookstore.events` section: the catalogue's change feed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct ChangeFeedConfiguration {
+    /// How many of the latest events are kept for clients to resume from.
+    pub keep_events: i64,
+    /// How often to check for changes made outside the service, such as by
+    /// the `books import` command, in seconds.
+    pub poll_seconds: u64,
+    /// How often idle connections are sent a keep-alive, in seconds.
+    pub keep_alive_seconds: u64,
+    /// The most clients that can follow the feed at once.
+    pub max_subscribers: usize,
+}
+
+impl Default for ChangeFeedConfiguration {
+    fn default() -> Self {
+        Self {
+            keep_events: 10_000,
+            poll_seconds: 2,
+            keep_alive_seconds: 15,
+            max_subscribers: 1000,
+        }
+    }
+}
+
+impl ChangeFeedConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if self.keep_events < 1
+            || self.poll_seconds == 0
+            || self.keep_alive_seconds == 0
+            || self.max_subscribers == 0
+        {
+            problems.push("bookstore.events: settings must be greater than zero".to_string());
+        }
+    }
+}
+
+/// The `bookstore.outbox` section: where the catalogue's domain events are
+/// relayed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OutboxConfiguration {
+    /// Write each event to the service's log.
+    pub log: bool,
+    /// POST each event to this URL.
+    pub webhook_url: Option<String>,
+    pub webhook_timeout_seconds: u64,
+    /// How often to check for events written outside the service, such as
+    /// by the `books import` command, in seconds.
+    pub poll_seconds: u64,
+    /// How long a sink that failed waits before trying again, in seconds.
+    pub retry_seconds: u64,
+    /// How many events are read at a time.
+    pub batch_size: i64,
+}
+
+impl Default for OutboxConfiguration {
+    fn default() -> Self {
+        Self {
+            log: false,
+            webhook_url: None,
+            webhook_timeout_seconds: 10,
+            poll_seconds: 2,
+            retry_seconds: 5,
+            batch_size: 100,
+        }
     }
-}
\ No newline at end of file
+}
+
+impl OutboxConfiguration {
+    fn validate(&self, problems: &mut Vec<String>) {
+        if let Some(url) = &self.webhook_url {
+            match reqwest::Url::parse(url) {
+                Ok(url) if matches!(url.scheme(), "http" | "https") => {}
+                _ => problems.push(format!("bookstore.outbox.webhook_url: {url} is not an http or https URL")),
+            }
+        }
+        if self.webhook_timeout_seconds == 0 || self.poll_seconds == 0 || self.retry_seconds == 0 || self.batch_size < 1 {
+            problems.push("bookstore.outbox: settings must be greater than zero".to_string());
+        }
+    }
+}
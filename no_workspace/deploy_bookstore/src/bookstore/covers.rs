@@ -0,0 +1,130 @@
+use std::{io::Cursor, path::PathBuf};
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use image::{codecs::jpeg::JpegEncoder, ImageFormat, ImageReader, Limits};
+use serde::Deserialize;
+use super::configuration::CoversConfiguration;
+
+/// Where cover images are kept. Keys are relative paths such as
+/// `12/thumb`, so an object store can use them as they are.
+#[async_trait]
+pub trait CoverStorage: Send + Sync {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()>;
+
+    /// `None` if there's nothing stored under `key`.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>>;
+
+    /// Succeeds if there's nothing to delete.
+    async fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// Keeps covers as files under a directory.
+pub struct FileCoverStorage {
+    directory: PathBuf,
+}
+
+impl FileCoverStorage {
+    pub fn new(directory: impl Into<PathBuf>) -> Self {
+        Self {
+            directory: directory.into(),
+        }
+    }
+}
+
+#[async_trait]
+impl CoverStorage for FileCoverStorage {
+    async fn put(&self, key: &str, bytes: Vec<u8>) -> Result<()> {
+        let path = self.directory.join(key);
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        // Write then rename, so readers never see half a file. Each upload
+        // has its own partial file, so uploads of the same cover can't mix.
+        let mut partial = path.clone().into_os_string();
+        partial.push(format!(".{}.partial", uuid::Uuid::new_v4()));
+        let partial = PathBuf::from(partial);
+        let stored = match tokio::fs::write(&partial, bytes).await {
+            Ok(()) => tokio::fs::rename(&partial, &path).await,
+            Err(e) => Err(e),
+        };
+        if stored.is_err() {
+            let _ = tokio::fs::remove_file(&partial).await;
+        }
+        stored.with_context(|| format!("unable to store {}", path.display()))
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>> {
+        match tokio::fs::read(self.directory.join(key)).await {
+            Ok(bytes) => Ok(Some(bytes)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<()> {
+        match tokio::fs::remove_file(self.directory.join(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The sizes a cover is served in.
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CoverSize {
+    #[default]
+    Original,
+    Thumb,
+}
+
+impl CoverSize {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Original => "original",
+            Self::Thumb => "thumb",
+        }
+    }
+
+    pub fn key(self, book_id: i32) -> String {
+        format!("{book_id}/{}", self.as_str())
+    }
+}
+
+pub const THUMBNAIL_TYPE: &str = "image/jpeg";
+
+/// An uploaded cover, checked and ready to store.
+pub struct ProcessedCover {
+    /// The type the upload's contents turned out to be.
+    pub content_type: &'static str,
+    pub thumbnail: Vec<u8>,
+}
+
+/// Works out what an upload is from its contents, whatever the client
+/// claimed, then decodes it and makes a JPEG thumbnail. Decoding is
+/// limited, so a small file can't claim enormous dimensions.
+pub fn process_cover(bytes: &[u8], config: &CoversConfiguration) -> Result<ProcessedCover> {
+    let format = image::guess_format(bytes).context("not an image")?;
+    let content_type = match format {
+        ImageFormat::Jpeg => "image/jpeg",
+        ImageFormat::Png => "image/png",
+        ImageFormat::WebP => "image/webp",
+        _ => bail!("covers must be JPEG, PNG or WebP"),
+    };
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(config.max_dimension);
+    limits.max_image_height = Some(config.max_dimension);
+    let mut reader = ImageReader::with_format(Cursor::new(bytes), format);
+    reader.limits(limits);
+    let image = reader.decode().context("unreadable image")?;
+
+    let thumbnail = image.thumbnail(config.thumbnail_size, config.thumbnail_size).into_rgb8();
+    let mut encoded = Vec::new();
+    JpegEncoder::new_with_quality(&mut encoded, 85).encode_image(&thumbnail)?;
+
+    Ok(ProcessedCover {
+        content_type,
+        thumbnail: encoded,
+    })
+}
@@ -1,72 +1,581 @@
+use std::sync::Arc;
 use anyhow::Result;
 use serde::{Deserialize, Serialize};
-use sqlx::{prelude::FromRow, sqlite::SqliteConnectOptions};
+use sqlx::{migrate::Migrator, prelude::FromRow, sqlite::SqliteConnectOptions, SqliteConnection};
+use tokio::sync::watch;
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use crate::event_bus::DomainEvent;
 
+/// The catalogue's connection pool. Writes to the catalogue wake the
+/// change feed and the outbox relay, which pick up the events written to
+/// the outbox with them.
 #[derive(Clone)]
-pub struct StoreDb(pub sqlx::SqlitePool);
+pub struct StoreDb(pub sqlx::SqlitePool, Arc<watch::Sender<u64>>);
+
+impl StoreDb {
+    pub fn changed(&self) {
+        self.1.send_modify(|writes| *writes += 1);
+    }
+
+    /// Follows writes to the catalogue. `changed()` on the receiver returns
+    /// once there's been a write since it last did.
+    pub fn watch_changes(&self) -> watch::Receiver<u64> {
+        self.1.subscribe()
+    }
+}
 
 pub async fn get_connection_pool(filename: &str) -> Result<StoreDb> {
     let options = SqliteConnectOptions::new()
         .filename(filename)
         .create_if_missing(true);
 
-    let connection_pool = sqlx::SqlitePool::connect_with(options)
-        .await?;
-    Ok(StoreDb(connection_pool))
+    let connection_pool = sqlx::SqlitePool::connect_lazy_with(options);
+    Ok(StoreDb(connection_pool, Arc::new(watch::Sender::new(0))))
 }
 
+/// This build's migrations, up and down.
+pub static MIGRATOR: Migrator = sqlx::migrate!("src/bookstore/migrations");
+
 pub async fn perform_migrations(db_pool: StoreDb) -> Result<()> {
-    sqlx::migrate!("src/bookstore/migrations")
-        .run(&db_pool.0)
-        .await?;
+    crate::migrations::migrate("bookstore", &MIGRATOR, &db_pool.0).await
+}
+
+pub async fn ping(db_pool: StoreDb) -> Result<()> {
+    sqlx::query("SELECT 1").execute(&db_pool.0).await?;
     Ok(())
 }
 
-#[derive(Serialize, Deserialize, Debug, FromRow)]
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
 pub struct Book {
     pub id: i32,
     pub title: String,
     pub author: String,
+    #[serde(default)]
+    pub isbn: Option<String>,
+}
+
+/// A book with its readers' rating, as the catalogue shows it.
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct CatalogueEntry {
+    #[serde(flatten)]
+    #[sqlx(flatten)]
+    pub book: Book,
+    /// Visible reviews only.
+    pub rating_count: i64,
+    /// Out of 5, to two decimal places. `None` until someone rates it.
+    pub average_rating: Option<f64>,
+    /// Changes whenever the cover does, so it can be added to cover URLs
+    /// to bust caches. `None` if the book has no cover.
+    pub cover_version: Option<i64>,
 }
 
-pub async fn list_books(db_pool: StoreDb) -> Result<Vec<Book>> {
-    let books = sqlx::query_as::<_, Book>("SELECT * FROM books")
+const CATALOGUE_QUERY: &str = "SELECT id, title, author, isbn, rating_count,
+    ROUND(CAST(rating_total AS REAL) / NULLIF(rating_count, 0), 2) AS average_rating,
+    CASE WHEN cover_type IS NOT NULL THEN cover_version END AS cover_version
+    FROM books";
+
+pub async fn list_books(db_pool: StoreDb, tenant_id: i32) -> Result<Vec<Book>> {
+    let books = sqlx::query_as::<_, Book>("SELECT id, title, author, isbn FROM books WHERE tenant_id = ?")
+        .bind(tenant_id)
         .fetch_all(&db_pool.0)
         .await?;
     Ok(books)
 }
 
-pub async fn get_book(db_pool: StoreDb, id: i32) -> Result<Book> {
-    let book = sqlx::query_as::<_, Book>("SELECT * FROM books WHERE id = ?")
+pub async fn list_catalogue(db_pool: StoreDb, tenant_id: i32) -> Result<Vec<CatalogueEntry>> {
+    let books = sqlx::query_as::<_, CatalogueEntry>(&format!("{CATALOGUE_QUERY} WHERE tenant_id = ?"))
+        .bind(tenant_id)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(books)
+}
+
+/// Streams a tenant's whole catalogue, one book at a time, without holding
+/// it all in memory.
+pub fn stream_books(db_pool: StoreDb, tenant_id: i32) -> ReceiverStream<Result<Book>> {
+    let (tx, rx) = tokio::sync::mpsc::channel(16);
+
+    tokio::spawn(async move {
+        let mut books = sqlx::query_as::<_, Book>("SELECT * FROM books WHERE tenant_id = ? ORDER BY id")
+            .bind(tenant_id)
+            .fetch(&db_pool.0);
+        while let Some(book) = books.next().await {
+            if tx.send(book.map_err(Into::into)).await.is_err() {
+                // The receiver hung up
+                break;
+            }
+        }
+    });
+
+    ReceiverStream::new(rx)
+}
+
+pub async fn get_book(db_pool: StoreDb, tenant_id: i32, id: i32) -> Result<Option<CatalogueEntry>> {
+    let book = sqlx::query_as::<_, CatalogueEntry>(&format!("{CATALOGUE_QUERY} WHERE id = ? AND tenant_id = ?"))
         .bind(id)
-        .fetch_one(&db_pool.0)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
         .await?;
     Ok(book)
 }
 
-pub async fn delete_book(db_pool: StoreDb, id: i32) -> Result<()> {
-    sqlx::query("DELETE FROM books WHERE id = ?")
+/// A book without its rating, or `None` if the tenant has no such book.
+pub async fn find_book(db_pool: StoreDb, tenant_id: i32, id: i32) -> Result<Option<Book>> {
+    let book = sqlx::query_as::<_, Book>("SELECT * FROM books WHERE id = ? AND tenant_id = ?")
         .bind(id)
-        .execute(&db_pool.0)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
         .await?;
+    Ok(book)
+}
+
+/// Deletes a book, returning false if the tenant has no such book.
+pub async fn delete_book(db_pool: StoreDb, tenant_id: i32, id: i32) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let deleted = sqlx::query("DELETE FROM books WHERE id = ? AND tenant_id = ?")
+        .bind(id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if deleted > 0 {
+        record_event(&mut tx, tenant_id, "book.deleted", id).await?;
+    }
+    tx.commit().await?;
+    db_pool.changed();
+    Ok(deleted > 0)
+}
+
+pub async fn add_book(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    title: String,
+    author: String,
+    isbn: Option<String>,
+) -> Result<()> {
+    let mut tx = db_pool.0.begin().await?;
+    insert_book(&mut tx, tenant_id, &title, &author, isbn.as_deref()).await?;
+    tx.commit().await?;
+    db_pool.changed();
     Ok(())
 }
 
-pub async fn add_book(db_pool: StoreDb, title: String, author: String) -> Result<()> {
-    sqlx::query("INSERT INTO books (title, author) VALUES (?, ?)")
+/// Updates a book, returning false if the tenant has no such book.
+pub async fn update_book(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    id: i32,
+    title: String,
+    author: String,
+    isbn: Option<String>,
+) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let updated = sqlx::query("UPDATE books SET title = ?, author = ?, isbn = ? WHERE id = ? AND tenant_id = ?")
+        .bind(title)
+        .bind(author)
+        .bind(isbn)
+        .bind(id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated > 0 {
+        record_event(&mut tx, tenant_id, "book.updated", id).await?;
+    }
+    tx.commit().await?;
+    db_pool.changed();
+    Ok(updated > 0)
+}
+
+/// Whether an error is the database refusing a second book with an ISBN
+/// the tenant already has.
+pub fn is_isbn_taken(error: &anyhow::Error) -> bool {
+    matches!(error.downcast_ref::<sqlx::Error>(), Some(sqlx::Error::Database(error)) if error.is_unique_violation())
+}
+
+/// Checks whether a book is already in the tenant's catalogue: by ISBN if
+/// there is one, otherwise by title and author.
+pub async fn is_duplicate_book(
+    connection: &mut SqliteConnection,
+    tenant_id: i32,
+    title: &str,
+    author: &str,
+    isbn: Option<&str>,
+) -> Result<bool> {
+    // Without an ISBN on both sides, the title and author have to do
+    let existing = match isbn {
+        Some(isbn) => sqlx::query(
+            "SELECT id FROM books
+             WHERE tenant_id = ? AND (isbn = ? OR (isbn IS NULL AND title = ? AND author = ?))",
+        )
+            .bind(tenant_id)
+            .bind(isbn)
+            .bind(title)
+            .bind(author)
+            .fetch_optional(&mut *connection)
+            .await?,
+        None => sqlx::query("SELECT id FROM books WHERE tenant_id = ? AND title = ? AND author = ?")
+            .bind(tenant_id)
+            .bind(title)
+            .bind(author)
+            .fetch_optional(&mut *connection)
+            .await?,
+    };
+    Ok(existing.is_some())
+}
+
+/// Adds a book to a tenant's catalogue using an existing connection, along
+/// with its event in the outbox, so it should be inside a transaction.
+/// Call `StoreDb::changed` once it's committed.
+pub async fn insert_book(
+    connection: &mut SqliteConnection,
+    tenant_id: i32,
+    title: &str,
+    author: &str,
+    isbn: Option<&str>,
+) -> Result<()> {
+    let id = sqlx::query_scalar("INSERT INTO books (tenant_id, title, author, isbn) VALUES (?, ?, ?, ?) RETURNING id")
+        .bind(tenant_id)
         .bind(title)
         .bind(author)
+        .bind(isbn)
+        .fetch_one(&mut *connection)
+        .await?;
+    record_event(connection, tenant_id, "book.added", id).await
+}
+
+/// A book's cover type and version, if it has a cover.
+#[derive(Debug, FromRow)]
+pub struct CoverInfo {
+    pub cover_type: String,
+    pub cover_version: i64,
+}
+
+pub async fn get_cover(db_pool: StoreDb, tenant_id: i32, book_id: i32) -> Result<Option<CoverInfo>> {
+    let cover = sqlx::query_as::<_, CoverInfo>(
+        "SELECT cover_type, cover_version FROM books WHERE id = ? AND tenant_id = ? AND cover_type IS NOT NULL",
+    )
+        .bind(book_id)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
+        .await?;
+    Ok(cover)
+}
+
+/// Records a new cover for one of the tenant's books, returning its
+/// version.
+pub async fn set_cover(db_pool: StoreDb, tenant_id: i32, book_id: i32, content_type: &str) -> Result<i64> {
+    let mut tx = db_pool.0.begin().await?;
+    let version = sqlx::query_scalar(
+        "UPDATE books SET cover_type = ?, cover_version = cover_version + 1
+         WHERE id = ? AND tenant_id = ? RETURNING cover_version",
+    )
+        .bind(content_type)
+        .bind(book_id)
+        .bind(tenant_id)
+        .fetch_one(&mut *tx)
+        .await?;
+    record_event(&mut tx, tenant_id, "book.updated", book_id).await?;
+    tx.commit().await?;
+    db_pool.changed();
+    Ok(version)
+}
+
+/// Removes a book's cover, returning false if the tenant has no such book
+/// with a cover.
+pub async fn clear_cover(db_pool: StoreDb, tenant_id: i32, book_id: i32) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let cleared = sqlx::query(
+        "UPDATE books SET cover_type = NULL WHERE id = ? AND tenant_id = ? AND cover_type IS NOT NULL",
+    )
+        .bind(book_id)
+        .bind(tenant_id)
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if cleared > 0 {
+        record_event(&mut tx, tenant_id, "book.updated", book_id).await?;
+    }
+    tx.commit().await?;
+    db_pool.changed();
+    Ok(cleared > 0)
+}
+
+#[derive(Serialize, Debug, FromRow)]
+pub struct Review {
+    pub id: i32,
+    pub book_id: i32,
+    pub user_id: i32,
+    pub rating: i64,
+    pub body: String,
+    /// Hidden by a moderator. Hidden reviews aren't listed or counted.
+    pub hidden: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Lists the reviews of one of a tenant's books, newest first.
+pub async fn list_reviews(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    book_id: i32,
+    include_hidden: bool,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Review>> {
+    let reviews = sqlx::query_as::<_, Review>(
+        "SELECT * FROM reviews
+         WHERE book_id = (SELECT id FROM books WHERE id = ? AND tenant_id = ?) AND (? OR NOT hidden)
+         ORDER BY updated_at DESC, id DESC LIMIT ? OFFSET ?",
+    )
+        .bind(book_id)
+        .bind(tenant_id)
+        .bind(include_hidden)
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(reviews)
+}
+
+/// Adds a user's review of one of a tenant's books, or replaces the one
+/// they wrote before. A hidden review stays hidden when it's edited.
+/// Returns `None` if the tenant has no such book.
+pub async fn save_review(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    book_id: i32,
+    user_id: i32,
+    rating: i64,
+    body: &str,
+) -> Result<Option<Review>> {
+    // With a WHERE, SQLite doesn't take ON CONFLICT for a join constraint
+    let review = sqlx::query_as::<_, Review>(
+        "INSERT INTO reviews (book_id, user_id, rating, body)
+         SELECT id, ?, ?, ? FROM books WHERE id = ? AND tenant_id = ?
+         ON CONFLICT (book_id, user_id) DO UPDATE
+         SET rating = excluded.rating, body = excluded.body, updated_at = unixepoch()
+         RETURNING *",
+    )
+        .bind(user_id)
+        .bind(rating)
+        .bind(body)
+        .bind(book_id)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
+        .await?;
+    Ok(review)
+}
+
+/// Deletes a user's review of one of a tenant's books. Returns false if
+/// they hadn't written one.
+pub async fn delete_review(db_pool: StoreDb, tenant_id: i32, book_id: i32, user_id: i32) -> Result<bool> {
+    let deleted = sqlx::query(
+        "DELETE FROM reviews
+         WHERE user_id = ? AND book_id = (SELECT id FROM books WHERE id = ? AND tenant_id = ?)",
+    )
+        .bind(user_id)
+        .bind(book_id)
+        .bind(tenant_id)
+        .execute(&db_pool.0)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+/// Hides or shows a review of one of a tenant's books. Returns false if
+/// there's no such review.
+pub async fn set_review_hidden(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    book_id: i32,
+    review_id: i32,
+    hidden: bool,
+) -> Result<bool> {
+    let updated = sqlx::query(
+        "UPDATE reviews SET hidden = ?
+         WHERE id = ? AND book_id = (SELECT id FROM books WHERE id = ? AND tenant_id = ?)",
+    )
+        .bind(hidden)
+        .bind(review_id)
+        .bind(book_id)
+        .bind(tenant_id)
+        .execute(&db_pool.0)
+        .await?
+        .rows_affected();
+    Ok(updated > 0)
+}
+
+/// What happened to a book.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum EventKind {
+    Added,
+    Updated,
+    Deleted,
+}
+
+impl EventKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Added => "added",
+            Self::Updated => "updated",
+            Self::Deleted => "deleted",
+        }
+    }
+
+    /// From the type of the outbox event, such as `book.added`.
+    fn parse(event_type: &str) -> Self {
+        match event_type {
+            "book.added" => Self::Added,
+            "book.deleted" => Self::Deleted,
+            _ => Self::Updated,
+        }
+    }
+}
+
+impl From<String> for EventKind {
+    fn from(kind: String) -> Self {
+        Self::parse(&kind)
+    }
+}
+
+/// A change to the catalogue, as recorded in the outbox.
+#[derive(Serialize, Clone, Debug, FromRow)]
+pub struct BookEvent {
+    pub id: i64,
+    #[sqlx(try_from = "String")]
+    pub kind: EventKind,
+    pub book_id: i32,
+    pub created_at: i64,
+    /// Subscribers only see their own tenant's events.
+    #[serde(skip)]
+    pub tenant_id: i32,
+    /// The book as it is now, which may be newer than this event. `None`
+    /// once it's been deleted.
+    #[sqlx(skip)]
+    pub book: Option<CatalogueEntry>,
+}
+
+/// Lists up to `limit` events after `after`, oldest first, with their books.
+pub async fn events_after(db_pool: StoreDb, after: i64, limit: i64) -> Result<Vec<BookEvent>> {
+    let mut events = sqlx::query_as::<_, BookEvent>(
+        "SELECT id, event_type AS kind, book_id, created_at, tenant_id FROM outbox WHERE id > ? ORDER BY id LIMIT ?",
+    )
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&db_pool.0)
+        .await?;
+    for event in &mut events {
+        event.book = get_book(db_pool.clone(), event.tenant_id, event.book_id).await?;
+    }
+    Ok(events)
+}
+
+/// The first and last events still kept, or `None` if there aren't any.
+pub async fn event_range(db_pool: StoreDb) -> Result<Option<(i64, i64)>> {
+    let range: (Option<i64>, Option<i64>) = sqlx::query_as("SELECT MIN(id), MAX(id) FROM outbox")
+        .fetch_one(&db_pool.0)
+        .await?;
+    Ok(range.0.zip(range.1))
+}
+
+/// The `data` of a `book.*` event.
+#[derive(Serialize, Debug)]
+struct BookChange {
+    book_id: i32,
+    tenant_id: i32,
+    /// The book after the change. `None` once it's been deleted.
+    book: Option<CatalogueEntry>,
+}
+
+/// Writes an event about a book to the outbox. It must be in the same
+/// transaction as the change, so the event is relayed and shown in the
+/// change feed if and only if the change is committed.
+async fn record_event(connection: &mut SqliteConnection, tenant_id: i32, event_type: &str, book_id: i32) -> Result<()> {
+    let book = sqlx::query_as::<_, CatalogueEntry>(&format!("{CATALOGUE_QUERY} WHERE id = ?"))
+        .bind(book_id)
+        .fetch_optional(&mut *connection)
+        .await?;
+    sqlx::query("INSERT INTO outbox (event_id, event_type, book_id, tenant_id, payload) VALUES (?, ?, ?, ?, ?)")
+        .bind(uuid::Uuid::new_v4().to_string())
+        .bind(event_type)
+        .bind(book_id)
+        .bind(tenant_id)
+        .bind(serde_json::to_string(&BookChange { book_id, tenant_id, book })?)
+        .execute(&mut *connection)
+        .await?;
+    Ok(())
+}
+
+/// An event in the outbox, with its position.
+#[derive(Debug, FromRow)]
+pub struct OutboxEntry {
+    pub id: i64,
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: String,
+    pub created_at: i64,
+}
+
+impl OutboxEntry {
+    pub fn to_event(&self) -> Result<DomainEvent> {
+        Ok(DomainEvent {
+            id: self.event_id.clone(),
+            event_type: self.event_type.clone(),
+            occurred_at: self.created_at,
+            data: serde_json::from_str(&self.payload)?,
+        })
+    }
+}
+
+/// Lists up to `limit` outbox entries after `after`, oldest first.
+pub async fn outbox_after(db_pool: StoreDb, after: i64, limit: i64) -> Result<Vec<OutboxEntry>> {
+    let entries = sqlx::query_as::<_, OutboxEntry>("SELECT * FROM outbox WHERE id > ? ORDER BY id LIMIT ?")
+        .bind(after)
+        .bind(limit)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(entries)
+}
+
+/// The last entry a sink has been given. A sink that hasn't got a cursor
+/// yet starts at the beginning of what's left in the outbox.
+pub async fn outbox_cursor(db_pool: StoreDb, sink: &str) -> Result<i64> {
+    sqlx::query("INSERT OR IGNORE INTO outbox_cursors (sink, last_id) VALUES (?, 0)")
+        .bind(sink)
+        .execute(&db_pool.0)
+        .await?;
+    let last_id = sqlx::query_scalar("SELECT last_id FROM outbox_cursors WHERE sink = ?")
+        .bind(sink)
+        .fetch_one(&db_pool.0)
+        .await?;
+    Ok(last_id)
+}
+
+pub async fn save_outbox_cursor(db_pool: StoreDb, sink: &str, last_id: i64) -> Result<()> {
+    sqlx::query("UPDATE outbox_cursors SET last_id = ? WHERE sink = ?")
+        .bind(last_id)
+        .bind(sink)
         .execute(&db_pool.0)
         .await?;
     Ok(())
 }
 
-pub async fn update_book(db_pool: StoreDb, id: i32, title: String, author: String) -> Result<()> {
-    sqlx::query("UPDATE books SET title = ?, author = ? WHERE id = ?")
-        .bind(title)
-        .bind(author)
-        .bind(id)
+/// Deletes the entries that all of `sinks` have been given, other than the
+/// latest `keep`, which the change feed resumes from. Sinks that are no
+/// longer configured don't hold entries back.
+pub async fn prune_outbox(db_pool: StoreDb, sinks: &[&str], keep: i64) -> Result<()> {
+    sqlx::query(
+        "DELETE FROM outbox WHERE id <= (SELECT MAX(id) FROM outbox) - ?
+         AND id <= COALESCE(
+             (SELECT MIN(last_id) FROM outbox_cursors WHERE sink IN (SELECT value FROM json_each(?))),
+             id
+         )",
+    )
+        .bind(keep)
+        .bind(serde_json::to_string(sinks)?)
         .execute(&db_pool.0)
         .await?;
     Ok(())
-}
\ No newline at end of file
+}
@@ -0,0 +1,197 @@
+use std::{sync::Arc, time::Duration};
+use anyhow::Result;
+use serde::Serialize;
+use tokio::sync::{
+    broadcast::{self, error::RecvError},
+    mpsc, Semaphore,
+};
+use tokio_stream::wrappers::ReceiverStream;
+use super::{
+    configuration::ChangeFeedConfiguration,
+    db::{self, BookEvent, StoreDb},
+};
+
+/// How many events are read from the database at a time.
+const PAGE_SIZE: i64 = 500;
+
+/// What a subscriber to the change feed receives.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum FeedMessage {
+    Event(BookEvent),
+    /// The events the subscriber wanted to resume from are no longer kept,
+    /// so it should reload the catalogue. Later events follow `id`.
+    Reset { id: i64 },
+}
+
+impl FeedMessage {
+    /// What a client sends as `Last-Event-ID` to resume after this message.
+    pub fn id(&self) -> i64 {
+        match self {
+            Self::Event(event) => event.id,
+            Self::Reset { id } => *id,
+        }
+    }
+
+    /// The SSE event type.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Event(event) => event.kind.as_str(),
+            Self::Reset { .. } => "reset",
+        }
+    }
+}
+
+/// Publishes changes to the catalogue to its subscribers as they're made.
+/// The events are read from the outbox, where each change writes its own,
+/// so subscribers can resume from any event that's still kept.
+#[derive(Clone)]
+pub struct ChangeFeed {
+    db_pool: StoreDb,
+    config: ChangeFeedConfiguration,
+    sender: broadcast::Sender<BookEvent>,
+    subscribers: Arc<Semaphore>,
+}
+
+impl ChangeFeed {
+    pub fn new(db_pool: StoreDb, config: ChangeFeedConfiguration) -> Self {
+        let (sender, _) = broadcast::channel(1024);
+        let subscribers = Arc::new(Semaphore::new(config.max_subscribers));
+        Self {
+            db_pool,
+            config,
+            sender,
+            subscribers,
+        }
+    }
+
+    pub fn keep_alive(&self) -> Duration {
+        Duration::from_secs(self.config.keep_alive_seconds)
+    }
+
+    /// Broadcasts new events for as long as the service is up.
+    pub async fn run(self) {
+        let poll = Duration::from_secs(self.config.poll_seconds);
+        let mut published = None;
+        let mut changes = self.db_pool.watch_changes();
+        loop {
+            if let Err(e) = self.publish(&mut published).await {
+                tracing::warn!("Unable to publish catalogue changes: {e:#}");
+            }
+            // Writes made here wake the feed at once. Polling picks up
+            // those made elsewhere, such as by the `books import` command.
+            let _ = tokio::time::timeout(poll, changes.changed()).await;
+        }
+    }
+
+    async fn publish(&self, published: &mut Option<i64>) -> Result<()> {
+        let Some(mut after) = *published else {
+            // Subscribers catch up on earlier events from the database
+            *published = Some(latest_event_id(self.db_pool.clone()).await?);
+            return Ok(());
+        };
+
+        loop {
+            let batch = db::events_after(self.db_pool.clone(), after, PAGE_SIZE).await?;
+            let count = batch.len();
+            for event in batch {
+                after = event.id;
+                *published = Some(after);
+                // Fails only if nobody is subscribed
+                let _ = self.sender.send(event);
+            }
+            if count < PAGE_SIZE as usize {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Streams a tenant's events after `last_event_id`, or from now on if
+    /// there isn't one. Event ids are shared by every tenant, so a
+    /// subscriber's ids have gaps. Returns `None` if there are already as
+    /// many subscribers as allowed.
+    pub fn subscribe(&self, tenant_id: i32, last_event_id: Option<i64>) -> Option<ReceiverStream<FeedMessage>> {
+        let permit = self.subscribers.clone().try_acquire_owned().ok()?;
+        // Subscribing before reading the database means no event falls
+        // between the two; any read twice are skipped by id
+        let live = self.sender.subscribe();
+        let (tx, rx) = mpsc::channel(64);
+
+        let feed = self.clone();
+        tokio::spawn(async move {
+            if let Err(e) = feed.forward(tenant_id, last_event_id, live, tx).await {
+                tracing::warn!("Change feed subscriber dropped: {e:#}");
+            }
+            drop(permit);
+        });
+        Some(ReceiverStream::new(rx))
+    }
+
+    async fn forward(
+        &self,
+        tenant_id: i32,
+        last_event_id: Option<i64>,
+        mut live: broadcast::Receiver<BookEvent>,
+        tx: mpsc::Sender<FeedMessage>,
+    ) -> Result<()> {
+        let mut sent = match last_event_id {
+            Some(id) => self.catch_up(tenant_id, id, &tx).await?,
+            None => latest_event_id(self.db_pool.clone()).await?,
+        };
+
+        loop {
+            let received = tokio::select! {
+                received = live.recv() => received,
+                // The subscriber hung up
+                _ = tx.closed() => return Ok(()),
+            };
+            match received {
+                Ok(event) if event.id <= sent => {}
+                Ok(event) if event.id == sent + 1 => {
+                    sent = event.id;
+                    if event.tenant_id == tenant_id && tx.send(FeedMessage::Event(event)).await.is_err() {
+                        return Ok(());
+                    }
+                }
+                // Missed some, either by falling behind or by subscribing
+                // before the feed started publishing
+                Ok(_) | Err(RecvError::Lagged(_)) => sent = self.catch_up(tenant_id, sent, &tx).await?,
+                Err(RecvError::Closed) => return Ok(()),
+            }
+        }
+    }
+
+    /// Sends a tenant's events after `after` from the database, returning
+    /// the id of the last one read.
+    async fn catch_up(&self, tenant_id: i32, mut after: i64, tx: &mpsc::Sender<FeedMessage>) -> Result<i64> {
+        let range = db::event_range(self.db_pool.clone()).await?;
+        let resumable = match range {
+            Some((first, last)) => after >= first - 1 && after <= last,
+            None => after == 0,
+        };
+        if !resumable {
+            let id = range.map_or(0, |(_, last)| last);
+            // An error means the subscriber hung up, which `forward` notices
+            let _ = tx.send(FeedMessage::Reset { id }).await;
+            return Ok(id);
+        }
+
+        loop {
+            let events = db::events_after(self.db_pool.clone(), after, PAGE_SIZE).await?;
+            let count = events.len();
+            for event in events {
+                after = event.id;
+                if event.tenant_id == tenant_id && tx.send(FeedMessage::Event(event)).await.is_err() {
+                    return Ok(after);
+                }
+            }
+            if count < PAGE_SIZE as usize {
+                return Ok(after);
+            }
+        }
+    }
+}
+
+async fn latest_event_id(db_pool: StoreDb) -> Result<i64> {
+    Ok(db::event_range(db_pool).await?.map_or(0, |(_, last)| last))
+}
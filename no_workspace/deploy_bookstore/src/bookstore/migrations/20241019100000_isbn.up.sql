@@ -0,0 +1,3 @@
+ALTER TABLE books ADD COLUMN isbn TEXT;
+
+CREATE UNIQUE INDEX books_isbn ON books (isbn) WHERE isbn IS NOT NULL;
@@ -0,0 +1,7 @@
+DROP TRIGGER reviews_update;
+DROP TRIGGER reviews_delete;
+DROP TRIGGER reviews_insert;
+DROP TABLE reviews;
+
+ALTER TABLE books DROP COLUMN rating_total;
+ALTER TABLE books DROP COLUMN rating_count;
@@ -0,0 +1,40 @@
+-- Kept up to date by the triggers below, so listing the catalogue doesn't
+-- have to aggregate every review. Hidden reviews don't count.
+ALTER TABLE books ADD COLUMN rating_count INTEGER NOT NULL DEFAULT 0;
+ALTER TABLE books ADD COLUMN rating_total INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE reviews (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    book_id INTEGER NOT NULL REFERENCES books (id) ON DELETE CASCADE,
+    user_id INTEGER NOT NULL,
+    rating INTEGER NOT NULL CHECK (rating BETWEEN 1 AND 5),
+    body TEXT NOT NULL DEFAULT '',
+    hidden BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    updated_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    UNIQUE (book_id, user_id)
+);
+
+CREATE TRIGGER reviews_insert AFTER INSERT ON reviews WHEN NOT NEW.hidden
+BEGIN
+    UPDATE books
+    SET rating_count = rating_count + 1, rating_total = rating_total + NEW.rating
+    WHERE id = NEW.book_id;
+END;
+
+CREATE TRIGGER reviews_delete AFTER DELETE ON reviews WHEN NOT OLD.hidden
+BEGIN
+    UPDATE books
+    SET rating_count = rating_count - 1, rating_total = rating_total - OLD.rating
+    WHERE id = OLD.book_id;
+END;
+
+CREATE TRIGGER reviews_update AFTER UPDATE OF rating, hidden ON reviews
+BEGIN
+    UPDATE books
+    SET rating_count = rating_count - (NOT OLD.hidden) + (NOT NEW.hidden),
+        rating_total = rating_total
+            - (CASE WHEN OLD.hidden THEN 0 ELSE OLD.rating END)
+            + (CASE WHEN NEW.hidden THEN 0 ELSE NEW.rating END)
+    WHERE id = NEW.book_id;
+END;
@@ -0,0 +1,3 @@
+-- The images themselves are left in cover storage
+ALTER TABLE books DROP COLUMN cover_version;
+ALTER TABLE books DROP COLUMN cover_type;
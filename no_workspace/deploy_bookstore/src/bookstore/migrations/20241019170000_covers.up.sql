@@ -0,0 +1,4 @@
+-- Set when a cover is uploaded; the image itself is in cover storage. The
+-- version goes up with every upload, so it can bust caches.
+ALTER TABLE books ADD COLUMN cover_type TEXT;
+ALTER TABLE books ADD COLUMN cover_version INTEGER NOT NULL DEFAULT 0;
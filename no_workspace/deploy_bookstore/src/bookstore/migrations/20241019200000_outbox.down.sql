@@ -0,0 +1,3 @@
+-- Events that no sink had been given yet are lost
+DROP TABLE outbox_cursors;
+DROP TABLE outbox;
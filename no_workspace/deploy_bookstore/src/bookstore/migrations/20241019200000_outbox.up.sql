@@ -0,0 +1,19 @@
+-- Every change to the catalogue, as a domain event written in the same
+-- transaction as the change, so an event exists if and only if its change
+-- is committed. The relay hands them to each sink in order, and the change
+-- feed streams them to clients, who can resume from any that are kept.
+CREATE TABLE outbox (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    event_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    book_id INTEGER NOT NULL,
+    payload TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+-- How far through the outbox each sink has got. Rows every sink has
+-- passed are deleted once the change feed no longer keeps them.
+CREATE TABLE outbox_cursors (
+    sink TEXT PRIMARY KEY,
+    last_id INTEGER NOT NULL
+);
@@ -0,0 +1,10 @@
+-- Only the default tenant's catalogue is kept. Their covers are left in
+-- cover storage.
+DELETE FROM books WHERE tenant_id != 1;
+DELETE FROM outbox WHERE tenant_id != 1;
+ALTER TABLE outbox DROP COLUMN tenant_id;
+
+DROP INDEX books_tenant;
+DROP INDEX books_isbn;
+CREATE UNIQUE INDEX books_isbn ON books (isbn) WHERE isbn IS NOT NULL;
+ALTER TABLE books DROP COLUMN tenant_id;
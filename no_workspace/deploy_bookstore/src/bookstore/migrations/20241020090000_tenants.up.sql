@@ -0,0 +1,12 @@
+-- Each tenant has its own catalogue. The tenants themselves are in the
+-- auth database; books from before there were tenants belong to the
+-- default one.
+ALTER TABLE books ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
+
+-- The same book can be in more than one tenant's catalogue
+DROP INDEX books_isbn;
+CREATE UNIQUE INDEX books_isbn ON books (tenant_id, isbn) WHERE isbn IS NOT NULL;
+CREATE INDEX books_tenant ON books (tenant_id, id);
+
+-- Events are recorded with their tenant, so each sees only its own
+ALTER TABLE outbox ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
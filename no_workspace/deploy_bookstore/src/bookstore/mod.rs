@@ -1,29 +1,182 @@
+pub mod commands;
 mod configuration;
+mod covers;
 mod db;
+mod events;
+mod outbox;
+mod transfer;
 mod web_service;
+#[cfg(test)]
+mod tests;
+use std::sync::Arc;
 use anyhow::Result;
-use axum::{middleware, routing::{get, post}, Extension, Router};
-use crate::auth::auth_layers;
+use async_trait::async_trait;
+use axum::{extract::DefaultBodyLimit, middleware, routing::{get, post, put}, Extension, Router};
+use sqlx::migrate::Migrator;
+use covers::{CoverStorage, FileCoverStorage};
+use events::ChangeFeed;
+use outbox::OutboxRelay;
+use crate::{
+    auth::auth_layers::{self, Scope},
+    cors::RouteGroup,
+    event_bus::OutboxSink,
+    modules::{BackgroundTask, Database, ModuleContext, ServiceModule},
+    idempotency::IdempotencyLayer,
+    rate_limit::RateLimitLayer,
+};
+pub use configuration::BookstoreConfiguration;
+pub use db::StoreDb;
+#[cfg(feature = "orders")]
+pub use db::find_book;
+pub use transfer::DataFormat;
 
-pub async fn setup_service() -> Result<Router> {
-    let config = configuration::BookstoreConfiguration::load()?;
-    let db_pool = db::get_connection_pool(&config.db_filename).await?;
+/// The book catalogue.
+pub struct BookstoreModule {
+    config: BookstoreConfiguration,
+    db_pool: StoreDb,
+    covers: Arc<dyn CoverStorage>,
+    feed: ChangeFeed,
+    relay: OutboxRelay,
+}
+
+impl BookstoreModule {
+    /// Changes to the catalogue are relayed from its outbox to `sinks`, as
+    /// well as to the sinks in its configuration.
+    pub async fn open(config: BookstoreConfiguration, sinks: Vec<Arc<dyn OutboxSink>>) -> Result<Self> {
+        let db_pool = db::get_connection_pool(&config.db_filename).await?;
+        let covers = Arc::new(FileCoverStorage::new(&config.covers.directory));
+        let feed = ChangeFeed::new(db_pool.clone(), config.events.clone());
+        let relay = OutboxRelay::new(db_pool.clone(), config.outbox.clone(), config.events.keep_events, sinks)?;
+        Ok(Self {
+            config,
+            db_pool,
+            covers,
+            feed,
+            relay,
+        })
+    }
+
+    /// The catalogue, for modules that sell or describe its books.
+    #[cfg_attr(not(feature = "orders"), allow(dead_code))]
+    pub fn db(&self) -> StoreDb {
+        self.db_pool.clone()
+    }
+}
+
+#[async_trait]
+impl ServiceModule for BookstoreModule {
+    fn name(&self) -> &'static str {
+        "bookstore"
+    }
+
+    fn mount_path(&self) -> &'static str {
+        "/api/v1/books"
+    }
+
+    fn route_group(&self) -> RouteGroup {
+        RouteGroup::Books
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &db::MIGRATOR
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        db::perform_migrations(self.db_pool.clone()).await
+    }
+
+    async fn router(&self, context: &ModuleContext) -> Result<Router> {
+        setup_service(
+            self.config.clone(),
+            self.db_pool.clone(),
+            self.covers.clone(),
+            self.feed.clone(),
+            context.rate_limit.clone(),
+            context.idempotency.clone(),
+        )
+        .await
+    }
 
-    db::perform_migrations(db_pool.clone()).await?;
+    async fn health_check(&self) -> Result<()> {
+        db::ping(self.db_pool.clone()).await
+    }
 
+    fn database(&self) -> Database {
+        Database {
+            filename: self.config.db_filename.clone(),
+            pool: self.db_pool.0.clone(),
+        }
+    }
+
+    async fn background_tasks(&self) -> Result<Vec<BackgroundTask>> {
+        let mut tasks: Vec<BackgroundTask> = vec![Box::pin(self.feed.clone().run())];
+        tasks.extend(self.relay.tasks());
+        Ok(tasks)
+    }
+}
+
+async fn setup_service(
+    config: BookstoreConfiguration,
+    db_pool: StoreDb,
+    covers: Arc<dyn CoverStorage>,
+    feed: ChangeFeed,
+    rate_limit: RateLimitLayer,
+    idempotency: IdempotencyLayer,
+) -> Result<Router> {
+    // Room for the multipart framing around the image
+    let max_cover_request = config.covers.max_upload_bytes + 64 * 1024;
     let secure_router = Router::new()
         .layer(Extension(config.clone()))
         .layer(Extension(db_pool.clone()))
         .route("/add", post(web_service::add_book))
         .route("/delete/:id", get(web_service::delete_book))
         .route("/update/:id", post(web_service::update_book))
+        .route("/export", get(web_service::export_books))
+        .route(
+            "/import",
+            post(web_service::import_books).layer(DefaultBodyLimit::max(config.max_import_bytes)),
+        )
+        .route(
+            "/:id/cover",
+            put(web_service::upload_cover)
+                .delete(web_service::delete_cover)
+                .layer(DefaultBodyLimit::max(max_cover_request)),
+        )
+        .route_layer(middleware::from_fn_with_state(Scope::BooksWrite, auth_layers::require_scope))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    // Any signed-in user can review books
+    let review_router = Router::new()
+        .route("/:id/reviews", post(web_service::save_review))
+        .route("/:id/reviews/delete", post(web_service::delete_review))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    let moderation_router = Router::new()
+        .route("/:id/reviews/all", get(web_service::moderate_reviews))
+        .route("/:id/reviews/:review_id/hide", post(web_service::hide_review))
+        .route("/:id/reviews/:review_id/unhide", post(web_service::unhide_review))
+        .route_layer(middleware::from_fn_with_state(Scope::ReviewsModerate, auth_layers::require_scope))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
         .route_layer(middleware::from_fn(auth_layers::require_token));
 
     let router = Router::new()
-        .merge(secure_router)
         .route("/", get(web_service::all_books))
+        .route("/events", get(web_service::book_events))
         .route("/:id", get(web_service::get_book))
+        .route("/:id/reviews", get(web_service::list_reviews))
+        .route("/:id/cover", get(web_service::get_cover))
+        .route_layer(rate_limit)
+        .merge(secure_router)
+        .merge(review_router)
+        .merge(moderation_router)
         .layer(Extension(config))
+        .layer(Extension(covers))
+        .layer(Extension(feed))
         .layer(Extension(db_pool));
 
     Ok(router)
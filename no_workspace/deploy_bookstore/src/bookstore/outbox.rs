@@ -0,0 +1,226 @@
+use std::{sync::Arc, time::Duration};
+use anyhow::Result;
+use async_trait::async_trait;
+use crate::{
+    event_bus::{DomainEvent, OutboxSink},
+    modules::BackgroundTask,
+};
+use super::{
+    configuration::OutboxConfiguration,
+    db::{self, StoreDb},
+};
+
+/// How often entries that are no longer needed are deleted.
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Writes events to the service's log.
+pub struct LogSink;
+
+#[async_trait]
+impl OutboxSink for LogSink {
+    fn name(&self) -> &'static str {
+        "log"
+    }
+
+    async fn publish(&self, event: &DomainEvent) -> Result<()> {
+        tracing::info!("{} {}: {}", event.event_type, event.id, event.data);
+        Ok(())
+    }
+}
+
+/// POSTs each event as JSON to a URL, which must answer with a 2xx status.
+pub struct WebhookSink {
+    url: String,
+    http: reqwest::Client,
+}
+
+impl WebhookSink {
+    pub fn new(url: &str, timeout: Duration) -> Result<Self> {
+        let http = reqwest::Client::builder()
+            .timeout(timeout)
+            .redirect(reqwest::redirect::Policy::none())
+            .build()?;
+        Ok(Self {
+            url: url.to_string(),
+            http,
+        })
+    }
+}
+
+#[async_trait]
+impl OutboxSink for WebhookSink {
+    fn name(&self) -> &'static str {
+        "webhook"
+    }
+
+    async fn publish(&self, event: &DomainEvent) -> Result<()> {
+        self.http
+            .post(&self.url)
+            .header("x-event-id", &event.id)
+            .header("x-event-type", &event.event_type)
+            .json(event)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+}
+
+/// Hands the catalogue's outbox to each sink: those configured, and those
+/// of other modules, such as webhooks. Every sink has its own cursor, kept
+/// in the database, so a sink that's failing holds up only itself, and
+/// each picks up where it left off after a restart.
+#[derive(Clone)]
+pub struct OutboxRelay {
+    db_pool: StoreDb,
+    config: OutboxConfiguration,
+    /// How many of the latest entries the change feed keeps.
+    keep_events: i64,
+    sinks: Vec<Arc<dyn OutboxSink>>,
+}
+
+impl OutboxRelay {
+    pub fn new(
+        db_pool: StoreDb,
+        config: OutboxConfiguration,
+        keep_events: i64,
+        mut sinks: Vec<Arc<dyn OutboxSink>>,
+    ) -> Result<Self> {
+        if config.log {
+            sinks.push(Arc::new(LogSink));
+        }
+        if let Some(url) = &config.webhook_url {
+            let timeout = Duration::from_secs(config.webhook_timeout_seconds);
+            sinks.push(Arc::new(WebhookSink::new(url, timeout)?));
+        }
+        Ok(Self {
+            db_pool,
+            config,
+            keep_events,
+            sinks,
+        })
+    }
+
+    /// A task for each sink, relaying for as long as the service is up, and
+    /// one that prunes the outbox.
+    pub fn tasks(&self) -> Vec<BackgroundTask> {
+        let mut tasks: Vec<BackgroundTask> = self
+            .sinks
+            .iter()
+            .map(|sink| Box::pin(self.clone().run(sink.clone())) as BackgroundTask)
+            .collect();
+        tasks.push(Box::pin(self.clone().prune_every(PRUNE_INTERVAL)));
+        tasks
+    }
+
+    async fn run(self, sink: Arc<dyn OutboxSink>) {
+        let poll = Duration::from_secs(self.config.poll_seconds);
+        let retry = Duration::from_secs(self.config.retry_seconds);
+        let mut changes = self.db_pool.watch_changes();
+        loop {
+            if let Err(e) = self.relay(sink.as_ref()).await {
+                tracing::warn!("Unable to relay catalogue events to the {} sink: {e:#}", sink.name());
+                tokio::time::sleep(retry).await;
+                continue;
+            }
+            // Writes made here wake the relay at once. Polling picks up
+            // those made elsewhere.
+            let _ = tokio::time::timeout(poll, changes.changed()).await;
+        }
+    }
+
+    /// Gives the sink everything after its cursor, moving the cursor on
+    /// after each event it accepts.
+    async fn relay(&self, sink: &dyn OutboxSink) -> Result<()> {
+        let mut after = db::outbox_cursor(self.db_pool.clone(), sink.name()).await?;
+        loop {
+            let entries = db::outbox_after(self.db_pool.clone(), after, self.config.batch_size).await?;
+            let count = entries.len();
+            for entry in entries {
+                sink.publish(&entry.to_event()?).await?;
+                after = entry.id;
+                db::save_outbox_cursor(self.db_pool.clone(), sink.name(), after).await?;
+            }
+            if count < self.config.batch_size as usize {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Deletes the entries every sink has been given, once the change feed
+    /// no longer keeps them.
+    async fn prune_every(self, period: Duration) {
+        let names: Vec<&str> = self.sinks.iter().map(|sink| sink.name()).collect();
+        let mut interval = tokio::time::interval(period);
+        loop {
+            interval.tick().await;
+            if let Err(e) = db::prune_outbox(self.db_pool.clone(), &names, self.keep_events).await {
+                tracing::warn!("Unable to prune the catalogue's outbox: {e:#}");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    };
+    use axum::http::StatusCode;
+    use serde_json::json;
+    use crate::{bookstore::configuration::OutboxConfiguration, test_harness::TestApp};
+    use super::*;
+
+    /// Records the events it's given, or refuses them while it's failing.
+    #[derive(Default)]
+    struct TestSink {
+        failing: AtomicBool,
+        received: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl OutboxSink for TestSink {
+        fn name(&self) -> &'static str {
+            "test"
+        }
+
+        async fn publish(&self, event: &DomainEvent) -> Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                anyhow::bail!("unavailable");
+            }
+            self.received.lock().unwrap().push(event.data["book"]["title"].to_string());
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn sinks_get_every_event_after_their_cursor() {
+        let app = TestApp::new().await;
+        let token = app.admin_token().await;
+        for title in ["Tehanu", "Tales from Earthsea"] {
+            let book = json!({ "id": 0, "title": title, "author": "Ursula K. Le Guin" });
+            assert_eq!(app.post("/api/v1/books/add", Some(&token), book).await.status, StatusCode::OK);
+        }
+        let db_pool = db::get_connection_pool(&app.config.bookstore.db_filename).await.unwrap();
+        let sink = Arc::new(TestSink::default());
+        let relay = OutboxRelay::new(db_pool.clone(), OutboxConfiguration::default(), 1, vec![sink.clone()]).unwrap();
+
+        // Nothing is lost while the sink is failing
+        sink.failing.store(true, Ordering::SeqCst);
+        assert!(relay.relay(sink.as_ref()).await.is_err());
+        assert_eq!(db::outbox_cursor(db_pool.clone(), "test").await.unwrap(), 0);
+        db::prune_outbox(db_pool.clone(), &["test"], 0).await.unwrap();
+        assert!(db::event_range(db_pool.clone()).await.unwrap().is_some());
+
+        sink.failing.store(false, Ordering::SeqCst);
+        relay.relay(sink.as_ref()).await.unwrap();
+        relay.relay(sink.as_ref()).await.unwrap();
+        assert_eq!(*sink.received.lock().unwrap(), ["\"Tehanu\"", "\"Tales from Earthsea\""]);
+
+        // The change feed keeps the latest for clients to resume from
+        db::prune_outbox(db_pool.clone(), &["test"], 1).await.unwrap();
+        let (first, last) = db::event_range(db_pool.clone()).await.unwrap().unwrap();
+        assert_eq!(first, last);
+    }
+}
@@ -0,0 +1,376 @@
+use std::io::Cursor;
+use axum::{
+    body::Body,
+    http::{header, Method, StatusCode},
+};
+use serde_json::{json, Value};
+use crate::{
+    auth::AuthMode,
+    test_harness::{multipart, request, TestApp, TestResponse},
+};
+use super::covers::{CoverStorage, FileCoverStorage};
+
+const BOOKS: &str = "/api/v1/books";
+
+/// Adds a book as the admin, returning its id.
+async fn add_book(app: &TestApp, token: &str, title: &str) -> i64 {
+    let book = json!({ "id": 0, "title": title, "author": "Ursula K. Le Guin", "isbn": "9780441478125" });
+    let response = app.post(&format!("{BOOKS}/add"), Some(token), book).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let books = app.get(BOOKS, None).await.json();
+    books.as_array().unwrap().iter().find(|book| book["title"] == title).unwrap()["id"]
+        .as_i64()
+        .unwrap()
+}
+
+/// Every title in the catalogue, which the migrations start with a few
+/// books in.
+async fn titles(app: &TestApp) -> Vec<String> {
+    let books = app.get(BOOKS, None).await.json();
+    books.as_array().unwrap().iter().map(|book| book["title"].as_str().unwrap().to_string()).collect()
+}
+
+fn png() -> Vec<u8> {
+    let mut png = Vec::new();
+    image::RgbImage::from_pixel(40, 60, image::Rgb([200, 30, 30]))
+        .write_to(&mut Cursor::new(&mut png), image::ImageFormat::Png)
+        .unwrap();
+    png
+}
+
+async fn upload_cover(app: &TestApp, token: &str, id: i64, file: &[u8]) -> TestResponse {
+    let builder = request(Method::PUT, &format!("{BOOKS}/{id}/cover"), Some(token));
+    app.send(multipart(builder, "cover.png", "image/png", file)).await
+}
+
+async fn import(app: &TestApp, token: &str, query: &str, csv: &str) -> TestResponse {
+    let builder = request(Method::POST, &format!("{BOOKS}/import{query}"), Some(token));
+    app.send(multipart(builder, "books.csv", "text/csv", csv.as_bytes())).await
+}
+
+#[tokio::test]
+async fn the_catalogue_is_public() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "The Left Hand of Darkness").await;
+
+    assert!(titles(&app).await.contains(&"The Left Hand of Darkness".to_string()));
+    let book = app.get(&format!("{BOOKS}/{id}"), None).await.json();
+    assert_eq!(book["title"], "The Left Hand of Darkness");
+    assert_eq!(book["rating_count"], 0);
+    assert_eq!(book["average_rating"], Value::Null);
+    assert_eq!(book["cover_version"], Value::Null);
+
+    assert_eq!(app.get(&format!("{BOOKS}/999"), None).await.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn changing_the_catalogue_needs_books_write() {
+    let app = TestApp::new().await;
+    let book = json!({ "id": 0, "title": "Dune", "author": "Frank Herbert" });
+
+    let response = app.post(&format!("{BOOKS}/add"), None, book.clone()).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    assert_eq!(response.text(), "invalid header");
+    let response = app.post(&format!("{BOOKS}/add"), Some("not-a-token"), book.clone()).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+
+    let reader = app.reader_token("reader").await;
+    let response = app.post(&format!("{BOOKS}/add"), Some(&reader), book).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+    assert!(!titles(&app).await.contains(&"Dune".to_string()));
+}
+
+#[tokio::test]
+async fn api_keys_with_books_write_can_add_books() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let new_key = json!({ "name": "importer", "scopes": ["books:write"] });
+    let key = app.post("/api/v1/auth/keys/add", Some(&token), new_key).await.json()["key"]
+        .as_str()
+        .unwrap()
+        .to_string();
+
+    let book = json!({ "id": 0, "title": "Dune", "author": "Frank Herbert" });
+    let request = request(Method::POST, &format!("{BOOKS}/add"), None)
+        .header(header::AUTHORIZATION, format!("ApiKey {key}"))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(book.to_string()))
+        .unwrap();
+    assert_eq!(app.send(request).await.status, StatusCode::OK);
+    assert!(titles(&app).await.contains(&"Dune".to_string()));
+}
+
+#[tokio::test]
+async fn an_unreachable_auth_service_is_reported() {
+    let app = TestApp::with_config(|config| {
+        config.auth.mode = AuthMode::Remote;
+        let remote = json!({ "url": "http://127.0.0.1:9", "shared_secret": "a-shared-secret", "timeout_seconds": 1 });
+        config.auth.remote = Some(serde_json::from_value(remote).unwrap());
+    })
+    .await;
+
+    let book = json!({ "id": 0, "title": "Dune", "author": "Frank Herbert" });
+    let response = app.post(&format!("{BOOKS}/add"), Some("some-token"), book).await;
+    assert_eq!(response.status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(response.text(), "auth service unavailable");
+}
+
+#[tokio::test]
+async fn books_are_updated_and_deleted() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "The Dispossessed").await;
+
+    let update = json!({ "id": id, "title": "The Dispossessed: An Ambiguous Utopia", "author": "Ursula K. Le Guin" });
+    let response = app.post(&format!("{BOOKS}/update/{id}"), Some(&token), update).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let book = app.get(&format!("{BOOKS}/{id}"), None).await.json();
+    assert_eq!(book["title"], "The Dispossessed: An Ambiguous Utopia");
+    assert_eq!(book["isbn"], Value::Null);
+
+    let response = app.get(&format!("{BOOKS}/delete/{id}"), Some(&token)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(app.get(&format!("{BOOKS}/{id}"), None).await.status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn isbns_are_unique_in_a_catalogue() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    add_book(&app, &token, "The Dispossessed").await;
+
+    let book = json!({ "id": 0, "title": "The Dispossessed", "author": "Ursula K. Le Guin", "isbn": "9780441478125" });
+    let response = app.post(&format!("{BOOKS}/add"), Some(&token), book).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+
+    let book = json!({ "id": 0, "title": "The Lathe of Heaven", "author": "Ursula K. Le Guin" });
+    let response = app.post(&format!("{BOOKS}/add"), Some(&token), book).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let books = app.get(BOOKS, None).await.json();
+    let id = books.as_array().unwrap().iter().find(|book| book["title"] == "The Lathe of Heaven").unwrap()["id"].clone();
+    let update = json!({ "id": id, "title": "The Lathe of Heaven", "author": "Ursula K. Le Guin", "isbn": "9780441478125" });
+    let response = app.post(&format!("{BOOKS}/update/{id}"), Some(&token), update).await;
+    assert_eq!(response.status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn imports_are_all_or_nothing() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let before = titles(&app).await;
+
+    let csv = "title,author,isbn\nDune,Frank Herbert,\nSolaris,Stanislaw Lem,not-an-isbn\n";
+    let response = import(&app, &token, "", csv).await;
+    assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let report = response.json();
+    assert_eq!(report["committed"], false);
+    assert_eq!(report["errors"].as_array().unwrap().len(), 1);
+    assert_eq!(titles(&app).await, before);
+
+    let csv = "title,author,isbn\nDune,Frank Herbert,\nSolaris,Stanislaw Lem,978-0-15-602760-1\n";
+    let response = import(&app, &token, "?dry_run=true", csv).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["committed"], false);
+    assert_eq!(titles(&app).await, before);
+
+    let response = import(&app, &token, "", csv).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["imported"], 2);
+    let response = import(&app, &token, "", csv).await;
+    assert_eq!(response.json()["duplicates"], 2);
+    assert_eq!(titles(&app).await.len(), before.len() + 2);
+
+    let response = import(&app, &token, "?format=json", csv).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn imported_books_match_with_or_without_an_isbn() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    add_book(&app, &token, "The Dispossessed").await;
+    let book = json!({ "id": 0, "title": "Always Coming Home", "author": "Ursula K. Le Guin" });
+    let response = app.post(&format!("{BOOKS}/add"), Some(&token), book).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let before = titles(&app).await;
+
+    // One without an ISBN matches a book that has one, and the other way round
+    let csv = "title,author,isbn\n\
+               The Dispossessed,Ursula K. Le Guin,\n\
+               Always Coming Home,Ursula K. Le Guin,9780520227354\n";
+    let response = import(&app, &token, "", csv).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(response.json()["duplicates"], 2);
+    assert_eq!(titles(&app).await, before);
+}
+
+#[tokio::test]
+async fn the_catalogue_is_exported_as_csv_or_ndjson() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "The Lathe of Heaven").await;
+
+    let response = app.get(&format!("{BOOKS}/export"), None).await;
+    assert_eq!(response.status, StatusCode::UNAUTHORIZED);
+    let reader = app.reader_token("reader").await;
+    let response = app.get(&format!("{BOOKS}/export"), Some(&reader)).await;
+    assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+    let response = app.get(&format!("{BOOKS}/export"), Some(&token)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert!(response.headers[header::CONTENT_TYPE].to_str().unwrap().starts_with("text/csv"));
+    let csv = response.text();
+    assert!(csv.starts_with("id,title,author,isbn\n"));
+    assert!(csv.contains(&format!("\n{id},The Lathe of Heaven,Ursula K. Le Guin,9780441478125\n")));
+
+    let response = app.get(&format!("{BOOKS}/export?format=ndjson"), Some(&token)).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let books: Vec<Value> = response.text().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert!(books.iter().any(|book| book["id"] == id && book["title"] == "The Lathe of Heaven"));
+
+    let response = app.get(&format!("{BOOKS}/export?format=json"), Some(&token)).await;
+    assert_eq!(response.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn changes_are_followed_as_server_sent_events() {
+    let app = TestApp::new().await;
+    let response = app
+        .open(request(Method::GET, &format!("{BOOKS}/events"), None).body(Body::empty()).unwrap())
+        .await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "text/event-stream");
+
+    let request = request(Method::GET, &format!("{BOOKS}/events"), None)
+        .header("last-event-id", "yesterday")
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.send(request).await.status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn covers_are_uploaded_served_and_deleted() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "A Wizard of Earthsea").await;
+    let cover = format!("{BOOKS}/{id}/cover");
+    assert_eq!(app.get(&cover, None).await.status, StatusCode::NOT_FOUND);
+
+    let response = upload_cover(&app, &token, id, b"not an image").await;
+    assert_eq!(response.status, StatusCode::UNSUPPORTED_MEDIA_TYPE);
+    assert_eq!(upload_cover(&app, &token, 999, &png()).await.status, StatusCode::NOT_FOUND);
+    let reader = app.reader_token("reader").await;
+    assert_eq!(upload_cover(&app, &reader, id, &png()).await.status, StatusCode::FORBIDDEN);
+
+    let response = upload_cover(&app, &token, id, &png()).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let version = response.json()["cover_version"].clone();
+    assert_eq!(app.get(&format!("{BOOKS}/{id}"), None).await.json()["cover_version"], version);
+
+    let response = app.get(&cover, None).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers[header::CONTENT_TYPE], "image/png");
+    assert_eq!(response.body.as_ref(), png().as_slice());
+    let etag = response.headers[header::ETAG].clone();
+    let revalidate = request(Method::GET, &cover, None)
+        .header(header::IF_NONE_MATCH, etag)
+        .body(Body::empty())
+        .unwrap();
+    assert_eq!(app.send(revalidate).await.status, StatusCode::NOT_MODIFIED);
+    let response = app.get(&format!("{cover}?size=thumb"), None).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.headers[header::CONTENT_TYPE], "image/jpeg");
+
+    let delete = request(Method::DELETE, &cover, Some(&token)).body(Body::empty()).unwrap();
+    assert_eq!(app.send(delete).await.status, StatusCode::OK);
+    assert_eq!(app.get(&cover, None).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&format!("{BOOKS}/{id}"), None).await.json()["cover_version"], Value::Null);
+}
+
+#[tokio::test]
+async fn uploads_of_the_same_cover_dont_mix() {
+    let app = TestApp::new().await;
+    let directory = &app.config.bookstore.covers.directory;
+    let storage = FileCoverStorage::new(directory);
+    let first = vec![1; 256 * 1024];
+    let second = vec![2; 256 * 1024];
+
+    let (stored_first, stored_second) = tokio::join!(
+        storage.put("12/original", first.clone()),
+        storage.put("12/original", second.clone()),
+    );
+    stored_first.unwrap();
+    stored_second.unwrap();
+    let stored = storage.get("12/original").await.unwrap().unwrap();
+    assert!(stored == first || stored == second);
+    let files = std::fs::read_dir(std::path::Path::new(directory).join("12")).unwrap().count();
+    assert_eq!(files, 1, "partial files were left behind");
+}
+
+#[tokio::test]
+async fn signed_in_users_review_books() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "The Tombs of Atuan").await;
+    let reviews = format!("{BOOKS}/{id}/reviews");
+
+    let review = json!({ "rating": 4, "body": "Dark and quiet." });
+    assert_eq!(app.post(&reviews, None, review.clone()).await.status, StatusCode::UNAUTHORIZED);
+    let reader = app.reader_token("reader").await;
+    let response = app.post(&reviews, Some(&reader), json!({ "rating": 6 })).await;
+    assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+    let response = app.post(&format!("{BOOKS}/999/reviews"), Some(&reader), review.clone()).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+
+    let response = app.post(&reviews, Some(&reader), review).await;
+    assert_eq!(response.status, StatusCode::OK);
+    assert_eq!(response.json()["rating"], 4);
+    // A second review replaces the first
+    let response = app.post(&reviews, Some(&reader), json!({ "rating": 2 })).await;
+    assert_eq!(response.status, StatusCode::OK);
+    let other = app.reader_token("other").await;
+    app.post(&reviews, Some(&other), json!({ "rating": 5 })).await;
+
+    let listed = app.get(&reviews, None).await.json();
+    assert_eq!(listed.as_array().unwrap().len(), 2);
+    let book = app.get(&format!("{BOOKS}/{id}"), None).await.json();
+    assert_eq!(book["rating_count"], 2);
+    assert_eq!(book["average_rating"], 3.5);
+
+    let delete = format!("{reviews}/delete");
+    assert_eq!(app.post(&delete, Some(&reader), json!({})).await.status, StatusCode::OK);
+    assert_eq!(app.post(&delete, Some(&reader), json!({})).await.status, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&reviews, None).await.json().as_array().unwrap().len(), 1);
+}
+
+#[tokio::test]
+async fn moderators_hide_reviews() {
+    let app = TestApp::new().await;
+    let token = app.admin_token().await;
+    let id = add_book(&app, &token, "The Farthest Shore").await;
+    let reader = app.reader_token("reader").await;
+    let review = app
+        .post(&format!("{BOOKS}/{id}/reviews"), Some(&reader), json!({ "rating": 1, "body": "spam" }))
+        .await
+        .json();
+    let review_id = review["id"].as_i64().unwrap();
+    let all = format!("{BOOKS}/{id}/reviews/all");
+
+    assert_eq!(app.get(&all, Some(&reader)).await.status, StatusCode::FORBIDDEN);
+    let hide = format!("{BOOKS}/{id}/reviews/{review_id}/hide");
+    assert_eq!(app.post(&hide, Some(&reader), json!({})).await.status, StatusCode::FORBIDDEN);
+
+    assert_eq!(app.post(&hide, Some(&token), json!({})).await.status, StatusCode::OK);
+    assert_eq!(app.get(&format!("{BOOKS}/{id}/reviews"), None).await.json(), json!([]));
+    assert_eq!(app.get(&format!("{BOOKS}/{id}"), None).await.json()["rating_count"], 0);
+    let moderated = app.get(&all, Some(&token)).await.json();
+    assert_eq!(moderated[0]["hidden"], true);
+
+    let unhide = format!("{BOOKS}/{id}/reviews/{review_id}/unhide");
+    assert_eq!(app.post(&unhide, Some(&token), json!({})).await.status, StatusCode::OK);
+    assert_eq!(app.get(&format!("{BOOKS}/{id}/reviews"), None).await.json()[0]["id"], review_id);
+
+    let missing = format!("{BOOKS}/{id}/reviews/999/hide");
+    assert_eq!(app.post(&missing, Some(&token), json!({})).await.status, StatusCode::NOT_FOUND);
+}
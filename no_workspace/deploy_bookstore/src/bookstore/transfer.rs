@@ -0,0 +1,322 @@
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use sqlx::{Sqlite, Transaction};
+use super::db::{self, Book, StoreDb};
+
+/// File formats supported for bulk import and export.
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DataFormat {
+    Json,
+    Ndjson,
+    Csv,
+}
+
+impl DataFormat {
+    pub fn parse(format: &str) -> Result<Self> {
+        match format {
+            "json" => Ok(Self::Json),
+            "ndjson" | "jsonl" => Ok(Self::Ndjson),
+            "csv" => Ok(Self::Csv),
+            _ => bail!("unknown format: {format}"),
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Ndjson => "application/x-ndjson",
+            Self::Csv => "text/csv; charset=utf-8",
+        }
+    }
+
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Json => "json",
+            Self::Ndjson => "ndjson",
+            Self::Csv => "csv",
+        }
+    }
+}
+
+/// A book as read from an import file. Any `id` column is ignored, so an
+/// export can be imported into another database.
+#[derive(Deserialize, Debug)]
+pub struct BookRecord {
+    pub title: String,
+    pub author: String,
+    #[serde(default)]
+    pub isbn: Option<String>,
+}
+
+impl BookRecord {
+    /// Trims the record, strips ISBN punctuation and rejects anything that
+    /// can't be stored.
+    fn normalize(self) -> Result<Self, String> {
+        let title = self.title.trim().to_string();
+        let author = self.author.trim().to_string();
+        if title.is_empty() {
+            return Err("title is empty".to_string());
+        }
+        if author.is_empty() {
+            return Err("author is empty".to_string());
+        }
+
+        let isbn = match self.isbn {
+            Some(isbn) => {
+                let isbn: String = isbn
+                    .chars()
+                    .filter(|c| !matches!(c, '-' | ' '))
+                    .map(|c| c.to_ascii_uppercase())
+                    .collect();
+                if isbn.is_empty() {
+                    None
+                } else if !matches!(isbn.len(), 10 | 13)
+                    || !isbn.chars().all(|c| c.is_ascii_digit() || c == 'X')
+                {
+                    return Err(format!("{isbn} is not a valid ISBN"));
+                } else {
+                    Some(isbn)
+                }
+            }
+            None => None,
+        };
+
+        Ok(Self { title, author, isbn })
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct RowError {
+    pub row: usize,
+    pub message: String,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ImportReport {
+    pub dry_run: bool,
+    pub committed: bool,
+    pub imported: usize,
+    pub duplicates: usize,
+    pub errors: Vec<RowError>,
+}
+
+/// Adds books to a tenant's catalogue inside a single transaction. Nothing
+/// is committed if any row fails, or if this is a dry run.
+pub struct Importer {
+    db_pool: StoreDb,
+    tenant_id: i32,
+    tx: Transaction<'static, Sqlite>,
+    splitter: RecordSplitter,
+    parser: RecordParser,
+    rows: usize,
+    report: ImportReport,
+}
+
+impl Importer {
+    pub async fn begin(db_pool: &StoreDb, tenant_id: i32, format: DataFormat, dry_run: bool) -> Result<Self> {
+        let tx = db_pool.0.begin().await?;
+        Ok(Self {
+            db_pool: db_pool.clone(),
+            tenant_id,
+            tx,
+            splitter: RecordSplitter::new(format),
+            parser: RecordParser::new(format),
+            rows: 0,
+            report: ImportReport {
+                dry_run,
+                ..Default::default()
+            },
+        })
+    }
+
+    /// Imports every complete record in the next chunk of an NDJSON or CSV
+    /// stream. Only database failures are returned as errors; bad rows go
+    /// into the report.
+    pub async fn push(&mut self, chunk: &[u8]) -> Result<()> {
+        for record in self.splitter.push(chunk) {
+            self.add_record(&record).await?;
+        }
+        Ok(())
+    }
+
+    async fn add_record(&mut self, record: &[u8]) -> Result<()> {
+        self.rows += 1;
+        match self.parser.parse(record) {
+            Ok(Some(book)) => self.add(self.rows, book).await,
+            Ok(None) => Ok(()),
+            Err(message) => {
+                self.reject(self.rows, message);
+                Ok(())
+            }
+        }
+    }
+
+    /// Records a row that couldn't be parsed.
+    pub fn reject(&mut self, row: usize, message: impl Into<String>) {
+        self.report.errors.push(RowError {
+            row,
+            message: message.into(),
+        });
+    }
+
+    /// Adds a parsed row, skipping books that are already in the catalogue
+    /// (including earlier rows of the same import).
+    pub async fn add(&mut self, row: usize, record: BookRecord) -> Result<()> {
+        let record = match record.normalize() {
+            Ok(record) => record,
+            Err(message) => {
+                self.reject(row, message);
+                return Ok(());
+            }
+        };
+
+        let isbn = record.isbn.as_deref();
+        if db::is_duplicate_book(&mut self.tx, self.tenant_id, &record.title, &record.author, isbn).await? {
+            self.report.duplicates += 1;
+        } else {
+            db::insert_book(&mut self.tx, self.tenant_id, &record.title, &record.author, isbn).await?;
+            self.report.imported += 1;
+        }
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> Result<ImportReport> {
+        if let Some(record) = self.splitter.finish() {
+            self.add_record(&record).await?;
+        }
+
+        if self.report.dry_run || !self.report.errors.is_empty() {
+            self.tx.rollback().await?;
+        } else {
+            self.tx.commit().await?;
+            self.db_pool.changed();
+            self.report.committed = true;
+        }
+        Ok(self.report)
+    }
+}
+
+/// Splits a byte stream into records as chunks arrive. For CSV, newlines
+/// inside quoted fields don't end a record.
+pub struct RecordSplitter {
+    csv_quotes: bool,
+    buffer: Vec<u8>,
+    scanned: usize,
+    in_quotes: bool,
+}
+
+impl RecordSplitter {
+    pub fn new(format: DataFormat) -> Self {
+        Self {
+            csv_quotes: format == DataFormat::Csv,
+            buffer: Vec::new(),
+            scanned: 0,
+            in_quotes: false,
+        }
+    }
+
+    /// Adds a chunk, returning every record it completes.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<Vec<u8>> {
+        self.buffer.extend_from_slice(chunk);
+        let mut records = Vec::new();
+        let mut start = 0;
+
+        for i in self.scanned..self.buffer.len() {
+            match self.buffer[i] {
+                b'"' if self.csv_quotes => self.in_quotes = !self.in_quotes,
+                b'\n' if !self.in_quotes => {
+                    records.push(trim_line_ending(&self.buffer[start..i]).to_vec());
+                    start = i + 1;
+                }
+                _ => {}
+            }
+        }
+
+        self.buffer.drain(..start);
+        self.scanned = self.buffer.len();
+        records
+    }
+
+    /// Returns the final record, if the input didn't end with a newline.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        let buffer = std::mem::take(&mut self.buffer);
+        self.scanned = 0;
+        let last = trim_line_ending(&buffer);
+        (!last.is_empty()).then(|| last.to_vec())
+    }
+}
+
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Parses records into books. CSV files must start with a header row.
+pub struct RecordParser {
+    format: DataFormat,
+    headers: Option<csv::StringRecord>,
+}
+
+impl RecordParser {
+    pub fn new(format: DataFormat) -> Self {
+        Self {
+            format,
+            headers: None,
+        }
+    }
+
+    /// Returns `Ok(None)` for rows that don't hold a book, such as blank
+    /// lines or the CSV header.
+    pub fn parse(&mut self, record: &[u8]) -> Result<Option<BookRecord>, String> {
+        if record.iter().all(u8::is_ascii_whitespace) {
+            return Ok(None);
+        }
+
+        match self.format {
+            DataFormat::Json | DataFormat::Ndjson => serde_json::from_slice(record)
+                .map(Some)
+                .map_err(|e| e.to_string()),
+            DataFormat::Csv => {
+                let row = csv::ReaderBuilder::new()
+                    .has_headers(false)
+                    .from_reader(record)
+                    .records()
+                    .next()
+                    .transpose()
+                    .map_err(|e| e.to_string())?
+                    .unwrap_or_default();
+                match &self.headers {
+                    None => {
+                        let headers: csv::StringRecord = row.iter().map(str::trim).collect();
+                        self.headers = Some(headers);
+                        Ok(None)
+                    }
+                    Some(headers) => row
+                        .deserialize(Some(headers))
+                        .map(Some)
+                        .map_err(|e| e.to_string()),
+                }
+            }
+        }
+    }
+}
+
+pub const CSV_HEADER: &[u8] = b"id,title,author,isbn\n";
+
+/// Encodes a single book as one line of a streamed export.
+pub fn encode_book(format: DataFormat, book: &Book) -> Result<Vec<u8>> {
+    match format {
+        DataFormat::Json | DataFormat::Ndjson => {
+            let mut line = serde_json::to_vec(book)?;
+            line.push(b'\n');
+            Ok(line)
+        }
+        DataFormat::Csv => {
+            let mut writer = csv::WriterBuilder::new()
+                .has_headers(false)
+                .from_writer(Vec::new());
+            writer.serialize(book)?;
+            Ok(writer.into_inner()?)
+        }
+    }
+}
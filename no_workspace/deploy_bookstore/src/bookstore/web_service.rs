@@ -1,47 +1,528 @@
-use axum::{http::StatusCode, Extension, Json};
-use super::db::{self, Book, StoreDb};
+use std::{sync::Arc, time::Duration};
+use axum::{
+    body::Body,
+    extract::{
+        multipart::Field,
+        ws::{Message, WebSocket, WebSocketUpgrade},
+        Multipart, Path, Query,
+    },
+    http::{header, HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio_stream::{wrappers::ReceiverStream, StreamExt};
+use crate::{
+    auth::auth_layers::Principal,
+    tenancy::{Tenant, TenantConfig},
+};
+use super::{
+    configuration::BookstoreConfiguration,
+    covers::{self, CoverSize, CoverStorage},
+    db::{self, Book, CatalogueEntry, Review, StoreDb},
+    events::{ChangeFeed, FeedMessage},
+    transfer::{self, DataFormat, ImportReport, Importer},
+};
 
 pub async fn all_books(
-    Extension(db_pool): Extension<StoreDb>
-) -> Result<Json<Vec<Book>>, StatusCode> {
-    let books = db::list_books(db_pool).await
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+) -> Result<Json<Vec<CatalogueEntry>>, StatusCode> {
+    let books = db::list_catalogue(db_pool, tenant.id).await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
     Ok(Json(books))
 }
 
 pub async fn get_book(
     Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
     path: axum::extract::Path<i32>
-) -> Result<Json<Book>, StatusCode> {
-    let book = db::get_book(db_pool, path.0).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+) -> Result<Json<CatalogueEntry>, StatusCode> {
+    let book = db::get_book(db_pool, tenant.id, path.0).await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
     Ok(Json(book))
 }
 
 pub async fn delete_book(
     Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(storage): Extension<Arc<dyn CoverStorage>>,
     path: axum::extract::Path<i32>
 ) -> Result<StatusCode, StatusCode> {
-    db::delete_book(db_pool, path.0).await
+    let deleted = db::delete_book(db_pool, tenant.id, path.0).await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !deleted {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    delete_cover_images(storage.as_ref(), path.0).await;
     Ok(StatusCode::OK)
 }
 
+/// A book whose ISBN is already in the catalogue gets a 409.
+fn conflict_or_internal(error: &anyhow::Error) -> StatusCode {
+    if db::is_isbn_taken(error) {
+        StatusCode::CONFLICT
+    } else {
+        StatusCode::INTERNAL_SERVER_ERROR
+    }
+}
+
 pub async fn add_book(
     Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
     Json(book): Json<Book>
 ) -> Result<StatusCode, StatusCode> {
-    db::add_book(db_pool, book.title, book.author).await
-        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    db::add_book(db_pool, tenant.id, book.title, book.author, book.isbn).await
+        .map_err(|e| conflict_or_internal(&e))?;
     Ok(StatusCode::OK)
 }
 
 pub async fn update_book(
     Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
     path: axum::extract::Path<i32>,
     Json(book): Json<Book>
 ) -> Result<StatusCode, StatusCode> {
-    db::update_book(db_pool, path.0, book.title, book.author).await
+    let updated = db::update_book(db_pool, tenant.id, path.0, book.title, book.author, book.isbn).await
+        .map_err(|e| conflict_or_internal(&e))?;
+    if !updated {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ImportOptions {
+    format: Option<DataFormat>,
+    #[serde(default)]
+    dry_run: bool,
+}
+
+/// Imports books from the first field of a multipart upload, streaming it
+/// row by row. Returns 422 with the per-row report if any row is invalid.
+pub async fn import_books(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Query(options): Query<ImportOptions>,
+    mut multipart: Multipart,
+) -> Result<(StatusCode, Json<ImportReport>), (StatusCode, String)> {
+    let mut field = multipart
+        .next_field()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .ok_or((StatusCode::BAD_REQUEST, "no file uploaded".to_string()))?;
+
+    let format = match options.format.or_else(|| format_of_field(&field)) {
+        Some(DataFormat::Json) | None => {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "upload CSV or NDJSON, or set ?format=csv|ndjson".to_string(),
+            ))
+        }
+        Some(format) => format,
+    };
+
+    let internal_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string());
+    let mut importer = Importer::begin(&db_pool, tenant.id, format, options.dry_run)
+        .await
+        .map_err(internal_error)?;
+    while let Some(chunk) = field
+        .chunk()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+    {
+        importer.push(&chunk).await.map_err(internal_error)?;
+    }
+    let report = importer.finish().await.map_err(internal_error)?;
+
+    let status = if report.errors.is_empty() {
+        StatusCode::OK
+    } else {
+        StatusCode::UNPROCESSABLE_ENTITY
+    };
+    Ok((status, Json(report)))
+}
+
+fn format_of_field(field: &Field) -> Option<DataFormat> {
+    match field.content_type() {
+        Some("text/csv") => return Some(DataFormat::Csv),
+        Some("application/x-ndjson" | "application/jsonl") => return Some(DataFormat::Ndjson),
+        _ => {}
+    }
+    let extension = field.file_name()?.rsplit_once('.')?.1;
+    match DataFormat::parse(&extension.to_lowercase()) {
+        Ok(DataFormat::Json) | Err(_) => None,
+        Ok(format) => Some(format),
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ExportOptions {
+    format: Option<DataFormat>,
+}
+
+/// Streams the whole catalogue as CSV (the default) or NDJSON.
+pub async fn export_books(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Query(options): Query<ExportOptions>,
+) -> Result<impl IntoResponse, (StatusCode, String)> {
+    let format = options.format.unwrap_or(DataFormat::Csv);
+    if format == DataFormat::Json {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "export supports ?format=csv|ndjson".to_string(),
+        ));
+    }
+
+    let header = (format == DataFormat::Csv).then(|| Ok(transfer::CSV_HEADER.to_vec()));
+    let books = db::stream_books(db_pool, tenant.id)
+        .map(move |book| book.and_then(|book| transfer::encode_book(format, &book)));
+    let body = Body::from_stream(tokio_stream::iter(header).chain(books));
+
+    let disposition = format!("attachment; filename=\"books.{}\"", format.extension());
+    Ok((
+        [
+            (header::CONTENT_TYPE, format.content_type().to_string()),
+            (header::CONTENT_DISPOSITION, disposition),
+        ],
+        body,
+    ))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct FeedOptions {
+    last_event_id: Option<i64>,
+}
+
+/// Follows changes to the catalogue, over a WebSocket if the client asks
+/// to upgrade and as Server-Sent Events otherwise. Clients resume after the
+/// event in `Last-Event-ID`, which browsers send when an `EventSource`
+/// reconnects, or in `?last_event_id=`, since WebSockets can't set headers.
+pub async fn book_events(
+    Extension(feed): Extension<ChangeFeed>,
+    Extension(tenant): Extension<Tenant>,
+    Query(options): Query<FeedOptions>,
+    ws: Option<WebSocketUpgrade>,
+    headers: HeaderMap,
+) -> Result<Response, (StatusCode, String)> {
+    let last_event_id = match headers.get("last-event-id") {
+        Some(value) => Some(
+            value
+                .to_str()
+                .ok()
+                .and_then(|id| id.parse().ok())
+                .ok_or((StatusCode::BAD_REQUEST, "invalid Last-Event-ID".to_string()))?,
+        ),
+        None => options.last_event_id,
+    };
+    let messages = feed.subscribe(tenant.id, last_event_id).ok_or((
+        StatusCode::SERVICE_UNAVAILABLE,
+        "too many subscribers; try again later".to_string(),
+    ))?;
+
+    let keep_alive = feed.keep_alive();
+    match ws {
+        Some(ws) => Ok(ws.on_upgrade(move |socket| send_events(socket, messages, keep_alive))),
+        None => {
+            let events = messages.map(|message| {
+                Event::default()
+                    .id(message.id().to_string())
+                    .event(message.name())
+                    .json_data(&message)
+            });
+            Ok(Sse::new(events)
+                .keep_alive(KeepAlive::new().interval(keep_alive))
+                .into_response())
+        }
+    }
+}
+
+/// Sends each message as JSON text, until either side hangs up.
+async fn send_events(mut socket: WebSocket, mut messages: ReceiverStream<FeedMessage>, keep_alive: Duration) {
+    let mut ping = tokio::time::interval(keep_alive);
+    loop {
+        tokio::select! {
+            message = messages.next() => {
+                let Some(Ok(text)) = message.map(|message| serde_json::to_string(&message)) else {
+                    break;
+                };
+                if socket.send(text.into()).await.is_err() {
+                    break;
+                }
+            }
+            // Replies to pings are sent for us; anything else is ignored
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_)) | Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+            _ = ping.tick() => {
+                if socket.send(Message::Ping(Vec::new())).await.is_err() {
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ReviewQuery {
+    #[serde(default = "default_review_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_review_limit() -> i64 {
+    50
+}
+
+/// A book's visible reviews, newest first.
+pub async fn list_reviews(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path(book_id): Path<i32>,
+    Query(query): Query<ReviewQuery>,
+) -> Result<Json<Vec<Review>>, StatusCode> {
+    let reviews = db::list_reviews(db_pool, tenant.id, book_id, false, query.limit.clamp(1, 500), query.offset.max(0))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(reviews))
+}
+
+/// Every review of a book, including hidden ones, for moderators.
+pub async fn moderate_reviews(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path(book_id): Path<i32>,
+    Query(query): Query<ReviewQuery>,
+) -> Result<Json<Vec<Review>>, StatusCode> {
+    let reviews = db::list_reviews(db_pool, tenant.id, book_id, true, query.limit.clamp(1, 500), query.offset.max(0))
+        .await
         .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(reviews))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewReview {
+    rating: i64,
+    #[serde(default)]
+    body: String,
+}
+
+/// Adds or replaces the signed-in user's review of a book.
+pub async fn save_review(
+    Extension(db_pool): Extension<StoreDb>,
+    TenantConfig(config): TenantConfig<BookstoreConfiguration>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Path(book_id): Path<i32>,
+    Json(review): Json<NewReview>,
+) -> Result<Json<Review>, (StatusCode, String)> {
+    let user_id = principal
+        .user_id()
+        .ok_or((StatusCode::FORBIDDEN, "reviews are by signed-in users".to_string()))?;
+    if !(1..=5).contains(&review.rating) {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "rating must be from 1 to 5".to_string()));
+    }
+    let body = review.body.trim();
+    if body.chars().count() > config.max_review_length {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("reviews can be at most {} characters", config.max_review_length),
+        ));
+    }
+
+    let review = db::save_review(db_pool, tenant.id, book_id, user_id, review.rating, body)
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string()))?
+        .ok_or((StatusCode::NOT_FOUND, "no such book".to_string()))?;
+    Ok(Json(review))
+}
+
+/// Deletes the signed-in user's review of a book.
+pub async fn delete_review(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Path(book_id): Path<i32>,
+) -> Result<StatusCode, StatusCode> {
+    let user_id = principal.user_id().ok_or(StatusCode::FORBIDDEN)?;
+    match db::delete_review(db_pool, tenant.id, book_id, user_id).await {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+pub async fn hide_review(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path((book_id, review_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, StatusCode> {
+    set_review_hidden(db_pool, tenant.id, book_id, review_id, true).await
+}
+
+pub async fn unhide_review(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path((book_id, review_id)): Path<(i32, i32)>,
+) -> Result<StatusCode, StatusCode> {
+    set_review_hidden(db_pool, tenant.id, book_id, review_id, false).await
+}
+
+async fn set_review_hidden(
+    db_pool: StoreDb,
+    tenant_id: i32,
+    book_id: i32,
+    review_id: i32,
+    hidden: bool,
+) -> Result<StatusCode, StatusCode> {
+    match db::set_review_hidden(db_pool, tenant_id, book_id, review_id, hidden).await {
+        Ok(true) => Ok(StatusCode::OK),
+        Ok(false) => Err(StatusCode::NOT_FOUND),
+        Err(_) => Err(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CoverUploaded {
+    cover_version: i64,
+}
+
+/// Stores the first file of a multipart upload as a book's cover, with a
+/// thumbnail. The file's type is judged from its contents.
+pub async fn upload_cover(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(config): Extension<BookstoreConfiguration>,
+    Extension(storage): Extension<Arc<dyn CoverStorage>>,
+    Path(book_id): Path<i32>,
+    mut multipart: Multipart,
+) -> Result<Json<CoverUploaded>, (StatusCode, String)> {
+    let internal_error = |_| (StatusCode::INTERNAL_SERVER_ERROR, "unable to store cover".to_string());
+    if db::find_book(db_pool.clone(), tenant.id, book_id).await.map_err(internal_error)?.is_none() {
+        return Err((StatusCode::NOT_FOUND, "no such book".to_string()));
+    }
+
+    let field = multipart
+        .next_field()
+        .await
+        .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?
+        .ok_or((StatusCode::BAD_REQUEST, "no file uploaded".to_string()))?;
+    let bytes = field.bytes().await.map_err(|e| (e.status(), e.body_text()))?;
+    if bytes.len() > config.covers.max_upload_bytes {
+        return Err((
+            StatusCode::PAYLOAD_TOO_LARGE,
+            format!("covers can be at most {} bytes", config.covers.max_upload_bytes),
+        ));
+    }
+
+    // Decoding is CPU-bound, so it's kept off the async workers
+    let covers_config = config.covers.clone();
+    let original = bytes.clone();
+    let cover = tokio::task::spawn_blocking(move || covers::process_cover(&original, &covers_config))
+        .await
+        .map_err(|_| (StatusCode::INTERNAL_SERVER_ERROR, "unable to process cover".to_string()))?
+        .map_err(|e| (StatusCode::UNSUPPORTED_MEDIA_TYPE, format!("{e:#}")))?;
+
+    storage
+        .put(&CoverSize::Original.key(book_id), bytes.to_vec())
+        .await
+        .map_err(internal_error)?;
+    storage
+        .put(&CoverSize::Thumb.key(book_id), cover.thumbnail)
+        .await
+        .map_err(internal_error)?;
+    let cover_version = db::set_cover(db_pool, tenant.id, book_id, cover.content_type)
+        .await
+        .map_err(internal_error)?;
+    Ok(Json(CoverUploaded { cover_version }))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CoverQuery {
+    #[serde(default)]
+    size: CoverSize,
+}
+
+/// Serves a cover or its thumbnail. Browsers may cache it, and revalidate
+/// with the ETag, which changes whenever the cover does.
+pub async fn get_cover(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(config): Extension<BookstoreConfiguration>,
+    Extension(storage): Extension<Arc<dyn CoverStorage>>,
+    Path(book_id): Path<i32>,
+    Query(query): Query<CoverQuery>,
+    headers: HeaderMap,
+) -> Result<Response, StatusCode> {
+    let cover = db::get_cover(db_pool, tenant.id, book_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+
+    let etag = format!("\"{book_id}-{}-{}\"", cover.cover_version, query.size.as_str());
+    let cache_control = format!("public, max-age={}", config.covers.cache_seconds);
+    if headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag))
+    {
+        return Ok((
+            StatusCode::NOT_MODIFIED,
+            [(header::ETAG, etag), (header::CACHE_CONTROL, cache_control)],
+        )
+            .into_response());
+    }
+
+    let bytes = storage
+        .get(&query.size.key(book_id))
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .ok_or(StatusCode::NOT_FOUND)?;
+    let content_type = match query.size {
+        CoverSize::Original => cover.cover_type,
+        CoverSize::Thumb => covers::THUMBNAIL_TYPE.to_string(),
+    };
+    Ok((
+        [
+            (header::CONTENT_TYPE, content_type),
+            (header::ETAG, etag),
+            (header::CACHE_CONTROL, cache_control),
+            (header::X_CONTENT_TYPE_OPTIONS, "nosniff".to_string()),
+        ],
+        bytes,
+    )
+        .into_response())
+}
+
+pub async fn delete_cover(
+    Extension(db_pool): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(storage): Extension<Arc<dyn CoverStorage>>,
+    Path(book_id): Path<i32>,
+) -> Result<StatusCode, StatusCode> {
+    let cleared = db::clear_cover(db_pool, tenant.id, book_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    if !cleared {
+        return Err(StatusCode::NOT_FOUND);
+    }
+    delete_cover_images(storage.as_ref(), book_id).await;
     Ok(StatusCode::OK)
-}
\ No newline at end of file
+}
+
+/// Removes a book's cover images. Failures are only logged: the database
+/// no longer refers to them.
+async fn delete_cover_images(storage: &dyn CoverStorage, book_id: i32) {
+    for size in [CoverSize::Original, CoverSize::Thumb] {
+        if let Err(e) = storage.delete(&size.key(book_id)).await {
+            tracing::warn!("Unable to delete cover {}: {e:#}", size.key(book_id));
+        }
+    }
+}
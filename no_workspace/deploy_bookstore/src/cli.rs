@@ -0,0 +1,358 @@
+use anyhow::{bail, Context, Result};
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use crate::{
+    auth::{self, AuthMode},
+    backup::{self, Backups},
+    migrations::{self, MigrationState},
+    modules::Registry,
+    service_config::ServiceConfig,
+};
+#[cfg(feature = "bookstore")]
+use crate::tenancy::{TenantLookup, DEFAULT_TENANT};
+#[cfg(feature = "bookstore")]
+use crate::bookstore::{self, DataFormat};
+
+/// Builds the command-line interface. Running without a subcommand serves,
+/// so the container can keep launching the bare binary.
+pub fn build() -> Command {
+    let username = Arg::new("username").required(true).help("The user to act on");
+    let password = Arg::new("password")
+        .short('p')
+        .long("password")
+        .value_name("PASSWORD")
+        .help("The new password. Read from stdin if omitted");
+    let tenant = tenant_arg();
+    let command = Command::new("deploy_bookstore")
+        .version("0.1.0")
+        .arg(
+            Arg::new("check-config")
+                .long("check-config")
+                .action(ArgAction::SetTrue)
+                .help("Prints the effective configuration, validates it and exits"),
+        )
+        .subcommand(Command::new("serve").about("Starts the server (the default)"))
+        .subcommand(
+            Command::new("migrate")
+                .about("Applies database migrations for every module")
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Lists pending migrations without applying them"),
+                )
+                .subcommand(
+                    Command::new("status")
+                        .about("Reports each module's migrations and any drift from the expected schema"),
+                )
+                .subcommand(
+                    Command::new("down")
+                        .about("Rolls a module's database back. Stop the service and back up first")
+                        .arg(
+                            Arg::new("module")
+                                .long("module")
+                                .value_name("MODULE")
+                                .required(true)
+                                .help("The module to roll back, such as bookstore"),
+                        )
+                        .arg(
+                            Arg::new("to")
+                                .long("to")
+                                .value_name("VERSION")
+                                .required(true)
+                                .value_parser(clap::value_parser!(i64))
+                                .help("The last migration to keep; 0 rolls back everything"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("user")
+                .about("Manages user accounts")
+                .subcommand_required(true)
+                .subcommand(
+                    Command::new("create")
+                        .about("Creates a user")
+                        .arg(username.clone())
+                        .arg(password.clone())
+                        .arg(tenant.clone())
+                        .arg(
+                            Arg::new("service-account")
+                                .long("service-account")
+                                .action(ArgAction::SetTrue)
+                                .help("Lets the user sign in with a client certificate named after it"),
+                        ),
+                )
+                .subcommand(
+                    Command::new("passwd")
+                        .about("Sets a user's password")
+                        .arg(username.clone())
+                        .arg(password)
+                        .arg(tenant.clone()),
+                )
+                .subcommand(
+                    Command::new("disable")
+                        .about("Disables a user and revokes their tokens")
+                        .arg(username)
+                        .arg(tenant),
+                ),
+        )
+        .subcommand(
+            Command::new("backup")
+                .about("Backs up and restores the databases")
+                .subcommand_required(true)
+                .subcommand(Command::new("create").about("Takes a backup, which is safe while the service runs"))
+                .subcommand(Command::new("list").about("Lists backups, newest first"))
+                .subcommand(
+                    Command::new("restore")
+                        .about("Replaces the databases with a backup's. Stop the service first")
+                        .arg(Arg::new("name").required(true).help("The backup to restore"))
+                        .arg(
+                            Arg::new("module")
+                                .short('m')
+                                .long("module")
+                                .value_name("MODULE")
+                                .action(ArgAction::Append)
+                                .help("Restores only this module's database. Can be repeated"),
+                        ),
+                ),
+        );
+
+    #[cfg(feature = "bookstore")]
+    let command = command.subcommand(books_command());
+
+    command
+}
+
+fn tenant_arg() -> Arg {
+    Arg::new("tenant")
+        .short('t')
+        .long("tenant")
+        .value_name("SLUG")
+        .help("The tenant to act on. Defaults to the default tenant")
+}
+
+#[cfg(feature = "bookstore")]
+fn books_command() -> Command {
+    let format = Arg::new("format")
+        .short('f')
+        .long("format")
+        .value_name("FORMAT")
+        .value_parser(["json", "ndjson", "csv"])
+        .default_value("json")
+        .help("The file format");
+
+    Command::new("books")
+        .about("Manages the book catalogue")
+        .subcommand_required(true)
+        .subcommand(
+            Command::new("export")
+                .about("Exports the catalogue")
+                .arg(format.clone())
+                .arg(tenant_arg())
+                .arg(
+                    Arg::new("output")
+                        .short('o')
+                        .long("output")
+                        .value_name("FILE")
+                        .help("File to write. Defaults to stdout"),
+                ),
+        )
+        .subcommand(
+            Command::new("import")
+                .about("Adds books from a file to the catalogue")
+                .arg(format)
+                .arg(tenant_arg())
+                .arg(Arg::new("input").value_name("FILE").help("File to read. Defaults to stdin"))
+                .arg(
+                    Arg::new("dry-run")
+                        .long("dry-run")
+                        .action(ArgAction::SetTrue)
+                        .help("Reports what would be imported without changing anything"),
+                ),
+        )
+}
+
+pub async fn migrate(settings: &ServiceConfig, matches: &ArgMatches) -> Result<()> {
+    let mut registry = Registry::open(settings).await?;
+    if let Some(("status", _)) = matches.subcommand() {
+        return migration_status(&registry).await;
+    }
+    let dry_run = matches.subcommand().is_none() && matches.get_flag("dry-run");
+    if !dry_run {
+        registry.lock().context("stop the service before migrating")?;
+    }
+    if let Some(("down", matches)) = matches.subcommand() {
+        return migrate_down(&registry, matches).await;
+    }
+
+    for module in registry.modules() {
+        if dry_run {
+            let pool = migrations::open_read_only(&module.database().filename).await?;
+            migrations::check(module.name(), module.migrator(), &pool, false).await?;
+            for migration in migrations::status(module.migrator(), &pool).await? {
+                if migration.state == MigrationState::Pending {
+                    println!("{}: would apply {} {}", module.name(), migration.version, migration.description);
+                }
+            }
+        } else {
+            module.migrate().await?;
+            println!("{}: migrations applied", module.name());
+        }
+    }
+    Ok(())
+}
+
+/// Prints every module's migrations and checks its schema against the one
+/// they produce. Fails if anything needs attention, so a deploy job can
+/// gate on it; pending migrations alone don't count.
+async fn migration_status(registry: &Registry) -> Result<()> {
+    let mut problems = 0;
+    for module in registry.modules() {
+        let pool = migrations::open_read_only(&module.database().filename).await?;
+        println!("{}:", module.name());
+        for migration in migrations::status(module.migrator(), &pool).await? {
+            let state = format!("{:?}", migration.state).to_lowercase();
+            let reversible = match migration.state {
+                MigrationState::Unknown => "  (from a newer build)",
+                _ if !migration.reversible => "  (irreversible)",
+                _ => "",
+            };
+            println!("  {}  {state:<8}  {}{reversible}", migration.version, migration.description);
+            if !matches!(migration.state, MigrationState::Applied | MigrationState::Pending) {
+                problems += 1;
+            }
+        }
+        let drift = migrations::drift(module.migrator(), &pool).await?;
+        if drift.is_empty() {
+            println!("  schema matches");
+        }
+        for difference in &drift {
+            println!("  drift: {difference}");
+        }
+        problems += drift.len();
+    }
+    if problems > 0 {
+        bail!("{problems} problem(s) with the database schemas");
+    }
+    Ok(())
+}
+
+async fn migrate_down(registry: &Registry, matches: &ArgMatches) -> Result<()> {
+    let name = matches.get_one::<String>("module").expect("clap requires a module");
+    let target = *matches.get_one::<i64>("to").expect("clap requires a version");
+    let module = registry
+        .modules()
+        .iter()
+        .find(|module| module.name() == name)
+        .with_context(|| format!("no module named {name} in this build"))?;
+    let reverted = migrations::undo(module.name(), module.migrator(), &module.database().pool, target).await?;
+    if reverted.is_empty() {
+        println!("{name}: nothing to roll back");
+    }
+    for migration in reverted {
+        println!("{name}: rolled back {} {}", migration.version, migration.description);
+    }
+    Ok(())
+}
+
+pub async fn user(settings: &ServiceConfig, matches: &ArgMatches) -> Result<()> {
+    if settings.auth.mode == AuthMode::Remote {
+        bail!("users are managed by the auth service; run this command there");
+    }
+    match matches.subcommand() {
+        Some(("create", matches)) => {
+            let password = password_from(matches)?;
+            let service_account = matches.get_flag("service-account");
+            let (tenant, username) = (tenant_from(matches), username_from(matches));
+            auth::commands::create_user(&settings.auth, tenant, username, &password, service_account).await
+        }
+        Some(("passwd", matches)) => {
+            let password = password_from(matches)?;
+            auth::commands::set_password(&settings.auth, tenant_from(matches), username_from(matches), &password).await
+        }
+        Some(("disable", matches)) => {
+            auth::commands::disable_user(&settings.auth, tenant_from(matches), username_from(matches)).await
+        }
+        _ => unreachable!("clap requires a user subcommand"),
+    }
+}
+
+pub async fn backup(settings: &ServiceConfig, matches: &ArgMatches) -> Result<()> {
+    let registry = Registry::open(settings).await?;
+    match matches.subcommand() {
+        Some(("create", _)) => {
+            let backup = Backups::new(settings.backup.clone(), &registry).create().await?;
+            println!("Backed up {} to {}", backup.databases.join(", "), backup.name);
+            Ok(())
+        }
+        Some(("list", _)) => {
+            for backup in Backups::new(settings.backup.clone(), &registry).list().await? {
+                println!("{}  {} bytes  {}", backup.name, backup.size_bytes, backup.databases.join(", "));
+            }
+            Ok(())
+        }
+        Some(("restore", matches)) => {
+            let name = matches.get_one::<String>("name").expect("clap requires a name");
+            let modules: Vec<String> = matches.get_many::<String>("module").unwrap_or_default().cloned().collect();
+            backup::restore(&settings.backup, registry, name, &modules).await
+        }
+        _ => unreachable!("clap requires a backup subcommand"),
+    }
+}
+
+#[cfg(feature = "bookstore")]
+pub async fn books(settings: &ServiceConfig, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("export", matches)) => {
+            let tenant_id = tenant_id_from(settings, matches).await?;
+            let output = matches.get_one::<String>("output").map(String::as_str);
+            bookstore::commands::export_books(&settings.bookstore, tenant_id, format_from(matches)?, output).await
+        }
+        Some(("import", matches)) => {
+            let tenant_id = tenant_id_from(settings, matches).await?;
+            let input = matches.get_one::<String>("input").map(String::as_str);
+            let dry_run = matches.get_flag("dry-run");
+            bookstore::commands::import_books(&settings.bookstore, tenant_id, format_from(matches)?, input, dry_run)
+                .await
+        }
+        _ => unreachable!("clap requires a books subcommand"),
+    }
+}
+
+/// Tenants are kept by the auth module, which may be another instance's.
+#[cfg(feature = "bookstore")]
+async fn tenant_id_from(settings: &ServiceConfig, matches: &ArgMatches) -> Result<i32> {
+    let Some(slug) = matches.get_one::<String>("tenant") else {
+        return Ok(DEFAULT_TENANT);
+    };
+    let registry = Registry::open(settings).await?;
+    match registry.authenticator().tenant(TenantLookup::Slug(slug)).await? {
+        Some(tenant) => Ok(tenant.id),
+        None => bail!("no such tenant: {slug}"),
+    }
+}
+
+fn tenant_from(matches: &ArgMatches) -> &str {
+    matches.get_one::<String>("tenant").map_or("default", String::as_str)
+}
+
+fn username_from(matches: &ArgMatches) -> &str {
+    matches
+        .get_one::<String>("username")
+        .expect("clap requires a username")
+}
+
+#[cfg(feature = "bookstore")]
+fn format_from(matches: &ArgMatches) -> Result<DataFormat> {
+    DataFormat::parse(matches.get_one::<String>("format").expect("format has a default"))
+}
+
+fn password_from(matches: &ArgMatches) -> Result<String> {
+    if let Some(password) = matches.get_one::<String>("password") {
+        return Ok(password.clone());
+    }
+    let mut password = String::new();
+    std::io::stdin()
+        .read_line(&mut password)
+        .context("unable to read password from stdin")?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
@@ -0,0 +1,192 @@
+use std::time::Duration;
+use anyhow::{Context, Result};
+use axum::http::{HeaderName, HeaderValue, Method};
+use serde::{Deserialize, Serialize};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+use crate::service_config::string_list;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum CorsProfile {
+    /// Only the configured policies apply.
+    Strict,
+    /// Any origin may call anything, with credentials. Never use in production.
+    Dev,
+}
+
+/// The cross-origin rules for one group of routes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// Origins such as `https://shop.example.com`, or `*` for any. Empty
+    /// means same-origin only.
+    #[serde(deserialize_with = "string_list")]
+    pub allowed_origins: Vec<String>,
+    #[serde(deserialize_with = "string_list")]
+    pub allowed_methods: Vec<String>,
+    #[serde(deserialize_with = "string_list")]
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age_seconds: u64,
+}
+
+impl Default for CorsPolicy {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: vec!["GET".to_string(), "POST".to_string()],
+            allowed_headers: vec![
+                "content-type".to_string(),
+                "token".to_string(),
+                "authorization".to_string(),
+            ],
+            allow_credentials: false,
+            max_age_seconds: 600,
+        }
+    }
+}
+
+impl CorsPolicy {
+    fn validate(&self, setting: &str, problems: &mut Vec<String>) {
+        if let Err(e) = self.layer() {
+            problems.push(format!("{setting}: {e:#}"));
+        }
+        let wildcard = |list: &[String]| list.iter().any(|item| item == "*");
+        if self.allow_credentials
+            && (wildcard(&self.allowed_origins)
+                || wildcard(&self.allowed_methods)
+                || wildcard(&self.allowed_headers))
+        {
+            problems.push(format!(
+                "{setting}: allow_credentials can't be combined with a `*` wildcard"
+            ));
+        }
+    }
+
+    fn layer(&self) -> Result<CorsLayer> {
+        let origins = if self.allowed_origins.iter().any(|o| o == "*") {
+            AllowOrigin::any()
+        } else {
+            let origins = self
+                .allowed_origins
+                .iter()
+                .map(|origin| HeaderValue::from_str(origin).with_context(|| format!("bad origin {origin}")))
+                .collect::<Result<Vec<_>>>()?;
+            AllowOrigin::list(origins)
+        };
+
+        let methods = if self.allowed_methods.iter().any(|m| m == "*") {
+            AllowMethods::any()
+        } else {
+            let methods = self
+                .allowed_methods
+                .iter()
+                .map(|method| {
+                    Method::from_bytes(method.to_uppercase().as_bytes())
+                        .with_context(|| format!("bad method {method}"))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            AllowMethods::list(methods)
+        };
+
+        let headers = if self.allowed_headers.iter().any(|h| h == "*") {
+            AllowHeaders::any()
+        } else {
+            let headers = self
+                .allowed_headers
+                .iter()
+                .map(|header| HeaderName::try_from(header.as_str()).with_context(|| format!("bad header {header}")))
+                .collect::<Result<Vec<_>>>()?;
+            AllowHeaders::list(headers)
+        };
+
+        Ok(CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods(methods)
+            .allow_headers(headers)
+            .allow_credentials(self.allow_credentials)
+            .max_age(Duration::from_secs(self.max_age_seconds)))
+    }
+}
+
+/// The groups of routes that can have their own policy.
+#[derive(Debug, Clone, Copy)]
+pub enum RouteGroup {
+    Auth,
+    #[cfg_attr(not(feature = "bookstore"), allow(dead_code))]
+    Books,
+    #[cfg_attr(not(feature = "orders"), allow(dead_code))]
+    Orders,
+    #[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+    Webhooks,
+    Admin,
+    StaticContent,
+}
+
+/// The `cors` section of the service configuration. Each route group uses
+/// its own policy if it has one, and `default` otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct CorsConfiguration {
+    pub profile: CorsProfile,
+    pub default: CorsPolicy,
+    pub auth: Option<CorsPolicy>,
+    pub books: Option<CorsPolicy>,
+    pub orders: Option<CorsPolicy>,
+    pub webhooks: Option<CorsPolicy>,
+    pub admin: Option<CorsPolicy>,
+    pub static_content: Option<CorsPolicy>,
+}
+
+impl Default for CorsConfiguration {
+    fn default() -> Self {
+        Self {
+            profile: CorsProfile::Strict,
+            default: CorsPolicy::default(),
+            auth: None,
+            books: None,
+            orders: None,
+            webhooks: None,
+            admin: None,
+            static_content: None,
+        }
+    }
+}
+
+impl CorsConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        self.default.validate("cors.default", problems);
+        for (setting, policy) in [
+            ("cors.auth", &self.auth),
+            ("cors.books", &self.books),
+            ("cors.orders", &self.orders),
+            ("cors.webhooks", &self.webhooks),
+            ("cors.admin", &self.admin),
+            ("cors.static_content", &self.static_content),
+        ] {
+            if let Some(policy) = policy {
+                policy.validate(setting, problems);
+            }
+        }
+    }
+
+    fn policy(&self, group: RouteGroup) -> &CorsPolicy {
+        let policy = match group {
+            RouteGroup::Auth => &self.auth,
+            RouteGroup::Books => &self.books,
+            RouteGroup::Orders => &self.orders,
+            RouteGroup::Webhooks => &self.webhooks,
+            RouteGroup::Admin => &self.admin,
+            RouteGroup::StaticContent => &self.static_content,
+        };
+        policy.as_ref().unwrap_or(&self.default)
+    }
+
+    /// Builds the CORS layer for a group of routes.
+    pub fn layer(&self, group: RouteGroup) -> Result<CorsLayer> {
+        match self.profile {
+            CorsProfile::Dev => Ok(CorsLayer::very_permissive()),
+            CorsProfile::Strict => self.policy(group).layer(),
+        }
+    }
+}
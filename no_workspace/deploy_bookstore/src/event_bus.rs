@@ -0,0 +1,39 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::Serialize;
+
+/// Every event type that modules publish.
+#[cfg_attr(not(feature = "webhooks"), allow(dead_code))]
+pub const EVENT_TYPES: &[&str] = &[
+    "book.added",
+    "book.updated",
+    "book.deleted",
+    "user.created",
+    "user.updated",
+    "user.deleted",
+];
+
+/// Something that happened in one module, for others to act on.
+#[derive(Serialize, Clone, Debug)]
+pub struct DomainEvent {
+    /// Unique, so receivers can tell a redelivered event from a new one.
+    pub id: String,
+    #[serde(rename = "type")]
+    pub event_type: String,
+    pub occurred_at: i64,
+    pub data: serde_json::Value,
+}
+
+/// Somewhere a module's outbox events go, such as the webhooks queue. A
+/// sink is given every event in order, at least once: after a failure or a
+/// restart it's given again everything after the last event it accepted,
+/// so it should tell repeats apart by id. Nothing is missed, so long as the
+/// sink only accepts an event once it's kept it.
+#[async_trait]
+pub trait OutboxSink: Send + Sync {
+    /// Names the sink's cursor, so it mustn't change between restarts.
+    fn name(&self) -> &'static str;
+
+    /// Returns an error if the event should be given again later.
+    async fn publish(&self, event: &DomainEvent) -> Result<()>;
+}
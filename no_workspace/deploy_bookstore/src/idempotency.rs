@@ -0,0 +1,484 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use tower::{Layer, Service};
+use crate::auth::auth_layers::Principal;
+
+pub const IDEMPOTENCY_KEY_HEADER: &str = "idempotency-key";
+/// Marks a response as a replay of the one stored for the key.
+pub const REPLAYED_HEADER: &str = "idempotent-replayed";
+
+/// Keys longer than this are refused.
+const MAX_KEY_LENGTH: usize = 255;
+
+/// The `idempotency` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct IdempotencyConfiguration {
+    pub enabled: bool,
+    /// How long a response is kept for replaying, in seconds.
+    pub ttl_seconds: u64,
+    /// The largest request body accepted with an `Idempotency-Key`, in
+    /// bytes. It's read whole to compare it with the first request's.
+    pub max_request_bytes: usize,
+    /// The largest response kept for replaying, in bytes. Larger ones, and
+    /// streamed ones such as exports, aren't kept.
+    pub max_response_bytes: usize,
+}
+
+impl Default for IdempotencyConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            ttl_seconds: 24 * 60 * 60,
+            max_request_bytes: 16 * 1024 * 1024,
+            max_response_bytes: 1024 * 1024,
+        }
+    }
+}
+
+impl IdempotencyConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.ttl_seconds == 0 || self.max_request_bytes == 0 || self.max_response_bytes == 0 {
+            problems.push("idempotency: ttl_seconds and the byte limits must be at least 1".to_string());
+        }
+    }
+}
+
+/// A response kept to be replayed.
+#[derive(Clone, Debug)]
+pub struct StoredResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl IntoResponse for StoredResponse {
+    fn into_response(self) -> Response {
+        let mut response = (self.status, self.headers, self.body).into_response();
+        response.headers_mut().insert(REPLAYED_HEADER, HeaderValue::from_static("true"));
+        response
+    }
+}
+
+/// What became of an attempt to claim a key.
+#[derive(Debug)]
+pub enum Claim {
+    /// The key is new; the request should go ahead and its response be
+    /// stored with `complete`, or the key released.
+    Claimed,
+    /// Another request with the key hasn't finished yet.
+    InProgress,
+    /// The key was used for a different request.
+    Mismatch,
+    Replay(StoredResponse),
+}
+
+/// Where keys and their responses live. The in-memory store suits a single
+/// replica; a shared store can implement this to cover every replica.
+#[async_trait]
+pub trait IdempotencyStore: Send + Sync {
+    /// Claims `key` for the request with `fingerprint`, for `ttl`.
+    async fn claim(&self, key: &str, fingerprint: &[u8], ttl: Duration) -> Result<Claim>;
+
+    /// Stores the response to the request that claimed `key`.
+    async fn complete(&self, key: &str, response: StoredResponse) -> Result<()>;
+
+    /// Forgets `key`, so the request can be tried again.
+    async fn release(&self, key: &str) -> Result<()>;
+}
+
+struct Entry {
+    fingerprint: Vec<u8>,
+    expires: Instant,
+    /// `None` while the request is in progress.
+    response: Option<StoredResponse>,
+}
+
+#[derive(Default)]
+pub struct InMemoryIdempotency {
+    entries: Mutex<HashMap<String, Entry>>,
+}
+
+/// Above this many keys, expired ones are dropped to bound memory use.
+const MAX_IDLE_KEYS: usize = 10_000;
+
+#[async_trait]
+impl IdempotencyStore for InMemoryIdempotency {
+    async fn claim(&self, key: &str, fingerprint: &[u8], ttl: Duration) -> Result<Claim> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() > MAX_IDLE_KEYS {
+            entries.retain(|_, entry| entry.expires > now);
+        }
+
+        if let Some(entry) = entries.get(key).filter(|entry| entry.expires > now) {
+            return Ok(if entry.fingerprint != fingerprint {
+                Claim::Mismatch
+            } else {
+                match &entry.response {
+                    Some(response) => Claim::Replay(response.clone()),
+                    None => Claim::InProgress,
+                }
+            });
+        }
+
+        entries.insert(
+            key.to_string(),
+            Entry {
+                fingerprint: fingerprint.to_vec(),
+                expires: now + ttl,
+                response: None,
+            },
+        );
+        Ok(Claim::Claimed)
+    }
+
+    async fn complete(&self, key: &str, response: StoredResponse) -> Result<()> {
+        if let Some(entry) = self.entries.lock().unwrap().get_mut(key) {
+            entry.response = Some(response);
+        }
+        Ok(())
+    }
+
+    async fn release(&self, key: &str) -> Result<()> {
+        self.entries.lock().unwrap().remove(key);
+        Ok(())
+    }
+}
+
+struct Idempotency {
+    config: IdempotencyConfiguration,
+    store: Arc<dyn IdempotencyStore>,
+}
+
+/// Releases a claimed key if the request never finishes, such as when the
+/// client hangs up, so a retry isn't told it's still in progress.
+struct ClaimGuard {
+    store: Arc<dyn IdempotencyStore>,
+    key: Option<String>,
+}
+
+impl ClaimGuard {
+    fn disarm(&mut self) -> String {
+        self.key.take().unwrap_or_default()
+    }
+}
+
+impl Drop for ClaimGuard {
+    fn drop(&mut self) {
+        if let Some(key) = self.key.take() {
+            let store = self.store.clone();
+            tokio::spawn(async move { store.release(&key).await });
+        }
+    }
+}
+
+/// Makes unsafe requests that carry an `Idempotency-Key` header safe to
+/// retry: the first response for a client's key is stored and replayed to
+/// later requests with the same key, and reusing a key for a different
+/// request is refused. Add it with `route_layer`, inside the authentication
+/// layer, since keys are per client.
+#[derive(Clone)]
+pub struct IdempotencyLayer {
+    idempotency: Arc<Idempotency>,
+}
+
+impl IdempotencyLayer {
+    pub fn new(config: IdempotencyConfiguration, store: Arc<dyn IdempotencyStore>) -> Self {
+        Self {
+            idempotency: Arc::new(Idempotency { config, store }),
+        }
+    }
+}
+
+impl<S> Layer<S> for IdempotencyLayer {
+    type Service = IdempotencyService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        IdempotencyService {
+            inner,
+            idempotency: self.idempotency.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct IdempotencyService<S> {
+    inner: S,
+    idempotency: Arc<Idempotency>,
+}
+
+impl<S> Service<Request> for IdempotencyService<S>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Take the service that was polled ready, leaving a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let idempotency = self.idempotency.clone();
+
+        Box::pin(async move {
+            let config = &idempotency.config;
+            let key = req.headers().get(IDEMPOTENCY_KEY_HEADER).cloned();
+            let principal = req.extensions().get::<Principal>().map(Principal::to_string);
+            let (Some(key), Some(principal)) = (key, principal) else {
+                return inner.call(req).await;
+            };
+            if !config.enabled || req.method().is_safe() {
+                return inner.call(req).await;
+            }
+            let key = match key.to_str() {
+                Ok(key) if !key.is_empty() && key.len() <= MAX_KEY_LENGTH => key.to_string(),
+                _ => {
+                    let message = format!("Idempotency-Key must be 1 to {MAX_KEY_LENGTH} visible ASCII characters");
+                    return Ok((StatusCode::BAD_REQUEST, message).into_response());
+                }
+            };
+
+            // The request is identified by what it does, so the body has to
+            // be read before it's passed on
+            let (parts, body) = req.into_parts();
+            let Ok(body) = axum::body::to_bytes(body, config.max_request_bytes).await else {
+                return Ok((StatusCode::PAYLOAD_TOO_LARGE, "request body is too large").into_response());
+            };
+            let mut hasher = Sha256::new();
+            hasher.update(parts.method.as_str());
+            hasher.update([0]);
+            hasher.update(parts.uri.to_string());
+            hasher.update([0]);
+            hasher.update(&body);
+            let fingerprint = hasher.finalize();
+            let req = Request::from_parts(parts, Body::from(body));
+
+            let store = idempotency.store.clone();
+            let key = format!("{principal}|{key}");
+            let ttl = Duration::from_secs(config.ttl_seconds);
+            match store.claim(&key, &fingerprint, ttl).await {
+                Ok(Claim::Claimed) => {}
+                Ok(Claim::Replay(response)) => return Ok(response.into_response()),
+                Ok(Claim::InProgress) => {
+                    let message = "a request with this Idempotency-Key is still in progress";
+                    return Ok((StatusCode::CONFLICT, message).into_response());
+                }
+                Ok(Claim::Mismatch) => {
+                    let message = "Idempotency-Key was already used for a different request";
+                    return Ok((StatusCode::UNPROCESSABLE_ENTITY, message).into_response());
+                }
+                Err(e) => {
+                    // Fail open: a broken store shouldn't take the API down
+                    tracing::warn!("Idempotency store failed: {e:#}");
+                    return inner.call(req).await;
+                }
+            }
+
+            let mut guard = ClaimGuard {
+                store: store.clone(),
+                key: Some(key),
+            };
+            let response = inner.call(req).await?;
+            let key = guard.disarm();
+
+            // Server errors aren't kept, so they can be retried. Nor are
+            // responses that would have to be held in memory whole to keep:
+            // streams, whose length isn't known, and large ones.
+            let too_large = response
+                .body()
+                .size_hint()
+                .exact()
+                .is_none_or(|length| length > config.max_response_bytes as u64);
+            if response.status().is_server_error() || too_large {
+                if let Err(e) = store.release(&key).await {
+                    tracing::warn!("Idempotency store failed: {e:#}");
+                }
+                return Ok(response);
+            }
+
+            let (parts, body) = response.into_parts();
+            let body = match axum::body::to_bytes(body, config.max_response_bytes).await {
+                Ok(body) => body,
+                Err(e) => {
+                    tracing::warn!("Unable to read a response to store for its Idempotency-Key: {e:#}");
+                    let _ = store.release(&key).await;
+                    return Ok(StatusCode::INTERNAL_SERVER_ERROR.into_response());
+                }
+            };
+            let stored = StoredResponse {
+                status: parts.status,
+                headers: parts.headers.clone(),
+                body: body.clone(),
+            };
+            if let Err(e) = store.complete(&key, stored).await {
+                tracing::warn!("Idempotency store failed: {e:#}");
+            }
+            Ok(Response::from_parts(parts, Body::from(body)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use axum::{
+        http::{header, Method},
+        routing::post,
+        Extension, Router,
+    };
+    use serde_json::{json, Value};
+    use tokio::sync::Notify;
+    use tower::ServiceExt;
+    use crate::{
+        auth::auth_layers::Role,
+        test_harness::{request, TestApp, TestResponse},
+    };
+    use super::*;
+
+    async fn send_with_key(app: &TestApp, method: Method, uri: &str, token: &str, key: &str, body: Value) -> TestResponse {
+        let request = request(method, uri, Some(token))
+            .header(IDEMPOTENCY_KEY_HEADER, key)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        app.send(request).await
+    }
+
+    /// A route behind the layer that answers with `respond`, as a signed-in
+    /// user, counting how often it runs.
+    fn counted<F>(respond: F) -> (Router, Arc<AtomicUsize>)
+    where
+        F: Fn() -> Response + Clone + Send + Sync + 'static,
+    {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let counter = calls.clone();
+        let handler = move || {
+            counter.fetch_add(1, Ordering::SeqCst);
+            let respond = respond.clone();
+            async move { respond() }
+        };
+        (router(post(handler)), calls)
+    }
+
+    fn router(route: axum::routing::MethodRouter) -> Router {
+        let layer = IdempotencyLayer::new(IdempotencyConfiguration::default(), Arc::new(InMemoryIdempotency::default()));
+        Router::new()
+            .route("/", route)
+            .route_layer(layer)
+            .layer(Extension(Principal::User {
+                user_id: 1,
+                role: Role::Reader,
+                tenant_id: 1,
+            }))
+    }
+
+    fn post_with_key(key: &str) -> Request {
+        Request::post("/").header(IDEMPOTENCY_KEY_HEADER, key).body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_get_the_first_response_again() {
+        let app = TestApp::new().await;
+        let token = app.admin_token().await;
+        let key = json!({ "name": "importer", "scopes": ["books:write"] });
+
+        let first = send_with_key(&app, Method::POST, "/api/v1/auth/keys/add", &token, "key-1", key.clone()).await;
+        assert_eq!(first.status, StatusCode::OK, "{}", first.text());
+        assert!(!first.headers.contains_key(REPLAYED_HEADER));
+        let retry = send_with_key(&app, Method::POST, "/api/v1/auth/keys/add", &token, "key-1", key.clone()).await;
+        assert_eq!(retry.status, StatusCode::OK);
+        assert_eq!(retry.headers[REPLAYED_HEADER], "true");
+        assert_eq!(retry.body, first.body);
+        assert_eq!(app.get("/api/v1/auth/keys", Some(&token)).await.json().as_array().unwrap().len(), 1);
+
+        let other = json!({ "name": "exporter", "scopes": ["books:read"] });
+        let reused = send_with_key(&app, Method::POST, "/api/v1/auth/keys/add", &token, "key-1", other).await;
+        assert_eq!(reused.status, StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[tokio::test]
+    async fn keys_are_per_client() {
+        let app = TestApp::new().await;
+        let alice = app.reader_token("alice").await;
+        let bob = app.reader_token("bob").await;
+        let update = json!({ "email": "reader@example.com" });
+
+        let response = send_with_key(&app, Method::PATCH, "/api/v1/auth/me", &alice, "same", update.clone()).await;
+        assert_eq!(response.json()["username"], "alice");
+        let response = send_with_key(&app, Method::PATCH, "/api/v1/auth/me", &bob, "same", update).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(!response.headers.contains_key(REPLAYED_HEADER));
+        assert_eq!(response.json()["username"], "bob");
+    }
+
+    #[tokio::test]
+    async fn retries_while_the_first_is_running_are_refused() {
+        let started = Arc::new(Notify::new());
+        let finish = Arc::new(Notify::new());
+        let handler = {
+            let (started, finish) = (started.clone(), finish.clone());
+            move || async move {
+                started.notify_one();
+                finish.notified().await;
+                StatusCode::CREATED
+            }
+        };
+        let app = router(post(handler));
+
+        let first = tokio::spawn(app.clone().oneshot(post_with_key("slow")));
+        started.notified().await;
+        let retry = app.clone().oneshot(post_with_key("slow")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CONFLICT);
+
+        finish.notify_one();
+        assert_eq!(first.await.unwrap().unwrap().status(), StatusCode::CREATED);
+        let retry = app.oneshot(post_with_key("slow")).await.unwrap();
+        assert_eq!(retry.status(), StatusCode::CREATED);
+        assert_eq!(retry.headers()[REPLAYED_HEADER], "true");
+    }
+
+    #[tokio::test]
+    async fn server_errors_and_streams_arent_kept() {
+        let (app, calls) = counted(|| StatusCode::SERVICE_UNAVAILABLE.into_response());
+        for _ in 0..2 {
+            let response = app.clone().oneshot(post_with_key("failing")).await.unwrap();
+            assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+
+        let (app, calls) = counted(|| {
+            let chunks = tokio_stream::iter([Ok::<_, std::io::Error>("a stream")]);
+            Body::from_stream(chunks).into_response()
+        });
+        for _ in 0..2 {
+            let response = app.clone().oneshot(post_with_key("streamed")).await.unwrap();
+            assert!(!response.headers().contains_key(REPLAYED_HEADER));
+            let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, "a stream");
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+}
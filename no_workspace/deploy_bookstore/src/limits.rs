@@ -0,0 +1,113 @@
+use std::time::Duration;
+use axum::{error_handling::HandleErrorLayer, extract::DefaultBodyLimit, http::StatusCode, BoxError, Router};
+use serde::{Deserialize, Serialize};
+use tower::ServiceBuilder;
+use tower_http::timeout::TimeoutLayer;
+use crate::cors::RouteGroup;
+
+/// Limits for one group of routes.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct GroupLimits {
+    /// The largest request body a handler will read, in bytes.
+    pub max_body_bytes: usize,
+    /// Requests that take longer than this get a 408.
+    pub timeout_seconds: u64,
+}
+
+impl Default for GroupLimits {
+    fn default() -> Self {
+        Self {
+            max_body_bytes: 64 * 1024,
+            timeout_seconds: 30,
+        }
+    }
+}
+
+/// The `limits` section of the service configuration. Each route group uses
+/// its own limits if it has them, and `default` otherwise.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct LimitsConfiguration {
+    /// Requests beyond this many in flight are rejected with a 503.
+    pub max_concurrent_requests: usize,
+    pub default: GroupLimits,
+    pub auth: Option<GroupLimits>,
+    pub books: Option<GroupLimits>,
+    pub orders: Option<GroupLimits>,
+    pub webhooks: Option<GroupLimits>,
+    pub admin: Option<GroupLimits>,
+    pub static_content: Option<GroupLimits>,
+}
+
+impl Default for LimitsConfiguration {
+    fn default() -> Self {
+        Self {
+            max_concurrent_requests: 512,
+            default: GroupLimits::default(),
+            auth: None,
+            books: None,
+            orders: None,
+            webhooks: None,
+            admin: None,
+            static_content: None,
+        }
+    }
+}
+
+impl LimitsConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if self.max_concurrent_requests == 0 {
+            problems.push("limits.max_concurrent_requests: must be at least 1".to_string());
+        }
+        for (setting, limits) in [
+            ("limits.default", Some(&self.default)),
+            ("limits.auth", self.auth.as_ref()),
+            ("limits.books", self.books.as_ref()),
+            ("limits.orders", self.orders.as_ref()),
+            ("limits.webhooks", self.webhooks.as_ref()),
+            ("limits.admin", self.admin.as_ref()),
+            ("limits.static_content", self.static_content.as_ref()),
+        ] {
+            if let Some(limits) = limits {
+                if limits.max_body_bytes == 0 || limits.timeout_seconds == 0 {
+                    problems.push(format!("{setting}: limits must be greater than zero"));
+                }
+            }
+        }
+    }
+
+    pub fn group(&self, group: RouteGroup) -> &GroupLimits {
+        let limits = match group {
+            RouteGroup::Auth => &self.auth,
+            RouteGroup::Books => &self.books,
+            RouteGroup::Orders => &self.orders,
+            RouteGroup::Webhooks => &self.webhooks,
+            RouteGroup::Admin => &self.admin,
+            RouteGroup::StaticContent => &self.static_content,
+        };
+        limits.as_ref().unwrap_or(&self.default)
+    }
+
+    /// Applies a group's body limit and timeout to its router. Routes can
+    /// still raise their own body limit with `DefaultBodyLimit`.
+    pub fn limit_group(&self, router: Router, group: RouteGroup) -> Router {
+        let limits = self.group(group);
+        router
+            .layer(TimeoutLayer::new(Duration::from_secs(limits.timeout_seconds)))
+            .layer(DefaultBodyLimit::max(limits.max_body_bytes))
+    }
+
+    /// Rejects requests with a 503 once too many are in flight, rather than
+    /// queueing them.
+    pub fn shed_load(&self, router: Router) -> Router {
+        router.layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(|_: BoxError| async {
+                    StatusCode::SERVICE_UNAVAILABLE
+                }))
+                .load_shed()
+                .concurrency_limit(self.max_concurrent_requests),
+        )
+    }
+}
@@ -1,41 +1,184 @@
 mod auth;
+mod backup;
+#[cfg(feature = "bookstore")]
 mod bookstore;
+mod cli;
+mod cors;
+mod event_bus;
+mod idempotency;
+mod limits;
+mod migrations;
+mod modules;
+#[cfg(feature = "orders")]
+mod orders;
+mod rate_limit;
+mod security_headers;
 mod service_config;
+mod storefront;
+mod tenancy;
+#[cfg(test)]
+mod test_harness;
+mod tls;
+#[cfg(feature = "webhooks")]
+mod webhooks;
 use anyhow::Result;
-use axum::Extension;
-use tower_http::{cors::CorsLayer, services::ServeDir};
-use tower::ServiceBuilder;
+use axum::{middleware, routing::get, Extension, Router};
+use backup::Backups;
+use cors::RouteGroup;
+use idempotency::{IdempotencyLayer, InMemoryIdempotency};
+use modules::{BackgroundTask, ModuleContext, Registry};
+use rate_limit::{InMemoryBuckets, RateLimitLayer};
+use tenancy::TenantResolver;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use tower_http::compression::CompressionLayer;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    tracing_subscriber::fmt::init();
+    let matches = cli::build().get_matches();
+
     let service_settings = service_config::ServiceConfig::load()?;
-    let auth_router = auth::setup_service().await?;
-    let books_router = bookstore::setup_service().await?;
+    if matches.get_flag("check-config") {
+        service_settings.print_redacted()?;
+        return service_settings.validate(true);
+    }
+    let serving = !matches!(matches.subcommand_name(), Some("migrate" | "user" | "backup" | "books"));
+    service_settings.validate(serving)?;
 
+    tracing_subscriber::fmt::init();
+    match matches.subcommand() {
+        Some(("migrate", matches)) => cli::migrate(&service_settings, matches).await,
+        Some(("user", matches)) => cli::user(&service_settings, matches).await,
+        Some(("backup", matches)) => cli::backup(&service_settings, matches).await,
+        #[cfg(feature = "bookstore")]
+        Some(("books", matches)) => cli::books(&service_settings, matches).await,
+        _ => serve(service_settings).await,
+    }
+}
+
+async fn serve(service_settings: service_config::ServiceConfig) -> Result<()> {
     // Listen address from configuration
     let listen_address = format!(
         "{}:{}",
         service_settings.listen_address, service_settings.listen_port
     );
     let listener = tokio::net::TcpListener::bind(&listen_address).await?;
+    let listen_address_only = service_settings.listen_address.clone();
+    let tls_settings = service_settings.tls.clone();
+
+    let (master_router, tasks) = build_app(service_settings).await?;
     tracing::info!("Listening on {}", listen_address);
+    for task in tasks {
+        tokio::spawn(task);
+    }
+
+    // Launch Axum, over HTTPS if it's configured
+    if tls_settings.enabled {
+        // Validation makes sure a redirect has a public host to go to
+        let redirect = tls_settings.redirect_port.zip(tls_settings.public_host.clone());
+        if let Some((redirect_port, public_host)) = redirect {
+            let redirect_address = format!("{}:{}", listen_address_only, redirect_port);
+            let redirect_listener = tokio::net::TcpListener::bind(&redirect_address).await?;
+            tracing::info!("Redirecting {} to HTTPS", redirect_address);
+            tokio::spawn(tls::serve_redirect(redirect_listener, public_host));
+        }
+        tls::serve_tls(listener, master_router, tls_settings).await?;
+    } else {
+        let service = master_router.into_make_service_with_connect_info::<SocketAddr>();
+        axum::serve(listener, service).await?;
+    }
 
-    // The default web server
-    let static_content = ServiceBuilder::new()
-        .layer(CorsLayer::very_permissive())
-        .service(ServeDir::new(&service_settings.static_content));
+    Ok(())
+}
 
-    // Build the master router
-    let master_router = axum::Router::new()
-        .layer(CorsLayer::very_permissive())
-        .nest("/api/v1/auth", auth_router)
-        .nest("/api/v1/books", books_router)
-        .layer(Extension(service_settings))
-        .nest_service("/", static_content);
+/// Opens and migrates every module, or just checks their schemas when
+/// `migrate_on_startup` is off, and builds the master router from them.
+/// The background tasks are returned rather than spawned, so they
+/// start only once every module has subscribed to the event bus, and none
+/// misses what another publishes as it starts.
+async fn build_app(service_settings: service_config::ServiceConfig) -> Result<(Router, Vec<BackgroundTask>)> {
+    let buckets = Arc::new(InMemoryBuckets::default());
+    let rate_limit = RateLimitLayer::new(service_settings.rate_limit.clone(), buckets.clone());
+    let idempotency = IdempotencyLayer::new(
+        service_settings.idempotency.clone(),
+        Arc::new(InMemoryIdempotency::default()),
+    );
+    let mut registry = Registry::open(&service_settings).await?;
+    registry.lock()?;
+    if service_settings.migrate_on_startup {
+        registry.migrate().await?;
+    } else {
+        registry.check_schema(true).await?;
+    }
+    let context = ModuleContext {
+        authenticator: registry.authenticator(),
+        rate_limit,
+        idempotency,
+    };
+    // Every module's routes are for a tenant, found before the request
+    // is authenticated
+    let tenants = TenantResolver::new(service_settings.tenancy.clone(), registry.authenticator());
+    let resolve_tenant = middleware::from_fn_with_state(tenants, tenancy::resolve_tenant);
 
-    // Launch Axum
-    axum::serve(listener, master_router).await?;
+    // The default web server, with security headers
+    let cors = &service_settings.cors;
+    let limits = &service_settings.limits;
+    let security_headers = service_settings.security_headers.headers(service_settings.tls.enabled)?;
+    let static_content = storefront::router(&service_settings.static_content, &service_settings.storefront)
+        .layer(middleware::map_response_with_state(
+            security_headers,
+            security_headers::set_security_headers,
+        ));
+    let static_content = limits
+        .limit_group(static_content, RouteGroup::StaticContent)
+        .layer(cors.layer(RouteGroup::StaticContent)?);
 
-    Ok(())
+    // Build the master router from every module, each with its group's
+    // limits and CORS policy
+    let mut master_router = Router::new();
+    let mut tasks: Vec<BackgroundTask> = Vec::new();
+    if service_settings.rate_limit.enabled {
+        tasks.push(Box::pin(buckets.prune_every(Duration::from_secs(60))));
+    }
+    for module in registry.modules() {
+        let router = module.router(&context).await?.layer(resolve_tenant.clone());
+        let router = limits
+            .limit_group(router, module.route_group())
+            .layer(cors.layer(module.route_group())?);
+        master_router = master_router.nest(module.mount_path(), router);
+        tasks.extend(module.background_tasks().await?);
+        tracing::info!("Mounted {} at {}", module.name(), module.mount_path());
+    }
+
+    let backups = Backups::new(service_settings.backup.clone(), &registry);
+    if service_settings.backup.interval_hours > 0 {
+        tasks.push(Box::pin(backups.clone().run()));
+    }
+    let admin_router = backup::router(backups.clone(), &context).layer(resolve_tenant);
+    let admin_router = limits
+        .limit_group(admin_router, RouteGroup::Admin)
+        .layer(cors.layer(RouteGroup::Admin)?);
+
+    let master_router = master_router
+        .nest("/api/v1/admin", admin_router)
+        .route("/healthz", get(modules::health))
+        .route("/readyz", get(modules::ready))
+        .layer(Extension(backups))
+        .layer(Extension(context.authenticator))
+        .layer(Extension(Arc::new(registry)));
+    // The storefront is compressed ahead of time
+    let master_router = if service_settings.compress_responses {
+        master_router.layer(CompressionLayer::new())
+    } else {
+        master_router
+    };
+    let master_router = master_router.nest_service("/", static_content);
+    let master_router = match &service_settings.tenancy.path_prefix {
+        Some(prefix) if service_settings.tenancy.enabled => tenancy::with_path_prefix(prefix.clone(), master_router),
+        _ => master_router,
+    };
+    let master_router = limits
+        .shed_load(master_router)
+        .layer(Extension(service_settings));
+
+    Ok((master_router, tasks))
 }
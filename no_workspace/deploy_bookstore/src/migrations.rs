@@ -0,0 +1,350 @@
+use std::collections::{BTreeMap, HashMap};
+use anyhow::{bail, Context, Result};
+use std::path::Path;
+use sqlx::{
+    migrate::{AppliedMigration, Migrate, Migrator},
+    sqlite::{SqliteConnectOptions, SqlitePoolOptions},
+    SqlitePool,
+};
+
+/// Where a module's migration is up to in its database.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MigrationState {
+    Applied,
+    Pending,
+    /// Applied, but the migration has been edited since.
+    Modified,
+    /// Applied by a newer build: this one doesn't have it.
+    Unknown,
+    /// Failed part way through.
+    Dirty,
+}
+
+#[derive(Clone, Debug)]
+pub struct MigrationStatus {
+    pub version: i64,
+    /// Empty for migrations this build doesn't have.
+    pub description: String,
+    pub state: MigrationState,
+    /// Whether this build can roll the migration back.
+    pub reversible: bool,
+}
+
+/// Opens a module's database to report on it, changing nothing. One that
+/// doesn't exist yet is reported as an empty database.
+pub async fn open_read_only(filename: &str) -> Result<SqlitePool> {
+    if !Path::new(filename).exists() {
+        return Ok(SqlitePoolOptions::new().max_connections(1).connect("sqlite::memory:").await?);
+    }
+    let options = SqliteConnectOptions::new().filename(filename).read_only(true);
+    Ok(SqlitePool::connect_with(options).await?)
+}
+
+/// The migrations applied to a database, and the one that failed part way
+/// if any. A database without the migrations table has none, and isn't
+/// given the table.
+async fn applied_migrations(pool: &SqlitePool) -> Result<(Vec<AppliedMigration>, Option<i64>)> {
+    let has_table: bool = sqlx::query_scalar(
+        "SELECT EXISTS (SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = '_sqlx_migrations')",
+    )
+    .fetch_one(pool)
+    .await?;
+    if !has_table {
+        return Ok((Vec::new(), None));
+    }
+    let mut connection = pool.acquire().await?;
+    let applied = connection.list_applied_migrations().await?;
+    let dirty = connection.dirty_version().await?;
+    Ok((applied, dirty))
+}
+
+/// Every migration this build has, and any the database has that it
+/// doesn't, in version order.
+pub async fn status(migrator: &Migrator, pool: &SqlitePool) -> Result<Vec<MigrationStatus>> {
+    let (applied, dirty) = applied_migrations(pool).await?;
+    let applied: HashMap<i64, _> = applied
+        .into_iter()
+        .map(|migration| (migration.version, migration.checksum))
+        .collect();
+
+    let mut report: Vec<MigrationStatus> = migrator
+        .iter()
+        .filter(|migration| migration.migration_type.is_up_migration())
+        .map(|migration| {
+            let state = match applied.get(&migration.version) {
+                _ if dirty == Some(migration.version) => MigrationState::Dirty,
+                Some(checksum) if *checksum != migration.checksum => MigrationState::Modified,
+                Some(_) => MigrationState::Applied,
+                None => MigrationState::Pending,
+            };
+            MigrationStatus {
+                version: migration.version,
+                description: migration.description.to_string(),
+                state,
+                reversible: is_reversible(migrator, migration.version),
+            }
+        })
+        .collect();
+    let known = |version: i64| migrator.iter().any(|migration| migration.version == version);
+    for version in applied.keys().filter(|version| !known(**version)) {
+        report.push(MigrationStatus {
+            version: *version,
+            description: String::new(),
+            state: if dirty == Some(*version) { MigrationState::Dirty } else { MigrationState::Unknown },
+            reversible: false,
+        });
+    }
+    report.sort_by_key(|migration| migration.version);
+    Ok(report)
+}
+
+fn is_reversible(migrator: &Migrator, version: i64) -> bool {
+    migrator
+        .iter()
+        .any(|migration| migration.version == version && migration.migration_type.is_down_migration())
+}
+
+/// Refuses a database this build can't safely use: one that a newer build
+/// has migrated, or whose applied migrations have since been edited or
+/// failed. With `require_current`, pending migrations are refused too.
+pub async fn check(module: &str, migrator: &Migrator, pool: &SqlitePool, require_current: bool) -> Result<()> {
+    let report = status(migrator, pool).await?;
+    for migration in &report {
+        let version = migration.version;
+        match migration.state {
+            MigrationState::Unknown => bail!(
+                "the {module} database has migration {version}, which this build doesn't: \
+                 its schema is newer. Run a newer build, or roll the database back with that \
+                 build's `migrate down`"
+            ),
+            MigrationState::Modified => bail!(
+                "migration {version} of the {module} database has been edited since it was applied"
+            ),
+            MigrationState::Dirty => bail!(
+                "migration {version} of the {module} database failed part way; restore a backup"
+            ),
+            MigrationState::Applied | MigrationState::Pending => {}
+        }
+    }
+
+    let pending = report.iter().filter(|migration| migration.state == MigrationState::Pending).count();
+    if require_current && pending > 0 {
+        bail!(
+            "the {module} database has {pending} pending migration(s); run `migrate` first, \
+             or set migrate_on_startup"
+        );
+    }
+    Ok(())
+}
+
+/// Applies pending migrations, once `check` has found nothing in the way.
+pub async fn migrate(module: &str, migrator: &Migrator, pool: &SqlitePool) -> Result<()> {
+    check(module, migrator, pool, false).await?;
+    migrator
+        .run(pool)
+        .await
+        .with_context(|| format!("unable to migrate the {module} database"))
+}
+
+/// Rolls back every applied migration after `target`, newest first,
+/// returning what was rolled back. Refuses if any of them can't be.
+pub async fn undo(module: &str, migrator: &Migrator, pool: &SqlitePool, target: i64) -> Result<Vec<MigrationStatus>> {
+    check(module, migrator, pool, false).await?;
+    let mut reverted: Vec<MigrationStatus> = status(migrator, pool)
+        .await?
+        .into_iter()
+        .filter(|migration| migration.state == MigrationState::Applied && migration.version > target)
+        .collect();
+    if let Some(migration) = reverted.iter().find(|migration| !migration.reversible) {
+        bail!("migration {} of the {module} database can't be rolled back", migration.version);
+    }
+
+    migrator
+        .undo(pool, target)
+        .await
+        .with_context(|| format!("unable to roll back the {module} database"))?;
+    reverted.reverse();
+    Ok(reverted)
+}
+
+/// Compares the database's schema with the one this build's migrations
+/// produce, up to the latest applied, returning a line per difference.
+/// Anything changed by hand shows up here.
+pub async fn drift(migrator: &Migrator, pool: &SqlitePool) -> Result<Vec<String>> {
+    let applied: Vec<i64> = applied_migrations(pool)
+        .await?
+        .0
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+
+    // Each connection to an in-memory database gets its own, so keep to one
+    let expected_pool = SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await?;
+    {
+        let mut connection = expected_pool.acquire().await?;
+        connection.ensure_migrations_table().await?;
+        for migration in migrator
+            .iter()
+            .filter(|migration| migration.migration_type.is_up_migration() && applied.contains(&migration.version))
+        {
+            connection.apply(migration).await?;
+        }
+    }
+    let expected = schema(&expected_pool).await?;
+    expected_pool.close().await;
+    let actual = schema(pool).await?;
+
+    let mut differences = Vec::new();
+    for ((kind, name), sql) in &expected {
+        match actual.get(&(kind.clone(), name.clone())) {
+            None => differences.push(format!("{kind} {name} is missing")),
+            Some(actual_sql) if actual_sql != sql => {
+                differences.push(format!("{kind} {name} differs: expected `{sql}`, found `{actual_sql}`"));
+            }
+            Some(_) => {}
+        }
+    }
+    for (kind, name) in actual.keys().filter(|key| !expected.contains_key(*key)) {
+        differences.push(format!("{kind} {name} is unexpected"));
+    }
+    Ok(differences)
+}
+
+/// The tables, indexes, triggers and views in a database, with the SQL that
+/// creates each, whitespace normalised. SQLite's own objects and the
+/// migrations table are left out.
+async fn schema(pool: &SqlitePool) -> Result<BTreeMap<(String, String), String>> {
+    let objects: Vec<(String, String, Option<String>)> = sqlx::query_as(
+        "SELECT type, name, sql FROM sqlite_master
+         WHERE name NOT LIKE 'sqlite_%' AND name != '_sqlx_migrations'",
+    )
+    .fetch_all(pool)
+    .await?;
+    Ok(objects
+        .into_iter()
+        .map(|(kind, name, sql)| {
+            let sql = sql.unwrap_or_default().split_whitespace().collect::<Vec<_>>().join(" ");
+            ((kind, name), sql)
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn empty_database() -> SqlitePool {
+        SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .unwrap()
+    }
+
+    static AUTH: Migrator = sqlx::migrate!("src/auth/migrations");
+
+    /// Every module's migrations, whether or not its feature is on.
+    fn migrators() -> Vec<(&'static str, Migrator)> {
+        vec![
+            ("auth", sqlx::migrate!("src/auth/migrations")),
+            ("bookstore", sqlx::migrate!("src/bookstore/migrations")),
+            ("orders", sqlx::migrate!("src/orders/migrations")),
+            ("webhooks", sqlx::migrate!("src/webhooks/migrations")),
+        ]
+    }
+
+    #[tokio::test]
+    async fn every_migration_rolls_back_cleanly() {
+        for (module, migrator) in &migrators() {
+            let pool = empty_database().await;
+            migrate(module, migrator, &pool).await.unwrap();
+            assert_eq!(drift(migrator, &pool).await.unwrap(), Vec::<String>::new(), "{module}");
+
+            let reverted = undo(module, migrator, &pool, 0).await.unwrap();
+            assert!(!reverted.is_empty(), "{module}");
+            assert!(schema(&pool).await.unwrap().is_empty(), "{module} left objects behind");
+
+            migrate(module, migrator, &pool).await.unwrap();
+            assert_eq!(drift(migrator, &pool).await.unwrap(), Vec::<String>::new(), "{module}");
+            check(module, migrator, &pool, true).await.unwrap();
+
+            // Partway back and forward again, to each version in turn
+            let versions: Vec<i64> = migrator.iter().map(|migration| migration.version).collect();
+            for version in versions {
+                undo(module, migrator, &pool, version).await.unwrap();
+                assert_eq!(drift(migrator, &pool).await.unwrap(), Vec::<String>::new(), "{module} at {version}");
+                migrate(module, migrator, &pool).await.unwrap();
+                assert_eq!(drift(migrator, &pool).await.unwrap(), Vec::<String>::new(), "{module} from {version}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn refuses_a_database_from_a_newer_build() {
+        let migrator = &AUTH;
+        let pool = empty_database().await;
+        migrate("auth", migrator, &pool).await.unwrap();
+        sqlx::query(
+            "INSERT INTO _sqlx_migrations (version, description, success, checksum, execution_time)
+             VALUES (99990101000000, 'from the future', 1, x'00', 0)",
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let error = migrate("auth", migrator, &pool).await.unwrap_err();
+        assert!(error.to_string().contains("newer"), "{error}");
+        let future = status(migrator, &pool).await.unwrap().pop().unwrap();
+        assert_eq!(future.state, MigrationState::Unknown);
+    }
+
+    #[tokio::test]
+    async fn pending_migrations_are_refused_only_when_required() {
+        let migrator = &AUTH;
+        let pool = empty_database().await;
+        check("auth", migrator, &pool, false).await.unwrap();
+        assert!(check("auth", migrator, &pool, true).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn reporting_changes_nothing() {
+        let migrator = &AUTH;
+        let directory = std::env::temp_dir().join(format!("deploy_bookstore-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let filename = directory.join("auth.db").to_string_lossy().into_owned();
+
+        // A database that doesn't exist yet has everything pending
+        let pool = open_read_only(&filename).await.unwrap();
+        let report = status(migrator, &pool).await.unwrap();
+        assert!(report.iter().all(|migration| migration.state == MigrationState::Pending));
+        assert!(drift(migrator, &pool).await.unwrap().is_empty());
+        assert!(!Path::new(&filename).exists());
+
+        // Nor is the migrations table added to one that exists
+        let options = SqliteConnectOptions::new().filename(&filename).create_if_missing(true);
+        SqlitePool::connect_with(options).await.unwrap().close().await;
+        let pool = open_read_only(&filename).await.unwrap();
+        check("auth", migrator, &pool, false).await.unwrap();
+        assert!(schema(&pool).await.unwrap().is_empty());
+        let tables: i64 = sqlx::query_scalar("SELECT count(*) FROM sqlite_master").fetch_one(&pool).await.unwrap();
+        assert_eq!(tables, 0);
+        pool.close().await;
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_changes_made_by_hand() {
+        let migrator = &AUTH;
+        let pool = empty_database().await;
+        migrate("auth", migrator, &pool).await.unwrap();
+        sqlx::query("CREATE INDEX users_by_hand ON users (username)").execute(&pool).await.unwrap();
+        sqlx::query("DROP TABLE api_keys").execute(&pool).await.unwrap();
+
+        let drift = drift(migrator, &pool).await.unwrap();
+        assert!(drift.iter().any(|line| line.contains("users_by_hand is unexpected")), "{drift:?}");
+        assert!(drift.iter().any(|line| line.contains("api_keys is missing")), "{drift:?}");
+    }
+}
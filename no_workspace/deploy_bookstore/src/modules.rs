@@ -0,0 +1,243 @@
+use std::{collections::BTreeMap, future::Future, pin::Pin, sync::Arc};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{http::StatusCode, Extension, Json};
+use serde::Serialize;
+use sqlx::migrate::Migrator;
+use crate::{
+    auth::{auth_layers::Authenticator, AuthMode, AuthModule, RemoteAuth},
+    backup::{self, Backup, Backups},
+    cors::RouteGroup,
+    event_bus::OutboxSink,
+    idempotency::IdempotencyLayer,
+    migrations,
+    rate_limit::RateLimitLayer,
+    service_config::ServiceConfig,
+};
+
+/// A task a module runs for as long as the service is up.
+pub type BackgroundTask = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+/// A module's SQLite database, for backing up and restoring.
+#[derive(Clone)]
+pub struct Database {
+    pub filename: String,
+    pub pool: sqlx::SqlitePool,
+}
+
+/// What the service gives every module when building its router.
+#[derive(Clone)]
+pub struct ModuleContext {
+    pub authenticator: Authenticator,
+    pub rate_limit: RateLimitLayer,
+    pub idempotency: IdempotencyLayer,
+}
+
+/// A self-contained part of the service, hosted in the modular monolith.
+/// Each module owns a configuration section, a database with its own
+/// migrations, and the routes mounted at `mount_path`.
+#[async_trait]
+pub trait ServiceModule: Send + Sync {
+    /// A short name for logs, health reports and `migrate` output.
+    fn name(&self) -> &'static str;
+
+    /// Where the module's router is nested, such as `/api/v1/books`.
+    fn mount_path(&self) -> &'static str;
+
+    /// Which CORS and limits policies apply to the module's routes.
+    fn route_group(&self) -> RouteGroup;
+
+    /// The module's migrations, for `migrate status` and `migrate down`.
+    fn migrator(&self) -> &'static Migrator;
+
+    async fn migrate(&self) -> Result<()>;
+
+    async fn router(&self, context: &ModuleContext) -> Result<axum::Router>;
+
+    /// Fails if the module can't serve requests, such as when its database
+    /// is unreachable.
+    async fn health_check(&self) -> Result<()>;
+
+    /// The module's database, which backups snapshot and a restore
+    /// replaces.
+    fn database(&self) -> Database;
+
+    /// Tasks to spawn once the module is serving. Anything they need, such
+    /// as a listening socket, is set up here, so that failing to stops the
+    /// service from starting.
+    async fn background_tasks(&self) -> Result<Vec<BackgroundTask>> {
+        Ok(Vec::new())
+    }
+}
+
+/// Environment variable prefixes for each module's configuration section,
+/// so that `BOOKSTORE_DB_FILENAME` sets `bookstore.db_filename`.
+pub const CONFIG_SECTIONS: &[(&str, &str)] = &[
+    ("AUTH", "auth"),
+    #[cfg(feature = "bookstore")]
+    ("BOOKSTORE", "bookstore"),
+    #[cfg(feature = "orders")]
+    ("ORDERS", "orders"),
+    #[cfg(feature = "webhooks")]
+    ("WEBHOOKS", "webhooks"),
+];
+
+/// The modules compiled into this build, selected with cargo features.
+pub struct Registry {
+    authenticator: Authenticator,
+    modules: Vec<Box<dyn ServiceModule>>,
+    /// Held until the registry is dropped, see `lock`.
+    locks: Vec<std::fs::File>,
+}
+
+impl Registry {
+    /// Opens every module's database, without migrating. The databases are
+    /// connected to on first use, so nothing is created by opening them to
+    /// report on with `migrations::open_read_only`. Auth comes first: the
+    /// other modules authenticate through it. In remote mode it isn't
+    /// hosted here, and credentials are checked by the auth service.
+    pub async fn open(config: &ServiceConfig) -> Result<Self> {
+        let mut modules: Vec<Box<dyn ServiceModule>> = Vec::new();
+        #[cfg(feature = "webhooks")]
+        let webhooks = crate::webhooks::WebhooksModule::open(config.webhooks.clone()).await?;
+        // Changes to users and the catalogue reach other modules through
+        // their outboxes
+        let sinks = || -> Vec<Arc<dyn OutboxSink>> {
+            Vec::from([
+                #[cfg(feature = "webhooks")]
+                webhooks.sink(),
+            ])
+        };
+
+        let authenticator = match (config.auth.mode, &config.auth.remote) {
+            (AuthMode::Remote, Some(remote)) => Authenticator::Remote(Arc::new(RemoteAuth::new(remote)?)),
+            _ => {
+                let auth = AuthModule::open(config.auth.clone(), config.tls.clone(), sinks()).await?;
+                let authenticator = Authenticator::Local(auth.db());
+                modules.push(Box::new(auth));
+                authenticator
+            }
+        };
+
+        #[cfg(feature = "bookstore")]
+        {
+            let bookstore = crate::bookstore::BookstoreModule::open(config.bookstore.clone(), sinks()).await?;
+            #[cfg(feature = "orders")]
+            let store_db = bookstore.db();
+            modules.push(Box::new(bookstore));
+            #[cfg(feature = "orders")]
+            modules.push(Box::new(
+                crate::orders::OrdersModule::open(config.orders.clone(), store_db).await?,
+            ));
+        }
+
+        #[cfg(feature = "webhooks")]
+        modules.push(Box::new(webhooks));
+
+        Ok(Self {
+            authenticator,
+            modules,
+            locks: Vec::new(),
+        })
+    }
+
+    /// Locks every module's database until the registry is dropped. The
+    /// service holds the locks while it runs, and a restore takes them
+    /// before replacing anything, so neither starts while the other is
+    /// using the databases.
+    pub fn lock(&mut self) -> Result<()> {
+        for module in &self.modules {
+            self.locks.push(backup::lock_database(&module.database().filename)?);
+        }
+        Ok(())
+    }
+
+    pub fn authenticator(&self) -> Authenticator {
+        self.authenticator.clone()
+    }
+
+    pub fn modules(&self) -> &[Box<dyn ServiceModule>] {
+        &self.modules
+    }
+
+    pub async fn migrate(&self) -> Result<()> {
+        for module in &self.modules {
+            module.migrate().await?;
+        }
+        Ok(())
+    }
+
+    /// Refuses to go on if any module's database was migrated by a newer
+    /// build or, with `require_current`, still has migrations to apply.
+    pub async fn check_schema(&self, require_current: bool) -> Result<()> {
+        for module in &self.modules {
+            let migrator = module.migrator();
+            migrations::check(module.name(), migrator, &module.database().pool, require_current).await?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct HealthReport {
+    healthy: bool,
+    /// Each module's status. Anyone can ask, so why a module is unhealthy
+    /// is only logged.
+    modules: BTreeMap<&'static str, &'static str>,
+}
+
+/// Reports on every module. Answers 503 if any of them is unhealthy.
+pub async fn health(Extension(registry): Extension<Arc<Registry>>) -> (StatusCode, Json<HealthReport>) {
+    let report = check_modules(&registry).await;
+    let status = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(report))
+}
+
+#[derive(Serialize, Debug)]
+pub struct ReadinessReport {
+    #[serde(flatten)]
+    health: HealthReport,
+    /// The latest backup, or `None` if none has been taken.
+    last_backup: Option<Backup>,
+}
+
+/// Like `/healthz`, and also reports when the last backup was taken.
+pub async fn ready(
+    Extension(registry): Extension<Arc<Registry>>,
+    Extension(backups): Extension<Backups>,
+) -> (StatusCode, Json<ReadinessReport>) {
+    let health = check_modules(&registry).await;
+    let last_backup = backups.latest().await.unwrap_or_else(|e| {
+        tracing::warn!("Unable to list backups: {e:#}");
+        None
+    });
+    let status = if health.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (status, Json(ReadinessReport { health, last_backup }))
+}
+
+async fn check_modules(registry: &Registry) -> HealthReport {
+    let mut report = HealthReport {
+        healthy: true,
+        modules: BTreeMap::new(),
+    };
+    for module in registry.modules() {
+        let status = match module.health_check().await {
+            Ok(()) => "healthy",
+            Err(e) => {
+                tracing::warn!("The {} module is unhealthy: {e:#}", module.name());
+                report.healthy = false;
+                "unhealthy"
+            }
+        };
+        report.modules.insert(module.name(), status);
+    }
+    report
+}
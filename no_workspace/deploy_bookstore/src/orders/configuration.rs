@@ -0,0 +1,35 @@
+use serde::{Deserialize, Serialize};
+use crate::service_config::check_writable_file;
+
+/// The `orders` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct OrdersConfiguration {
+    pub db_filename: String,
+    /// The most different books a cart can hold.
+    pub max_cart_items: usize,
+    /// The most copies of one book a cart can hold.
+    pub max_quantity: i64,
+}
+
+impl Default for OrdersConfiguration {
+    fn default() -> Self {
+        Self {
+            db_filename: "orders.db".to_string(),
+            max_cart_items: 50,
+            max_quantity: 100,
+        }
+    }
+}
+
+impl OrdersConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        check_writable_file("orders.db_filename", &self.db_filename, problems);
+        if self.max_cart_items == 0 {
+            problems.push("orders.max_cart_items: must be at least 1".to_string());
+        }
+        if self.max_quantity < 1 {
+            problems.push("orders.max_quantity: must be at least 1".to_string());
+        }
+    }
+}
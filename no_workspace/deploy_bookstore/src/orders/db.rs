@@ -0,0 +1,361 @@
+use std::collections::HashMap;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::Migrator, prelude::FromRow, sqlite::SqliteConnectOptions};
+
+#[derive(Clone)]
+pub struct OrdersDb(pub sqlx::SqlitePool);
+
+pub async fn get_connection_pool(filename: &str) -> Result<OrdersDb> {
+    let options = SqliteConnectOptions::new()
+        .filename(filename)
+        .create_if_missing(true);
+
+    let connection_pool = sqlx::SqlitePool::connect_lazy_with(options);
+    Ok(OrdersDb(connection_pool))
+}
+
+/// This build's migrations, up and down.
+pub static MIGRATOR: Migrator = sqlx::migrate!("src/orders/migrations");
+
+pub async fn perform_migrations(db_pool: OrdersDb) -> Result<()> {
+    crate::migrations::migrate("orders", &MIGRATOR, &db_pool.0).await
+}
+
+pub async fn ping(db_pool: OrdersDb) -> Result<()> {
+    sqlx::query("SELECT 1").execute(&db_pool.0).await?;
+    Ok(())
+}
+
+/// Where an order is up to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum OrderStatus {
+    Placed,
+    Paid,
+    Shipped,
+    Delivered,
+    Cancelled,
+}
+
+impl OrderStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Placed => "placed",
+            Self::Paid => "paid",
+            Self::Shipped => "shipped",
+            Self::Delivered => "delivered",
+            Self::Cancelled => "cancelled",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "paid" => Self::Paid,
+            "shipped" => Self::Shipped,
+            "delivered" => Self::Delivered,
+            "cancelled" => Self::Cancelled,
+            _ => Self::Placed,
+        }
+    }
+
+    /// Orders move forward one step at a time, and can be cancelled until
+    /// they're shipped.
+    pub fn can_become(self, next: Self) -> bool {
+        matches!(
+            (self, next),
+            (Self::Placed, Self::Paid)
+                | (Self::Paid, Self::Shipped)
+                | (Self::Shipped, Self::Delivered)
+                | (Self::Placed | Self::Paid, Self::Cancelled)
+        )
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, FromRow)]
+pub struct InventoryItem {
+    pub book_id: i32,
+    pub price_cents: i64,
+    pub stock: i64,
+}
+
+pub async fn get_inventory(db_pool: OrdersDb, tenant_id: i32, book_id: i32) -> Result<Option<InventoryItem>> {
+    let item = sqlx::query_as::<_, InventoryItem>(
+        "SELECT book_id, price_cents, stock FROM inventory WHERE book_id = ? AND tenant_id = ?",
+    )
+        .bind(book_id)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
+        .await?;
+    Ok(item)
+}
+
+/// Sets a book's price and stock. Book ids are unique across tenants, so
+/// the book is only ever stocked by the tenant whose catalogue it's in.
+pub async fn set_inventory(db_pool: OrdersDb, tenant_id: i32, item: &InventoryItem) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO inventory (tenant_id, book_id, price_cents, stock) VALUES (?, ?, ?, ?)
+         ON CONFLICT (book_id) DO UPDATE
+         SET price_cents = excluded.price_cents, stock = excluded.stock, updated_at = unixepoch()",
+    )
+        .bind(tenant_id)
+        .bind(item.book_id)
+        .bind(item.price_cents)
+        .bind(item.stock)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+/// A line in a cart, with the book's current price and stock. They're
+/// `None` if the book isn't for sale.
+#[derive(Serialize, Debug, FromRow)]
+pub struct CartItem {
+    pub book_id: i32,
+    pub quantity: i64,
+    pub price_cents: Option<i64>,
+    pub stock: Option<i64>,
+}
+
+pub async fn list_cart(db_pool: OrdersDb, user_id: i32) -> Result<Vec<CartItem>> {
+    let items = sqlx::query_as::<_, CartItem>(
+        "SELECT cart_items.book_id, quantity, price_cents, stock
+         FROM cart_items LEFT JOIN inventory ON inventory.book_id = cart_items.book_id
+         WHERE user_id = ? ORDER BY cart_items.book_id",
+    )
+        .bind(user_id)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(items)
+}
+
+/// Sets how many copies of a book are in the cart, adding it if needed.
+pub async fn set_cart_item(db_pool: OrdersDb, user_id: i32, book_id: i32, quantity: i64) -> Result<()> {
+    sqlx::query(
+        "INSERT INTO cart_items (user_id, book_id, quantity) VALUES (?, ?, ?)
+         ON CONFLICT (user_id, book_id) DO UPDATE SET quantity = excluded.quantity",
+    )
+        .bind(user_id)
+        .bind(book_id)
+        .bind(quantity)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+pub async fn remove_cart_item(db_pool: OrdersDb, user_id: i32, book_id: i32) -> Result<()> {
+    sqlx::query("DELETE FROM cart_items WHERE user_id = ? AND book_id = ?")
+        .bind(user_id)
+        .bind(book_id)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+#[derive(Serialize, Debug, FromRow)]
+pub struct OrderSummary {
+    pub id: i32,
+    pub user_id: i32,
+    #[sqlx(try_from = "String")]
+    pub status: OrderStatus,
+    pub total_cents: i64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl From<String> for OrderStatus {
+    fn from(status: String) -> Self {
+        Self::parse(&status)
+    }
+}
+
+/// A line of an order, priced as it was when the order was placed.
+#[derive(Serialize, Debug, FromRow)]
+pub struct OrderItem {
+    pub book_id: i32,
+    pub title: String,
+    pub quantity: i64,
+    pub unit_price_cents: i64,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Order {
+    #[serde(flatten)]
+    pub summary: OrderSummary,
+    pub items: Vec<OrderItem>,
+}
+
+/// An order with its line items, or `None` if the tenant has no such
+/// order.
+pub async fn get_order(db_pool: OrdersDb, tenant_id: i32, order_id: i32) -> Result<Option<Order>> {
+    let Some(summary) = sqlx::query_as::<_, OrderSummary>("SELECT * FROM orders WHERE id = ? AND tenant_id = ?")
+        .bind(order_id)
+        .bind(tenant_id)
+        .fetch_optional(&db_pool.0)
+        .await?
+    else {
+        return Ok(None);
+    };
+    let items = sqlx::query_as::<_, OrderItem>(
+        "SELECT book_id, title, quantity, unit_price_cents FROM order_items WHERE order_id = ? ORDER BY book_id",
+    )
+        .bind(order_id)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(Some(Order { summary, items }))
+}
+
+/// Lists a tenant's orders, newest first, optionally only one user's or
+/// only those with a status.
+pub async fn list_orders(
+    db_pool: OrdersDb,
+    tenant_id: i32,
+    user_id: Option<i32>,
+    status: Option<OrderStatus>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<OrderSummary>> {
+    let orders = sqlx::query_as::<_, OrderSummary>(
+        "SELECT * FROM orders
+         WHERE tenant_id = ?5 AND (?1 IS NULL OR user_id = ?1) AND (?2 IS NULL OR status = ?2)
+         ORDER BY id DESC LIMIT ?3 OFFSET ?4",
+    )
+        .bind(user_id)
+        .bind(status.map(OrderStatus::as_str))
+        .bind(limit)
+        .bind(offset)
+        .bind(tenant_id)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(orders)
+}
+
+/// What came of a checkout.
+#[derive(Debug)]
+pub enum Checkout {
+    Placed(i32),
+    EmptyCart,
+    /// The book isn't in the catalogue or isn't for sale.
+    Unavailable { book_id: i32 },
+    OutOfStock { book_id: i32, available: i64 },
+    /// The total is more cents than can be counted.
+    TotalTooLarge,
+}
+
+/// Turns a user's cart into an order in one transaction: the tenant's
+/// stock is taken, prices and `titles` are copied onto the line items and
+/// the cart is emptied. If any book can't be supplied, nothing changes.
+pub async fn checkout(
+    db_pool: OrdersDb,
+    tenant_id: i32,
+    user_id: i32,
+    titles: &HashMap<i32, String>,
+) -> Result<Checkout> {
+    let mut tx = db_pool.0.begin().await?;
+
+    // Writing first takes SQLite's write lock at the start, so concurrent
+    // checkouts queue up instead of failing to upgrade a read lock
+    let order_id: i32 = sqlx::query_scalar("INSERT INTO orders (tenant_id, user_id) VALUES (?, ?) RETURNING id")
+        .bind(tenant_id)
+        .bind(user_id)
+        .fetch_one(&mut *tx)
+        .await?;
+
+    let items: Vec<(i32, i64)> = sqlx::query_as(
+        "SELECT book_id, quantity FROM cart_items WHERE user_id = ? ORDER BY book_id",
+    )
+        .bind(user_id)
+        .fetch_all(&mut *tx)
+        .await?;
+    if items.is_empty() {
+        return Ok(Checkout::EmptyCart);
+    }
+
+    let mut total_cents: i64 = 0;
+    for (book_id, quantity) in items {
+        let Some(title) = titles.get(&book_id) else {
+            return Ok(Checkout::Unavailable { book_id });
+        };
+
+        // Only takes the stock if there's enough of it
+        let price_cents: Option<i64> = sqlx::query_scalar(
+            "UPDATE inventory SET stock = stock - ?1, updated_at = unixepoch()
+             WHERE book_id = ?2 AND tenant_id = ?3 AND stock >= ?1 RETURNING price_cents",
+        )
+            .bind(quantity)
+            .bind(book_id)
+            .bind(tenant_id)
+            .fetch_optional(&mut *tx)
+            .await?;
+        let Some(price_cents) = price_cents else {
+            let available: Option<i64> =
+                sqlx::query_scalar("SELECT stock FROM inventory WHERE book_id = ? AND tenant_id = ?")
+                    .bind(book_id)
+                    .bind(tenant_id)
+                    .fetch_optional(&mut *tx)
+                    .await?;
+            return Ok(match available {
+                Some(available) => Checkout::OutOfStock { book_id, available },
+                None => Checkout::Unavailable { book_id },
+            });
+        };
+
+        sqlx::query(
+            "INSERT INTO order_items (order_id, book_id, title, quantity, unit_price_cents) VALUES (?, ?, ?, ?, ?)",
+        )
+            .bind(order_id)
+            .bind(book_id)
+            .bind(title)
+            .bind(quantity)
+            .bind(price_cents)
+            .execute(&mut *tx)
+            .await?;
+        let line_cents = price_cents.checked_mul(quantity);
+        let Some(total) = line_cents.and_then(|line_cents| total_cents.checked_add(line_cents)) else {
+            return Ok(Checkout::TotalTooLarge);
+        };
+        total_cents = total;
+    }
+
+    sqlx::query("UPDATE orders SET total_cents = ? WHERE id = ?")
+        .bind(total_cents)
+        .bind(order_id)
+        .execute(&mut *tx)
+        .await?;
+    sqlx::query("DELETE FROM cart_items WHERE user_id = ?")
+        .bind(user_id)
+        .execute(&mut *tx)
+        .await?;
+    tx.commit().await?;
+
+    Ok(Checkout::Placed(order_id))
+}
+
+/// Moves an order from `from` to `to`, returning false if it's no longer
+/// at `from`. Cancelling puts the order's stock back.
+pub async fn set_order_status(db_pool: OrdersDb, order_id: i32, from: OrderStatus, to: OrderStatus) -> Result<bool> {
+    let mut tx = db_pool.0.begin().await?;
+    let updated = sqlx::query("UPDATE orders SET status = ?, updated_at = unixepoch() WHERE id = ? AND status = ?")
+        .bind(to.as_str())
+        .bind(order_id)
+        .bind(from.as_str())
+        .execute(&mut *tx)
+        .await?
+        .rows_affected();
+    if updated == 0 {
+        return Ok(false);
+    }
+
+    if to == OrderStatus::Cancelled {
+        sqlx::query(
+            "UPDATE inventory SET stock = stock + order_items.quantity, updated_at = unixepoch()
+             FROM order_items
+             WHERE order_items.order_id = ? AND order_items.book_id = inventory.book_id",
+        )
+            .bind(order_id)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    Ok(true)
+}
@@ -0,0 +1,39 @@
+-- What's for sale. Books with no row here can't be bought.
+CREATE TABLE inventory (
+    book_id INTEGER PRIMARY KEY,
+    price_cents INTEGER NOT NULL CHECK (price_cents >= 0),
+    -- The check is the last line of defence against overselling
+    stock INTEGER NOT NULL CHECK (stock >= 0),
+    updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE TABLE cart_items (
+    user_id INTEGER NOT NULL,
+    book_id INTEGER NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    added_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    PRIMARY KEY (user_id, book_id)
+);
+
+CREATE TABLE orders (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'placed',
+    total_cents INTEGER NOT NULL DEFAULT 0,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX orders_user ON orders (user_id);
+CREATE INDEX orders_status ON orders (status);
+
+-- Titles and prices are copied when the order is placed, so later
+-- catalogue and price changes don't rewrite history.
+CREATE TABLE order_items (
+    order_id INTEGER NOT NULL REFERENCES orders (id),
+    book_id INTEGER NOT NULL,
+    title TEXT NOT NULL,
+    quantity INTEGER NOT NULL CHECK (quantity > 0),
+    unit_price_cents INTEGER NOT NULL,
+    PRIMARY KEY (order_id, book_id)
+);
@@ -0,0 +1,9 @@
+-- Only the default tenant's inventory and orders are kept
+DELETE FROM order_items WHERE order_id IN (SELECT id FROM orders WHERE tenant_id != 1);
+DELETE FROM orders WHERE tenant_id != 1;
+DELETE FROM inventory WHERE tenant_id != 1;
+
+DROP INDEX orders_tenant;
+ALTER TABLE orders DROP COLUMN tenant_id;
+DROP INDEX inventory_tenant;
+ALTER TABLE inventory DROP COLUMN tenant_id;
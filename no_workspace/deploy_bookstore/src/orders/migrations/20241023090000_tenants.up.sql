@@ -0,0 +1,8 @@
+-- Each tenant stocks its own books and sees only its own orders. The
+-- tenants themselves are in the auth database; inventory and orders from
+-- before there were tenants belong to the default one.
+ALTER TABLE inventory ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
+CREATE INDEX inventory_tenant ON inventory (tenant_id, book_id);
+
+ALTER TABLE orders ADD COLUMN tenant_id INTEGER NOT NULL DEFAULT 1;
+CREATE INDEX orders_tenant ON orders (tenant_id, id);
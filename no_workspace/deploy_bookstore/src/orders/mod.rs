@@ -0,0 +1,126 @@
+mod configuration;
+mod db;
+mod web_service;
+#[cfg(test)]
+mod tests;
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{middleware, routing::{get, post}, Extension, Router};
+use sqlx::migrate::Migrator;
+use crate::{
+    auth::auth_layers::{self, Scope},
+    bookstore::StoreDb,
+    cors::RouteGroup,
+    modules::{Database, ModuleContext, ServiceModule},
+    idempotency::IdempotencyLayer,
+    rate_limit::RateLimitLayer,
+};
+use db::OrdersDb;
+
+pub use configuration::OrdersConfiguration;
+
+/// Inventory, carts and orders for books in the catalogue.
+pub struct OrdersModule {
+    config: OrdersConfiguration,
+    db_pool: OrdersDb,
+    store_db: StoreDb,
+}
+
+impl OrdersModule {
+    /// Orders are for books in the catalogue, so the module reads the
+    /// bookstore's database as well as its own.
+    pub async fn open(config: OrdersConfiguration, store_db: StoreDb) -> Result<Self> {
+        let db_pool = db::get_connection_pool(&config.db_filename).await?;
+        Ok(Self {
+            config,
+            db_pool,
+            store_db,
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceModule for OrdersModule {
+    fn name(&self) -> &'static str {
+        "orders"
+    }
+
+    fn mount_path(&self) -> &'static str {
+        "/api/v1/orders"
+    }
+
+    fn route_group(&self) -> RouteGroup {
+        RouteGroup::Orders
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &db::MIGRATOR
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        db::perform_migrations(self.db_pool.clone()).await
+    }
+
+    async fn router(&self, context: &ModuleContext) -> Result<Router> {
+        setup_service(
+            self.config.clone(),
+            self.db_pool.clone(),
+            self.store_db.clone(),
+            context.rate_limit.clone(),
+            context.idempotency.clone(),
+        )
+        .await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        db::ping(self.db_pool.clone()).await
+    }
+
+    fn database(&self) -> Database {
+        Database {
+            filename: self.config.db_filename.clone(),
+            pool: self.db_pool.0.clone(),
+        }
+    }
+}
+
+async fn setup_service(
+    config: OrdersConfiguration,
+    db_pool: OrdersDb,
+    store_db: StoreDb,
+    rate_limit: RateLimitLayer,
+    idempotency: IdempotencyLayer,
+) -> Result<Router> {
+    let admin_router = Router::new()
+        .route("/all", get(web_service::all_orders))
+        .route("/status/:id", post(web_service::update_status))
+        .route("/inventory/update/:book_id", post(web_service::set_inventory))
+        .route_layer(middleware::from_fn_with_state(Scope::OrdersAdmin, auth_layers::require_scope))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    // Routes for any signed-in user, about their own cart and orders
+    let customer_router = Router::new()
+        .route("/", get(web_service::my_orders))
+        .route("/:id", get(web_service::get_order))
+        .route("/cancel/:id", post(web_service::cancel_order))
+        .route("/cart", get(web_service::get_cart))
+        .route("/cart/set", post(web_service::set_cart_item))
+        .route("/cart/remove/:book_id", post(web_service::remove_cart_item))
+        .route("/checkout", post(web_service::checkout))
+        .route_layer(idempotency.clone())
+        .route_layer(rate_limit.clone())
+        .route_layer(middleware::from_fn(auth_layers::require_token));
+
+    let router = Router::new()
+        .route("/inventory/:book_id", get(web_service::get_inventory))
+        .route_layer(rate_limit)
+        .merge(admin_router)
+        .merge(customer_router)
+        .layer(Extension(config))
+        .layer(Extension(store_db))
+        .layer(Extension(db_pool));
+
+    Ok(router)
+}
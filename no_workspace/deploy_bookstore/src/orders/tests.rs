@@ -0,0 +1,118 @@
+use axum::http::StatusCode;
+use serde_json::json;
+use crate::test_harness::TestApp;
+
+const ORDERS: &str = "/api/v1/orders";
+
+/// Puts the first book in the catalogue up for sale.
+async fn stock_book(app: &TestApp, price_cents: i64, stock: i64) {
+    let token = app.admin_token().await;
+    let inventory = json!({ "price_cents": price_cents, "stock": stock });
+    let response = app.post(&format!("{ORDERS}/inventory/update/1"), Some(&token), inventory).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+}
+
+async fn stock(app: &TestApp) -> i64 {
+    app.get(&format!("{ORDERS}/inventory/1"), None).await.json()["stock"].as_i64().unwrap()
+}
+
+async fn fill_cart(app: &TestApp, token: &str, quantity: i64) {
+    let item = json!({ "book_id": 1, "quantity": quantity });
+    let response = app.post(&format!("{ORDERS}/cart/set"), Some(token), item).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+}
+
+async fn login_to_north(app: &TestApp, username: &str, password: &str) -> String {
+    let credentials = json!({ "username": username, "password": password });
+    let response = app.post("/t/north/api/v1/auth/login", None, credentials).await;
+    response.json()["Success"]["token"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn checkouts_race_for_the_last_copy() {
+    let app = TestApp::new().await;
+    stock_book(&app, 1999, 1).await;
+    let alice = app.reader_token("alice").await;
+    let bob = app.reader_token("bob").await;
+    fill_cart(&app, &alice, 1).await;
+    fill_cart(&app, &bob, 1).await;
+
+    let checkout = format!("{ORDERS}/checkout");
+    let (first, second) = tokio::join!(
+        app.post(&checkout, Some(&alice), json!({})),
+        app.post(&checkout, Some(&bob), json!({})),
+    );
+    let mut statuses = [first.status, second.status];
+    statuses.sort();
+    assert_eq!(statuses, [StatusCode::CREATED, StatusCode::CONFLICT]);
+    assert_eq!(stock(&app).await, 0);
+}
+
+#[tokio::test]
+async fn totals_too_large_to_charge_are_refused() {
+    let app = TestApp::new().await;
+    stock_book(&app, i64::MAX / 2, 10).await;
+    let token = app.reader_token("reader").await;
+    fill_cart(&app, &token, 3).await;
+
+    let response = app.post(&format!("{ORDERS}/checkout"), Some(&token), json!({})).await;
+    assert_eq!(response.status, StatusCode::UNPROCESSABLE_ENTITY);
+    assert_eq!(stock(&app).await, 10);
+    assert_eq!(app.get(&format!("{ORDERS}/cart"), Some(&token)).await.json()[0]["quantity"], 3);
+}
+
+#[tokio::test]
+async fn tenants_books_are_sold_in_their_shops() {
+    let app = TestApp::with_config(|config| config.tenancy.enabled = true).await;
+    let admin = app.admin_token().await;
+    let tenant = json!({
+        "slug": "north",
+        "name": "North",
+        "admin_username": "north-admin",
+        "admin_password": "north-password",
+    });
+    let response = app.post("/api/v1/auth/tenants/add", Some(&admin), tenant).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    let north_admin = login_to_north(&app, "north-admin", "north-password").await;
+    let book = json!({ "id": 0, "title": "Dune", "author": "Frank Herbert" });
+    let response = app.post("/t/north/api/v1/books/add", Some(&north_admin), book).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let books = app.get("/t/north/api/v1/books", None).await.json();
+    let book_id = books[0]["id"].as_i64().unwrap();
+
+    // Each tenant's admins stock only the books in its catalogue
+    let inventory = json!({ "price_cents": 999, "stock": 2 });
+    let response = app.post(&format!("{ORDERS}/inventory/update/{book_id}"), Some(&admin), inventory.clone()).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    let update = format!("/t/north{ORDERS}/inventory/update/{book_id}");
+    let response = app.post(&update, Some(&north_admin), inventory).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    assert_eq!(app.get(&format!("{ORDERS}/inventory/{book_id}"), None).await.status, StatusCode::NOT_FOUND);
+    let response = app.get(&format!("/t/north{ORDERS}/inventory/{book_id}"), None).await;
+    assert_eq!(response.json()["stock"], 2);
+
+    let registration = json!({ "username": "reader", "password": "reader-password" });
+    let response = app.post("/t/north/api/v1/auth/register", None, registration).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    let reader = login_to_north(&app, "reader", "reader-password").await;
+    let item = json!({ "book_id": book_id, "quantity": 1 });
+    let response = app.post(&format!("/t/north{ORDERS}/cart/set"), Some(&reader), item.clone()).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+    let response = app.post(&format!("/t/north{ORDERS}/checkout"), Some(&reader), json!({})).await;
+    assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+    assert_eq!(response.json()["items"][0]["title"], "Dune");
+    let order_id = response.json()["id"].as_i64().unwrap();
+
+    // Other tenants' shops don't sell it, and their admins don't see the order
+    let other = app.reader_token("other").await;
+    let response = app.post(&format!("{ORDERS}/cart/set"), Some(&other), item).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    assert_eq!(app.get(&format!("{ORDERS}/all"), Some(&admin)).await.json(), json!([]));
+    let response = app.get(&format!("{ORDERS}/{order_id}"), Some(&admin)).await;
+    assert_eq!(response.status, StatusCode::NOT_FOUND);
+    let orders = app.get(&format!("/t/north{ORDERS}/all"), Some(&north_admin)).await.json();
+    assert_eq!(orders[0]["id"], order_id);
+    let status = json!({ "status": "paid" });
+    let response = app.post(&format!("/t/north{ORDERS}/status/{order_id}"), Some(&north_admin), status).await;
+    assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+}
@@ -0,0 +1,294 @@
+use std::collections::HashMap;
+use axum::{extract::{Path, Query}, http::StatusCode, Extension, Json};
+use serde::Deserialize;
+use crate::{
+    auth::auth_layers::{Principal, Scope},
+    bookstore::{self, StoreDb},
+    tenancy::Tenant,
+};
+use super::{
+    configuration::OrdersConfiguration,
+    db::{self, CartItem, Checkout, InventoryItem, Order, OrderStatus, OrderSummary, OrdersDb},
+};
+
+type ApiError = (StatusCode, String);
+
+fn internal(e: anyhow::Error) -> ApiError {
+    tracing::error!("Orders database error: {e:#}");
+    (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string())
+}
+
+/// Carts and orders belong to users, so API keys can't have them.
+fn signed_in_user(principal: &Principal) -> Result<i32, ApiError> {
+    principal
+        .user_id()
+        .ok_or((StatusCode::FORBIDDEN, "sign in to shop".to_string()))
+}
+
+pub async fn get_inventory(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path(book_id): Path<i32>,
+) -> Result<Json<InventoryItem>, StatusCode> {
+    db::get_inventory(db_pool, tenant.id, book_id)
+        .await
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct InventoryUpdate {
+    price_cents: i64,
+    stock: i64,
+}
+
+/// Puts a book in the tenant's catalogue up for sale, or changes its price
+/// or stock.
+pub async fn set_inventory(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(store_db): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path(book_id): Path<i32>,
+    Json(update): Json<InventoryUpdate>,
+) -> Result<StatusCode, ApiError> {
+    if update.price_cents < 0 || update.stock < 0 {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "price and stock can't be negative".to_string()));
+    }
+    if bookstore::find_book(store_db, tenant.id, book_id).await.map_err(internal)?.is_none() {
+        return Err((StatusCode::NOT_FOUND, "no such book".to_string()));
+    }
+
+    let item = InventoryItem {
+        book_id,
+        price_cents: update.price_cents,
+        stock: update.stock,
+    };
+    db::set_inventory(db_pool, tenant.id, &item).await.map_err(internal)?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn get_cart(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(principal): Extension<Principal>,
+) -> Result<Json<Vec<CartItem>>, ApiError> {
+    let user_id = signed_in_user(&principal)?;
+    let cart = db::list_cart(db_pool, user_id).await.map_err(internal)?;
+    Ok(Json(cart))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct CartUpdate {
+    book_id: i32,
+    quantity: i64,
+}
+
+/// Sets how many copies of a book are in the cart. Stock isn't reserved
+/// until checkout.
+pub async fn set_cart_item(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(store_db): Extension<StoreDb>,
+    Extension(config): Extension<OrdersConfiguration>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Json(update): Json<CartUpdate>,
+) -> Result<StatusCode, ApiError> {
+    let user_id = signed_in_user(&principal)?;
+    if !(1..=config.max_quantity).contains(&update.quantity) {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("quantity must be between 1 and {}", config.max_quantity),
+        ));
+    }
+    let in_catalogue = bookstore::find_book(store_db, tenant.id, update.book_id)
+        .await
+        .map_err(internal)?
+        .is_some();
+    if !in_catalogue || db::get_inventory(db_pool.clone(), tenant.id, update.book_id).await.map_err(internal)?.is_none() {
+        return Err((StatusCode::NOT_FOUND, "book isn't for sale".to_string()));
+    }
+
+    let cart = db::list_cart(db_pool.clone(), user_id).await.map_err(internal)?;
+    let in_cart = cart.iter().any(|item| item.book_id == update.book_id);
+    if !in_cart && cart.len() >= config.max_cart_items {
+        return Err((StatusCode::CONFLICT, "cart is full".to_string()));
+    }
+
+    db::set_cart_item(db_pool, user_id, update.book_id, update.quantity)
+        .await
+        .map_err(internal)?;
+    Ok(StatusCode::OK)
+}
+
+pub async fn remove_cart_item(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(principal): Extension<Principal>,
+    Path(book_id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    let user_id = signed_in_user(&principal)?;
+    db::remove_cart_item(db_pool, user_id, book_id).await.map_err(internal)?;
+    Ok(StatusCode::OK)
+}
+
+/// Places an order for everything in the cart. Answers 409, leaving the
+/// cart alone, if any of it can't be supplied, or 422 if the total is too
+/// large to charge.
+pub async fn checkout(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(store_db): Extension<StoreDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+) -> Result<(StatusCode, Json<Order>), ApiError> {
+    let user_id = signed_in_user(&principal)?;
+
+    // Titles come from the catalogue, which has its own database
+    let mut titles = HashMap::new();
+    for item in db::list_cart(db_pool.clone(), user_id).await.map_err(internal)? {
+        if let Some(book) = bookstore::find_book(store_db.clone(), tenant.id, item.book_id).await.map_err(internal)? {
+            titles.insert(book.id, book.title);
+        }
+    }
+
+    let order_id = match db::checkout(db_pool.clone(), tenant.id, user_id, &titles).await.map_err(internal)? {
+        Checkout::Placed(order_id) => order_id,
+        Checkout::EmptyCart => return Err((StatusCode::CONFLICT, "cart is empty".to_string())),
+        Checkout::Unavailable { book_id } => {
+            return Err((StatusCode::CONFLICT, format!("book {book_id} is no longer for sale")));
+        }
+        Checkout::OutOfStock { book_id, available } => {
+            return Err((
+                StatusCode::CONFLICT,
+                format!("only {available} of book {book_id} in stock"),
+            ));
+        }
+        Checkout::TotalTooLarge => {
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, "order total is too large".to_string()));
+        }
+    };
+
+    let order = db::get_order(db_pool, tenant.id, order_id)
+        .await
+        .map_err(internal)?
+        .ok_or_else(|| internal(anyhow::anyhow!("order {order_id} vanished")))?;
+    Ok((StatusCode::CREATED, Json(order)))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct OrderQuery {
+    status: Option<OrderStatus>,
+    user_id: Option<i32>,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+/// The signed-in user's orders, newest first.
+pub async fn my_orders(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Query(query): Query<OrderQuery>,
+) -> Result<Json<Vec<OrderSummary>>, ApiError> {
+    let user_id = signed_in_user(&principal)?;
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    let orders = db::list_orders(db_pool, tenant.id, Some(user_id), query.status, limit, offset)
+        .await
+        .map_err(internal)?;
+    Ok(Json(orders))
+}
+
+/// Every order in the tenant's shop, optionally filtered by status or
+/// user.
+pub async fn all_orders(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Query(query): Query<OrderQuery>,
+) -> Result<Json<Vec<OrderSummary>>, ApiError> {
+    let (limit, offset) = (query.limit.clamp(1, 1000), query.offset.max(0));
+    let orders = db::list_orders(db_pool, tenant.id, query.user_id, query.status, limit, offset)
+        .await
+        .map_err(internal)?;
+    Ok(Json(orders))
+}
+
+/// An order with its line items. Users only see their own; other orders
+/// are reported as missing.
+pub async fn get_order(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Path(order_id): Path<i32>,
+) -> Result<Json<Order>, ApiError> {
+    let order = db::get_order(db_pool, tenant.id, order_id).await.map_err(internal)?;
+    match order {
+        Some(order)
+            if principal.has_scope(Scope::OrdersAdmin)
+                || principal.user_id() == Some(order.summary.user_id) =>
+        {
+            Ok(Json(order))
+        }
+        _ => Err((StatusCode::NOT_FOUND, "no such order".to_string())),
+    }
+}
+
+/// Lets users cancel their own orders until they've been paid for.
+pub async fn cancel_order(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Extension(principal): Extension<Principal>,
+    Path(order_id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    let user_id = signed_in_user(&principal)?;
+    let order = db::get_order(db_pool.clone(), tenant.id, order_id).await.map_err(internal)?;
+    let Some(order) = order.filter(|order| order.summary.user_id == user_id) else {
+        return Err((StatusCode::NOT_FOUND, "no such order".to_string()));
+    };
+    if order.summary.status != OrderStatus::Placed {
+        return Err((StatusCode::CONFLICT, format!("order is {}", order.summary.status.as_str())));
+    }
+
+    if !db::set_order_status(db_pool, order_id, OrderStatus::Placed, OrderStatus::Cancelled)
+        .await
+        .map_err(internal)?
+    {
+        return Err((StatusCode::CONFLICT, "order changed; try again".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct StatusUpdate {
+    status: OrderStatus,
+}
+
+pub async fn update_status(
+    Extension(db_pool): Extension<OrdersDb>,
+    Extension(tenant): Extension<Tenant>,
+    Path(order_id): Path<i32>,
+    Json(update): Json<StatusUpdate>,
+) -> Result<StatusCode, ApiError> {
+    let order = db::get_order(db_pool.clone(), tenant.id, order_id)
+        .await
+        .map_err(internal)?
+        .ok_or((StatusCode::NOT_FOUND, "no such order".to_string()))?;
+    let current = order.summary.status;
+    if !current.can_become(update.status) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("order can't go from {} to {}", current.as_str(), update.status.as_str()),
+        ));
+    }
+
+    if !db::set_order_status(db_pool, order_id, current, update.status)
+        .await
+        .map_err(internal)?
+    {
+        return Err((StatusCode::CONFLICT, "order changed; try again".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
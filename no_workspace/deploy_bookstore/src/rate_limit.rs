@@ -0,0 +1,384 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::SocketAddr,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::{HeaderMap, HeaderValue, StatusCode},
+    response::{IntoResponse, Response},
+};
+use serde::{Deserialize, Serialize};
+use tower::{Layer, Service};
+use crate::auth::auth_layers::Principal;
+
+/// A token bucket: up to `requests` at once, refilled evenly over
+/// `per_seconds`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub requests: u32,
+    pub per_seconds: u64,
+}
+
+impl RateLimit {
+    fn refill_per_second(&self) -> f64 {
+        self.requests as f64 / self.per_seconds as f64
+    }
+}
+
+/// The `rate_limit` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RateLimitConfiguration {
+    pub enabled: bool,
+    /// Applies to every route without its own limit. `None` leaves them
+    /// unlimited.
+    pub default: Option<RateLimit>,
+    /// Limits keyed by route, as written in the router: `/api/v1/books/:id`.
+    pub routes: HashMap<String, RateLimit>,
+    /// How many reverse proxies in front of the service add to
+    /// `X-Forwarded-For`. The client IP is the address the outermost of
+    /// them added, that many from the right; anything further left came
+    /// from the client. 0 ignores the header.
+    pub trusted_proxies: usize,
+}
+
+impl Default for RateLimitConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            default: Some(RateLimit {
+                requests: 120,
+                per_seconds: 60,
+            }),
+            routes: HashMap::from([(
+                "/api/v1/auth/login".to_string(),
+                RateLimit {
+                    requests: 10,
+                    per_seconds: 60,
+                },
+            )]),
+            trusted_proxies: 0,
+        }
+    }
+}
+
+impl RateLimitConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        let limits = self.default.iter().map(|limit| ("rate_limit.default".to_string(), limit));
+        let routes = self
+            .routes
+            .iter()
+            .map(|(route, limit)| (format!("rate_limit.routes.{route}"), limit));
+        for (setting, limit) in limits.chain(routes) {
+            if limit.requests == 0 || limit.per_seconds == 0 {
+                problems.push(format!("{setting}: requests and per_seconds must be at least 1"));
+            }
+        }
+    }
+}
+
+/// The result of trying to take a token from a bucket.
+#[derive(Debug, Clone, Copy)]
+pub struct Decision {
+    pub allowed: bool,
+    pub limit: u32,
+    pub remaining: u32,
+    /// Seconds until the next token if refused, or until the bucket is full.
+    pub reset_seconds: u64,
+}
+
+/// Where buckets live. The in-memory store suits a single replica; a shared
+/// store (such as Redis) can implement this to limit across replicas.
+#[async_trait]
+pub trait BucketStore: Send + Sync {
+    async fn take(&self, key: &str, limit: RateLimit) -> Result<Decision>;
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+    limit: RateLimit,
+}
+
+impl Bucket {
+    fn is_full(&self, now: Instant) -> bool {
+        let refilled = now.duration_since(self.updated).as_secs_f64() * self.limit.refill_per_second();
+        self.tokens + refilled >= self.limit.requests as f64
+    }
+}
+
+#[derive(Default)]
+pub struct InMemoryBuckets {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+impl InMemoryBuckets {
+    /// Drops buckets that have refilled, which are no different from new
+    /// ones, to bound memory use.
+    pub fn prune(&self) {
+        let now = Instant::now();
+        self.buckets.lock().unwrap().retain(|_, bucket| !bucket.is_full(now));
+    }
+
+    /// Prunes every `interval`, for as long as the service runs.
+    pub async fn prune_every(self: Arc<Self>, interval: Duration) {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            self.prune();
+        }
+    }
+}
+
+#[async_trait]
+impl BucketStore for InMemoryBuckets {
+    async fn take(&self, key: &str, limit: RateLimit) -> Result<Decision> {
+        let now = Instant::now();
+        let capacity = limit.requests as f64;
+        let refill = limit.refill_per_second();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: capacity,
+            updated: now,
+            limit,
+        });
+        bucket.limit = limit;
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill).min(capacity);
+        bucket.updated = now;
+
+        let allowed = bucket.tokens >= 1.0;
+        if allowed {
+            bucket.tokens -= 1.0;
+        }
+        let wait = if allowed {
+            (capacity - bucket.tokens) / refill
+        } else {
+            (1.0 - bucket.tokens) / refill
+        };
+
+        Ok(Decision {
+            allowed,
+            limit: limit.requests,
+            remaining: bucket.tokens.floor() as u32,
+            reset_seconds: wait.ceil() as u64,
+        })
+    }
+}
+
+struct RateLimiter {
+    config: RateLimitConfiguration,
+    store: Arc<dyn BucketStore>,
+}
+
+impl RateLimiter {
+    fn limit_for(&self, route: &str) -> Option<RateLimit> {
+        if !self.config.enabled {
+            return None;
+        }
+        self.config.routes.get(route).copied().or(self.config.default)
+    }
+
+    /// Identifies the client: the authenticated user or API key if there is
+    /// one, otherwise the client's IP address.
+    fn client_key<B>(&self, req: &Request<B>) -> String {
+        if let Some(principal) = req.extensions().get::<Principal>() {
+            return principal.to_string();
+        }
+        if let Some(address) = forwarded_client(req.headers(), self.config.trusted_proxies) {
+            return format!("ip:{address}");
+        }
+        match req.extensions().get::<ConnectInfo<SocketAddr>>() {
+            Some(ConnectInfo(address)) => format!("ip:{}", address.ip()),
+            None => "ip:unknown".to_string(),
+        }
+    }
+}
+
+/// The client address the outermost of `trusted_proxies` proxies added to
+/// `X-Forwarded-For`. Entries to its left are whatever the client sent, so
+/// they can't be trusted.
+fn forwarded_client(headers: &HeaderMap, trusted_proxies: usize) -> Option<String> {
+    if trusted_proxies == 0 {
+        return None;
+    }
+    let mut addresses = Vec::new();
+    for value in headers.get_all("x-forwarded-for") {
+        addresses.extend(value.to_str().ok()?.split(',').map(str::trim));
+    }
+    let index = addresses.len().checked_sub(trusted_proxies)?;
+    Some(addresses[index].to_string()).filter(|address| !address.is_empty())
+}
+
+/// Limits requests per client and route. Add it with `route_layer`, inside
+/// any authentication layer, so the authenticated user is known.
+#[derive(Clone)]
+pub struct RateLimitLayer {
+    limiter: Arc<RateLimiter>,
+}
+
+impl RateLimitLayer {
+    pub fn new(config: RateLimitConfiguration, store: Arc<dyn BucketStore>) -> Self {
+        Self {
+            limiter: Arc::new(RateLimiter { config, store }),
+        }
+    }
+}
+
+impl<S> Layer<S> for RateLimitLayer {
+    type Service = RateLimitService<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RateLimitService {
+            inner,
+            limiter: self.limiter.clone(),
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct RateLimitService<S> {
+    inner: S,
+    limiter: Arc<RateLimiter>,
+}
+
+impl<S, B> Service<Request<B>> for RateLimitService<S>
+where
+    S: Service<Request<B>, Response = Response> + Clone + Send + 'static,
+    S::Future: Send,
+    B: Send + 'static,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        // Take the service that was polled ready, leaving a clone behind
+        let clone = self.inner.clone();
+        let mut inner = std::mem::replace(&mut self.inner, clone);
+        let limiter = self.limiter.clone();
+
+        Box::pin(async move {
+            let route = req
+                .extensions()
+                .get::<MatchedPath>()
+                .map(|path| path.as_str().to_string())
+                .unwrap_or_else(|| req.uri().path().to_string());
+            let Some(limit) = limiter.limit_for(&route) else {
+                return inner.call(req).await;
+            };
+
+            let key = format!("{}|{route}", limiter.client_key(&req));
+            let decision = match limiter.store.take(&key, limit).await {
+                Ok(decision) => decision,
+                Err(e) => {
+                    // Fail open: a broken store shouldn't take the API down
+                    tracing::warn!("Rate limit store failed: {e:#}");
+                    return inner.call(req).await;
+                }
+            };
+
+            let mut response = if decision.allowed {
+                inner.call(req).await?
+            } else {
+                let mut response = (StatusCode::TOO_MANY_REQUESTS, "rate limit exceeded").into_response();
+                response
+                    .headers_mut()
+                    .insert("retry-after", HeaderValue::from(decision.reset_seconds));
+                response
+            };
+            set_rate_limit_headers(response.headers_mut(), &decision, limit);
+            Ok(response)
+        })
+    }
+}
+
+fn set_rate_limit_headers(headers: &mut HeaderMap, decision: &Decision, limit: RateLimit) {
+    let policy = format!("{};w={}", limit.requests, limit.per_seconds);
+    headers.insert("ratelimit-limit", HeaderValue::from(decision.limit));
+    headers.insert("ratelimit-remaining", HeaderValue::from(decision.remaining));
+    headers.insert("ratelimit-reset", HeaderValue::from(decision.reset_seconds));
+    if let Ok(policy) = HeaderValue::from_str(&policy) {
+        headers.insert("ratelimit-policy", policy);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, time::Duration};
+    use axum::http::{HeaderMap, HeaderValue, StatusCode};
+    use serde_json::json;
+    use crate::test_harness::TestApp;
+    use super::{forwarded_client, BucketStore, InMemoryBuckets, RateLimit};
+
+    #[test]
+    fn forwarded_clients_are_read_from_the_right() {
+        let mut headers = HeaderMap::new();
+        headers.append("x-forwarded-for", HeaderValue::from_static("10.9.9.9, 203.0.113.7"));
+        headers.append("x-forwarded-for", HeaderValue::from_static("198.51.100.2"));
+
+        assert_eq!(forwarded_client(&headers, 0), None);
+        assert_eq!(forwarded_client(&headers, 1).as_deref(), Some("198.51.100.2"));
+        assert_eq!(forwarded_client(&headers, 2).as_deref(), Some("203.0.113.7"));
+        assert_eq!(forwarded_client(&headers, 4), None);
+        assert_eq!(forwarded_client(&HeaderMap::new(), 1), None);
+    }
+
+    #[tokio::test]
+    async fn refilled_buckets_are_pruned() {
+        let buckets = InMemoryBuckets::default();
+        let slow = RateLimit { requests: 2, per_seconds: 3600 };
+        let fast = RateLimit { requests: 1000, per_seconds: 1 };
+        buckets.take("slow", slow).await.unwrap();
+        buckets.take("fast", fast).await.unwrap();
+        tokio::time::sleep(Duration::from_millis(10)).await;
+
+        buckets.prune();
+        let remaining: Vec<String> = buckets.buckets.lock().unwrap().keys().cloned().collect();
+        assert_eq!(remaining, ["slow"]);
+        assert_eq!(buckets.take("slow", slow).await.unwrap().remaining, 0);
+    }
+
+    #[tokio::test]
+    async fn routes_are_limited_by_their_own_limits() {
+        let app = TestApp::with_config(|config| {
+            config.rate_limit.enabled = true;
+            config.rate_limit.default = None;
+            config.rate_limit.routes = HashMap::from([(
+                "/api/v1/auth/login".to_string(),
+                RateLimit { requests: 3, per_seconds: 60 },
+            )]);
+        })
+        .await;
+        let login = json!({ "username": "admin", "password": "wrong" });
+
+        for remaining in ["2", "1", "0"] {
+            let response = app.post("/api/v1/auth/login", None, login.clone()).await;
+            assert_eq!(response.status, StatusCode::OK);
+            assert_eq!(response.headers["ratelimit-limit"], "3");
+            assert_eq!(response.headers["ratelimit-remaining"], remaining);
+            assert_eq!(response.headers["ratelimit-policy"], "3;w=60");
+        }
+        let response = app.post("/api/v1/auth/login", None, login).await;
+        assert_eq!(response.status, StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers["retry-after"], "20");
+        assert_eq!(response.headers["ratelimit-remaining"], "0");
+        assert_eq!(response.headers["ratelimit-reset"], "20");
+
+        // Routes without a limit of their own, with no default, are unlimited
+        let response = app.get("/api/v1/auth/me", None).await;
+        assert!(!response.headers.contains_key("ratelimit-limit"));
+    }
+}
@@ -0,0 +1,85 @@
+use std::sync::Arc;
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    http::{header, HeaderName, HeaderValue},
+    response::Response,
+};
+use serde::{Deserialize, Serialize};
+
+/// The `security_headers` section of the service configuration. These are
+/// added to static content responses.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct SecurityHeadersConfiguration {
+    pub content_security_policy: String,
+    pub referrer_policy: String,
+    /// Only sent over HTTPS. Zero turns HSTS off.
+    pub hsts_max_age_seconds: u64,
+}
+
+impl Default for SecurityHeadersConfiguration {
+    fn default() -> Self {
+        Self {
+            // The storefront pages load jQuery and Bootstrap from CDNs, and
+            // use inline scripts.
+            content_security_policy: "default-src 'self'; \
+                script-src 'self' 'unsafe-inline' https://cdn.jsdelivr.net https://ajax.googleapis.com http://ajax.googleapis.com; \
+                style-src 'self' https://cdn.jsdelivr.net; \
+                img-src 'self' data:; \
+                frame-ancestors 'none'"
+                .to_string(),
+            referrer_policy: "strict-origin-when-cross-origin".to_string(),
+            hsts_max_age_seconds: 31_536_000,
+        }
+    }
+}
+
+impl SecurityHeadersConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Err(e) = self.headers(false) {
+            problems.push(format!("security_headers: {e:#}"));
+        }
+    }
+
+    /// The headers to add. HSTS is only included when serving HTTPS.
+    pub fn headers(&self, https: bool) -> Result<SecurityHeaders> {
+        let mut headers = vec![(
+            header::X_CONTENT_TYPE_OPTIONS,
+            HeaderValue::from_static("nosniff"),
+        )];
+        if !self.content_security_policy.is_empty() {
+            let policy = HeaderValue::from_str(&self.content_security_policy)
+                .context("invalid content_security_policy")?;
+            headers.push((header::CONTENT_SECURITY_POLICY, policy));
+        }
+        if !self.referrer_policy.is_empty() {
+            let policy = HeaderValue::from_str(&self.referrer_policy)
+                .context("invalid referrer_policy")?;
+            headers.push((header::REFERRER_POLICY, policy));
+        }
+        if https && self.hsts_max_age_seconds > 0 {
+            let hsts = format!("max-age={}; includeSubDomains", self.hsts_max_age_seconds);
+            headers.push((header::STRICT_TRANSPORT_SECURITY, HeaderValue::from_str(&hsts)?));
+        }
+        Ok(SecurityHeaders(Arc::new(headers)))
+    }
+}
+
+#[derive(Clone)]
+pub struct SecurityHeaders(Arc<Vec<(HeaderName, HeaderValue)>>);
+
+/// For use with `middleware::map_response_with_state`. Headers the handler
+/// already set are left alone.
+pub async fn set_security_headers(
+    State(headers): State<SecurityHeaders>,
+    mut response: Response,
+) -> Response {
+    for (name, value) in headers.0.iter() {
+        response
+            .headers_mut()
+            .entry(name)
+            .or_insert_with(|| value.clone());
+    }
+    response
+}
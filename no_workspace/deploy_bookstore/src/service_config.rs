@@ -1,29 +1,260 @@
-use config::Config;
-use serde::{Deserialize, Serialize};
-use anyhow::Result;
+use anyhow::{bail, Context, Result};
+use config::{Config, ConfigError, Map, Source, Value};
+use serde::{Deserialize, Deserializer, Serialize};
+use std::{fs::OpenOptions, path::Path};
+use crate::{
+    auth::AuthConfiguration, backup::BackupConfiguration, cors::CorsConfiguration,
+    idempotency::IdempotencyConfiguration, limits::LimitsConfiguration, modules,
+    rate_limit::RateLimitConfiguration, security_headers::SecurityHeadersConfiguration,
+    storefront::StorefrontConfiguration, tenancy::TenancyConfiguration, tls::TlsConfiguration,
+};
+#[cfg(feature = "bookstore")]
+use crate::bookstore::BookstoreConfiguration;
+#[cfg(feature = "orders")]
+use crate::orders::OrdersConfiguration;
+#[cfg(feature = "webhooks")]
+use crate::webhooks::WebhooksConfiguration;
 
+/// The complete, merged configuration tree for the service. Each module
+/// gets its own section, with defaults supplied by the module itself.
 #[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
 pub struct ServiceConfig {
     pub listen_address: String,
-    pub listen_port: String,
+    pub listen_port: u16,
     pub static_content: String,
+    /// Applies pending migrations as the service starts. Turn this off to
+    /// run them from a separate job with `migrate`; the service then won't
+    /// start until they have been.
+    pub migrate_on_startup: bool,
+    /// Compresses API responses for clients that accept Brotli or gzip.
+    pub compress_responses: bool,
+    pub storefront: StorefrontConfiguration,
+    pub tls: TlsConfiguration,
+    pub cors: CorsConfiguration,
+    pub limits: LimitsConfiguration,
+    pub rate_limit: RateLimitConfiguration,
+    pub idempotency: IdempotencyConfiguration,
+    pub backup: BackupConfiguration,
+    pub security_headers: SecurityHeadersConfiguration,
+    pub tenancy: TenancyConfiguration,
+    pub auth: AuthConfiguration,
+    #[cfg(feature = "bookstore")]
+    pub bookstore: BookstoreConfiguration,
+    #[cfg(feature = "orders")]
+    pub orders: OrdersConfiguration,
+    #[cfg(feature = "webhooks")]
+    pub webhooks: WebhooksConfiguration,
+}
+
+impl Default for ServiceConfig {
+    fn default() -> Self {
+        Self {
+            listen_address: "127.0.0.1".to_string(),
+            listen_port: 3001,
+            static_content: "static_html".to_string(),
+            migrate_on_startup: true,
+            compress_responses: true,
+            storefront: StorefrontConfiguration::default(),
+            tls: TlsConfiguration::default(),
+            cors: CorsConfiguration::default(),
+            limits: LimitsConfiguration::default(),
+            rate_limit: RateLimitConfiguration::default(),
+            idempotency: IdempotencyConfiguration::default(),
+            backup: BackupConfiguration::default(),
+            security_headers: SecurityHeadersConfiguration::default(),
+            tenancy: TenancyConfiguration::default(),
+            auth: AuthConfiguration::default(),
+            #[cfg(feature = "bookstore")]
+            bookstore: BookstoreConfiguration::default(),
+            #[cfg(feature = "orders")]
+            orders: OrdersConfiguration::default(),
+            #[cfg(feature = "webhooks")]
+            webhooks: WebhooksConfiguration::default(),
+        }
+    }
 }
 
 impl ServiceConfig {
+    /// Loads the configuration in layers: defaults, then an optional
+    /// `settings` file, then environment variables. `APP_` variables set
+    /// top-level items, and each module's prefix (such as `AUTH_`) sets its
+    /// section. Nested keys use a double underscore, as in
+    /// `APP_TLS__CERT_PATH`.
     pub fn load() -> Result<Self> {
         // Load any .env files
         // Ignore the result of loading .env --- it's ok if it doesn't exist
         let _ = dotenvy::dotenv();
 
-        let settings_reader = Config::builder()
+        let mut builder = Config::builder()
             .add_source(config::File::with_name("settings").required(false))
-            .add_source(config::Environment::with_prefix("APP"))
-            .build()?;
+            .add_source(environment("APP"));
+        for (prefix, section) in modules::CONFIG_SECTIONS {
+            builder = builder.add_source(SectionEnvironment::new(prefix, section));
+        }
+        let settings_reader = builder
+            .build()
+            .context("unable to read configuration sources")?;
 
         let settings = settings_reader
-            .try_deserialize()?;
+            .try_deserialize()
+            .context("invalid configuration value")?;
 
         Ok(settings)
+    }
+
+    /// Checks the configuration for problems that would otherwise only show
+    /// up once the service is running. All problems are reported at once.
+    /// The storefront only has to exist when `serving`, so that admin
+    /// commands work in images without it.
+    pub fn validate(&self, serving: bool) -> Result<()> {
+        let mut problems = Vec::new();
+
+        if self.listen_port == 0 {
+            problems.push("listen_port: must be between 1 and 65535".to_string());
+        }
+        if serving && !Path::new(&self.static_content).is_dir() {
+            problems.push(format!(
+                "static_content: directory {} does not exist",
+                self.static_content
+            ));
+        }
+        self.storefront.validate(&mut problems);
+        self.tls.validate(self.listen_port, &mut problems);
+        self.cors.validate(&mut problems);
+        self.limits.validate(&mut problems);
+        self.rate_limit.validate(&mut problems);
+        self.idempotency.validate(&mut problems);
+        self.backup.validate(&mut problems);
+        self.security_headers.validate(&mut problems);
+        self.tenancy.validate(&mut problems);
+        self.auth.validate(&mut problems);
+        if self.auth.grpc.as_ref().is_some_and(|grpc| grpc.tls) && !self.tls.enabled {
+            problems.push("auth.grpc.tls: needs tls.enabled, whose certificate it's served with".to_string());
+        }
+        if self.auth.password_reset_log && self.tls.enabled {
+            problems.push("auth.password_reset_log: reset tokens can't be logged with tls.enabled".to_string());
+        }
+        #[cfg(feature = "bookstore")]
+        self.bookstore.validate(&mut problems);
+        #[cfg(feature = "orders")]
+        self.orders.validate(&mut problems);
+        #[cfg(feature = "webhooks")]
+        self.webhooks.validate(&mut problems);
+
+        if !problems.is_empty() {
+            bail!("invalid configuration:\n  {}", problems.join("\n  "));
+        }
+        Ok(())
+    }
+
+    /// Prints the effective configuration as JSON, with anything that
+    /// looks like a secret replaced.
+    pub fn print_redacted(&self) -> Result<()> {
+        let mut settings = serde_json::to_value(self)?;
+        redact_secrets(&mut settings);
+        println!("{}", serde_json::to_string_pretty(&settings)?);
+        Ok(())
+    }
+}
+
+/// Adds a problem to `problems` if `filename` can't be written, or can't be
+/// created because its directory is missing or read-only.
+pub fn check_writable_file(setting: &str, filename: &str, problems: &mut Vec<String>) {
+    let path = Path::new(filename);
+    if path.exists() {
+        if let Err(e) = OpenOptions::new().write(true).open(path) {
+            problems.push(format!("{setting}: {filename} is not writable ({e})"));
+        }
+        return;
+    }
+
+    let directory = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match std::fs::metadata(directory) {
+        Ok(meta) if meta.is_dir() && !meta.permissions().readonly() => {}
+        Ok(_) => problems.push(format!(
+            "{setting}: directory {} is not writable",
+            directory.display()
+        )),
+        Err(e) => problems.push(format!(
+            "{setting}: directory {} does not exist ({e})",
+            directory.display()
+        )),
+    }
+}
+
+/// Deserializes a list from either a sequence or a comma-separated string,
+/// so lists can also be set from environment variables.
+pub fn string_list<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrList {
+        String(String),
+        List(Vec<String>),
+    }
+
+    Ok(match StringOrList::deserialize(deserializer)? {
+        StringOrList::String(list) => list
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(String::from)
+            .collect(),
+        StringOrList::List(list) => list,
+    })
+}
 
+const SECRET_WORDS: &[&str] = &["password", "secret", "token"];
+
+fn redact_secrets(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Object(map) => {
+            for (key, value) in map.iter_mut() {
+                if SECRET_WORDS.iter().any(|word| key.contains(word)) && !value.is_null() {
+                    *value = serde_json::Value::String("<redacted>".to_string());
+                } else {
+                    redact_secrets(value);
+                }
+            }
+        }
+        serde_json::Value::Array(items) => items.iter_mut().for_each(redact_secrets),
+        _ => {}
     }
-}
\ No newline at end of file
+}
+
+fn environment(prefix: &str) -> config::Environment {
+    config::Environment::with_prefix(prefix)
+        .prefix_separator("_")
+        .separator("__")
+}
+
+/// Reads environment variables with a prefix into a section of the
+/// configuration tree, so `AUTH_DB_FILENAME` becomes `auth.db_filename`.
+#[derive(Debug, Clone)]
+struct SectionEnvironment {
+    prefix: &'static str,
+    section: &'static str,
+}
+
+impl SectionEnvironment {
+    fn new(prefix: &'static str, section: &'static str) -> Self {
+        Self { prefix, section }
+    }
+}
+
+impl Source for SectionEnvironment {
+    fn clone_into_box(&self) -> Box<dyn Source + Send + Sync> {
+        Box::new(self.clone())
+    }
+
+    fn collect(&self) -> Result<Map<String, Value>, ConfigError> {
+        let variables = environment(self.prefix).collect()?;
+        Ok(variables
+            .into_iter()
+            .map(|(key, value)| (format!("{}.{key}", self.section), value))
+            .collect())
+    }
+}
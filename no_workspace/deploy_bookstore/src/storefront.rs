@@ -0,0 +1,272 @@
+use std::path::{Component, Path, PathBuf};
+use axum::{
+    extract::{Request, State},
+    handler::Handler,
+    http::{header, HeaderValue, Method, StatusCode},
+    middleware::{self, Next},
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde::{Deserialize, Serialize};
+use tower::ServiceExt;
+use tower_http::services::{ServeDir, ServeFile};
+
+/// The `storefront` section of the service configuration, for the pages
+/// served from `static_content`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct StorefrontConfiguration {
+    /// Serve `index.html` for page paths that aren't files, so that a
+    /// single-page app can do its own routing.
+    pub spa_fallback: bool,
+    /// Sent with 404s, relative to `static_content`. A bare 404 is sent if
+    /// it doesn't exist.
+    pub not_found_page: Option<String>,
+    /// How long browsers may keep files with a content hash in their name,
+    /// such as `app.3f9a2c1b.js`. Other files are revalidated every time.
+    pub immutable_max_age_seconds: u64,
+}
+
+impl Default for StorefrontConfiguration {
+    fn default() -> Self {
+        Self {
+            spa_fallback: false,
+            not_found_page: Some("404.html".to_string()),
+            immutable_max_age_seconds: 31_536_000,
+        }
+    }
+}
+
+impl StorefrontConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Some(page) = &self.not_found_page {
+            let inside = Path::new(page).components().all(|component| matches!(component, Component::Normal(_)));
+            if page.is_empty() || !inside {
+                problems.push(format!("storefront.not_found_page: {page:?} must be a path within static_content"));
+            }
+        }
+        if self.immutable_max_age_seconds == 0 {
+            problems.push("storefront.immutable_max_age_seconds: must be greater than zero".to_string());
+        }
+    }
+}
+
+/// Serves the storefront from `directory`. Clients that accept Brotli or
+/// gzip get a file's `.br` or `.gz` copy, where there is one.
+pub fn router(directory: &str, config: &StorefrontConfiguration) -> Router {
+    let directory = PathBuf::from(directory);
+    let missing_pages = MissingPages {
+        index: config.spa_fallback.then(|| directory.join("index.html")),
+        not_found: config.not_found_page.as_ref().map(|page| directory.join(page)),
+    };
+    let files = ServeDir::new(&directory)
+        .precompressed_br()
+        .precompressed_gzip()
+        .fallback(serve_missing.with_state(missing_pages));
+    let immutable = format!("public, max-age={}, immutable", config.immutable_max_age_seconds);
+    let immutable = HeaderValue::from_str(&immutable).expect("a number makes a valid header");
+
+    Router::new()
+        .fallback_service(files)
+        .layer(middleware::from_fn_with_state(immutable, set_cache_control))
+}
+
+/// What to send for paths that aren't files.
+#[derive(Clone)]
+struct MissingPages {
+    index: Option<PathBuf>,
+    not_found: Option<PathBuf>,
+}
+
+async fn serve_missing(State(pages): State<MissingPages>, req: Request) -> Response {
+    if let Some(index) = pages.index.filter(|_| is_page_request(&req)) {
+        let response = serve_file(index, clone_request(&req)).await;
+        if response.status() != StatusCode::NOT_FOUND {
+            return response;
+        }
+    }
+    let Some(page) = pages.not_found else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+    let mut response = serve_file(page, req).await;
+    if response.status().is_success() {
+        *response.status_mut() = StatusCode::NOT_FOUND;
+    }
+    response
+}
+
+async fn serve_file(path: PathBuf, req: Request) -> Response {
+    let file = ServeFile::new(path).precompressed_br().precompressed_gzip();
+    match file.oneshot(req).await {
+        Ok(response) => response.into_response(),
+        Err(never) => match never {},
+    }
+}
+
+/// The parts of a request `ServeFile` looks at. The body isn't needed.
+fn clone_request(req: &Request) -> Request {
+    let mut clone = Request::new(axum::body::Body::empty());
+    *clone.method_mut() = req.method().clone();
+    *clone.uri_mut() = req.uri().clone();
+    *clone.headers_mut() = req.headers().clone();
+    clone
+}
+
+/// A browser loading a page, rather than an asset: the last part of the
+/// path has no extension.
+fn is_page_request(req: &Request) -> bool {
+    let name = req.uri().path().rsplit('/').next().unwrap_or_default();
+    matches!(*req.method(), Method::GET | Method::HEAD) && !name.contains('.')
+}
+
+/// Lets browsers keep hashed files for good, and has them revalidate the
+/// rest. Files may be sent compressed, so caches keep a copy per encoding.
+async fn set_cache_control(State(immutable): State<HeaderValue>, req: Request, next: Next) -> Response {
+    let hashed = is_hashed_asset(req.uri().path());
+    let mut response = next.run(req).await;
+    if response.status().is_success() || response.status() == StatusCode::NOT_MODIFIED {
+        let cache_control = if hashed { immutable } else { HeaderValue::from_static("no-cache") };
+        let headers = response.headers_mut();
+        headers.entry(header::CACHE_CONTROL).or_insert(cache_control);
+        headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+    }
+    response
+}
+
+/// Whether a file's name has a content hash in it, as bundlers write them:
+/// `app.3f9a2c1b.js` or `index-BdH3k2x9.css`. The hash is the last part of
+/// the name before the extension, at least eight letters and digits with
+/// a digit among them. Pages keep their names, so they never count.
+fn is_hashed_asset(path: &str) -> bool {
+    let name = path.rsplit('/').next().unwrap_or_default();
+    let Some((stem, extension)) = name.rsplit_once('.') else {
+        return false;
+    };
+    let Some((_, hash)) = stem.rsplit_once(['.', '-']) else {
+        return false;
+    };
+    !extension.eq_ignore_ascii_case("html")
+        && hash.len() >= 8
+        && hash.bytes().all(|byte| byte.is_ascii_alphanumeric())
+        && hash.bytes().any(|byte| byte.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use axum::{
+        body::Body,
+        http::{header, Method, StatusCode},
+    };
+    use crate::test_harness::{request, TestApp, TestResponse};
+    use super::is_hashed_asset;
+
+    fn write(app: &TestApp, name: &str, contents: &[u8]) {
+        let path = Path::new(&app.config.static_content).join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, contents).unwrap();
+    }
+
+    async fn get(app: &TestApp, uri: &str, accept_encoding: &str) -> TestResponse {
+        let request = request(Method::GET, uri, None)
+            .header(header::ACCEPT_ENCODING, accept_encoding)
+            .body(Body::empty())
+            .unwrap();
+        app.send(request).await
+    }
+
+    #[test]
+    fn hashed_assets_are_recognised() {
+        assert!(is_hashed_asset("/assets/app.3f9a2c1b.js"));
+        assert!(is_hashed_asset("/assets/index-BdH3k2x9.css"));
+        assert!(!is_hashed_asset("/assets/app.js"));
+        assert!(!is_hashed_asset("/assets/polyfills-legacy.js"));
+        assert!(!is_hashed_asset("/index.html"));
+        assert!(!is_hashed_asset("/report-20240101.html"));
+    }
+
+    #[tokio::test]
+    async fn precompressed_files_are_sent_to_clients_that_accept_them() {
+        let app = TestApp::new().await;
+        write(&app, "app.js", b"plain");
+        write(&app, "app.js.br", b"brotli");
+        write(&app, "app.js.gz", b"gzip");
+
+        let response = get(&app, "/app.js", "gzip, br").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "br");
+        assert_eq!(response.headers[header::CONTENT_TYPE], "application/javascript");
+        assert_eq!(response.headers[header::VARY], "accept-encoding");
+        assert_eq!(response.text(), "brotli");
+        assert_eq!(get(&app, "/app.js", "gzip").await.text(), "gzip");
+        let response = get(&app, "/app.js", "identity").await;
+        assert!(!response.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.text(), "plain");
+    }
+
+    #[tokio::test]
+    async fn only_hashed_files_are_cached_for_good() {
+        let app = TestApp::new().await;
+        write(&app, "index.html", b"<h1>Books</h1>");
+        write(&app, "assets/app.3f9a2c1b.js", b"hashed");
+
+        let response = get(&app, "/assets/app.3f9a2c1b.js", "").await;
+        assert_eq!(response.headers[header::CACHE_CONTROL], "public, max-age=31536000, immutable");
+        let response = get(&app, "/", "").await;
+        assert_eq!(response.text(), "<h1>Books</h1>");
+        assert_eq!(response.headers[header::CACHE_CONTROL], "no-cache");
+        let response = get(&app, "/assets/app.00000000.js", "").await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert!(!response.headers.contains_key(header::CACHE_CONTROL));
+    }
+
+    #[tokio::test]
+    async fn missing_files_get_the_not_found_page() {
+        let app = TestApp::new().await;
+        assert_eq!(get(&app, "/nowhere", "").await.status, StatusCode::NOT_FOUND);
+
+        write(&app, "404.html", b"<h1>Not found</h1>");
+        let response = get(&app, "/nowhere", "").await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.text(), "<h1>Not found</h1>");
+        assert_eq!(response.headers[header::CONTENT_TYPE], "text/html");
+    }
+
+    #[tokio::test]
+    async fn single_page_apps_get_the_index_for_their_routes() {
+        let app = TestApp::with_config(|config| config.storefront.spa_fallback = true).await;
+        write(&app, "index.html", b"<div id=app></div>");
+        write(&app, "404.html", b"<h1>Not found</h1>");
+
+        let response = get(&app, "/books/42", "").await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.text(), "<div id=app></div>");
+        assert_eq!(response.headers[header::CACHE_CONTROL], "no-cache");
+        let response = get(&app, "/assets/missing.js", "").await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        assert_eq!(response.text(), "<h1>Not found</h1>");
+    }
+
+    #[tokio::test]
+    async fn api_responses_are_compressed() {
+        async fn list_users(app: &TestApp) -> TestResponse {
+            // Enough users that the list is worth compressing
+            app.reader_token("reader").await;
+            let token = app.admin_token().await;
+            let request = request(Method::GET, "/api/v1/auth/users", Some(&token))
+                .header(header::ACCEPT_ENCODING, "gzip")
+                .body(Body::empty())
+                .unwrap();
+            app.send(request).await
+        }
+
+        let response = list_users(&TestApp::new().await).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.headers[header::CONTENT_ENCODING], "gzip");
+
+        let app = TestApp::with_config(|config| config.compress_responses = false).await;
+        let response = list_users(&app).await;
+        assert!(!response.headers.contains_key(header::CONTENT_ENCODING));
+        assert_eq!(response.json()[0]["username"], "admin");
+    }
+}
@@ -0,0 +1,466 @@
+use std::collections::BTreeMap;
+use anyhow::Result;
+use axum::{
+    async_trait,
+    extract::{FromRequestParts, Request, State},
+    http::{header::HOST, request::Parts, StatusCode, Uri},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Router,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sqlx::prelude::FromRow;
+use tower::ServiceBuilder;
+use crate::auth::{auth_layers::Authenticator, AuthConfiguration};
+
+/// The tenant everything belonged to before there were tenants, and that
+/// every request is for while tenancy is off. Only its users and keys can
+/// hold deployment-wide scopes.
+pub const DEFAULT_TENANT: i32 = 1;
+
+/// The `tenancy` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TenancyConfiguration {
+    /// Host more than one shop. While this is off, every request is for
+    /// the default tenant.
+    pub enabled: bool,
+    /// Find the tenant from the `Host` header, for tenants with a host.
+    pub by_host: bool,
+    /// Find the tenant from a path prefix, as in `/t/<slug>/api/v1/books`.
+    /// The prefix is removed before routing.
+    pub path_prefix: Option<String>,
+    /// When neither the path nor the host names a tenant, use the tenant
+    /// of the request's token or API key.
+    pub by_credential: bool,
+}
+
+impl Default for TenancyConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            by_host: true,
+            path_prefix: Some("/t".to_string()),
+            by_credential: true,
+        }
+    }
+}
+
+impl TenancyConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        if let Some(prefix) = &self.path_prefix {
+            if !prefix.starts_with('/') || prefix.ends_with('/') {
+                problems.push(format!(
+                    "tenancy.path_prefix: {prefix:?} must start with / and not end with one"
+                ));
+            }
+        }
+    }
+}
+
+/// A shop hosted on the deployment, with its own users, API keys and
+/// catalogue.
+#[derive(Serialize, Deserialize, Clone, Debug, FromRow)]
+pub struct Tenant {
+    pub id: i32,
+    /// Names the tenant in paths, as in `/t/<slug>/`.
+    pub slug: String,
+    pub name: String,
+    /// The host its storefront is served on, if it has one of its own.
+    pub host: Option<String>,
+    #[sqlx(try_from = "String")]
+    pub overrides: TenantOverrides,
+}
+
+impl Tenant {
+    /// The default tenant as it is while tenancy is off, without looking
+    /// it up.
+    pub fn default_tenant() -> Self {
+        Self {
+            id: DEFAULT_TENANT,
+            slug: "default".to_string(),
+            name: "Default".to_string(),
+            host: None,
+            overrides: TenantOverrides::default(),
+        }
+    }
+}
+
+/// How to find a tenant.
+#[derive(Clone, Copy, Debug)]
+pub enum TenantLookup<'a> {
+    Id(i32),
+    Slug(&'a str),
+    Host(&'a str),
+}
+
+/// How `resolve_tenant` found a request's tenant. It's added to the
+/// request's extensions along with the `Tenant`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum TenantSource {
+    /// From the path prefix.
+    Path,
+    /// From the `Host` header.
+    Host,
+    /// Nothing named one, so it's the default tenant until a credential
+    /// says otherwise.
+    Unnamed,
+    /// The default tenant, and nothing can change it.
+    Fixed,
+    /// From the request's token or API key.
+    Credential,
+}
+
+/// Settings a tenant has changed from the deployment's, by configuration
+/// section, as in `{"auth": {"min_password_length": 12}}`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct TenantOverrides(pub BTreeMap<String, Map<String, Value>>);
+
+impl TryFrom<String> for TenantOverrides {
+    type Error = serde_json::Error;
+
+    fn try_from(json: String) -> Result<Self, Self::Error> {
+        serde_json::from_str(&json)
+    }
+}
+
+/// A module's configuration section, parts of which tenants can override.
+pub trait Overridable: Serialize + DeserializeOwned + Default + Clone + Send + Sync + 'static {
+    const SECTION: &'static str;
+    /// The settings a tenant may change. The rest are the deployment's.
+    const OVERRIDABLE: &'static [&'static str];
+}
+
+impl TenantOverrides {
+    /// `config` with the tenant's settings for its section.
+    pub fn apply<T: Overridable>(&self, config: &T) -> Result<T> {
+        let Some(settings) = self.0.get(T::SECTION) else {
+            return Ok(config.clone());
+        };
+        let mut merged = serde_json::to_value(config)?;
+        if let Value::Object(fields) = &mut merged {
+            for (key, value) in settings.iter().filter(|(key, _)| T::OVERRIDABLE.contains(&key.as_str())) {
+                fields.insert(key.clone(), value.clone());
+            }
+        }
+        Ok(serde_json::from_value(merged)?)
+    }
+
+    /// Checks that every setting can be overridden, and has a value of the
+    /// right type. All problems are reported at once.
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        for (section, settings) in &self.0 {
+            match section.as_str() {
+                AuthConfiguration::SECTION => self.check::<AuthConfiguration>(settings, problems),
+                #[cfg(feature = "bookstore")]
+                crate::bookstore::BookstoreConfiguration::SECTION => {
+                    self.check::<crate::bookstore::BookstoreConfiguration>(settings, problems);
+                }
+                _ => problems.push(format!("{section}: has no settings tenants can change")),
+            }
+        }
+    }
+
+    fn check<T: Overridable>(&self, settings: &Map<String, Value>, problems: &mut Vec<String>) {
+        for key in settings.keys().filter(|key| !T::OVERRIDABLE.contains(&key.as_str())) {
+            problems.push(format!("{}.{key}: can't be changed per tenant", T::SECTION));
+        }
+        if let Err(e) = self.apply(&T::default()) {
+            problems.push(format!("{}: {e}", T::SECTION));
+        }
+    }
+}
+
+/// A module's configuration, with the request's tenant's overrides
+/// applied. The module adds its configuration as an extension.
+pub struct TenantConfig<T>(pub T);
+
+#[async_trait]
+impl<T: Overridable, S: Send + Sync> FromRequestParts<S> for TenantConfig<T> {
+    type Rejection = (StatusCode, String);
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let (Some(config), Some(tenant)) = (parts.extensions.get::<T>(), parts.extensions.get::<Tenant>()) else {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "no tenant".to_string()));
+        };
+        match tenant.overrides.apply(config) {
+            Ok(config) => Ok(Self(config)),
+            Err(e) => {
+                tracing::warn!("Tenant {} has invalid overrides: {e:#}", tenant.slug);
+                Err((StatusCode::INTERNAL_SERVER_ERROR, "invalid tenant settings".to_string()))
+            }
+        }
+    }
+}
+
+/// The slug from a request's path prefix, noted by `with_path_prefix` for
+/// `resolve_tenant`.
+#[derive(Clone, Debug)]
+struct TenantPath(String);
+
+/// Routes `<prefix>/<slug>/...` as `/...` for the tenant `slug`. It has
+/// to wrap the whole router, since the prefix comes before every route.
+pub fn with_path_prefix(prefix: String, router: Router) -> Router {
+    let service = ServiceBuilder::new()
+        .map_request(move |req: Request| strip_path_prefix(&prefix, req))
+        .service(router);
+    Router::new().fallback_service(service)
+}
+
+fn strip_path_prefix(prefix: &str, mut req: Request) -> Request {
+    let Some(rest) = req.uri().path().strip_prefix(prefix).and_then(|rest| rest.strip_prefix('/')) else {
+        return req;
+    };
+    let (slug, rest) = rest.split_once('/').unwrap_or((rest, ""));
+    if slug.is_empty() {
+        return req;
+    }
+    let path = match req.uri().query() {
+        Some(query) => format!("/{rest}?{query}"),
+        None => format!("/{rest}"),
+    };
+    let slug = slug.to_string();
+
+    let mut parts = req.uri().clone().into_parts();
+    let Ok(path) = path.parse() else {
+        return req;
+    };
+    parts.path_and_query = Some(path);
+    let Ok(uri) = Uri::from_parts(parts) else {
+        return req;
+    };
+    *req.uri_mut() = uri;
+    req.extensions_mut().insert(TenantPath(slug));
+    req
+}
+
+/// Works out which tenant requests are for. Add it to routers with
+/// `middleware::from_fn_with_state(resolver, resolve_tenant)`.
+#[derive(Clone)]
+pub struct TenantResolver {
+    config: TenancyConfiguration,
+    authenticator: Authenticator,
+}
+
+impl TenantResolver {
+    pub fn new(config: TenancyConfiguration, authenticator: Authenticator) -> Self {
+        Self { config, authenticator }
+    }
+
+    /// The path prefix comes first, then the host. A slug that isn't a
+    /// tenant is refused, but an unknown host is just the default tenant's.
+    async fn resolve(
+        &self,
+        path: Option<&TenantPath>,
+        host: Option<String>,
+    ) -> Result<(Tenant, TenantSource), (StatusCode, String)> {
+        if !self.config.enabled {
+            return Ok((Tenant::default_tenant(), TenantSource::Fixed));
+        }
+        if let Some(TenantPath(slug)) = path {
+            let tenant = self
+                .find(TenantLookup::Slug(slug))
+                .await?
+                .ok_or((StatusCode::NOT_FOUND, "no such tenant".to_string()))?;
+            return Ok((tenant, TenantSource::Path));
+        }
+        if self.config.by_host {
+            if let Some(host) = host {
+                if let Some(tenant) = self.find(TenantLookup::Host(&host)).await? {
+                    return Ok((tenant, TenantSource::Host));
+                }
+            }
+        }
+
+        let tenant = self
+            .find(TenantLookup::Id(DEFAULT_TENANT))
+            .await?
+            .ok_or((StatusCode::INTERNAL_SERVER_ERROR, "no default tenant".to_string()))?;
+        let source = if self.config.by_credential { TenantSource::Unnamed } else { TenantSource::Fixed };
+        Ok((tenant, source))
+    }
+
+    async fn find(&self, lookup: TenantLookup<'_>) -> Result<Option<Tenant>, (StatusCode, String)> {
+        self.authenticator
+            .tenant(lookup)
+            .await
+            .map_err(|e| self.authenticator.failure(e))
+    }
+}
+
+/// The request's host, lowercase and without a port.
+fn request_host(req: &Request) -> Option<String> {
+    let host = req
+        .headers()
+        .get(HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| req.uri().host())?;
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if port.bytes().all(|byte| byte.is_ascii_digit()) => name,
+        _ => host,
+    };
+    Some(host.trim_end_matches('.').to_ascii_lowercase())
+}
+
+/// Adds the request's `Tenant` and `TenantSource` to its extensions.
+pub async fn resolve_tenant(State(resolver): State<TenantResolver>, mut req: Request, next: Next) -> Response {
+    let path = req.extensions().get::<TenantPath>().cloned();
+    let host = request_host(&req);
+    match resolver.resolve(path.as_ref(), host).await {
+        Ok((tenant, source)) => {
+            req.extensions_mut().insert(tenant);
+            req.extensions_mut().insert(source);
+            next.run(req).await
+        }
+        Err(rejection) => rejection.into_response(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::http::StatusCode;
+    use serde_json::{json, Value};
+    use crate::test_harness::{TestApp, TestResponse};
+
+    async fn tenancy_app() -> TestApp {
+        TestApp::with_config(|config| config.tenancy.enabled = true).await
+    }
+
+    /// Creates a tenant as the default tenant's admin. Its admin is
+    /// `<slug>-admin`, with the password `<slug>-password`.
+    async fn add_tenant(app: &TestApp, slug: &str, host: Option<&str>, overrides: Value) -> TestResponse {
+        let token = app.admin_token().await;
+        let tenant = json!({
+            "slug": slug,
+            "name": slug.to_uppercase(),
+            "host": host,
+            "overrides": overrides,
+            "admin_username": format!("{slug}-admin"),
+            "admin_password": format!("{slug}-password"),
+        });
+        app.post("/api/v1/auth/tenants/add", Some(&token), tenant).await
+    }
+
+    async fn tenant_login(app: &TestApp, slug: &str, username: &str, password: &str) -> TestResponse {
+        let credentials = json!({ "username": username, "password": password });
+        app.post(&format!("/t/{slug}/api/v1/auth/login"), None, credentials).await
+    }
+
+    async fn tenant_admin_token(app: &TestApp, slug: &str) -> String {
+        let response = tenant_login(app, slug, &format!("{slug}-admin"), &format!("{slug}-password")).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+        response.json()["Success"]["token"].as_str().unwrap().to_string()
+    }
+
+    #[tokio::test]
+    async fn only_the_deployments_admins_manage_tenants() {
+        let app = tenancy_app().await;
+        let response = add_tenant(&app, "north", Some("North.Example.com"), json!({})).await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+        assert_eq!(response.json()["host"], "north.example.com");
+        assert_eq!(add_tenant(&app, "north", None, json!({})).await.status, StatusCode::CONFLICT);
+        assert_eq!(add_tenant(&app, "North!", None, json!({})).await.status, StatusCode::BAD_REQUEST);
+
+        // A tenant's admin administers its users, but not the deployment
+        let token = tenant_admin_token(&app, "north").await;
+        let response = app.get("/t/north/api/v1/auth/users", Some(&token)).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert_eq!(response.json().as_array().unwrap().len(), 1);
+        let response = app.get("/t/north/api/v1/auth/tenants", Some(&token)).await;
+        assert_eq!(response.status, StatusCode::FORBIDDEN);
+
+        let reader = app.reader_token("reader").await;
+        assert_eq!(app.get("/api/v1/auth/tenants", Some(&reader)).await.status, StatusCode::FORBIDDEN);
+        let tenants = app.get("/api/v1/auth/tenants", Some(&app.admin_token().await)).await.json();
+        let slugs: Vec<&str> = tenants.as_array().unwrap().iter().map(|t| t["slug"].as_str().unwrap()).collect();
+        assert_eq!(slugs, ["default", "north"]);
+    }
+
+    #[tokio::test]
+    async fn users_and_tokens_belong_to_one_tenant() {
+        let app = tenancy_app().await;
+        add_tenant(&app, "north", None, json!({})).await;
+
+        // The same username can be taken in each tenant
+        let password = "reader-password";
+        let registration = json!({ "username": "reader", "password": password });
+        let response = app.post("/t/north/api/v1/auth/register", None, registration).await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+        app.reader_token("reader").await;
+        let response = tenant_login(&app, "north", "reader", password).await;
+        assert_eq!(response.status, StatusCode::OK);
+        assert!(tenant_login(&app, "north", "admin", "admin").await.json()["Success"].is_null());
+
+        // A tenant's token isn't good for another's routes
+        let token = tenant_admin_token(&app, "north").await;
+        assert_eq!(app.get("/t/north/api/v1/auth/me", Some(&token)).await.status, StatusCode::OK);
+        assert_eq!(app.get("/t/default/api/v1/auth/me", Some(&token)).await.status, StatusCode::UNAUTHORIZED);
+        let admin = app.admin_token().await;
+        assert_eq!(app.get("/t/north/api/v1/auth/users", Some(&admin)).await.status, StatusCode::UNAUTHORIZED);
+
+        // Without a tenant in the path, the token says which it is
+        let users = app.get("/api/v1/auth/users", Some(&token)).await.json();
+        let usernames: Vec<&str> = users.as_array().unwrap().iter().map(|u| u["username"].as_str().unwrap()).collect();
+        assert_eq!(usernames, ["north-admin", "reader"]);
+
+        assert_eq!(app.get("/t/south/api/v1/auth/me", Some(&token)).await.status, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn overrides_change_a_tenants_settings() {
+        let app = tenancy_app().await;
+        let overrides = json!({ "auth": { "open_registration": false } });
+        assert_eq!(add_tenant(&app, "closed", None, overrides).await.status, StatusCode::CREATED);
+        let overrides = json!({ "auth": { "min_password_length": 20 } });
+        assert_eq!(add_tenant(&app, "strict", None, overrides).await.status, StatusCode::BAD_REQUEST);
+        let overrides = json!({ "auth": { "db_filename": "other.db" }, "orders": {} });
+        let response = add_tenant(&app, "sneaky", None, overrides).await;
+        assert_eq!(response.status, StatusCode::BAD_REQUEST);
+        assert!(response.text().contains("auth.db_filename"), "{}", response.text());
+
+        let registration = json!({ "username": "reader", "password": "reader-password" });
+        let response = app.post("/t/closed/api/v1/auth/register", None, registration.clone()).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let response = app.post("/api/v1/auth/register", None, registration).await;
+        assert_eq!(response.status, StatusCode::CREATED);
+    }
+
+    #[cfg(feature = "bookstore")]
+    #[tokio::test]
+    async fn each_tenant_has_its_own_catalogue() {
+        use axum::{body::Body, http::{header::HOST, Method}};
+        use crate::test_harness::request;
+
+        let app = tenancy_app().await;
+        add_tenant(&app, "north", Some("north.example.com"), json!({})).await;
+        let token = tenant_admin_token(&app, "north").await;
+        let book = json!({ "id": 0, "title": "Dune", "author": "Frank Herbert", "isbn": "9780441013593" });
+        let response = app.post("/t/north/api/v1/books/add", Some(&token), book.clone()).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+        let sequel = json!({ "id": 0, "title": "Dune Messiah", "author": "Frank Herbert" });
+        let response = app.post("/api/v1/books/add", Some(&token), sequel).await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+        // The default tenant can have the same ISBN
+        let admin = app.admin_token().await;
+        assert_eq!(app.post("/api/v1/books/add", Some(&admin), book).await.status, StatusCode::OK);
+
+        let north = app.get("/t/north/api/v1/books", None).await.json();
+        let north = north.as_array().unwrap();
+        assert_eq!(north.len(), 2);
+        let by_host = request(Method::GET, "/api/v1/books", None)
+            .header(HOST, "North.Example.com:8443")
+            .body(Body::empty())
+            .unwrap();
+        assert_eq!(app.send(by_host).await.json().as_array().unwrap().len(), 2);
+        let default = app.get("/api/v1/books", None).await.json();
+        assert_eq!(default.as_array().unwrap().len(), 6);
+
+        let id = north[0]["id"].as_i64().unwrap();
+        assert_eq!(app.get(&format!("/api/v1/books/{id}"), None).await.status, StatusCode::NOT_FOUND);
+        let response = app.get(&format!("/api/v1/books/delete/{id}"), Some(&admin)).await;
+        assert_eq!(response.status, StatusCode::NOT_FOUND);
+        let response = app.get(&format!("/t/north/api/v1/books/delete/{id}"), Some(&token)).await;
+        assert_eq!(response.status, StatusCode::OK);
+    }
+}
@@ -0,0 +1,204 @@
+//! Boots the whole service in-process for tests, against databases in a
+//! temporary directory, and sends it requests with `tower::ServiceExt`.
+
+use std::path::PathBuf;
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, Method, Request, StatusCode},
+    response::Response,
+    Router,
+};
+use serde_json::Value;
+use tower::ServiceExt;
+use crate::service_config::ServiceConfig;
+
+/// The admin the auth module's first migration creates.
+pub const ADMIN_USERNAME: &str = "admin";
+pub const ADMIN_PASSWORD: &str = "admin";
+
+/// The service's master router, with its own databases, covers and backups.
+/// Everything is deleted when it's dropped. Background tasks aren't run.
+pub struct TestApp {
+    pub config: ServiceConfig,
+    router: Router,
+    directory: PathBuf,
+}
+
+/// A response, read whole.
+pub struct TestResponse {
+    pub status: StatusCode,
+    #[cfg_attr(not(feature = "bookstore"), allow(dead_code))]
+    pub headers: HeaderMap,
+    pub body: Bytes,
+}
+
+impl TestResponse {
+    pub fn json(&self) -> Value {
+        serde_json::from_slice(&self.body)
+            .unwrap_or_else(|e| panic!("{} is not JSON ({e}): {}", self.status, self.text()))
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+impl TestApp {
+    pub async fn new() -> Self {
+        Self::with_config(|_| {}).await
+    }
+
+    /// Builds the app after `configure` has changed the test configuration.
+    /// Open registration is on and rate limiting is off, so tests can make
+    /// as many users and requests as they like.
+    pub async fn with_config(configure: impl FnOnce(&mut ServiceConfig)) -> Self {
+        let directory = std::env::temp_dir().join(format!("deploy_bookstore-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(directory.join("static_html")).unwrap();
+        let path = |name: &str| directory.join(name).to_string_lossy().into_owned();
+
+        let mut config = ServiceConfig {
+            static_content: path("static_html"),
+            ..ServiceConfig::default()
+        };
+        config.rate_limit.enabled = false;
+        config.backup.directory = path("backups");
+        config.backup.interval_hours = 0;
+        config.auth.db_filename = path("auth.db");
+        config.auth.open_registration = true;
+        #[cfg(feature = "bookstore")]
+        {
+            config.bookstore.db_filename = path("bookstore.db");
+            config.bookstore.covers.directory = path("covers");
+        }
+        #[cfg(feature = "orders")]
+        {
+            config.orders.db_filename = path("orders.db");
+        }
+        #[cfg(feature = "webhooks")]
+        {
+            config.webhooks.db_filename = path("webhooks.db");
+        }
+        configure(&mut config);
+
+        let (router, _tasks) = crate::build_app(config.clone()).await.unwrap();
+        Self {
+            config,
+            router,
+            directory,
+        }
+    }
+
+    /// Shuts the service down and lets go of its databases, keeping its
+    /// files, as before a restore.
+    pub async fn stop(&mut self) {
+        self.router = Router::new();
+        // The connections close on their own threads once dropped
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+    }
+
+    /// Starts the service again, on the same files.
+    pub async fn start(&mut self) {
+        (self.router, _) = crate::build_app(self.config.clone()).await.unwrap();
+    }
+
+    pub async fn send(&self, request: Request<Body>) -> TestResponse {
+        let response = self.router.clone().oneshot(request).await.unwrap();
+        let (parts, body) = response.into_parts();
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            body: axum::body::to_bytes(body, usize::MAX).await.unwrap(),
+        }
+    }
+
+    /// Sends a request without reading the response body, for streams that
+    /// don't end.
+    #[cfg_attr(not(feature = "bookstore"), allow(dead_code))]
+    pub async fn open(&self, request: Request<Body>) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    pub async fn get(&self, uri: &str, token: Option<&str>) -> TestResponse {
+        self.send(request(Method::GET, uri, token).body(Body::empty()).unwrap())
+            .await
+    }
+
+    pub async fn post(&self, uri: &str, token: Option<&str>, body: Value) -> TestResponse {
+        self.send_json(Method::POST, uri, token, body).await
+    }
+
+    pub async fn send_json(&self, method: Method, uri: &str, token: Option<&str>, body: Value) -> TestResponse {
+        let request = request(method, uri, token)
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap();
+        self.send(request).await
+    }
+
+    /// Logs in, returning the session token.
+    pub async fn login(&self, username: &str, password: &str) -> String {
+        let response = self
+            .post(
+                "/api/v1/auth/login",
+                None,
+                serde_json::json!({ "username": username, "password": password }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::OK, "{}", response.text());
+        let token = &response.json()["Success"]["token"];
+        token
+            .as_str()
+            .unwrap_or_else(|| panic!("{username} couldn't log in: {}", response.text()))
+            .to_string()
+    }
+
+    pub async fn admin_token(&self) -> String {
+        self.login(ADMIN_USERNAME, ADMIN_PASSWORD).await
+    }
+
+    /// Registers a reader and logs them in, returning their token.
+    pub async fn reader_token(&self, username: &str) -> String {
+        let password = format!("{username}-password");
+        let response = self
+            .post(
+                "/api/v1/auth/register",
+                None,
+                serde_json::json!({ "username": username, "password": password }),
+            )
+            .await;
+        assert_eq!(response.status, StatusCode::CREATED, "{}", response.text());
+        self.login(username, &password).await
+    }
+}
+
+impl Drop for TestApp {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.directory);
+    }
+}
+
+/// A request with a session token, if there is one.
+pub fn request(method: Method, uri: &str, token: Option<&str>) -> axum::http::request::Builder {
+    let builder = Request::builder().method(method).uri(uri);
+    match token {
+        Some(token) => builder.header("Token", token),
+        None => builder,
+    }
+}
+
+/// A `multipart/form-data` body holding one file.
+#[cfg_attr(not(feature = "bookstore"), allow(dead_code))]
+pub fn multipart(builder: axum::http::request::Builder, filename: &str, content_type: &str, file: &[u8]) -> Request<Body> {
+    const BOUNDARY: &str = "test-boundary-7MA4YWxkTrZu0gW";
+    let mut body = format!(
+        "--{BOUNDARY}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{filename}\"\r\n\
+         Content-Type: {content_type}\r\n\r\n"
+    )
+    .into_bytes();
+    body.extend_from_slice(file);
+    body.extend_from_slice(format!("\r\n--{BOUNDARY}--\r\n").as_bytes());
+    builder
+        .header(header::CONTENT_TYPE, format!("multipart/form-data; boundary={BOUNDARY}"))
+        .body(Body::from(body))
+        .unwrap()
+}
@@ -0,0 +1,374 @@
+use std::{
+    fs::File,
+    io::BufReader,
+    net::SocketAddr,
+    path::Path,
+    str::FromStr,
+    sync::{Arc, RwLock},
+    time::{Duration, SystemTime},
+};
+use anyhow::{Context, Result};
+use axum::{
+    extract::{ConnectInfo, Request},
+    http::{uri::Authority, Uri},
+    response::Redirect,
+    Router,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto::Builder,
+    service::TowerToHyperService,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tokio_rustls::{
+    rustls::{crypto::ring, server::WebPkiClientVerifier, RootCertStore, ServerConfig, ServerConnection},
+    TlsAcceptor,
+};
+use tower::{service_fn, ServiceExt};
+
+/// The `tls` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct TlsConfiguration {
+    pub enabled: bool,
+    /// PEM certificate chain, leaf first.
+    pub cert_path: String,
+    /// PEM private key for the leaf certificate.
+    pub key_path: String,
+    /// PEM bundle of CAs allowed to sign client certificates. Setting this
+    /// enables (optional) mutual TLS.
+    pub client_ca_path: Option<String>,
+    /// If set, plain HTTP on this port redirects to HTTPS.
+    pub redirect_port: Option<u16>,
+    /// The host, with a port unless it's 443, that redirects send clients
+    /// to. Needed with `redirect_port`: the Host header can't be trusted.
+    pub public_host: Option<String>,
+    /// How often to check the certificate files for changes.
+    pub reload_interval_seconds: u64,
+}
+
+impl Default for TlsConfiguration {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            cert_path: "cert.pem".to_string(),
+            key_path: "key.pem".to_string(),
+            client_ca_path: None,
+            redirect_port: None,
+            public_host: None,
+            reload_interval_seconds: 30,
+        }
+    }
+}
+
+impl TlsConfiguration {
+    pub fn validate(&self, listen_port: u16, problems: &mut Vec<String>) {
+        if !self.enabled {
+            return;
+        }
+        for (setting, path) in self.watched_files() {
+            if !Path::new(path).is_file() {
+                problems.push(format!("{setting}: {path} does not exist"));
+            }
+        }
+        if let Some(port) = self.redirect_port {
+            if port == 0 || port == listen_port {
+                problems.push("tls.redirect_port: must be a free port other than listen_port".to_string());
+            }
+            match self.public_host.as_deref().map(Authority::from_str) {
+                None => problems.push("tls.public_host: must be set to redirect to HTTPS".to_string()),
+                Some(Ok(authority)) if !authority.as_str().contains('@') => {}
+                Some(_) => problems.push("tls.public_host: must be a host name, with an optional port".to_string()),
+            }
+        }
+        if self.reload_interval_seconds == 0 {
+            problems.push("tls.reload_interval_seconds: must be at least 1".to_string());
+        }
+    }
+
+    fn watched_files(&self) -> Vec<(&'static str, &str)> {
+        let mut files = vec![
+            ("tls.cert_path", self.cert_path.as_str()),
+            ("tls.key_path", self.key_path.as_str()),
+        ];
+        if let Some(path) = &self.client_ca_path {
+            files.push(("tls.client_ca_path", path.as_str()));
+        }
+        files
+    }
+}
+
+/// The verified certificate a client presented on a mutual TLS connection.
+/// It's added to every request on that connection.
+#[derive(Clone, Debug)]
+pub struct ClientCertificate {
+    pub common_name: String,
+}
+
+fn load_server_config(tls: &TlsConfiguration) -> Result<ServerConfig> {
+    let provider = Arc::new(ring::default_provider());
+
+    let certificates = rustls_pemfile::certs(&mut BufReader::new(File::open(&tls.cert_path)?))
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("unable to read certificates from {}", tls.cert_path))?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(&tls.key_path)?))?
+        .with_context(|| format!("no private key in {}", tls.key_path))?;
+
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &tls.client_ca_path {
+        Some(path) => {
+            let mut roots = RootCertStore::empty();
+            for certificate in rustls_pemfile::certs(&mut BufReader::new(File::open(path)?)) {
+                roots.add(certificate?)?;
+            }
+            // Clients without a certificate can still connect and use tokens
+            let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+                .allow_unauthenticated()
+                .build()?;
+            builder.with_client_cert_verifier(verifier)
+        }
+        None => builder.with_no_client_auth(),
+    };
+
+    let mut config = builder.with_single_cert(certificates, key)?;
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+fn modification_times(tls: &TlsConfiguration) -> Vec<Option<SystemTime>> {
+    tls.watched_files()
+        .into_iter()
+        .map(|(_, path)| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Polls the certificate files, swapping in a new configuration whenever
+/// they change. New connections pick it up; existing ones are unaffected.
+fn watch_certificates(tls: TlsConfiguration, current: Arc<RwLock<Arc<ServerConfig>>>) {
+    tokio::spawn(async move {
+        let mut last_seen = modification_times(&tls);
+        let mut interval = tokio::time::interval(Duration::from_secs(tls.reload_interval_seconds));
+        loop {
+            interval.tick().await;
+            let seen = modification_times(&tls);
+            if seen == last_seen {
+                continue;
+            }
+            match load_server_config(&tls) {
+                Ok(config) => {
+                    *current.write().unwrap() = Arc::new(config);
+                    last_seen = seen;
+                    tracing::info!("Reloaded TLS certificates");
+                }
+                // Files may be half-written; try again next time around
+                Err(e) => tracing::warn!("Keeping the current TLS certificates: {e:#}"),
+            }
+        }
+    });
+}
+
+fn client_certificate(connection: &ServerConnection) -> Option<ClientCertificate> {
+    let certificate = connection.peer_certificates()?.first()?;
+    let (_, certificate) = x509_parser::parse_x509_certificate(certificate).ok()?;
+    let common_name = certificate.subject().iter_common_name().next()?.as_str().ok()?;
+    Some(ClientCertificate {
+        common_name: common_name.to_string(),
+    })
+}
+
+/// How long a client has to finish the TLS handshake. Without a limit,
+/// connections that never send a ClientHello would be held open forever.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Serves `router` over HTTPS until the listener fails.
+pub async fn serve_tls(listener: TcpListener, router: Router, tls: TlsConfiguration) -> Result<()> {
+    let current = Arc::new(RwLock::new(Arc::new(load_server_config(&tls)?)));
+    watch_certificates(tls, current.clone());
+
+    loop {
+        let (stream, remote_address) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                tracing::warn!("Failed to accept a connection: {e}");
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(current.read().unwrap().clone());
+        let router = router.clone();
+
+        tokio::spawn(async move {
+            let stream = match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(e)) => {
+                    tracing::debug!("TLS handshake with {remote_address} failed: {e}");
+                    return;
+                }
+                Err(_) => {
+                    tracing::debug!("TLS handshake with {remote_address} timed out");
+                    return;
+                }
+            };
+            let certificate = client_certificate(stream.get_ref().1);
+
+            let service = service_fn(move |mut request: Request<hyper::body::Incoming>| {
+                request.extensions_mut().insert(ConnectInfo::<SocketAddr>(remote_address));
+                if let Some(certificate) = &certificate {
+                    request.extensions_mut().insert(certificate.clone());
+                }
+                router.clone().oneshot(request)
+            });
+
+            if let Err(e) = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(service))
+                .await
+            {
+                tracing::debug!("Connection from {remote_address} ended: {e}");
+            }
+        });
+    }
+}
+
+/// Serves plain HTTP on `listener`, redirecting everything to HTTPS on
+/// `public_host`.
+pub async fn serve_redirect(listener: TcpListener, public_host: String) -> Result<()> {
+    let redirect = move |uri: Uri| async move {
+        let path = uri.path_and_query().map(|p| p.as_str()).unwrap_or("/");
+        Redirect::permanent(&format!("https://{public_host}{path}"))
+    };
+
+    axum::serve(listener, Router::new().fallback(redirect)).await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+    use axum::{http::{header, StatusCode}, routing::get, Extension};
+    use reqwest::{redirect::Policy, Certificate, Client, Identity};
+    use super::*;
+
+    const CERTS: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/tls");
+
+    fn fixture(name: &str) -> String {
+        format!("{CERTS}/{name}")
+    }
+
+    /// A configuration whose certificate files are copies of `server`'s, so
+    /// they can be rewritten.
+    fn configuration(server: &str) -> (TlsConfiguration, PathBuf) {
+        let directory = std::env::temp_dir().join(format!("deploy_bookstore-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let tls = TlsConfiguration {
+            enabled: true,
+            cert_path: directory.join("cert.pem").display().to_string(),
+            key_path: directory.join("key.pem").display().to_string(),
+            client_ca_path: None,
+            redirect_port: None,
+            public_host: None,
+            reload_interval_seconds: 1,
+        };
+        install(&tls, server);
+        (tls, directory)
+    }
+
+    fn install(tls: &TlsConfiguration, server: &str) {
+        std::fs::copy(fixture(&format!("{server}.pem")), &tls.cert_path).unwrap();
+        std::fs::copy(fixture(&format!("{server}.key")), &tls.key_path).unwrap();
+    }
+
+    /// Serves a route that echoes the client certificate's common name.
+    async fn serve(tls: TlsConfiguration) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let router = Router::new().route(
+            "/",
+            get(|certificate: Option<Extension<ClientCertificate>>| async move {
+                certificate.map(|Extension(certificate)| certificate.common_name).unwrap_or_default()
+            }),
+        );
+        tokio::spawn(serve_tls(listener, router, tls));
+        port
+    }
+
+    /// A client that trusts only the certificates `ca` signed. It makes a
+    /// new connection, with a new handshake, for every request.
+    fn client(ca: &str) -> reqwest::ClientBuilder {
+        let ca = Certificate::from_pem(&std::fs::read(fixture(&format!("{ca}.pem"))).unwrap()).unwrap();
+        Client::builder()
+            .use_rustls_tls()
+            .tls_built_in_root_certs(false)
+            .add_root_certificate(ca)
+            .pool_max_idle_per_host(0)
+    }
+
+    async fn fetch(client: &Client, port: u16) -> reqwest::Result<String> {
+        client.get(format!("https://localhost:{port}/")).send().await?.text().await
+    }
+
+    #[tokio::test]
+    async fn certificates_are_reloaded_when_the_files_change() {
+        let (tls, directory) = configuration("server1");
+        let port = serve(tls.clone()).await;
+        let first = client("ca1").build().unwrap();
+        let second = client("ca2").build().unwrap();
+        assert!(fetch(&first, port).await.is_ok());
+        assert!(fetch(&second, port).await.is_err());
+
+        // A half-written pair is ignored until it's complete
+        std::fs::write(&tls.cert_path, "").unwrap();
+        assert!(load_server_config(&tls).is_err());
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(fetch(&first, port).await.is_ok());
+
+        install(&tls, "server2");
+        assert!(load_server_config(&tls).is_ok());
+        let mut reloaded = false;
+        for _ in 0..30 {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            if fetch(&second, port).await.is_ok() {
+                reloaded = true;
+                break;
+            }
+        }
+        assert!(reloaded, "the new certificate was not served");
+        assert!(fetch(&first, port).await.is_err());
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn client_certificates_are_passed_to_requests() {
+        let (mut tls, directory) = configuration("server1");
+        tls.client_ca_path = Some(fixture("ca1.pem"));
+        let port = serve(tls).await;
+
+        let mut identity = std::fs::read(fixture("client.pem")).unwrap();
+        identity.extend(std::fs::read(fixture("client.key")).unwrap());
+        let with_certificate = client("ca1").identity(Identity::from_pem(&identity).unwrap()).build().unwrap();
+        assert_eq!(fetch(&with_certificate, port).await.unwrap(), "reporting");
+        let without = client("ca1").build().unwrap();
+        assert_eq!(fetch(&without, port).await.unwrap(), "");
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn plain_http_is_redirected_to_the_public_host() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(serve_redirect(listener, "shop.example.com:8443".to_string()));
+
+        let client = Client::builder().redirect(Policy::none()).build().unwrap();
+        let response = client
+            .get(format!("http://127.0.0.1:{port}/api/v1/books?page=2"))
+            .header(header::HOST, "attacker.example.com")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        assert_eq!(
+            response.headers()[header::LOCATION],
+            "https://shop.example.com:8443/api/v1/books?page=2"
+        );
+    }
+}
@@ -0,0 +1,62 @@
+use serde::{Deserialize, Serialize};
+use crate::service_config::{check_writable_file, string_list};
+
+/// The `webhooks` section of the service configuration.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct WebhooksConfiguration {
+    pub db_filename: String,
+    /// Deliveries that have failed this many times are dead-lettered.
+    pub max_attempts: i64,
+    /// The wait before the first retry, in seconds. It doubles with each
+    /// failed attempt, up to `max_backoff_seconds`.
+    pub initial_backoff_seconds: i64,
+    pub max_backoff_seconds: i64,
+    /// How long a receiver has to answer, in seconds.
+    pub timeout_seconds: u64,
+    /// How often to look for retries that are due, in seconds.
+    pub poll_seconds: u64,
+    /// The most deliveries sent at once.
+    pub batch_size: i64,
+    /// Delivered and dead deliveries are deleted after this many days.
+    pub keep_days: i64,
+    /// Hosts that may receive webhooks even though they're on a private
+    /// network, such as a receiver inside the deployment. Others must be
+    /// public.
+    #[serde(deserialize_with = "string_list")]
+    pub allowed_hosts: Vec<String>,
+}
+
+impl Default for WebhooksConfiguration {
+    fn default() -> Self {
+        Self {
+            db_filename: "webhooks.db".to_string(),
+            max_attempts: 8,
+            initial_backoff_seconds: 10,
+            max_backoff_seconds: 60 * 60,
+            timeout_seconds: 10,
+            poll_seconds: 5,
+            batch_size: 20,
+            keep_days: 30,
+            allowed_hosts: Vec::new(),
+        }
+    }
+}
+
+impl WebhooksConfiguration {
+    pub fn validate(&self, problems: &mut Vec<String>) {
+        check_writable_file("webhooks.db_filename", &self.db_filename, problems);
+        if self.max_attempts < 1
+            || self.initial_backoff_seconds < 1
+            || self.timeout_seconds == 0
+            || self.poll_seconds == 0
+            || self.batch_size < 1
+            || self.keep_days < 1
+        {
+            problems.push("webhooks: limits must be greater than zero".to_string());
+        }
+        if self.max_backoff_seconds < self.initial_backoff_seconds {
+            problems.push("webhooks.max_backoff_seconds: must be at least initial_backoff_seconds".to_string());
+        }
+    }
+}
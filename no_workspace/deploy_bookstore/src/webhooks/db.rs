@@ -0,0 +1,251 @@
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use sqlx::{migrate::Migrator, prelude::FromRow, sqlite::SqliteConnectOptions};
+use crate::event_bus::DomainEvent;
+
+#[derive(Clone)]
+pub struct WebhooksDb(pub sqlx::SqlitePool);
+
+pub async fn get_connection_pool(filename: &str) -> Result<WebhooksDb> {
+    let options = SqliteConnectOptions::new()
+        .filename(filename)
+        .create_if_missing(true);
+
+    let connection_pool = sqlx::SqlitePool::connect_lazy_with(options);
+    Ok(WebhooksDb(connection_pool))
+}
+
+/// This build's migrations, up and down.
+pub static MIGRATOR: Migrator = sqlx::migrate!("src/webhooks/migrations");
+
+pub async fn perform_migrations(db_pool: WebhooksDb) -> Result<()> {
+    crate::migrations::migrate("webhooks", &MIGRATOR, &db_pool.0).await
+}
+
+pub async fn ping(db_pool: WebhooksDb) -> Result<()> {
+    sqlx::query("SELECT 1").execute(&db_pool.0).await?;
+    Ok(())
+}
+
+/// Where to send which events. The secret isn't included.
+#[derive(Serialize, Debug, FromRow)]
+pub struct Subscription {
+    pub id: i32,
+    pub url: String,
+    #[sqlx(json)]
+    pub event_types: Vec<String>,
+    pub created_at: i64,
+}
+
+pub async fn list_subscriptions(db_pool: WebhooksDb) -> Result<Vec<Subscription>> {
+    let subscriptions = sqlx::query_as::<_, Subscription>(
+        "SELECT id, url, event_types, created_at FROM subscriptions ORDER BY id",
+    )
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(subscriptions)
+}
+
+pub async fn add_subscription(db_pool: WebhooksDb, url: &str, event_types: &[String], secret: &str) -> Result<i32> {
+    let id = sqlx::query_scalar("INSERT INTO subscriptions (url, event_types, secret) VALUES (?, ?, ?) RETURNING id")
+        .bind(url)
+        .bind(serde_json::to_string(event_types)?)
+        .bind(secret)
+        .fetch_one(&db_pool.0)
+        .await?;
+    Ok(id)
+}
+
+/// Deletes a subscription with its deliveries. Returns false if there's no
+/// such subscription.
+pub async fn delete_subscription(db_pool: WebhooksDb, id: i32) -> Result<bool> {
+    let deleted = sqlx::query("DELETE FROM subscriptions WHERE id = ?")
+        .bind(id)
+        .execute(&db_pool.0)
+        .await?
+        .rows_affected();
+    Ok(deleted > 0)
+}
+
+/// Queues an event for every subscription that wants it, returning how
+/// many deliveries were queued. Subscriptions the event is already queued
+/// for are skipped.
+pub async fn enqueue(db_pool: WebhooksDb, event: &DomainEvent) -> Result<u64> {
+    let queued = sqlx::query(
+        "INSERT OR IGNORE INTO deliveries (subscription_id, event_id, event_type, payload)
+         SELECT id, ?1, ?2, ?3 FROM subscriptions
+         WHERE EXISTS (SELECT 1 FROM json_each(event_types) WHERE value = ?2)",
+    )
+        .bind(&event.id)
+        .bind(&event.event_type)
+        .bind(serde_json::to_string(event)?)
+        .execute(&db_pool.0)
+        .await?
+        .rows_affected();
+    Ok(queued)
+}
+
+/// Where a delivery is up to.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum DeliveryStatus {
+    Pending,
+    Delivered,
+    /// Gave up after too many failed attempts.
+    Dead,
+}
+
+impl DeliveryStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Pending => "pending",
+            Self::Delivered => "delivered",
+            Self::Dead => "dead",
+        }
+    }
+
+    fn parse(status: &str) -> Self {
+        match status {
+            "delivered" => Self::Delivered,
+            "dead" => Self::Dead,
+            _ => Self::Pending,
+        }
+    }
+}
+
+impl From<String> for DeliveryStatus {
+    fn from(status: String) -> Self {
+        Self::parse(&status)
+    }
+}
+
+/// A delivery that's due to be attempted, with what's needed to send it.
+#[derive(Debug, FromRow)]
+pub struct DueDelivery {
+    pub id: i32,
+    pub url: String,
+    pub secret: String,
+    pub event_id: String,
+    pub event_type: String,
+    pub payload: String,
+    pub attempts: i64,
+}
+
+/// The oldest deliveries whose next attempt is due, up to `limit`.
+pub async fn due_deliveries(db_pool: WebhooksDb, limit: i64) -> Result<Vec<DueDelivery>> {
+    let deliveries = sqlx::query_as::<_, DueDelivery>(
+        "SELECT deliveries.id, url, secret, event_id, event_type, payload, attempts
+         FROM deliveries JOIN subscriptions ON subscriptions.id = subscription_id
+         WHERE status = 'pending' AND next_attempt_at <= unixepoch()
+         ORDER BY next_attempt_at, deliveries.id LIMIT ?",
+    )
+        .bind(limit)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(deliveries)
+}
+
+pub async fn mark_delivered(db_pool: WebhooksDb, id: i32, response_status: u16) -> Result<()> {
+    sqlx::query(
+        "UPDATE deliveries SET status = 'delivered', attempts = attempts + 1, last_response_status = ?,
+         last_error = NULL, updated_at = unixepoch() WHERE id = ?",
+    )
+        .bind(response_status)
+        .bind(id)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+/// Records a failed attempt. The delivery is retried after `retry_in`
+/// seconds, or dead-lettered if that's `None`.
+pub async fn mark_failed(
+    db_pool: WebhooksDb,
+    id: i32,
+    response_status: Option<u16>,
+    error: &str,
+    retry_in: Option<i64>,
+) -> Result<()> {
+    let status = match retry_in {
+        Some(_) => DeliveryStatus::Pending,
+        None => DeliveryStatus::Dead,
+    };
+    sqlx::query(
+        "UPDATE deliveries SET status = ?, attempts = attempts + 1, last_response_status = ?, last_error = ?,
+         next_attempt_at = unixepoch() + ?, updated_at = unixepoch() WHERE id = ?",
+    )
+        .bind(status.as_str())
+        .bind(response_status)
+        .bind(error)
+        .bind(retry_in.unwrap_or(0))
+        .bind(id)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
+
+/// An entry in the delivery log.
+#[derive(Serialize, Debug, FromRow)]
+pub struct Delivery {
+    pub id: i32,
+    pub subscription_id: i32,
+    pub event_id: String,
+    pub event_type: String,
+    #[sqlx(json)]
+    pub payload: serde_json::Value,
+    #[sqlx(try_from = "String")]
+    pub status: DeliveryStatus,
+    pub attempts: i64,
+    /// When a pending delivery will next be attempted.
+    pub next_attempt_at: i64,
+    pub last_response_status: Option<i64>,
+    pub last_error: Option<String>,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+/// Lists deliveries, newest first, optionally only one subscription's or
+/// only those with a status.
+pub async fn list_deliveries(
+    db_pool: WebhooksDb,
+    subscription_id: Option<i32>,
+    status: Option<DeliveryStatus>,
+    limit: i64,
+    offset: i64,
+) -> Result<Vec<Delivery>> {
+    let deliveries = sqlx::query_as::<_, Delivery>(
+        "SELECT * FROM deliveries
+         WHERE (?1 IS NULL OR subscription_id = ?1) AND (?2 IS NULL OR status = ?2)
+         ORDER BY id DESC LIMIT ?3 OFFSET ?4",
+    )
+        .bind(subscription_id)
+        .bind(status.map(DeliveryStatus::as_str))
+        .bind(limit)
+        .bind(offset)
+        .fetch_all(&db_pool.0)
+        .await?;
+    Ok(deliveries)
+}
+
+/// Queues a dead delivery again, with a fresh set of attempts. Returns
+/// false if there's no such dead delivery.
+pub async fn retry_delivery(db_pool: WebhooksDb, id: i32) -> Result<bool> {
+    let updated = sqlx::query(
+        "UPDATE deliveries SET status = 'pending', attempts = 0, next_attempt_at = unixepoch(),
+         updated_at = unixepoch() WHERE id = ? AND status = 'dead'",
+    )
+        .bind(id)
+        .execute(&db_pool.0)
+        .await?
+        .rows_affected();
+    Ok(updated > 0)
+}
+
+/// Deletes finished deliveries older than `days`.
+pub async fn purge_finished(db_pool: WebhooksDb, days: i64) -> Result<()> {
+    sqlx::query("DELETE FROM deliveries WHERE status != 'pending' AND updated_at < unixepoch() - ? * 86400")
+        .bind(days)
+        .execute(&db_pool.0)
+        .await?;
+    Ok(())
+}
@@ -0,0 +1,375 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use anyhow::Result;
+use async_trait::async_trait;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use tokio::{sync::Notify, task::JoinSet};
+use crate::event_bus::{DomainEvent, OutboxSink};
+use super::{
+    configuration::WebhooksConfiguration,
+    db::{self, DueDelivery, WebhooksDb},
+    destinations::Destinations,
+};
+
+pub const SIGNATURE_HEADER: &str = "x-webhook-signature";
+pub const EVENT_ID_HEADER: &str = "x-webhook-id";
+pub const EVENT_TYPE_HEADER: &str = "x-webhook-event";
+
+/// Signs a payload as `t=<timestamp>,v1=<signature>`, where the signature
+/// is the hex HMAC-SHA256 of `<timestamp>.<body>`. Signing the timestamp
+/// lets receivers turn away old requests replayed at them.
+pub fn sign(secret: &str, timestamp: i64, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC takes any key length");
+    mac.update(format!("{timestamp}.{body}").as_bytes());
+    format!("t={timestamp},v1={:x}", mac.finalize().into_bytes())
+}
+
+/// How long to wait, in seconds, before retrying a delivery that has
+/// failed `attempts` times.
+pub fn backoff(config: &WebhooksConfiguration, attempts: i64) -> i64 {
+    let doublings = (attempts - 1).clamp(0, 30);
+    config
+        .initial_backoff_seconds
+        .saturating_mul(1 << doublings)
+        .min(config.max_backoff_seconds)
+}
+
+/// Queues the events another module's outbox relays, such as the
+/// catalogue's or the users'. An event is only accepted once its
+/// deliveries are written to the webhooks database, so the outbox never
+/// moves past one that could still be lost.
+pub struct QueueSink {
+    pub db_pool: WebhooksDb,
+    pub wake: Arc<Notify>,
+}
+
+#[async_trait]
+impl OutboxSink for QueueSink {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    async fn publish(&self, event: &DomainEvent) -> Result<()> {
+        if db::enqueue(self.db_pool.clone(), event).await? > 0 {
+            self.wake.notify_one();
+        }
+        Ok(())
+    }
+}
+
+/// Sends queued deliveries to their subscribers.
+#[derive(Clone)]
+pub struct Deliverer {
+    db_pool: WebhooksDb,
+    config: WebhooksConfiguration,
+    destinations: Destinations,
+    http: reqwest::Client,
+}
+
+impl Deliverer {
+    pub fn new(db_pool: WebhooksDb, config: WebhooksConfiguration) -> Result<Self> {
+        // Redirects aren't followed, so a receiver can't bounce deliveries
+        // somewhere else, and names are only resolved to public addresses
+        let destinations = Destinations::new(&config.allowed_hosts);
+        let http = reqwest::Client::builder()
+            .timeout(Duration::from_secs(config.timeout_seconds))
+            .redirect(reqwest::redirect::Policy::none())
+            .dns_resolver(Arc::new(destinations.clone()))
+            .build()?;
+        Ok(Self {
+            db_pool,
+            config,
+            destinations,
+            http,
+        })
+    }
+
+    /// Delivers for as long as the service is up: whenever `wake` is
+    /// notified of new deliveries, and every `poll_seconds` for retries.
+    pub async fn run(self, wake: Arc<Notify>) {
+        let poll = Duration::from_secs(self.config.poll_seconds);
+        loop {
+            match self.deliver_due().await {
+                // There may be more waiting
+                Ok(count) if count as i64 == self.config.batch_size => continue,
+                Ok(_) => {}
+                Err(e) => tracing::warn!("Unable to deliver webhooks: {e:#}"),
+            }
+            let _ = tokio::time::timeout(poll, wake.notified()).await;
+        }
+    }
+
+    /// Attempts every due delivery, up to `batch_size` at once. Returns how
+    /// many were attempted.
+    pub async fn deliver_due(&self) -> Result<usize> {
+        let due = db::due_deliveries(self.db_pool.clone(), self.config.batch_size).await?;
+        let count = due.len();
+
+        let mut attempts = JoinSet::new();
+        for delivery in due {
+            let deliverer = self.clone();
+            attempts.spawn(async move { deliverer.attempt(delivery).await });
+        }
+        while let Some(attempt) = attempts.join_next().await {
+            if let Ok(Err(e)) = attempt {
+                tracing::warn!("Unable to record a webhook delivery: {e:#}");
+            }
+        }
+        Ok(count)
+    }
+
+    async fn attempt(&self, delivery: DueDelivery) -> Result<()> {
+        // Checked again, as a name may resolve somewhere else by now
+        let destination = match reqwest::Url::parse(&delivery.url) {
+            Ok(url) => self.destinations.check(&url).await,
+            Err(e) => Err(e.to_string()),
+        };
+        if let Err(error) = destination {
+            return self.failed(delivery, None, error).await;
+        }
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_secs() as i64);
+        let response = self
+            .http
+            .post(&delivery.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(SIGNATURE_HEADER, sign(&delivery.secret, timestamp, &delivery.payload))
+            .header(EVENT_ID_HEADER, &delivery.event_id)
+            .header(EVENT_TYPE_HEADER, &delivery.event_type)
+            .body(delivery.payload.clone())
+            .send()
+            .await;
+
+        let (response_status, error) = match response {
+            Ok(response) if response.status().is_success() => {
+                return db::mark_delivered(self.db_pool.clone(), delivery.id, response.status().as_u16()).await;
+            }
+            Ok(response) => (
+                Some(response.status().as_u16()),
+                format!("receiver answered {}", response.status()),
+            ),
+            Err(e) => (None, e.to_string()),
+        };
+        self.failed(delivery, response_status, error).await
+    }
+
+    /// Records a failed attempt, scheduling a retry or dead-lettering it.
+    async fn failed(&self, delivery: DueDelivery, response_status: Option<u16>, error: String) -> Result<()> {
+        let attempts = delivery.attempts + 1;
+        let retry_in = (attempts < self.config.max_attempts).then(|| backoff(&self.config, attempts));
+        if retry_in.is_none() {
+            tracing::warn!("Webhook delivery {} failed {attempts} times; giving up: {error}", delivery.id);
+        }
+        db::mark_failed(self.db_pool.clone(), delivery.id, response_status, &error, retry_in).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+    use axum::{extract::State, http::{HeaderMap, StatusCode}, routing::post, Router};
+    use crate::{
+        event_bus::DomainEvent,
+        test_harness::TestApp,
+        webhooks::db::{Delivery, DeliveryStatus},
+    };
+    use super::*;
+
+    type Received = Arc<Mutex<Vec<(HeaderMap, String)>>>;
+
+    const SECRET: &str = "a-test-secret-of-some-length";
+
+    /// The webhooks database of an app, which deletes it when it's dropped.
+    async fn test_db() -> (TestApp, WebhooksDb) {
+        let app = TestApp::new().await;
+        let db_pool = db::get_connection_pool(&app.config.webhooks.db_filename).await.unwrap();
+        (app, db_pool)
+    }
+
+    /// Lets deliveries go to receivers on this machine.
+    fn config() -> WebhooksConfiguration {
+        WebhooksConfiguration {
+            allowed_hosts: vec!["127.0.0.1".to_string()],
+            ..Default::default()
+        }
+    }
+
+    /// Starts a receiver that records each request and answers `status`,
+    /// returning its URL.
+    async fn receiver(status: StatusCode) -> (String, Received) {
+        let received = Received::default();
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(move |State(received): State<Received>, headers: HeaderMap, body: String| async move {
+                    received.lock().unwrap().push((headers, body));
+                    status
+                }),
+            )
+            .with_state(received.clone());
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        (url, received)
+    }
+
+    fn event(event_type: &str) -> DomainEvent {
+        DomainEvent {
+            id: uuid::Uuid::new_v4().to_string(),
+            event_type: event_type.to_string(),
+            occurred_at: 1_700_000_000,
+            data: serde_json::json!({ "book_id": 1 }),
+        }
+    }
+
+    async fn only_delivery(db_pool: &WebhooksDb) -> Delivery {
+        let mut deliveries = db::list_deliveries(db_pool.clone(), None, None, 10, 0).await.unwrap();
+        assert_eq!(deliveries.len(), 1);
+        deliveries.remove(0)
+    }
+
+    #[tokio::test]
+    async fn delivers_signed_events_to_matching_subscriptions() {
+        let (_app, db_pool) = test_db().await;
+        let (url, received) = receiver(StatusCode::NO_CONTENT).await;
+        db::add_subscription(db_pool.clone(), &url, &["book.added".to_string()], SECRET).await.unwrap();
+
+        let added = event("book.added");
+        assert_eq!(db::enqueue(db_pool.clone(), &added).await.unwrap(), 1);
+        assert_eq!(db::enqueue(db_pool.clone(), &event("user.created")).await.unwrap(), 0);
+
+        let deliverer = Deliverer::new(db_pool.clone(), config()).unwrap();
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 1);
+
+        let (headers, body) = {
+            let mut received = received.lock().unwrap();
+            assert_eq!(received.len(), 1);
+            received.remove(0)
+        };
+        assert_eq!(headers[EVENT_ID_HEADER], added.id.as_str());
+        assert_eq!(headers[EVENT_TYPE_HEADER], "book.added");
+        let payload: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(payload["type"], "book.added");
+        assert_eq!(payload["data"]["book_id"], 1);
+
+        let signature = headers[SIGNATURE_HEADER].to_str().unwrap();
+        let timestamp: i64 = signature
+            .strip_prefix("t=")
+            .and_then(|rest| rest.split(',').next())
+            .and_then(|timestamp| timestamp.parse().ok())
+            .unwrap();
+        assert_eq!(signature, sign(SECRET, timestamp, &body));
+        assert_ne!(signature, sign("some-other-secret", timestamp, &body));
+
+        let delivery = only_delivery(&db_pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Delivered);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_response_status, Some(204));
+    }
+
+    #[tokio::test]
+    async fn failed_deliveries_are_retried_later() {
+        let (_app, db_pool) = test_db().await;
+        let (url, received) = receiver(StatusCode::INTERNAL_SERVER_ERROR).await;
+        db::add_subscription(db_pool.clone(), &url, &["book.added".to_string()], SECRET).await.unwrap();
+        db::enqueue(db_pool.clone(), &event("book.added")).await.unwrap();
+
+        let config = config();
+        let deliverer = Deliverer::new(db_pool.clone(), config.clone()).unwrap();
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 1);
+
+        let delivery = only_delivery(&db_pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 1);
+        assert_eq!(delivery.last_response_status, Some(500));
+        assert!(delivery.next_attempt_at >= delivery.updated_at + config.initial_backoff_seconds);
+
+        // Not due again until the backoff has passed
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 0);
+        assert_eq!(received.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_after_the_last_attempt() {
+        let (_app, db_pool) = test_db().await;
+        // Nothing is listening here once the receiver's port is released
+        let url = {
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            format!("http://{}/hook", listener.local_addr().unwrap())
+        };
+        db::add_subscription(db_pool.clone(), &url, &["user.deleted".to_string()], SECRET).await.unwrap();
+        db::enqueue(db_pool.clone(), &event("user.deleted")).await.unwrap();
+
+        let config = WebhooksConfiguration {
+            max_attempts: 1,
+            ..config()
+        };
+        let deliverer = Deliverer::new(db_pool.clone(), config).unwrap();
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 1);
+
+        let delivery = only_delivery(&db_pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Dead);
+        assert_eq!(delivery.last_response_status, None);
+        assert!(delivery.last_error.is_some());
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 0);
+
+        // An admin can queue it again
+        assert!(db::retry_delivery(db_pool.clone(), delivery.id).await.unwrap());
+        let delivery = only_delivery(&db_pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert_eq!(delivery.attempts, 0);
+    }
+
+    #[tokio::test]
+    async fn private_receivers_are_refused_at_delivery() {
+        let (_app, db_pool) = test_db().await;
+        let (url, received) = receiver(StatusCode::NO_CONTENT).await;
+        db::add_subscription(db_pool.clone(), &url, &["book.added".to_string()], SECRET).await.unwrap();
+        db::enqueue(db_pool.clone(), &event("book.added")).await.unwrap();
+
+        let deliverer = Deliverer::new(db_pool.clone(), WebhooksConfiguration::default()).unwrap();
+        assert_eq!(deliverer.deliver_due().await.unwrap(), 1);
+
+        assert!(received.lock().unwrap().is_empty());
+        let delivery = only_delivery(&db_pool).await;
+        assert_eq!(delivery.status, DeliveryStatus::Pending);
+        assert!(delivery.last_error.unwrap().contains("private address"));
+    }
+
+    #[tokio::test]
+    async fn outbox_events_are_queued_once() {
+        let (_app, db_pool) = test_db().await;
+        db::add_subscription(db_pool.clone(), "https://example.com/hook", &["book.added".to_string()], SECRET)
+            .await
+            .unwrap();
+        let sink = QueueSink {
+            db_pool: db_pool.clone(),
+            wake: Arc::new(Notify::new()),
+        };
+
+        // The outbox gives an event again if it stopped before moving on
+        let added = event("book.added");
+        sink.publish(&added).await.unwrap();
+        sink.publish(&added).await.unwrap();
+        sink.publish(&event("book.deleted")).await.unwrap();
+        assert_eq!(only_delivery(&db_pool).await.event_id, added.id);
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_limit() {
+        let config = WebhooksConfiguration {
+            initial_backoff_seconds: 10,
+            max_backoff_seconds: 100,
+            ..Default::default()
+        };
+        let waits: Vec<i64> = (1..=6).map(|attempts| backoff(&config, attempts)).collect();
+        assert_eq!(waits, [10, 20, 40, 80, 100, 100]);
+        assert_eq!(backoff(&config, 1000), 100);
+    }
+}
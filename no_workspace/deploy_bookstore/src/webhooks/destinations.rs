@@ -0,0 +1,162 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+};
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Url,
+};
+
+/// Where webhooks may be sent. Receivers must be on the public internet, so
+/// that a subscription can't reach the service's own network: loopback,
+/// private and link-local addresses are refused, which covers cloud
+/// metadata endpoints such as 169.254.169.254. Hosts in `allowed_hosts` are
+/// let through whatever they resolve to.
+///
+/// Used as the deliverer's resolver, so the addresses checked are the ones
+/// connected to, even if a name resolves differently by then.
+#[derive(Clone, Debug)]
+pub struct Destinations {
+    allowed_hosts: Arc<[String]>,
+}
+
+impl Destinations {
+    pub fn new(allowed_hosts: &[String]) -> Self {
+        Self {
+            allowed_hosts: allowed_hosts.into(),
+        }
+    }
+
+    fn is_allowed_host(&self, host: &str) -> bool {
+        self.allowed_hosts.iter().any(|allowed| allowed.eq_ignore_ascii_case(host))
+    }
+
+    /// Checks that `url` is somewhere webhooks may go, resolving its host.
+    /// The error says why not.
+    pub async fn check(&self, url: &Url) -> Result<(), String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("url must be http or https".to_string());
+        }
+        let Some(host) = url.host_str() else {
+            return Err("url has no host".to_string());
+        };
+        // IPv6 hosts are written in brackets
+        let host = host.trim_start_matches('[').trim_end_matches(']');
+        if self.is_allowed_host(host) {
+            return Ok(());
+        }
+        let addresses = match host.parse::<IpAddr>() {
+            Ok(address) => vec![address],
+            Err(_) => self.lookup(host).await?,
+        };
+        match addresses.into_iter().find(|address| !is_public(*address)) {
+            Some(address) => Err(format!("{host} is a private address ({address})")),
+            None => Ok(()),
+        }
+    }
+
+    async fn lookup(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let addresses: Vec<IpAddr> = tokio::net::lookup_host((host, 0))
+            .await
+            .map_err(|e| format!("unable to resolve {host}: {e}"))?
+            .map(|address| address.ip())
+            .collect();
+        if addresses.is_empty() {
+            return Err(format!("unable to resolve {host}"));
+        }
+        Ok(addresses)
+    }
+}
+
+impl Resolve for Destinations {
+    fn resolve(&self, name: Name) -> Resolving {
+        let destinations = self.clone();
+        Box::pin(async move {
+            let host = name.as_str();
+            let addresses = destinations.lookup(host).await?;
+            if !destinations.is_allowed_host(host) {
+                if let Some(address) = addresses.iter().find(|address| !is_public(**address)) {
+                    return Err(format!("{host} is a private address ({address})").into());
+                }
+            }
+            let addresses: Addrs = Box::new(addresses.into_iter().map(|address| SocketAddr::new(address, 0)));
+            Ok(addresses)
+        })
+    }
+}
+
+/// Whether `address` is on the public internet.
+fn is_public(address: IpAddr) -> bool {
+    match address {
+        IpAddr::V4(address) => {
+            let [first, second, ..] = address.octets();
+            // 100.64.0.0/10 is shared by carriers, and hosts some clouds'
+            // metadata services
+            let shared = first == 100 && (64..128).contains(&second);
+            !(address.is_loopback()
+                || address.is_private()
+                || address.is_link_local()
+                || address.is_unspecified()
+                || address.is_broadcast()
+                || address.is_multicast()
+                || address.is_documentation()
+                || shared
+                || first == 0)
+        }
+        IpAddr::V6(address) => {
+            if let Some(mapped) = address.to_ipv4_mapped() {
+                return is_public(mapped.into());
+            }
+            let first = address.segments()[0];
+            let unique_local = first & 0xfe00 == 0xfc00;
+            let link_local = first & 0xffc0 == 0xfe80;
+            !(address.is_loopback() || address.is_unspecified() || address.is_multicast() || unique_local || link_local)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn check(destinations: &Destinations, url: &str) -> Result<(), String> {
+        destinations.check(&Url::parse(url).unwrap()).await
+    }
+
+    #[test]
+    fn private_addresses_arent_public() {
+        for address in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.100.100.200",
+            "0.0.0.0",
+            "::1",
+            "fd00:ec2::254",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(address.parse().unwrap()), "{address}");
+        }
+        for address in ["93.184.215.14", "2606:2800:21f:cb07:6820:80da:af6b:8b2c"] {
+            assert!(is_public(address.parse().unwrap()), "{address}");
+        }
+    }
+
+    #[tokio::test]
+    async fn private_hosts_are_refused_unless_allowed() {
+        let destinations = Destinations::new(&[]);
+        assert!(check(&destinations, "http://169.254.169.254/latest/meta-data").await.is_err());
+        assert!(check(&destinations, "http://[::1]:8080/hook").await.is_err());
+        assert!(check(&destinations, "http://localhost:8080/hook").await.is_err());
+        assert!(check(&destinations, "ftp://93.184.215.14/hook").await.is_err());
+        assert!(check(&destinations, "https://93.184.215.14/hook").await.is_ok());
+
+        let destinations = Destinations::new(&["LOCALHOST".to_string(), "::1".to_string()]);
+        assert!(check(&destinations, "http://localhost:8080/hook").await.is_ok());
+        assert!(check(&destinations, "http://[::1]:8080/hook").await.is_ok());
+        assert!(check(&destinations, "http://127.0.0.1:8080/hook").await.is_err());
+    }
+}
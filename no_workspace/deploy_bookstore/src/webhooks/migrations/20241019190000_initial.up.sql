@@ -0,0 +1,32 @@
+CREATE TABLE subscriptions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    url TEXT NOT NULL,
+    -- A JSON array of event types, such as ["book.added", "user.created"]
+    event_types TEXT NOT NULL,
+    -- Kept as it is, since payloads are signed with it
+    secret TEXT NOT NULL,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+-- The delivery queue, which doubles as the delivery log. Each event gets a
+-- row per subscription, which is retried until it's delivered or dead.
+CREATE TABLE deliveries (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    subscription_id INTEGER NOT NULL REFERENCES subscriptions (id) ON DELETE CASCADE,
+    event_id TEXT NOT NULL,
+    event_type TEXT NOT NULL,
+    payload TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    attempts INTEGER NOT NULL DEFAULT 0,
+    next_attempt_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    last_response_status INTEGER,
+    last_error TEXT,
+    created_at INTEGER NOT NULL DEFAULT (unixepoch()),
+    updated_at INTEGER NOT NULL DEFAULT (unixepoch())
+);
+
+CREATE INDEX deliveries_due ON deliveries (status, next_attempt_at);
+CREATE INDEX deliveries_subscription ON deliveries (subscription_id, id);
+-- An event given again, such as by the catalogue's outbox after a restart,
+-- isn't queued twice
+CREATE UNIQUE INDEX deliveries_event ON deliveries (subscription_id, event_id);
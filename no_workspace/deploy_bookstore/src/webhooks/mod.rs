@@ -0,0 +1,146 @@
+mod configuration;
+mod db;
+mod delivery;
+mod destinations;
+mod web_service;
+use std::{sync::Arc, time::Duration};
+use anyhow::Result;
+use async_trait::async_trait;
+use axum::{middleware, routing::{get, post}, Extension, Router};
+use sqlx::migrate::Migrator;
+use tokio::sync::Notify;
+use crate::{
+    auth::auth_layers::{self, Scope},
+    cors::RouteGroup,
+    event_bus::OutboxSink,
+    modules::{BackgroundTask, Database, ModuleContext, ServiceModule},
+    idempotency::IdempotencyLayer,
+    rate_limit::RateLimitLayer,
+};
+use db::WebhooksDb;
+use delivery::{Deliverer, QueueSink};
+use destinations::Destinations;
+
+pub use configuration::WebhooksConfiguration;
+
+/// How often old deliveries are cleared out of the log.
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Sends the service's events to subscribers' URLs, signed, retrying
+/// failures until they're delivered or dead-lettered.
+pub struct WebhooksModule {
+    config: WebhooksConfiguration,
+    db_pool: WebhooksDb,
+    deliverer: Deliverer,
+    /// Wakes the deliverer when there's something new to send.
+    wake: Arc<Notify>,
+}
+
+impl WebhooksModule {
+    /// Events given to `sink()` are queued for delivery.
+    pub async fn open(config: WebhooksConfiguration) -> Result<Self> {
+        let db_pool = db::get_connection_pool(&config.db_filename).await?;
+        let deliverer = Deliverer::new(db_pool.clone(), config.clone())?;
+        Ok(Self {
+            config,
+            db_pool,
+            deliverer,
+            wake: Arc::new(Notify::new()),
+        })
+    }
+
+    /// Queues the events of another module's outbox, such as the
+    /// catalogue's or the users'.
+    pub fn sink(&self) -> Arc<dyn OutboxSink> {
+        Arc::new(QueueSink {
+            db_pool: self.db_pool.clone(),
+            wake: self.wake.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl ServiceModule for WebhooksModule {
+    fn name(&self) -> &'static str {
+        "webhooks"
+    }
+
+    fn mount_path(&self) -> &'static str {
+        "/api/v1/webhooks"
+    }
+
+    fn route_group(&self) -> RouteGroup {
+        RouteGroup::Webhooks
+    }
+
+    fn migrator(&self) -> &'static Migrator {
+        &db::MIGRATOR
+    }
+
+    async fn migrate(&self) -> Result<()> {
+        db::perform_migrations(self.db_pool.clone()).await
+    }
+
+    async fn router(&self, context: &ModuleContext) -> Result<Router> {
+        setup_service(
+            self.db_pool.clone(),
+            Destinations::new(&self.config.allowed_hosts),
+            self.wake.clone(),
+            context.rate_limit.clone(),
+            context.idempotency.clone(),
+        )
+        .await
+    }
+
+    async fn health_check(&self) -> Result<()> {
+        db::ping(self.db_pool.clone()).await
+    }
+
+    fn database(&self) -> Database {
+        Database {
+            filename: self.config.db_filename.clone(),
+            pool: self.db_pool.0.clone(),
+        }
+    }
+
+    async fn background_tasks(&self) -> Result<Vec<BackgroundTask>> {
+        let mut tasks: Vec<BackgroundTask> = vec![Box::pin(self.deliverer.clone().run(self.wake.clone()))];
+
+        let db_pool = self.db_pool.clone();
+        let keep_days = self.config.keep_days;
+        tasks.push(Box::pin(async move {
+            let mut interval = tokio::time::interval(PURGE_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = db::purge_finished(db_pool.clone(), keep_days).await {
+                    tracing::warn!("Unable to purge old webhook deliveries: {e:#}");
+                }
+            }
+        }));
+        Ok(tasks)
+    }
+}
+
+async fn setup_service(
+    db_pool: WebhooksDb,
+    destinations: Destinations,
+    wake: Arc<Notify>,
+    rate_limit: RateLimitLayer,
+    idempotency: IdempotencyLayer,
+) -> Result<Router> {
+    let router = Router::new()
+        .route("/", get(web_service::list_subscriptions))
+        .route("/add", post(web_service::add_subscription))
+        .route("/delete/:id", post(web_service::delete_subscription))
+        .route("/deliveries", get(web_service::list_deliveries))
+        .route("/deliveries/retry/:id", post(web_service::retry_delivery))
+        .route_layer(middleware::from_fn_with_state(Scope::WebhooksAdmin, auth_layers::require_scope))
+        .route_layer(idempotency)
+        .route_layer(rate_limit)
+        .route_layer(middleware::from_fn(auth_layers::require_token))
+        .layer(Extension(destinations))
+        .layer(Extension(wake))
+        .layer(Extension(db_pool));
+
+    Ok(router)
+}
@@ -0,0 +1,141 @@
+use std::sync::Arc;
+use axum::{extract::{Path, Query}, http::StatusCode, Extension, Json};
+use serde::{Deserialize, Serialize};
+use tokio::sync::Notify;
+use crate::event_bus::EVENT_TYPES;
+use super::{
+    db::{self, Delivery, DeliveryStatus, Subscription, WebhooksDb},
+    destinations::Destinations,
+};
+
+type ApiError = (StatusCode, String);
+
+fn internal(_: anyhow::Error) -> ApiError {
+    (StatusCode::INTERNAL_SERVER_ERROR, "database error".to_string())
+}
+
+/// Secrets shorter than this are too easy to guess.
+const MIN_SECRET_LENGTH: usize = 16;
+
+pub async fn list_subscriptions(
+    Extension(db_pool): Extension<WebhooksDb>,
+) -> Result<Json<Vec<Subscription>>, ApiError> {
+    let subscriptions = db::list_subscriptions(db_pool).await.map_err(internal)?;
+    Ok(Json(subscriptions))
+}
+
+#[derive(Deserialize, Debug)]
+pub struct NewSubscription {
+    url: String,
+    event_types: Vec<String>,
+    /// Generated if it's left out.
+    secret: Option<String>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct CreatedSubscription {
+    id: i32,
+    /// For checking signatures. It isn't shown again.
+    secret: String,
+}
+
+/// Subscribes a URL to events. It must be on the public internet, unless
+/// its host is one of `allowed_hosts`.
+pub async fn add_subscription(
+    Extension(db_pool): Extension<WebhooksDb>,
+    Extension(destinations): Extension<Destinations>,
+    Json(subscription): Json<NewSubscription>,
+) -> Result<(StatusCode, Json<CreatedSubscription>), ApiError> {
+    let url = reqwest::Url::parse(&subscription.url)
+        .map_err(|_| (StatusCode::UNPROCESSABLE_ENTITY, "url is invalid".to_string()))?;
+    destinations
+        .check(&url)
+        .await
+        .map_err(|problem| (StatusCode::UNPROCESSABLE_ENTITY, problem))?;
+    if subscription.event_types.is_empty() {
+        return Err((StatusCode::UNPROCESSABLE_ENTITY, "event_types is empty".to_string()));
+    }
+    if let Some(unknown) = subscription
+        .event_types
+        .iter()
+        .find(|event_type| !EVENT_TYPES.contains(&event_type.as_str()))
+    {
+        return Err((
+            StatusCode::UNPROCESSABLE_ENTITY,
+            format!("unknown event type {unknown}; expected one of {}", EVENT_TYPES.join(", ")),
+        ));
+    }
+
+    let secret = match subscription.secret {
+        Some(secret) if secret.len() < MIN_SECRET_LENGTH => {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                format!("secret must be at least {MIN_SECRET_LENGTH} characters"),
+            ));
+        }
+        Some(secret) => secret,
+        None => format!("whsec_{}{}", uuid::Uuid::new_v4().simple(), uuid::Uuid::new_v4().simple()),
+    };
+
+    let id = db::add_subscription(db_pool, url.as_str(), &subscription.event_types, &secret)
+        .await
+        .map_err(internal)?;
+    tracing::info!("Added webhook subscription {id} for {url}");
+    Ok((StatusCode::CREATED, Json(CreatedSubscription { id, secret })))
+}
+
+/// Deletes a subscription, along with its queued and logged deliveries.
+pub async fn delete_subscription(
+    Extension(db_pool): Extension<WebhooksDb>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    if !db::delete_subscription(db_pool, id).await.map_err(internal)? {
+        return Err((StatusCode::NOT_FOUND, "no such subscription".to_string()));
+    }
+    Ok(StatusCode::OK)
+}
+
+#[derive(Deserialize, Debug)]
+pub struct DeliveryQuery {
+    subscription_id: Option<i32>,
+    status: Option<DeliveryStatus>,
+    #[serde(default = "default_limit")]
+    limit: i64,
+    #[serde(default)]
+    offset: i64,
+}
+
+fn default_limit() -> i64 {
+    100
+}
+
+/// The delivery log, newest first, optionally filtered by subscription or
+/// status. `?status=dead` lists the dead letters.
+pub async fn list_deliveries(
+    Extension(db_pool): Extension<WebhooksDb>,
+    Query(query): Query<DeliveryQuery>,
+) -> Result<Json<Vec<Delivery>>, ApiError> {
+    let deliveries = db::list_deliveries(
+        db_pool,
+        query.subscription_id,
+        query.status,
+        query.limit.clamp(1, 1000),
+        query.offset.max(0),
+    )
+    .await
+    .map_err(internal)?;
+    Ok(Json(deliveries))
+}
+
+/// Queues a dead delivery again.
+pub async fn retry_delivery(
+    Extension(db_pool): Extension<WebhooksDb>,
+    Extension(wake): Extension<Arc<Notify>>,
+    Path(id): Path<i32>,
+) -> Result<StatusCode, ApiError> {
+    if !db::retry_delivery(db_pool, id).await.map_err(internal)? {
+        return Err((StatusCode::NOT_FOUND, "no such dead delivery".to_string()));
+    }
+    wake.notify_one();
+    Ok(StatusCode::OK)
+}